@@ -1,17 +1,51 @@
 use std::cell::RefCell;
 use std::env;
+#[cfg(feature = "readline")]
+use std::io::IsTerminal;
 use std::io::{self, Write};
+use std::ops::Range;
+#[cfg(feature = "readline")]
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::Instant;
 
 pub mod mem_storage;
+pub mod output;
 pub mod tokenizer;
 
-use crate::mem_storage::{ExecuteResult, Table, execute_statement};
-use crate::tokenizer::{MetaCommandResult, PrepareResult, Statement, do_meta_command};
+use crate::mem_storage::{ExecuteResult, Table, column_names, execute_statement};
+use crate::output::{Output, OutputMode};
+use crate::tokenizer::{
+    MetaCommandResult, PrepareError, do_meta_command, prepare, split_statements,
+};
+#[cfg(feature = "readline")]
+use rustyline::error::ReadlineError;
+
+const PROMPT: &str = "db > ";
+// Kept in the home directory
+#[cfg(feature = "readline")]
+const HISTORY_FILE: &str = ".rustlite_history";
 
 pub struct InputBuffer {
     buffer: String,
     input_lenght: usize,
+    // Set when the input is a terminal
+    editor: Option<Editor>,
+}
+
+// What the meta commands set for the rest of the session
+#[derive(Default)]
+pub struct Settings {
+    pub mode: OutputMode,
+    // Statements are followed by how long they took and the pages they
+    // read and wrote
+    pub timer: bool,
+}
+
+impl Default for InputBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl InputBuffer {
@@ -19,21 +53,104 @@ impl InputBuffer {
         Self {
             buffer: String::new(),
             input_lenght: 0,
+            editor: Editor::open(),
         }
     }
 
-    pub fn read_input(&mut self) {
-        print!("db > ");
-        io::stdout().flush().unwrap();
-
+    // False once the input has ended
+    pub fn read_input(&mut self) -> bool {
         self.buffer.clear();
-        io::stdin()
-            .read_line(&mut self.buffer)
-            .expect("Failed to read line");
+        let read = match &mut self.editor {
+            Some(editor) => editor.read_line(&mut self.buffer),
+            None => {
+                print!("{}", PROMPT);
+                io::stdout().flush().unwrap();
+                io::stdin()
+                    .read_line(&mut self.buffer)
+                    .expect("Failed to read line")
+                    > 0
+            }
+        };
+        if !read {
+            println!();
+            return false;
+        }
 
         // Remove the last char -> \n
-        self.buffer.pop().unwrap();
+        if self.buffer.ends_with('\n') {
+            self.buffer.pop();
+        }
         self.input_lenght = self.buffer.trim_end().len();
+        true
+    }
+
+    pub fn save_history(&mut self) {
+        if let Some(editor) = &mut self.editor {
+            editor.save_history();
+        }
+    }
+}
+
+// Reads the lines typed at a terminal, with arrow key editing, Ctrl-R to
+// search the history and the history kept across sessions
+#[cfg(feature = "readline")]
+struct Editor {
+    editor: rustyline::DefaultEditor,
+    history: Option<PathBuf>,
+}
+
+#[cfg(feature = "readline")]
+impl Editor {
+    fn open() -> Option<Self> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+        let mut editor = rustyline::DefaultEditor::new().ok()?;
+        let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        if let Some(path) = &history {
+            // There is none yet the first time
+            let _ = editor.load_history(path);
+        }
+        Some(Self { editor, history })
+    }
+
+    // False once Ctrl-D ends the input. Ctrl-C drops the line being typed.
+    fn read_line(&mut self, buffer: &mut String) -> bool {
+        match self.editor.readline(PROMPT) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = self.editor.add_history_entry(line.as_str());
+                }
+                *buffer = line;
+                true
+            }
+            Err(ReadlineError::Interrupted) => true,
+            Err(_) => false,
+        }
+    }
+
+    fn save_history(&mut self) {
+        if let Some(path) = &self.history {
+            let _ = self.editor.save_history(path);
+        }
+    }
+}
+
+#[cfg(not(feature = "readline"))]
+enum Editor {}
+
+#[cfg(not(feature = "readline"))]
+impl Editor {
+    fn open() -> Option<Self> {
+        None
+    }
+
+    fn read_line(&mut self, _buffer: &mut String) -> bool {
+        match *self {}
+    }
+
+    fn save_history(&mut self) {
+        match *self {}
     }
 }
 
@@ -45,13 +162,22 @@ fn main() {
     }
 
     let filename = &args[1];
-    let table = Rc::new(RefCell::new(Table::db_open(filename.as_str())));
+    // An optional key the database is encrypted with
+    let key = args.get(2).map(String::as_str);
+    let table = match Table::db_open(filename.as_str(), key) {
+        Ok(table) => Rc::new(RefCell::new(table)),
+        Err(message) => {
+            println!("{}", message);
+            std::process::exit(0);
+        }
+    };
     let mut input_buffer = InputBuffer::new();
+    let mut settings = Settings::default();
 
-    loop {
-        input_buffer.read_input();
+    // The database is written out and closed however the session ends
+    while input_buffer.read_input() {
         if input_buffer.buffer.starts_with('.') {
-            match do_meta_command(&input_buffer, &mut table.borrow_mut()) {
+            match do_meta_command(&input_buffer, &table, &mut settings) {
                 MetaCommandResult::CommandSuccess => {
                     continue;
                 }
@@ -59,40 +185,189 @@ fn main() {
                     println!("Unrecognized command '{}'", input_buffer.buffer);
                     continue;
                 }
+                MetaCommandResult::CommandFailed(result) => {
+                    print_result(result);
+                    continue;
+                }
+                MetaCommandResult::Exit => break,
             }
         }
 
-        let mut statement = Statement::new();
-        match statement.prepare_statement(&input_buffer) {
-            PrepareResult::Success => {}
-            PrepareResult::UnrecognizedStatement => {
-                println!(
-                    "Unrecognized keyword at start of '{}'.",
-                    input_buffer.buffer
-                );
-                continue;
-            }
-            PrepareResult::SyntaxError => {
-                println!("Syntax error. Could not parse the statement.");
-                continue;
-            }
-            PrepareResult::StringTooLong => {
-                println!("String is too long.");
-                continue;
-            }
-            PrepareResult::IdIssue => {
-                println!("Id must be a positive integer.");
-                continue;
-            }
+        for sql in split_statements(&input_buffer.buffer) {
+            run_statement(&table, &settings, &sql);
         }
+    }
+    input_buffer.save_history();
+    if let Err(error) = table.borrow_mut().db_close() {
+        print_result(error.into());
+    }
+}
 
-        match execute_statement(Rc::clone(&table), &statement) {
-            ExecuteResult::Success => {
-                println!("Executed.");
-            }
-            ExecuteResult::TableFull => {
-                println!("Error: Table full.");
-            }
+// Prepare and execute one statement, printing its outcome
+fn run_statement(table: &Rc<RefCell<Table>>, settings: &Settings, sql: &str) {
+    let statement = match prepare(sql) {
+        Ok(statement) => statement,
+        Err(PrepareError::UnrecognizedStatement) => {
+            println!("Unrecognized keyword at start of '{}'.", sql);
+            return;
+        }
+        Err(PrepareError::SyntaxError(error)) => {
+            println!("Syntax error: {}.", error.message);
+            print_error_position(sql, &error.span);
+            return;
+        }
+        Err(PrepareError::StringTooLong) => {
+            println!("String is too long.");
+            return;
         }
+        Err(PrepareError::ParameterCountMismatch) => {
+            println!("Wrong number of parameters.");
+            return;
+        }
+    };
+
+    let names = column_names(&table.borrow(), &statement);
+    let mut output = Output::new(settings.mode, names);
+    let started = Instant::now();
+    let before = table.borrow().page_counts();
+    let result = execute_statement(Rc::clone(table), &statement, &mut |values| {
+        output.row(values)
+    });
+    output.finish();
+    print_result(result);
+    if table.borrow().unsynced() {
+        println!("Not on disk until the commit window ends.");
+    }
+    if settings.timer {
+        let after = table.borrow().page_counts();
+        println!(
+            "Run Time: {:.6} s, {} read, {} written.",
+            started.elapsed().as_secs_f64(),
+            plural(after.read - before.read, "page"),
+            plural(after.written - before.written, "page")
+        );
     }
 }
+
+fn print_result(result: ExecuteResult) {
+    match result {
+        ExecuteResult::Success => {
+            println!("Executed.");
+        }
+        ExecuteResult::Affected(rows) => {
+            println!("Executed. {} affected.", plural(rows, "row"));
+        }
+        ExecuteResult::Returned(rows) => {
+            println!("Executed. {} returned.", plural(rows, "row"));
+        }
+        ExecuteResult::Plan(plan) => {
+            print!("{}", plan);
+            println!("Executed.");
+        }
+        ExecuteResult::TableFull => {
+            println!("Error: Table full.");
+        }
+        ExecuteResult::TableExists => {
+            println!("Error: Table already exists.");
+        }
+        ExecuteResult::NoSuchTable => {
+            println!("Error: No such table.");
+        }
+        ExecuteResult::NoSuchColumn => {
+            println!("Error: No such column.");
+        }
+        ExecuteResult::ColumnExists => {
+            println!("Error: Column already exists.");
+        }
+        ExecuteResult::TypeMismatch => {
+            println!("Error: Value does not match the column type.");
+        }
+        ExecuteResult::StringTooLong => {
+            println!("Error: String is too long.");
+        }
+        ExecuteResult::ColumnCountMismatch => {
+            println!("Error: Column count does not match.");
+        }
+        ExecuteResult::RowTooLarge => {
+            println!("Error: Table definition is too large.");
+        }
+        ExecuteResult::NotNullViolation => {
+            println!("Error: NOT NULL constraint failed.");
+        }
+        ExecuteResult::UniqueViolation => {
+            println!("Error: UNIQUE constraint failed.");
+        }
+        ExecuteResult::DuplicateKey => {
+            println!("Error: Duplicate key.");
+        }
+        ExecuteResult::CheckViolation => {
+            println!("Error: CHECK constraint failed.");
+        }
+        ExecuteResult::UnboundParameters => {
+            println!("Error: Statement has unbound parameters.");
+        }
+        ExecuteResult::TriggerExists => {
+            println!("Error: Trigger already exists.");
+        }
+        ExecuteResult::IndexExists => {
+            println!("Error: Index already exists.");
+        }
+        ExecuteResult::NoSuchSavepoint => {
+            println!("Error: No such savepoint.");
+        }
+        ExecuteResult::NoSuchPragma => {
+            println!("Error: No such pragma.");
+        }
+        ExecuteResult::InvalidPragmaValue => {
+            println!("Error: Invalid pragma value.");
+        }
+        ExecuteResult::PageSizeFixed => {
+            println!("Error: Page size can only be set before the database is written.");
+        }
+        ExecuteResult::PageTooSmall => {
+            println!("Error: Pages have to be larger than a file system block to compress.");
+        }
+        ExecuteResult::Corrupt => {
+            println!("Error: Database disk image is malformed.");
+        }
+        ExecuteResult::Locked => {
+            println!("Error: Database is locked.");
+        }
+        ExecuteResult::NoSuchRow => {
+            println!("Error: No such row.");
+        }
+        ExecuteResult::ExpiredBlob => {
+            println!("Error: The row of the blob has changed.");
+        }
+        ExecuteResult::ReadOnlyBlob => {
+            println!("Error: Blob column is indexed or constrained, it cannot be written.");
+        }
+        ExecuteResult::CannotOpen(message) => {
+            println!("Error: {}", message);
+        }
+        ExecuteResult::IoError(message) => {
+            println!("Error: Disk I/O error, {}.", message);
+        }
+    }
+}
+
+// The count followed by the noun, in the plural unless the count is one
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        _ => format!("{} {}s", count, noun),
+    }
+}
+
+// The line of the statement the span starts on, with carets under the span
+fn print_error_position(sql: &str, span: &Range<usize>) {
+    let line_start = sql[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = sql[span.start..]
+        .find('\n')
+        .map_or(sql.len(), |i| span.start + i);
+    let indent = sql[line_start..span.start].chars().count();
+    let width = sql[span.start..span.end.min(line_end)].chars().count();
+
+    println!("{}", &sql[line_start..line_end]);
+    println!("{}{}", " ".repeat(indent), "^".repeat(width.max(1)));
+}
@@ -1,313 +1,9215 @@
 use std::{
-    cell::{RefCell, RefMut},
+    borrow::Cow,
+    cell::{Ref, RefCell},
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
+    fmt,
     fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
-    rc::Rc,
+    hash::{Hash, Hasher},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::{Bound, RangeInclusive},
+    rc::{Rc, Weak},
+    time::{Duration, Instant},
 };
 
-use crate::tokenizer::{Statement, StatementType};
+use crate::tokenizer::{
+    Aggregate, ArithOp, CommonTable, CompareOp, CreateIndexStmt, DeleteStmt, Expr, Function,
+    Having, InsertStmt, Join, JoinKind, OrderBy, PragmaStmt, SelectItem, SelectStmt, Statement,
+    parse_expr, parse_timestamp, prepare, row_references, split_statements,
+};
 
 pub enum ExecuteResult {
     Success,
+    // Rows an insert or a delete wrote, and rows a select returned
+    Affected(usize),
+    Returned(usize),
+    // The plan EXPLAIN shows, one node per line
+    Plan(String),
     TableFull,
+    TableExists,
+    NoSuchTable,
+    NoSuchColumn,
+    ColumnExists,
+    TypeMismatch,
+    StringTooLong,
+    ColumnCountMismatch,
+    RowTooLarge,
+    NotNullViolation,
+    UniqueViolation,
+    DuplicateKey,
+    CheckViolation,
+    // The statement has ? placeholders that were never bound
+    UnboundParameters,
+    TriggerExists,
+    IndexExists,
+    NoSuchSavepoint,
+    NoSuchPragma,
+    InvalidPragmaValue,
+    // The database was already written with its page size
+    PageSizeFixed,
+    // Pages no larger than a block of the file system take as many blocks
+    // compressed
+    PageTooSmall,
+    // A page read from disk didn't match its checksum, or a row or a schema
+    // in one couldn't be read
+    Corrupt,
+    // Another process has the database open, so nothing can be written
+    Locked,
+    NoSuchRow,
+    // The row of an open blob was changed or deleted since it was opened
+    ExpiredBlob,
+    // The blob's column has an index or a constraint a write would bypass
+    ReadOnlyBlob,
+    // The database file could not be opened, with the reason why
+    CannotOpen(String),
+    // Reading or writing a file failed, with the reason why
+    IoError(String),
 }
 
-const ID_SIZE: usize = 4;
-pub const USERNAME_SIZE: usize = 32;
-pub const EMAIL_SIZE: usize = 255;
+impl From<io::Error> for ExecuteResult {
+    fn from(error: io::Error) -> Self {
+        ExecuteResult::IoError(error.to_string())
+    }
+}
 
-const ID_OFFSET: usize = 0;
-const USERNAME_OFFSET: usize = ID_OFFSET + ID_SIZE;
-const EMAIL_OFFSET: usize = USERNAME_OFFSET + USERNAME_SIZE;
-const ROW_SIZE: usize = ID_SIZE + USERNAME_SIZE + EMAIL_SIZE;
+impl ExecuteResult {
+    // Whether the statement was carried out, whatever it hands back
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self,
+            ExecuteResult::Success
+                | ExecuteResult::Affected(_)
+                | ExecuteResult::Returned(_)
+                | ExecuteResult::Plan(_)
+        )
+    }
+}
 
-pub struct Row {
-    pub id: u32,
-    pub username: String,
-    pub email: String,
+// A literal or column value. NULL sorts first, then numbers, dates and
+// timestamps, text and blobs.
+#[derive(Clone)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Boolean(bool),
+    // Seconds since 1970-01-01 00:00:00 UTC, dates are at midnight
+    Date(i64),
+    Timestamp(i64),
+    Text(String),
+    Blob(Vec<u8>),
 }
 
-impl Row {
-    pub fn new() -> Self {
-        Self {
-            id: 0,
-            username: String::new(),
-            email: String::new(),
-        }
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
     }
 
-    pub fn serialize_row(&self) -> Vec<u8> {
-        let mut buffer = Vec::with_capacity(ID_SIZE + USERNAME_SIZE + EMAIL_SIZE);
-        buffer.extend(self.id.to_le_bytes());
+    // Booleans behave as the integers 0 and 1, dates and timestamps as
+    // their number of seconds
+    fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) | Value::Date(i) | Value::Timestamp(i) => Some(*i),
+            Value::Boolean(b) => Some(*b as i64),
+            _ => None,
+        }
+    }
 
-        let mut username_bytes = [0u8; USERNAME_SIZE];
-        username_bytes[..self.username.len()].copy_from_slice(self.username.as_bytes());
-        buffer.extend(&username_bytes);
+    fn as_real(&self) -> f64 {
+        match self {
+            Value::Real(r) => *r,
+            _ => self.as_integer().map_or(f64::NAN, |i| i as f64),
+        }
+    }
 
-        let mut email_bytes = [0u8; EMAIL_SIZE];
-        email_bytes[..self.email.len()].copy_from_slice(self.email.as_bytes());
-        buffer.extend(&email_bytes);
+    // The value written as a literal the parser reads back as the same value
+    pub fn to_sql(&self) -> String {
+        match self {
+            Value::Null => "null".to_string(),
+            Value::Date(_) => format!("date '{}'", self),
+            Value::Timestamp(_) => format!("timestamp '{}'", self),
+            Value::Text(s) => format!("'{}'", s.replace('\'', "''")),
+            _ => self.to_string(),
+        }
+    }
 
-        buffer
+    // Position of the value's type in the sort order
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Integer(_) | Value::Real(_) | Value::Boolean(_) => 1,
+            Value::Date(_) | Value::Timestamp(_) => 2,
+            Value::Text(_) => 3,
+            Value::Blob(_) => 4,
+        }
     }
 
-    pub fn deserialize_row(buffer: &[u8]) -> Option<Self> {
-        if buffer.len() < ID_SIZE + USERNAME_SIZE + EMAIL_SIZE {
-            return None;
+    // The value as a number for sum and avg. Text that doesn't look like a
+    // number counts as zero.
+    fn to_number(&self) -> Value {
+        match self {
+            Value::Null | Value::Integer(_) | Value::Real(_) => self.clone(),
+            Value::Boolean(_) | Value::Date(_) | Value::Timestamp(_) => {
+                Value::Integer(self.as_integer().unwrap_or_default())
+            }
+            Value::Text(s) => match s.trim().parse::<i64>() {
+                Ok(i) => Value::Integer(i),
+                Err(_) => Value::Real(s.trim().parse().unwrap_or(0.0)),
+            },
+            Value::Blob(_) => Value::Integer(0),
         }
+    }
+}
 
-        let id = u32::from_le_bytes(buffer[ID_OFFSET..ID_SIZE].try_into().ok()?);
-        let username_bytes = &buffer[USERNAME_OFFSET..USERNAME_OFFSET + USERNAME_SIZE];
-        let email_bytes = &buffer[EMAIL_OFFSET..EMAIL_OFFSET + EMAIL_SIZE];
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank()
+            .cmp(&other.rank())
+            .then_with(|| match (self, other) {
+                (Value::Null, Value::Null) => Ordering::Equal,
+                (Value::Text(a), Value::Text(b)) => a.cmp(b),
+                (Value::Blob(a), Value::Blob(b)) => a.cmp(b),
+                (a, b) => match (a.as_integer(), b.as_integer()) {
+                    (Some(a), Some(b)) => a.cmp(&b),
+                    // Integers and reals compare by numeric value
+                    _ => a.as_real().total_cmp(&b.as_real()),
+                },
+            })
+    }
+}
 
-        let username = String::from_utf8(username_bytes.to_vec()).ok()?;
-        let email = String::from_utf8(email_bytes.to_vec()).ok()?;
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-        Some(Row {
-            id,
-            username,
-            email,
-        })
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
     }
 }
 
-const PAGE_SIZE: usize = 4096;
-const TABLE_MAX_PAGES: usize = 100;
-const ROWS_PER_PAGE: usize = PAGE_SIZE / ROW_SIZE;
-const TABLE_MAX_ROWS: usize = ROWS_PER_PAGE * TABLE_MAX_PAGES;
+impl Eq for Value {}
 
-pub struct Pager {
-    file: File,
-    file_length: usize,
-    pages: [Option<Box<[u8; PAGE_SIZE]>>; TABLE_MAX_PAGES],
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Null => {}
+            Value::Integer(i) | Value::Date(i) | Value::Timestamp(i) => i.hash(state),
+            Value::Boolean(b) => (*b as i64).hash(state),
+            // Whole reals equal an integer, so they must hash like one
+            Value::Real(r) if r.fract() == 0.0 => (*r as i64).hash(state),
+            Value::Real(r) => r.to_bits().hash(state),
+            Value::Text(s) => s.hash(state),
+            Value::Blob(b) => b.hash(state),
+        }
+    }
 }
 
-impl Pager {
-    pub fn pager_open(filename: &str) -> Self {
-        let file = match OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(filename)
-        {
-            Ok(f) => f,
-            Err(_) => {
-                println!("Unable to open file.");
-                std::process::exit(0);
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Real(r) => write!(f, "{:?}", r),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Date(seconds) => {
+                let (year, month, day) = civil_from_days(seconds.div_euclid(SECONDS_PER_DAY));
+                write!(f, "{:04}-{:02}-{:02}", year, month, day)
             }
-        };
+            Value::Timestamp(seconds) => {
+                let (year, month, day) = civil_from_days(seconds.div_euclid(SECONDS_PER_DAY));
+                let time = seconds.rem_euclid(SECONDS_PER_DAY);
+                write!(
+                    f,
+                    "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                    year,
+                    month,
+                    day,
+                    time / 3600,
+                    time / 60 % 60,
+                    time % 60
+                )
+            }
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Blob(bytes) => {
+                write!(f, "x'")?;
+                for byte in bytes {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, "'")
+            }
+        }
+    }
+}
+
+pub const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Year, month and day of a day number counted from 1970-01-01, using the
+// proleptic Gregorian calendar
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // Shift the epoch to 0000-03-01 so leap days fall at the end of a year
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+// The day number of a date, the inverse of civil_from_days
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// Text and blob columns hold up to this many bytes unless given a size
+pub const DEFAULT_TEXT_SIZE: usize = 255;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Integer,
+    Real,
+    Boolean,
+    Date,
+    Timestamp,
+    // Maximum length in bytes
+    Text(usize),
+    Blob(usize),
+}
 
-        let metadata = match file.metadata() {
-            Ok(m) => m,
-            Err(_) => {
-                println!("Unable to get metadata.");
-                std::process::exit(0);
+// Text and blob values longer than this keep their first bytes in the row,
+// followed by the first of the overflow pages holding the rest of them
+const MAX_INLINE_SIZE: usize = DEFAULT_TEXT_SIZE;
+const TEXT_LENGTH_SIZE: usize = 4;
+const OVERFLOW_PAGE_SIZE: usize = 4;
+
+impl ColumnType {
+    // Bytes the largest value of the column takes in a row, see write_field
+    fn max_field_size(self) -> usize {
+        match self {
+            ColumnType::Integer | ColumnType::Real => 8,
+            ColumnType::Date | ColumnType::Timestamp => 8,
+            ColumnType::Boolean => 1,
+            ColumnType::Text(size) | ColumnType::Blob(size) if size > MAX_INLINE_SIZE => {
+                TEXT_LENGTH_SIZE + MAX_INLINE_SIZE + OVERFLOW_PAGE_SIZE
             }
-        };
+            ColumnType::Text(size) | ColumnType::Blob(size) => TEXT_LENGTH_SIZE + size,
+        }
+    }
 
-        let file_length = metadata.len() as usize;
+    // Whether values of the column can go on onto overflow pages
+    fn spills(self) -> bool {
+        matches!(self, ColumnType::Text(size) | ColumnType::Blob(size) if size > MAX_INLINE_SIZE)
+    }
 
-        Self {
-            file,
-            file_length,
-            pages: std::array::from_fn(|_| None),
+    // Bytes the field at the start of the row bytes takes, and how many
+    // bytes of its value are kept on overflow pages
+    fn field_size(self, field: &[u8]) -> Option<(usize, usize)> {
+        match self {
+            ColumnType::Text(_) | ColumnType::Blob(_) => {
+                let length = read_u32(field.get(..TEXT_LENGTH_SIZE)?, 0);
+                match length > MAX_INLINE_SIZE {
+                    true => Some((
+                        TEXT_LENGTH_SIZE + MAX_INLINE_SIZE + OVERFLOW_PAGE_SIZE,
+                        length - MAX_INLINE_SIZE,
+                    )),
+                    false => Some((TEXT_LENGTH_SIZE + length, 0)),
+                }
+            }
+            _ => Some((self.max_field_size(), 0)),
         }
     }
 
-    pub fn get_page_mut(&mut self, page_num: usize) -> &mut [u8; PAGE_SIZE] {
-        if page_num > TABLE_MAX_PAGES {
-            println!(
-                "Tried to fetch page number out of bounds. {} > {}",
-                page_num, TABLE_MAX_PAGES
-            );
-            std::process::exit(0);
+    // Type code and size as stored on the schema page
+    fn code(self) -> (u8, usize) {
+        match self {
+            ColumnType::Integer => (1, 0),
+            ColumnType::Real => (2, 0),
+            ColumnType::Boolean => (3, 0),
+            ColumnType::Text(size) => (4, size),
+            ColumnType::Blob(size) => (5, size),
+            ColumnType::Date => (6, 0),
+            ColumnType::Timestamp => (7, 0),
+        }
+    }
+
+    fn from_code(code: u8, size: usize) -> Option<Self> {
+        match code {
+            1 => Some(ColumnType::Integer),
+            2 => Some(ColumnType::Real),
+            3 => Some(ColumnType::Boolean),
+            4 => Some(ColumnType::Text(size)),
+            5 => Some(ColumnType::Blob(size)),
+            6 => Some(ColumnType::Date),
+            7 => Some(ColumnType::Timestamp),
+            _ => None,
+        }
+    }
+
+    // Converts a value for storage in a column of this type, None when the
+    // value can't be represented. NULL fits every type.
+    pub fn coerce(self, value: Value) -> Option<Value> {
+        match (self, value) {
+            (_, Value::Null) => Some(Value::Null),
+            (ColumnType::Integer, Value::Real(r)) if r.fract() == 0.0 => {
+                Some(Value::Integer(r as i64))
+            }
+            (ColumnType::Integer, Value::Text(s)) => s.trim().parse().ok().map(Value::Integer),
+            (ColumnType::Date | ColumnType::Timestamp, Value::Text(s)) => {
+                self.coerce(parse_timestamp(&s)?)
+            }
+            (ColumnType::Date, value) => {
+                let seconds = ColumnType::Timestamp.coerce(value)?.as_integer()?;
+                Some(Value::Date(seconds - seconds.rem_euclid(SECONDS_PER_DAY)))
+            }
+            // Integers are taken as seconds since the epoch
+            (ColumnType::Timestamp, Value::Integer(i) | Value::Date(i) | Value::Timestamp(i)) => {
+                Some(Value::Timestamp(i))
+            }
+            (ColumnType::Integer, value) => value.as_integer().map(Value::Integer),
+            (ColumnType::Real, Value::Text(s)) => s.trim().parse().ok().map(Value::Real),
+            (ColumnType::Real, Value::Integer(i)) => Some(Value::Real(i as f64)),
+            (ColumnType::Real, Value::Real(r)) => Some(Value::Real(r)),
+            (ColumnType::Boolean, Value::Boolean(b)) => Some(Value::Boolean(b)),
+            (ColumnType::Boolean, Value::Integer(i @ (0 | 1))) => Some(Value::Boolean(i == 1)),
+            (ColumnType::Boolean, Value::Text(s)) => match s.to_ascii_lowercase().as_str() {
+                "true" => Some(Value::Boolean(true)),
+                "false" => Some(Value::Boolean(false)),
+                _ => None,
+            },
+            (ColumnType::Text(_), Value::Blob(_)) => None,
+            (ColumnType::Text(_), Value::Text(s)) => Some(Value::Text(s)),
+            (ColumnType::Text(_), value) => Some(Value::Text(value.to_string())),
+            (ColumnType::Blob(_), Value::Blob(b)) => Some(Value::Blob(b)),
+            (ColumnType::Blob(_), Value::Text(s)) => Some(Value::Blob(s.into_bytes())),
+            _ => None,
+        }
+    }
+
+    // CAST never fails: values that don't convert become 0, 0.0 or false,
+    // and NULL for dates and timestamps. Text sizes are not enforced.
+    pub fn cast(self, value: Value) -> Value {
+        if value.is_null() {
+            return Value::Null;
+        }
+
+        match self {
+            ColumnType::Integer => match value.to_number() {
+                Value::Real(r) => Value::Integer(r as i64),
+                number => number,
+            },
+            ColumnType::Real => Value::Real(value.to_number().as_real()),
+            ColumnType::Boolean => match self.coerce(value.clone()) {
+                Some(boolean) => boolean,
+                None => Value::Boolean(value.to_number().as_real() != 0.0),
+            },
+            ColumnType::Date | ColumnType::Timestamp => self.coerce(value).unwrap_or(Value::Null),
+            ColumnType::Text(_) => match value {
+                Value::Blob(bytes) => Value::Text(String::from_utf8_lossy(&bytes).into_owned()),
+                value => Value::Text(value.to_string()),
+            },
+            ColumnType::Blob(_) => match value {
+                Value::Blob(bytes) => Value::Blob(bytes),
+                value => Value::Blob(value.to_string().into_bytes()),
+            },
         }
+    }
+
+    // Whether a coerced value is short enough for the column
+    fn fits(self, value: &Value) -> bool {
+        match (self, value) {
+            (ColumnType::Text(size), Value::Text(s)) => s.len() <= size,
+            (ColumnType::Blob(size), Value::Blob(b)) => b.len() <= size,
+            _ => true,
+        }
+    }
 
-        if self.pages[page_num].is_none() {
-            // Allocate memory and load from file
-            self.pages[page_num] = Some(Box::new([0u8; PAGE_SIZE]));
-            let mut num_pages = self.file_length / PAGE_SIZE;
+    // Coerces a value and checks that it fits, ready to be stored
+    fn convert(self, value: Value) -> Result<Value, ExecuteResult> {
+        let Some(value) = self.coerce(value) else {
+            return Err(ExecuteResult::TypeMismatch);
+        };
+        if !self.fits(&value) {
+            return Err(ExecuteResult::StringTooLong);
+        }
+        Ok(value)
+    }
 
-            // We might save a partial page at the end of the file
-            if self.file_length % PAGE_SIZE != 0 {
-                num_pages += 1;
+    // Reads the value of the field at the start of the row bytes, taking
+    // the rest of a long value off the front of the overflow bytes. Returns
+    // the bytes the field takes along with the value, which borrows the
+    // bytes unless part of it was on the overflow.
+    fn read_field<'a>(
+        self,
+        field: &'a [u8],
+        overflow: &mut &'a [u8],
+    ) -> Option<(ValueRef<'a>, usize)> {
+        let number = || field.get(..8)?.try_into().ok();
+        let value = match self {
+            ColumnType::Integer => ValueRef::Integer(i64::from_le_bytes(number()?)),
+            ColumnType::Real => ValueRef::Real(f64::from_le_bytes(number()?)),
+            ColumnType::Boolean => ValueRef::Boolean(*field.first()? != 0),
+            ColumnType::Date => ValueRef::Date(i64::from_le_bytes(number()?)),
+            ColumnType::Timestamp => ValueRef::Timestamp(i64::from_le_bytes(number()?)),
+            ColumnType::Text(_) | ColumnType::Blob(_) => {
+                let (size, overflow_size) = self.field_size(field)?;
+                let length = read_u32(field, 0);
+                let inline = length.min(MAX_INLINE_SIZE);
+                let inline = field.get(TEXT_LENGTH_SIZE..TEXT_LENGTH_SIZE + inline)?;
+                let (rest, remaining) = overflow.split_at_checked(overflow_size)?;
+                *overflow = remaining;
+                let bytes = match rest {
+                    [] => Cow::Borrowed(inline),
+                    rest => Cow::Owned([inline, rest].concat()),
+                };
+                let value = match (self, bytes) {
+                    (ColumnType::Text(_), Cow::Borrowed(bytes)) => {
+                        ValueRef::Text(Cow::Borrowed(std::str::from_utf8(bytes).ok()?))
+                    }
+                    (ColumnType::Text(_), Cow::Owned(bytes)) => {
+                        ValueRef::Text(Cow::Owned(String::from_utf8(bytes).ok()?))
+                    }
+                    (_, bytes) => ValueRef::Blob(bytes),
+                };
+                return Some((value, size));
             }
+        };
+        Some((value, self.max_field_size()))
+    }
+}
+
+// A value read in place from a row's bytes. Text and blobs borrow the
+// bytes, except long ones that continue on overflow pages.
+pub enum ValueRef<'a> {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Boolean(bool),
+    Date(i64),
+    Timestamp(i64),
+    Text(Cow<'a, str>),
+    Blob(Cow<'a, [u8]>),
+}
+
+impl ValueRef<'_> {
+    pub fn is_null(&self) -> bool {
+        matches!(self, ValueRef::Null)
+    }
+
+    pub fn into_value(self) -> Value {
+        match self {
+            ValueRef::Null => Value::Null,
+            ValueRef::Integer(i) => Value::Integer(i),
+            ValueRef::Real(r) => Value::Real(r),
+            ValueRef::Boolean(b) => Value::Boolean(b),
+            ValueRef::Date(d) => Value::Date(d),
+            ValueRef::Timestamp(t) => Value::Timestamp(t),
+            ValueRef::Text(s) => Value::Text(s.into_owned()),
+            ValueRef::Blob(b) => Value::Blob(b.into_owned()),
+        }
+    }
 
-            if page_num <= num_pages {
-                // Move the cursor and read
-                let offset = (page_num * PAGE_SIZE) as u64;
-                let _ = self.file.seek(SeekFrom::Start(offset));
-                let _ = self
-                    .file
-                    .read_exact(self.pages[page_num].as_deref_mut().unwrap());
+    // Compares like the owned value would under the collation. Text and
+    // blobs are compared where they are, other values are cheap to own.
+    fn compare(self, other: &Value, collation: Collation) -> Ordering {
+        match (self, other, collation) {
+            (ValueRef::Text(a), Value::Text(b), Collation::Binary) => a.as_ref().cmp(b.as_str()),
+            (ValueRef::Text(a), Value::Text(b), Collation::NoCase) => {
+                let (a, b) = (a.bytes(), b.bytes());
+                a.map(|c| c.to_ascii_lowercase())
+                    .cmp(b.map(|c| c.to_ascii_lowercase()))
             }
+            (ValueRef::Blob(a), Value::Blob(b), _) => a.as_ref().cmp(b.as_slice()),
+            (value, other, collation) => collation.compare(&value.into_value(), other),
+        }
+    }
+}
+
+impl<'a> From<&'a Value> for ValueRef<'a> {
+    fn from(value: &'a Value) -> Self {
+        match value {
+            Value::Null => ValueRef::Null,
+            Value::Integer(i) => ValueRef::Integer(*i),
+            Value::Real(r) => ValueRef::Real(*r),
+            Value::Boolean(b) => ValueRef::Boolean(*b),
+            Value::Date(d) => ValueRef::Date(*d),
+            Value::Timestamp(t) => ValueRef::Timestamp(*t),
+            Value::Text(s) => ValueRef::Text(Cow::Borrowed(s)),
+            Value::Blob(b) => ValueRef::Blob(Cow::Borrowed(b)),
+        }
+    }
+}
+
+// Appends the value's field to a row: eight bytes for numbers, dates and
+// timestamps, one for booleans, and for text and blobs a four byte length
+// followed by the bytes. The bytes past MAX_INLINE_SIZE go to the overflow
+// bytes instead, and the number of their first overflow page is filled in
+// when the row is stored. NULLs take no bytes.
+fn write_field(value: &Value, row: &mut Vec<u8>, overflow: &mut Vec<u8>) {
+    match value {
+        Value::Null => {}
+        Value::Integer(i) | Value::Date(i) | Value::Timestamp(i) => {
+            row.extend_from_slice(&i.to_le_bytes())
         }
+        Value::Real(r) => row.extend_from_slice(&r.to_le_bytes()),
+        Value::Boolean(b) => row.push(*b as u8),
+        Value::Text(s) => write_bytes(s.as_bytes(), row, overflow),
+        Value::Blob(b) => write_bytes(b, row, overflow),
+    }
+}
+
+// How text values are compared, sorted, grouped and checked for
+// uniqueness. Other values always compare the same way.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Collation {
+    #[default]
+    Binary,
+    // ASCII letters equal their other case
+    NoCase,
+}
 
-        self.pages[page_num].as_deref_mut().unwrap()
+impl Collation {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "binary" => Some(Collation::Binary),
+            "nocase" => Some(Collation::NoCase),
+            _ => None,
+        }
     }
 
-    fn flush(&mut self, page_num: usize, size: usize) {
-        if self.pages[page_num].is_none() {
-            println!("Tried to flush null page.");
-            std::process::exit(0);
+    pub fn compare(self, left: &Value, right: &Value) -> Ordering {
+        match (self, left, right) {
+            (Collation::NoCase, Value::Text(a), Value::Text(b)) => {
+                a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+            }
+            _ => left.cmp(right),
         }
+    }
 
-        let page = self
-            .pages
-            .get(page_num)
-            .and_then(|p| p.as_ref())
-            .expect("Tried to flush null page.");
+    // A value equal to every value the collation considers equal to this
+    // one, for hashing
+    fn key(self, value: Value) -> Value {
+        match (self, value) {
+            (Collation::NoCase, Value::Text(s)) => Value::Text(s.to_ascii_lowercase()),
+            (_, value) => value,
+        }
+    }
+}
 
-        let offset = (page_num * PAGE_SIZE) as u64;
-        let _ = self.file.seek(SeekFrom::Start(offset));
-        let _ = self.file.write_all(&page[..size]);
+impl fmt::Display for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Collation::Binary => write!(f, "binary"),
+            Collation::NoCase => write!(f, "nocase"),
+        }
     }
 }
 
-pub struct Table {
-    pager: Pager,
-    num_rows: usize,
+// The type as written in CREATE TABLE
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColumnType::Integer => write!(f, "integer"),
+            ColumnType::Real => write!(f, "real"),
+            ColumnType::Boolean => write!(f, "boolean"),
+            ColumnType::Date => write!(f, "date"),
+            ColumnType::Timestamp => write!(f, "timestamp"),
+            ColumnType::Text(size) => write!(f, "text({})", size),
+            ColumnType::Blob(size) => write!(f, "blob({})", size),
+        }
+    }
 }
 
-type TableRef = Rc<RefCell<Table>>;
+fn write_bytes(bytes: &[u8], row: &mut Vec<u8>, overflow: &mut Vec<u8>) {
+    row.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    let inline = bytes.len().min(MAX_INLINE_SIZE);
+    row.extend_from_slice(&bytes[..inline]);
+    if bytes.len() > inline {
+        row.extend_from_slice(&[0; OVERFLOW_PAGE_SIZE]);
+        overflow.extend_from_slice(&bytes[inline..]);
+    }
+}
 
-pub struct Cursor {
-    table: TableRef,
-    row_num: usize,
-    end_of_table: bool,
+#[derive(Clone)]
+pub struct ColumnDef {
+    pub name: String,
+    pub ctype: ColumnType,
+    // Used when an insert leaves the column out
+    pub default: Value,
+    pub not_null: bool,
+    pub unique: bool,
+    // Primary key columns are also NOT NULL
+    pub primary_key: bool,
+    pub collation: Collation,
 }
 
-impl Cursor {
-    pub fn from_start(table: TableRef) -> Self {
-        let num_rows = table.borrow().num_rows;
-        Self {
-            table,
-            row_num: 0,
-            end_of_table: (num_rows == 0),
+// The column as written in CREATE TABLE, with the constraints it has
+impl fmt::Display for ColumnDef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.ctype)?;
+        if !self.default.is_null() {
+            write!(f, " default {}", self.default.to_sql())?;
+        }
+        if self.primary_key {
+            write!(f, " primary key")?;
+        } else if self.not_null {
+            write!(f, " not null")?;
+        }
+        if self.unique {
+            write!(f, " unique")?;
         }
+        if self.collation != Collation::Binary {
+            write!(f, " collate {}", self.collation)?;
+        }
+        Ok(())
     }
+}
 
-    pub fn from_end(table: TableRef) -> Self {
-        let num_rows = table.borrow().num_rows;
+impl ColumnDef {
+    pub fn new(name: &str, ctype: ColumnType) -> Self {
         Self {
-            table,
-            row_num: num_rows,
-            end_of_table: true,
+            name: name.to_string(),
+            ctype,
+            default: Value::Null,
+            not_null: false,
+            unique: false,
+            primary_key: false,
+            collation: Collation::Binary,
         }
     }
 
-    pub fn get_value(&self) -> RefMut<[u8; PAGE_SIZE]> {
-        let row_num = self.row_num;
-        let page_num = row_num / ROWS_PER_PAGE;
+    // Defaults are stored already converted to the column's type, in a slot
+    // of the schema page they have to fit in whole
+    fn convert_default(&mut self) -> Result<(), ExecuteResult> {
+        let default = std::mem::replace(&mut self.default, Value::Null);
+        match self.ctype.convert(default)? {
+            Value::Text(s) if s.len() > MAX_INLINE_SIZE => Err(ExecuteResult::StringTooLong),
+            Value::Blob(b) if b.len() > MAX_INLINE_SIZE => Err(ExecuteResult::StringTooLong),
+            default => {
+                self.default = default;
+                Ok(())
+            }
+        }
+    }
+}
 
-        RefMut::map(self.table.borrow_mut(), |table| {
-            table.get_page_mut(page_num)
-        })
+// Column entry on the schema page: zero padded name, type code, size and
+// flags, followed by the default value when there is one
+pub const COLUMN_NAME_SIZE: usize = 32;
+const COLUMN_HEADER_SIZE: usize = COLUMN_NAME_SIZE + 1 + 4 + 1;
+const HAS_DEFAULT: u8 = 1;
+const NOT_NULL: u8 = 2;
+const UNIQUE: u8 = 4;
+const PRIMARY_KEY: u8 = 8;
+const NOCASE: u8 = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TriggerEvent {
+    Insert,
+    Delete,
+}
+
+impl TriggerEvent {
+    // How the trigger's body refers to the row, new.<column> or old.<column>
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            TriggerEvent::Insert => "new",
+            TriggerEvent::Delete => "old",
+        }
     }
 
-    pub fn advance(&mut self) {
-        self.row_num += 1;
-        if self.row_num >= self.table.borrow().num_rows {
-            self.end_of_table = true;
+    fn code(&self) -> u8 {
+        match self {
+            TriggerEvent::Insert => 1,
+            TriggerEvent::Delete => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(TriggerEvent::Insert),
+            2 => Some(TriggerEvent::Delete),
+            _ => None,
         }
     }
 }
 
-impl Table {
-    pub fn db_open(filename: &str) -> Self {
-        let pager = Pager::pager_open(filename);
-        let num_rows = pager.file_length / ROW_SIZE;
+// Statements run after each row the event adds to or removes from a table
+#[derive(Clone)]
+pub struct Trigger {
+    pub name: String,
+    pub event: TriggerEvent,
+    // Semicolon separated statements, as written in create trigger
+    pub body: String,
+}
 
+// An index on one column of a table. Its entries live in a tree of their
+// own, stored in the catalog under the index's name.
+#[derive(Clone)]
+pub struct Index {
+    pub name: String,
+    pub column: String,
+}
+
+// The columns of a table, in the order they are stored
+#[derive(Clone)]
+pub struct Schema {
+    pub columns: Vec<ColumnDef>,
+    // CHECK constraints of the table and of its columns
+    pub checks: Vec<Expr>,
+    pub triggers: Vec<Trigger>,
+    pub indexes: Vec<Index>,
+}
+
+// The id/username/email layout of tables created without a column list,
+// keyed on id
+impl Default for Schema {
+    fn default() -> Self {
+        let mut id = ColumnDef::new("id", ColumnType::Integer);
+        id.primary_key = true;
+        id.not_null = true;
         Self {
-            pager: pager,
-            num_rows,
+            columns: vec![
+                id,
+                ColumnDef::new("username", ColumnType::Text(32)),
+                ColumnDef::new("email", ColumnType::Text(DEFAULT_TEXT_SIZE)),
+            ],
+            checks: Vec::new(),
+            triggers: Vec::new(),
+            indexes: Vec::new(),
         }
     }
+}
 
-    // Flushes the page cache to disk
-    // Closes the database file
-    // Frees the memory for the pager and table data structures
-    pub fn db_close(&mut self) {
-        let pager = &mut self.pager;
-        let num_full_pages = self.num_rows / ROWS_PER_PAGE;
+impl Schema {
+    // A schema of only the columns, without constraints, triggers or
+    // indexes
+    fn with_columns(columns: Vec<ColumnDef>) -> Self {
+        Self {
+            columns,
+            checks: Vec::new(),
+            triggers: Vec::new(),
+            indexes: Vec::new(),
+        }
+    }
+
+    // The columns of the rows a join reads are named after their table as
+    // well, as in users.id. Those are found by their own name too, the
+    // first of them when both tables have it.
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        let exact = self.columns.iter().position(|column| column.name == name);
+        exact.or_else(|| {
+            (self.columns.iter()).position(|column| {
+                column
+                    .name
+                    .split_once('.')
+                    .is_some_and(|(_, column)| column == name)
+            })
+        })
+    }
+
+    // Binary for names that aren't columns of the schema
+    fn collation(&self, name: &str) -> Collation {
+        self.column_index(name)
+            .map_or(Collation::Binary, |index| self.columns[index].collation)
+    }
 
-        for i in 0..num_full_pages {
-            if pager.pages[i].is_none() {
+    pub fn primary_key(&self) -> Option<usize> {
+        self.columns.iter().position(|column| column.primary_key)
+    }
+
+    // The INTEGER PRIMARY KEY column. Its value is the row's key in the
+    // table's tree, tables without one key rows in insertion order.
+    pub fn key_column(&self) -> Option<usize> {
+        self.primary_key()
+            .filter(|&index| self.columns[index].ctype == ColumnType::Integer)
+    }
+
+    // Rows start with their length, their number of columns and one NULL
+    // flag bit per column
+    fn row_header_size(&self) -> usize {
+        ROW_FLAGS_OFFSET + self.columns.len().div_ceil(8)
+    }
+
+    // Bytes the longest row of the table takes, leaving out overflow pages
+    pub fn max_row_size(&self) -> usize {
+        let columns: usize = (self.columns.iter())
+            .map(|column| column.ctype.max_field_size())
+            .sum();
+        self.row_header_size() + columns
+    }
+
+    // The fields of a serialized row whose values go on onto overflow pages:
+    // where the number of their first overflow page is, and the number of
+    // bytes they have there
+    fn overflow_slots(&self, row: &[u8]) -> Vec<(usize, usize)> {
+        let mut slots = Vec::new();
+        let mut offset = stored_header_size(row);
+        for (i, column) in self.columns.iter().take(row_columns(row)).enumerate() {
+            if is_null_field(row, i) {
                 continue;
             }
-            pager.flush(i, PAGE_SIZE);
-            pager.pages[i] = None;
+            let field = row
+                .get(offset..)
+                .and_then(|field| column.ctype.field_size(field));
+            let Some((size, overflow_size)) = field else {
+                break;
+            };
+            offset += size;
+            if overflow_size > 0 {
+                slots.push((offset - OVERFLOW_PAGE_SIZE, overflow_size));
+            }
+        }
+        slots
+    }
+
+    // Overflow pages of the size storing the serialized row takes
+    fn overflow_pages(&self, data: &[u8], usable_size: usize) -> usize {
+        (self.overflow_slots(data).iter())
+            .map(|(_, size)| size.div_ceil(overflow_data_size(usable_size)))
+            .sum()
+    }
+
+    // A default is stored as a row field, it is never long enough for
+    // overflow pages
+    fn column_entry_size(column: &ColumnDef) -> usize {
+        match column.default {
+            Value::Null => COLUMN_HEADER_SIZE,
+            _ => COLUMN_HEADER_SIZE + field_bytes(&column.default).len(),
         }
+    }
+
+    // Bytes the schema takes on its page
+    fn encoded_size(&self) -> usize {
+        let columns: usize = self.columns.iter().map(Self::column_entry_size).sum();
+        let checks: usize = (self.checks.iter())
+            .map(|check| 4 + check.to_string().len())
+            .sum();
+        let triggers: usize = (self.triggers.iter())
+            .map(|trigger| 4 + trigger.name.len() + 1 + 4 + trigger.body.len())
+            .sum();
+        let indexes: usize = (self.indexes.iter())
+            .map(|index| 4 + index.name.len() + 4 + index.column.len())
+            .sum();
+        4 + columns + 4 + checks + 4 + triggers + 4 + indexes
+    }
 
-        // There may be a partial page to write to end of the file
-        let num_additional_rows = self.num_rows % ROWS_PER_PAGE;
-        if num_additional_rows > 0 {
-            let page_num = num_full_pages;
-            if !pager.pages[page_num].is_none() {
-                pager.flush(page_num, num_additional_rows * ROW_SIZE);
-                pager.pages[page_num] = None;
+    // Schema page: number of columns followed by their entries, then the
+    // number of checks followed by the length and SQL text of each, the
+    // number of triggers followed by their name, event and body, then the
+    // number of indexes followed by their name and column
+    fn write_to(&self, page: &mut [u8]) {
+        write_u32(page, 0, self.columns.len());
+        let mut offset = 4;
+        for column in &self.columns {
+            let entry = &mut page[offset..];
+            entry[..column.name.len()].copy_from_slice(column.name.as_bytes());
+            let (code, size) = column.ctype.code();
+            entry[COLUMN_NAME_SIZE] = code;
+            write_u32(entry, COLUMN_NAME_SIZE + 1, size);
+            if column.not_null {
+                entry[COLUMN_NAME_SIZE + 5] |= NOT_NULL;
+            }
+            if column.unique {
+                entry[COLUMN_NAME_SIZE + 5] |= UNIQUE;
+            }
+            if column.primary_key {
+                entry[COLUMN_NAME_SIZE + 5] |= PRIMARY_KEY;
+            }
+            if column.collation == Collation::NoCase {
+                entry[COLUMN_NAME_SIZE + 5] |= NOCASE;
             }
+            if !column.default.is_null() {
+                entry[COLUMN_NAME_SIZE + 5] |= HAS_DEFAULT;
+                let field = field_bytes(&column.default);
+                entry[COLUMN_HEADER_SIZE..COLUMN_HEADER_SIZE + field.len()].copy_from_slice(&field);
+            }
+            offset += Self::column_entry_size(column);
+        }
+
+        write_u32(page, offset, self.checks.len());
+        offset += 4;
+        for check in &self.checks {
+            let text = check.to_string();
+            write_u32(page, offset, text.len());
+            page[offset + 4..offset + 4 + text.len()].copy_from_slice(text.as_bytes());
+            offset += 4 + text.len();
+        }
+
+        write_u32(page, offset, self.triggers.len());
+        offset += 4;
+        for trigger in &self.triggers {
+            write_u32(page, offset, trigger.name.len());
+            offset += 4;
+            page[offset..offset + trigger.name.len()].copy_from_slice(trigger.name.as_bytes());
+            offset += trigger.name.len();
+            page[offset] = trigger.event.code();
+            write_u32(page, offset + 1, trigger.body.len());
+            offset += 5;
+            page[offset..offset + trigger.body.len()].copy_from_slice(trigger.body.as_bytes());
+            offset += trigger.body.len();
         }
 
-        for i in 0..TABLE_MAX_PAGES {
-            if !pager.pages[i].is_none() {
-                pager.pages[i] = None;
+        write_u32(page, offset, self.indexes.len());
+        offset += 4;
+        for index in &self.indexes {
+            for text in [&index.name, &index.column] {
+                write_u32(page, offset, text.len());
+                page[offset + 4..offset + 4 + text.len()].copy_from_slice(text.as_bytes());
+                offset += 4 + text.len();
             }
         }
     }
 
-    fn get_page_mut(&mut self, page_num: usize) -> &mut [u8; PAGE_SIZE] {
-        self.pager.get_page_mut(page_num)
-    }
-}
+    fn read_from(page: &[u8]) -> Option<Self> {
+        let num_columns = read_u32(page, 0);
+        if num_columns == 0 {
+            return None;
+        }
 
-fn execute_insert(table: TableRef, statement: &Statement) -> ExecuteResult {
-    {
-        if table.borrow().num_rows >= TABLE_MAX_ROWS {
-            return ExecuteResult::TableFull;
+        let mut columns = Vec::with_capacity(num_columns);
+        let mut offset = 4;
+        for _ in 0..num_columns {
+            let entry = page.get(offset..offset + COLUMN_HEADER_SIZE)?;
+            let name = String::from_utf8_lossy(trim_padding(&entry[..COLUMN_NAME_SIZE]));
+            let size = read_u32(entry, COLUMN_NAME_SIZE + 1);
+            let ctype = ColumnType::from_code(entry[COLUMN_NAME_SIZE], size)?;
+            let mut column = ColumnDef::new(&name, ctype);
+            column.not_null = entry[COLUMN_NAME_SIZE + 5] & NOT_NULL != 0;
+            column.unique = entry[COLUMN_NAME_SIZE + 5] & UNIQUE != 0;
+            column.primary_key = entry[COLUMN_NAME_SIZE + 5] & PRIMARY_KEY != 0;
+            if entry[COLUMN_NAME_SIZE + 5] & NOCASE != 0 {
+                column.collation = Collation::NoCase;
+            }
+
+            if entry[COLUMN_NAME_SIZE + 5] & HAS_DEFAULT != 0 {
+                let start = offset + COLUMN_HEADER_SIZE;
+                column.default = ctype
+                    .read_field(page.get(start..)?, &mut &[][..])?
+                    .0
+                    .into_value();
+            }
+            offset += Self::column_entry_size(&column);
+            columns.push(column);
+        }
+
+        let num_checks = read_u32(page.get(offset..offset + 4)?, 0);
+        offset += 4;
+        let mut checks = Vec::with_capacity(num_checks);
+        for _ in 0..num_checks {
+            let len = read_u32(page.get(offset..offset + 4)?, 0);
+            let text = std::str::from_utf8(page.get(offset + 4..offset + 4 + len)?).ok()?;
+            checks.push(parse_expr(text)?);
+            offset += 4 + len;
+        }
+
+        let num_triggers = read_u32(page.get(offset..offset + 4)?, 0);
+        offset += 4;
+        let mut triggers = Vec::with_capacity(num_triggers);
+        let text = |start: usize, len: usize| {
+            Some(
+                std::str::from_utf8(page.get(start..start + len)?)
+                    .ok()?
+                    .to_string(),
+            )
+        };
+        for _ in 0..num_triggers {
+            let len = read_u32(page.get(offset..offset + 4)?, 0);
+            let name = text(offset + 4, len)?;
+            offset += 4 + len;
+            let event = TriggerEvent::from_code(*page.get(offset)?)?;
+            let len = read_u32(page.get(offset + 1..offset + 5)?, 0);
+            let body = text(offset + 5, len)?;
+            offset += 5 + len;
+            triggers.push(Trigger { name, event, body });
         }
+
+        let num_indexes = read_u32(page.get(offset..offset + 4)?, 0);
+        offset += 4;
+        let mut indexes = Vec::with_capacity(num_indexes);
+        for _ in 0..num_indexes {
+            let len = read_u32(page.get(offset..offset + 4)?, 0);
+            let name = text(offset + 4, len)?;
+            offset += 4 + len;
+            let len = read_u32(page.get(offset..offset + 4)?, 0);
+            let column = text(offset + 4, len)?;
+            offset += 4 + len;
+            indexes.push(Index { name, column });
+        }
+
+        Some(Self {
+            columns,
+            checks,
+            triggers,
+            indexes,
+        })
     }
 
-    let serialized_data = statement.row_to_insert.serialize_row();
-    let cursor = Cursor::from_end(Rc::clone(&table));
+    // Whether writing the column's values in place would bypass an index or
+    // a constraint on it
+    fn is_constrained(&self, index: usize) -> bool {
+        let column = &self.columns[index];
+        let mut names = Vec::new();
+        for check in &self.checks {
+            check.column_names(&mut names);
+        }
+        column.unique
+            || column.primary_key
+            || names.contains(&column.name.as_str())
+            || (self.indexes.iter()).any(|index| index.column == column.name)
+    }
 
-    let row_offset = (cursor.row_num % ROWS_PER_PAGE) * ROW_SIZE;
-    {
-        let mut page = cursor.get_value();
-        page[row_offset..row_offset + ROW_SIZE].copy_from_slice(&serialized_data);
+    // Where the field of the column starts in a serialized row, None when
+    // the value is NULL or the row was written before the column was added
+    fn field_offset(&self, row: &[u8], index: usize) -> Option<usize> {
+        if index >= row_columns(row) || is_null_field(row, index) {
+            return None;
+        }
+        let mut offset = stored_header_size(row);
+        for (i, column) in self.columns[..index].iter().enumerate() {
+            if !is_null_field(row, i) {
+                offset += column.ctype.field_size(row.get(offset..)?)?.0;
+            }
+        }
+        Some(offset)
     }
-    {
-        table.borrow_mut().num_rows += 1;
+
+    // Whether every column the checks mention is in the schema
+    fn has_check_columns(&self) -> bool {
+        let mut names = Vec::new();
+        for check in &self.checks {
+            check.column_names(&mut names);
+        }
+        names.iter().all(|name| self.column_index(name).is_some())
     }
+}
 
-    ExecuteResult::Success
+// A serialized row starts with its length in bytes and the number of
+// columns it was written with, then the NULL flags, then the fields of the
+// values that aren't NULL. Rows written before columns were added to the
+// table are left as they are, the columns they lack read as their default.
+const ROW_LENGTH_SIZE: usize = 2;
+const ROW_COLUMNS_SIZE: usize = 2;
+const ROW_FLAGS_OFFSET: usize = ROW_LENGTH_SIZE + ROW_COLUMNS_SIZE;
+
+fn row_length(row: &[u8]) -> usize {
+    u16::from_le_bytes([row[0], row[1]]) as usize
 }
 
-fn execute_select(table: TableRef) -> ExecuteResult {
-    let mut cursor = Cursor::from_start(Rc::clone(&table));
+fn row_columns(row: &[u8]) -> usize {
+    u16::from_le_bytes([row[ROW_LENGTH_SIZE], row[ROW_LENGTH_SIZE + 1]]) as usize
+}
 
-    while !cursor.end_of_table {
-        {
-            let row_offset = (cursor.row_num % ROWS_PER_PAGE) * ROW_SIZE;
-            let page = cursor.get_value();
-            let row_data = &page[row_offset..row_offset + ROW_SIZE];
+// The header of the row as it was written, which is shorter than the
+// schema's for rows that predate some of its columns
+fn stored_header_size(row: &[u8]) -> usize {
+    ROW_FLAGS_OFFSET + row_columns(row).div_ceil(8)
+}
 
-            if let Some(row) = Row::deserialize_row(row_data) {
-                println!("({}, {}, {})", row.id, row.username, row.email);
-            } else {
-                println!("Error deserializing data.");
+fn is_null_field(row: &[u8], column: usize) -> bool {
+    row[ROW_FLAGS_OFFSET + column / 8] & (1 << (column % 8)) != 0
+}
+
+// The field of a value too short for overflow pages
+fn field_bytes(value: &Value) -> Vec<u8> {
+    let mut field = Vec::new();
+    write_field(value, &mut field, &mut Vec::new());
+    field
+}
+
+pub struct Row {
+    pub values: Vec<Value>,
+}
+
+impl Row {
+    // The row, followed by the rest of the values too long to be kept in it
+    // whole. The table moves that rest onto overflow pages when it stores
+    // the row, and puts it back when it reads it.
+    pub fn serialize_row(&self, schema: &Schema) -> Vec<u8> {
+        let mut buffer = vec![0u8; schema.row_header_size()];
+        let mut overflow = Vec::new();
+
+        for (i, value) in self.values.iter().enumerate() {
+            if value.is_null() {
+                buffer[ROW_FLAGS_OFFSET + i / 8] |= 1 << (i % 8);
             }
+            write_field(value, &mut buffer, &mut overflow);
         }
 
-        cursor.advance();
+        let length = buffer.len() as u16;
+        buffer[..ROW_LENGTH_SIZE].copy_from_slice(&length.to_le_bytes());
+        let columns = self.values.len() as u16;
+        buffer[ROW_LENGTH_SIZE..ROW_FLAGS_OFFSET].copy_from_slice(&columns.to_le_bytes());
+        buffer.extend_from_slice(&overflow);
+        buffer
     }
 
-    ExecuteResult::Success
+    pub fn deserialize_row(buffer: &[u8], schema: &Schema) -> Option<Self> {
+        RowRef::new(buffer, schema).map(|row| row.to_row())
+    }
+
+    // Value of the named column, NULL when the schema has no such column
+    pub fn get(&self, schema: &Schema, name: &str) -> Value {
+        schema
+            .column_index(name)
+            .map_or(Value::Null, |index| self.values[index].clone())
+    }
+
+    // Compare two rows by a single column
+    pub fn compare(&self, other: &Row, index: usize, collation: Collation) -> Ordering {
+        collation.compare(&self.values[index], &other.values[index])
+    }
+}
+
+// A serialized row read where it is, decoding only the values asked for
+pub struct RowRef<'a> {
+    data: &'a [u8],
+    schema: &'a Schema,
 }
 
-pub fn execute_statement(table: TableRef, statement: &Statement) -> ExecuteResult {
-    match statement.stype {
-        StatementType::Insert => execute_insert(Rc::clone(&table), statement),
-        StatementType::Select => execute_select(Rc::clone(&table)),
+impl<'a> RowRef<'a> {
+    // None when the bytes don't hold a whole row of the schema, or of the
+    // schema as it was before columns were added
+    pub fn new(data: &'a [u8], schema: &'a Schema) -> Option<Self> {
+        if data.len() < ROW_FLAGS_OFFSET || row_columns(data) > schema.columns.len() {
+            return None;
+        }
+        let header_size = stored_header_size(data);
+        if data.len() < header_size || !(header_size..=data.len()).contains(&row_length(data)) {
+            return None;
+        }
+        let row = Self { data, schema };
+        (row.values().count() == schema.columns.len()).then_some(row)
+    }
+
+    // The values in column order, up to the first that can't be read. Each
+    // field starts where the one before it ends, so they are read in turn.
+    fn values(&self) -> impl Iterator<Item = ValueRef<'a>> + use<'a> {
+        let length = row_length(self.data);
+        let row = &self.data[..length];
+        let mut overflow = &self.data[length..];
+        let mut offset = stored_header_size(row);
+        let columns = row_columns(row);
+        (self.schema.columns.iter().enumerate()).map_while(move |(i, column)| {
+            if i >= columns {
+                return Some(ValueRef::from(&column.default));
+            }
+            if is_null_field(row, i) {
+                return Some(ValueRef::Null);
+            }
+            let (value, size) = column.ctype.read_field(row.get(offset..)?, &mut overflow)?;
+            offset += size;
+            Some(value)
+        })
+    }
+
+    pub fn get(&self, index: usize) -> ValueRef<'a> {
+        self.values().nth(index).unwrap_or(ValueRef::Null)
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            values: self.values().map(ValueRef::into_value).collect(),
+        }
+    }
+}
+
+// Names are stored zero padded to their full size
+fn trim_padding(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+// The page size is chosen when the database is created, a power of two
+// within these
+const DEFAULT_PAGE_SIZE: usize = 4096;
+const MIN_PAGE_SIZE: usize = 1024;
+const MAX_PAGE_SIZE: usize = 65536;
+// The end of every page on disk holds the length of its compressed image,
+// 0 when it is stored as it is, then a checksum of everything before it.
+// Pages in the cache are uncompressed and have it zeroed.
+const PAGE_TRAILER_SIZE: usize = 8;
+// Page numbers are stored in 4 bytes
+const MAX_PAGES: usize = u32::MAX as usize;
+// Pages the pager keeps in memory unless told otherwise
+const DEFAULT_CACHE_SIZE: usize = 2000;
+// Pages the log grows to before a commit copies it into the file, unless
+// told otherwise
+const DEFAULT_WAL_AUTOCHECKPOINT: usize = 1000;
+// Bytes of rows an ORDER BY sorts in memory unless told otherwise
+const DEFAULT_SORT_MEMORY: usize = 64 << 20;
+// Runs a sort merges at once, more are merged into longer runs first
+const MERGE_FAN_IN: usize = 16;
+// Opened in place of a file name for a database that is never written out
+pub const MEMORY_DATABASE: &str = ":memory:";
+// Set around each statement that writes, no statement can name it
+const STATEMENT_SAVEPOINT: &str = "";
+
+// Page 0 describes the database: a magic string telling the file apart,
+// the version of its format, the page size, flags and the salt of the key
+// when it is encrypted, which are never encrypted themselves, then the
+// number of pages in use, the head of the free page list and the catalog of
+// tables. Entries past those that fit on page 0 go on a chain of catalog
+// pages, each starting with the number of the next one.
+const MAGIC: &[u8; 16] = b"rustlite format\0";
+const FORMAT_VERSION: usize = 7;
+const HEADER_VERSION_OFFSET: usize = 16;
+const HEADER_PAGE_SIZE_OFFSET: usize = 20;
+const HEADER_FLAGS_OFFSET: usize = 24;
+const HEADER_SALT_OFFSET: usize = 28;
+const HEADER_PLAIN_SIZE: usize = HEADER_SALT_OFFSET + SALT_SIZE;
+const HEADER_NUM_PAGES_OFFSET: usize = 44;
+const HEADER_FREE_LIST_OFFSET: usize = 48;
+const HEADER_CATALOG_OFFSET: usize = 52;
+const HEADER_NUM_TABLES_OFFSET: usize = 56;
+const HEADER_SIZE: usize = 60;
+const FLAG_ENCRYPTED: usize = 1;
+const FLAG_AUTO_VACUUM: usize = 2;
+const FLAG_COMPRESSION: usize = 4;
+
+// An encrypted page keeps the authentication tag and the nonce it was
+// encrypted with ahead of its trailer
+const SALT_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const ENCRYPTION_RESERVE: usize = TAG_SIZE + NONCE_SIZE;
+#[cfg(feature = "encryption")]
+const KEY_ITERATIONS: u32 = 100_000;
+const NO_ENCRYPTION: &str = "Encryption needs the encryption feature.";
+
+// Catalog entry: zero padded name, root page of the table's tree, row count,
+// the page holding the table's schema and the smallest and largest keys of
+// its rows
+pub const TABLE_NAME_SIZE: usize = 32;
+const CATALOG_ENTRY_SIZE: usize = TABLE_NAME_SIZE + 4 + 4 + 4 + KEY_SIZE + KEY_SIZE;
+
+const CATALOG_PAGE_HEADER_SIZE: usize = 4;
+
+// Tables whose catalog entries fit on page 0
+fn max_tables(usable_size: usize) -> usize {
+    (usable_size - HEADER_SIZE) / CATALOG_ENTRY_SIZE
+}
+
+// Tables whose catalog entries fit on page 0 and the catalog pages
+fn catalog_capacity(usable_size: usize, catalog_pages: usize) -> usize {
+    max_tables(usable_size)
+        + catalog_pages * ((usable_size - CATALOG_PAGE_HEADER_SIZE) / CATALOG_ENTRY_SIZE)
+}
+
+// A table is a B+tree. Every page of it starts with the node type and the
+// page of the parent node, 0 for the root.
+const NODE_TYPE_OFFSET: usize = 0;
+const NODE_PARENT_OFFSET: usize = 1;
+const NODE_HEADER_SIZE: usize = 5;
+const NODE_LEAF: u8 = 1;
+const NODE_INTERNAL: u8 = 2;
+const KEY_SIZE: usize = 8;
+
+// Leaf node: the next leaf of the table (0 for the last one) and the number
+// of cells, then the offset of each cell in the page and the cells. A cell
+// is the row's key followed by the row, and keys increase along the leaf and
+// from one leaf to the next.
+const LEAF_NEXT_OFFSET: usize = NODE_HEADER_SIZE;
+const LEAF_NUM_CELLS_OFFSET: usize = NODE_HEADER_SIZE + 4;
+const LEAF_HEADER_SIZE: usize = NODE_HEADER_SIZE + 8;
+const LEAF_CELL_POINTER_SIZE: usize = 2;
+
+// A cell and its offset take at most half of a leaf, so the cells of a leaf
+// that overflowed always fit on two
+fn max_cell_size(usable_size: usize) -> usize {
+    (usable_size - LEAF_HEADER_SIZE) / 2 - LEAF_CELL_POINTER_SIZE
+}
+
+// Internal node: the number of keys and the rightmost child, then the cells.
+// A cell is a child page followed by the largest key under it, and the
+// rightmost child holds the keys above the last cell's.
+const INTERNAL_NUM_KEYS_OFFSET: usize = NODE_HEADER_SIZE;
+const INTERNAL_RIGHT_CHILD_OFFSET: usize = NODE_HEADER_SIZE + 4;
+const INTERNAL_HEADER_SIZE: usize = NODE_HEADER_SIZE + 8;
+const INTERNAL_CELL_SIZE: usize = 4 + KEY_SIZE;
+
+fn internal_max_keys(usable_size: usize) -> usize {
+    (usable_size - INTERNAL_HEADER_SIZE) / INTERNAL_CELL_SIZE
+}
+
+// Free pages form a chain, each one starts with the number of the next free
+// page (0 for the last one)
+
+// Overflow pages holding the rest of a long value form a chain the same
+// way. They start with a type where nodes have theirs, then the number of
+// the next page and the key of the row the value belongs to, which leads
+// back to the row when the page is moved, then the bytes.
+const OVERFLOW_TYPE: u8 = 3;
+const OVERFLOW_NEXT_OFFSET: usize = NODE_TYPE_OFFSET + 1;
+const OVERFLOW_KEY_OFFSET: usize = OVERFLOW_NEXT_OFFSET + 4;
+const OVERFLOW_HEADER_SIZE: usize = OVERFLOW_KEY_OFFSET + KEY_SIZE;
+
+fn overflow_data_size(usable_size: usize) -> usize {
+    usable_size - OVERFLOW_HEADER_SIZE
+}
+
+// Table used by statements that don't name one
+pub const DEFAULT_TABLE: &str = "main";
+
+fn read_u32(buffer: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+fn write_u32(buffer: &mut [u8], offset: usize, value: usize) {
+    buffer[offset..offset + 4].copy_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn node_parent(page: &[u8]) -> usize {
+    read_u32(page, NODE_PARENT_OFFSET)
+}
+
+fn set_node_parent(page: &mut [u8], parent: usize) {
+    write_u32(page, NODE_PARENT_OFFSET, parent);
+}
+
+fn initialize_leaf(page: &mut [u8], parent: usize, next_leaf: usize) {
+    page[NODE_TYPE_OFFSET] = NODE_LEAF;
+    set_node_parent(page, parent);
+    write_u32(page, LEAF_NEXT_OFFSET, next_leaf);
+    write_u32(page, LEAF_NUM_CELLS_OFFSET, 0);
+}
+
+fn leaf_next(page: &[u8]) -> usize {
+    read_u32(page, LEAF_NEXT_OFFSET)
+}
+
+fn leaf_num_cells(page: &[u8]) -> usize {
+    read_u32(page, LEAF_NUM_CELLS_OFFSET)
+}
+
+fn leaf_cell_offset(page: &[u8], cell_num: usize) -> usize {
+    let pointer = LEAF_HEADER_SIZE + cell_num * LEAF_CELL_POINTER_SIZE;
+    u16::from_le_bytes([page[pointer], page[pointer + 1]]) as usize
+}
+
+fn leaf_key(page: &[u8], cell_num: usize) -> i64 {
+    cell_key(&page[leaf_cell_offset(page, cell_num)..])
+}
+
+fn cell_key(cell: &[u8]) -> i64 {
+    i64::from_le_bytes(cell[..KEY_SIZE].try_into().unwrap())
+}
+
+// The cells of a leaf, read out of its page to be changed
+fn leaf_cells(page: &[u8]) -> Vec<Vec<u8>> {
+    (0..leaf_num_cells(page))
+        .map(|cell_num| {
+            let offset = leaf_cell_offset(page, cell_num);
+            let size = KEY_SIZE + row_length(&page[offset + KEY_SIZE..]);
+            page[offset..offset + size].to_vec()
+        })
+        .collect()
+}
+
+// Bytes a leaf holding the cells takes
+fn leaf_size(cells: &[Vec<u8>]) -> usize {
+    let cells: usize = (cells.iter())
+        .map(|cell| LEAF_CELL_POINTER_SIZE + cell.len())
+        .sum();
+    LEAF_HEADER_SIZE + cells
+}
+
+// Replaces the cells of a leaf, which have to fit on it
+fn write_leaf_cells(page: &mut [u8], cells: &[Vec<u8>]) {
+    page[LEAF_HEADER_SIZE..].fill(0);
+    let mut offset = LEAF_HEADER_SIZE + cells.len() * LEAF_CELL_POINTER_SIZE;
+    for (cell_num, cell) in cells.iter().enumerate() {
+        let pointer = LEAF_HEADER_SIZE + cell_num * LEAF_CELL_POINTER_SIZE;
+        page[pointer..pointer + LEAF_CELL_POINTER_SIZE]
+            .copy_from_slice(&(offset as u16).to_le_bytes());
+        page[offset..offset + cell.len()].copy_from_slice(cell);
+        offset += cell.len();
+    }
+    write_u32(page, LEAF_NUM_CELLS_OFFSET, cells.len());
+}
+
+// How many of the cells go to the left one of two leaves so the bytes are
+// shared between them as evenly as they can be
+fn split_point(cells: &[Vec<u8>]) -> usize {
+    let total = leaf_size(cells);
+    let mut left = LEAF_HEADER_SIZE;
+    let mut best = (usize::MAX, 1);
+    for (count, cell) in cells.iter().enumerate().take(cells.len() - 1) {
+        left += LEAF_CELL_POINTER_SIZE + cell.len();
+        let larger = left.max(total - left + LEAF_HEADER_SIZE);
+        if larger < best.0 {
+            best = (larger, count + 1);
+        }
+    }
+    best.1
+}
+
+// The cells of an internal node, read out of its page to be searched or
+// changed. There is one more child than keys, the last one is the rightmost.
+struct InternalNode {
+    children: Vec<usize>,
+    keys: Vec<i64>,
+}
+
+impl InternalNode {
+    fn read(page: &[u8]) -> Self {
+        let num_keys = read_u32(page, INTERNAL_NUM_KEYS_OFFSET);
+        let mut children = Vec::with_capacity(num_keys + 1);
+        let mut keys = Vec::with_capacity(num_keys);
+        for i in 0..num_keys {
+            let offset = INTERNAL_HEADER_SIZE + i * INTERNAL_CELL_SIZE;
+            children.push(read_u32(page, offset));
+            keys.push(i64::from_le_bytes(
+                page[offset + 4..offset + INTERNAL_CELL_SIZE]
+                    .try_into()
+                    .unwrap(),
+            ));
+        }
+        children.push(read_u32(page, INTERNAL_RIGHT_CHILD_OFFSET));
+        Self { children, keys }
+    }
+
+    // Writes the cells over the page, keeping its parent
+    fn write(&self, page: &mut [u8]) {
+        page[NODE_TYPE_OFFSET] = NODE_INTERNAL;
+        page[NODE_HEADER_SIZE..].fill(0);
+        write_u32(page, INTERNAL_NUM_KEYS_OFFSET, self.keys.len());
+        write_u32(
+            page,
+            INTERNAL_RIGHT_CHILD_OFFSET,
+            self.children[self.keys.len()],
+        );
+        for (i, key) in self.keys.iter().enumerate() {
+            let offset = INTERNAL_HEADER_SIZE + i * INTERNAL_CELL_SIZE;
+            write_u32(page, offset, self.children[i]);
+            page[offset + 4..offset + INTERNAL_CELL_SIZE].copy_from_slice(&key.to_le_bytes());
+        }
+    }
+
+    // The child whose keys take in the key
+    fn child_for(&self, key: i64) -> usize {
+        self.children[self.keys.partition_point(|&other| other < key)]
+    }
+
+    // The position of the child and its right sibling, or of its left
+    // sibling and the child when it is the rightmost one, with both pages
+    fn siblings(&self, child: usize) -> (usize, usize, usize) {
+        let position = (self.children.iter())
+            .position(|&other| other == child)
+            .unwrap();
+        let position = position.min(self.children.len() - 2);
+        (
+            position,
+            self.children[position],
+            self.children[position + 1],
+        )
+    }
+}
+
+// Position of the first cell whose key is not below the key, found by
+// binary search
+fn leaf_find(page: &[u8], key: i64) -> usize {
+    let (mut low, mut high) = (0, leaf_num_cells(page));
+    while low < high {
+        let middle = (low + high) / 2;
+        if leaf_key(page, middle) < key {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
+
+// Each frame of the write-ahead log is a page image after a header: the
+// page number, the size of the database in pages on the last frame of a
+// commit (0 on the others), the page size and a checksum of the rest of the
+// frame
+const WAL_FRAME_HEADER_SIZE: usize = 16;
+const WAL_PAGE_SIZE_OFFSET: usize = 8;
+const WAL_CHECKSUM_OFFSET: usize = 12;
+
+// The same frames make up the rollback journal, after a header holding the
+// size of the database in pages before the commit, the page size and their
+// checksum
+const JOURNAL_HEADER_SIZE: usize = 12;
+
+// FNV-1a, enough to tell a frame that was only partly written
+fn checksum(bytes: &[u8]) -> u32 {
+    (bytes.iter()).fold(0x811c_9dc5_u32, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+// The write-ahead log next to the database file. Changed pages are appended
+// to it, and only once a commit's frames are on disk are they copied into
+// the database file, so a crash leaves the pages of every commit whole.
+struct Wal {
+    file: File,
+    path: String,
+    // End of the frames that are kept, anything after it is overwritten
+    size: u64,
+    // Offset of the latest frame of each page in the log
+    frames: HashMap<usize, u64>,
+    // Frames appended since the last commit
+    pending: bool,
+    // Bytes in the database as of the last commit in the log, 0 when there
+    // is none
+    db_size: u64,
+}
+
+impl Wal {
+    // Opens the log, keeping the frames of the commits that made it to disk
+    // whole. The frames of an unfinished commit are dropped.
+    fn open(path: String) -> io::Result<Self> {
+        let mut wal = Self {
+            file: read_write_file(&path)?,
+            path,
+            size: 0,
+            frames: HashMap::new(),
+            pending: false,
+            db_size: 0,
+        };
+        wal.read_commits(u64::MAX);
+        Ok(wal)
+    }
+
+    // Finds the commits whose frames are whole before the offset
+    fn read_commits(&mut self, end: u64) {
+        self.size = 0;
+        self.frames.clear();
+        self.pending = false;
+        self.db_size = 0;
+        let mut committed = HashMap::new();
+        let mut offset = 0;
+        while offset < end
+            && let Some(frame) = read_frame(&mut self.file, offset)
+        {
+            let page_num = read_u32(&frame, 0);
+            committed.insert(page_num, offset);
+            offset += frame.len() as u64;
+            let commit_size = read_u32(&frame, 4);
+            if commit_size != 0 {
+                self.frames.extend(committed.drain());
+                self.size = offset;
+                self.db_size = (commit_size * (frame.len() - WAL_FRAME_HEADER_SIZE)) as u64;
+            }
+        }
+    }
+
+    // Copies the latest frame of the page into the buffer, false when the
+    // log has none
+    fn read_page(&mut self, page_num: usize, data: &mut [u8]) -> bool {
+        let Some(&offset) = self.frames.get(&page_num) else {
+            return false;
+        };
+        let _ = (self.file).seek(SeekFrom::Start(offset + WAL_FRAME_HEADER_SIZE as u64));
+        let _ = self.file.read_exact(data);
+        true
+    }
+
+    // Adds a frame for the page, ending a commit when given the size of the
+    // database. A frame that fails to be written is overwritten by the next.
+    fn append(&mut self, page_num: usize, data: &[u8], commit_size: usize) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.size))?;
+        (self.file).write_all(&encode_frame(page_num, data, commit_size))?;
+        self.frames.insert(page_num, self.size);
+        self.size += (WAL_FRAME_HEADER_SIZE + data.len()) as u64;
+        self.pending = commit_size == 0;
+        if commit_size != 0 {
+            self.db_size = (commit_size * data.len()) as u64;
+        }
+        Ok(())
+    }
+
+    // Frames in the log of pages of the size
+    fn num_frames(&self, page_size: usize) -> usize {
+        self.size as usize / (WAL_FRAME_HEADER_SIZE + page_size)
+    }
+
+    // Drops the frames from the offset on, keeping the commits before them.
+    // Frames left in the file past the end are overwritten by the next ones.
+    fn drop_from(&mut self, end: u64) {
+        self.read_commits(end);
+        let _ = self.file.set_len(self.size);
+    }
+
+    // Drops the frames appended since the last commit
+    fn drop_pending(&mut self) {
+        self.drop_from(u64::MAX);
+    }
+
+    // Empties the log once its pages are in the database file. Unless it is
+    // synced, the frames may come back after a crash and be copied again.
+    fn reset(&mut self, sync: bool) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.size = 0;
+        self.frames.clear();
+        self.pending = false;
+        self.db_size = 0;
+        if sync {
+            self.file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+// The rollback journal next to the database file. Before a page of the
+// file is overwritten its original image is saved here, so a crash part way
+// through a commit can be undone. It is deleted once the commit is done.
+struct Journal {
+    path: String,
+    // Open while a commit is being written to the file
+    file: Option<File>,
+    size: u64,
+    // Pages the database file had before the commit, later ones are new
+    original_pages: usize,
+    // Pages whose original images are saved
+    saved: HashSet<usize>,
+}
+
+impl Journal {
+    fn new(path: String) -> Self {
+        Self {
+            path,
+            file: None,
+            size: 0,
+            original_pages: 0,
+            saved: HashSet::new(),
+        }
+    }
+
+    // Puts back the pages saved in a journal a crash left behind, and cuts
+    // the database file back to its size before the commit. The journal is
+    // only removed once that is synced.
+    fn roll_back(&self, db: &mut File) -> io::Result<()> {
+        if !self.is_hot(db) {
+            let _ = std::fs::remove_file(&self.path);
+            return Ok(());
+        }
+        let mut file = read_write_file(&self.path)?;
+        let mut header = [0; JOURNAL_HEADER_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        // Nothing reaches the database file before the header is on disk
+        if file.read_exact(&mut header).is_ok()
+            && read_u32(&header, 8) == checksum(&header[..8]) as usize
+        {
+            let mut offset = JOURNAL_HEADER_SIZE as u64;
+            while let Some(frame) = read_frame(&mut file, offset) {
+                write_page(db, read_u32(&frame, 0), &frame[WAL_FRAME_HEADER_SIZE..])?;
+                offset += frame.len() as u64;
+            }
+            let original_size = read_u32(&header, 0) * read_u32(&header, 4);
+            db.set_len(original_size as u64)?;
+            db.sync_all()?;
+        }
+        std::fs::remove_file(&self.path)
+    }
+
+    // Whether a journal was left behind by a commit to the database file
+    // that did not finish. An empty file was never written by a commit,
+    // a journal next to it belongs to a database that was deleted.
+    fn is_hot(&self, db: &File) -> bool {
+        std::path::Path::new(&self.path).exists() && db.metadata().is_ok_and(|m| m.len() > 0)
+    }
+
+    // Saves the original images of the pages about to be written to the
+    // database file, and syncs them to disk when told to
+    fn save(
+        &mut self,
+        db: &mut File,
+        page_size: usize,
+        page_nums: &[usize],
+        sync: bool,
+    ) -> io::Result<()> {
+        if self.file.is_none() {
+            let db_size = db.metadata().map_or(0, |m| m.len()) as usize;
+            self.original_pages = db_size.div_ceil(page_size);
+            let mut header = [0; JOURNAL_HEADER_SIZE];
+            write_u32(&mut header, 0, self.original_pages);
+            write_u32(&mut header, 4, page_size);
+            let sum = checksum(&header[..8]);
+            write_u32(&mut header, 8, sum as usize);
+
+            let mut file = read_write_file(&self.path)?;
+            file.set_len(0)?;
+            file.write_all(&header)?;
+            self.file = Some(file);
+            self.size = JOURNAL_HEADER_SIZE as u64;
+        }
+
+        let mut data = vec![0; page_size];
+        let file = self.file.as_mut().unwrap();
+        for &page_num in page_nums {
+            if page_num >= self.original_pages || !self.saved.insert(page_num) {
+                continue;
+            }
+            read_page(db, page_num, &mut data);
+            let frame = encode_frame(page_num, &data, 0);
+            file.seek(SeekFrom::Start(self.size))?;
+            file.write_all(&frame)?;
+            self.size += frame.len() as u64;
+        }
+        if sync {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Deletes the journal once the commit is in the database file. The
+    // deletion is what commits, it only survives a crash for sure once the
+    // directory is synced.
+    fn finish(&mut self, sync: bool) -> io::Result<()> {
+        self.saved.clear();
+        if self.file.take().is_some() {
+            std::fs::remove_file(&self.path)?;
+            if sync {
+                sync_directory(&self.path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The double-write buffer next to the database file. Pages are saved in
+// it and synced before they are written into the file, so a page a crash
+// left half written there can be put back. It is emptied once the file is
+// synced.
+struct DoubleWrite {
+    path: String,
+    // Open while pages go through the buffer
+    file: Option<File>,
+    size: u64,
+}
+
+impl DoubleWrite {
+    fn new(path: String) -> Self {
+        Self {
+            path,
+            file: None,
+            size: 0,
+        }
+    }
+
+    // Whether a crash left pages in the buffer that may not be whole in
+    // the database file
+    fn left_behind(&self) -> bool {
+        std::fs::metadata(&self.path).is_ok_and(|m| m.len() > 0)
+    }
+
+    // Puts back the pages of the database file that fail their checksum
+    // from the latest copies in a buffer a crash left behind, then removes
+    // the buffer
+    fn restore(&self, db: &mut File) -> io::Result<()> {
+        let Ok(mut file) = File::open(&self.path) else {
+            return Ok(());
+        };
+        let mut copies = HashMap::new();
+        let mut offset = 0;
+        while let Some(frame) = read_frame(&mut file, offset) {
+            offset += frame.len() as u64;
+            copies.insert(read_u32(&frame, 0), frame);
+        }
+        let mut restored = false;
+        for (page_num, frame) in copies {
+            let copy = &frame[WAL_FRAME_HEADER_SIZE..];
+            let mut data = vec![0; copy.len()];
+            read_page(db, page_num, &mut data);
+            if !page_intact(&data) {
+                write_page(db, page_num, copy)?;
+                restored = true;
+            }
+        }
+        if restored {
+            db.sync_all()?;
+        }
+        std::fs::remove_file(&self.path)
+    }
+
+    fn is_on(&self) -> bool {
+        self.file.is_some()
+    }
+
+    fn set_on(&mut self, on: bool) -> io::Result<()> {
+        if on && self.file.is_none() {
+            let file = read_write_file(&self.path)?;
+            file.set_len(0)?;
+            self.file = Some(file);
+            self.size = 0;
+        } else if !on && let Some(file) = &self.file {
+            // Copies left in it could be older than pages written later
+            file.set_len(0)?;
+            self.file = None;
+            self.size = 0;
+        }
+        Ok(())
+    }
+
+    // Saves a page about to be written into the database file
+    fn append(&mut self, page_num: usize, data: &[u8]) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            let frame = encode_frame(page_num, data, 0);
+            file.seek(SeekFrom::Start(self.size))?;
+            file.write_all(&frame)?;
+            self.size += frame.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_all(),
+            None => Ok(()),
+        }
+    }
+
+    // Empties the buffer once the pages saved in it are synced in the file
+    fn clear(&mut self) -> io::Result<()> {
+        if let Some(file) = &self.file
+            && self.size > 0
+        {
+            file.set_len(0)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+// Syncs the directory holding the file, so files created or removed in it
+// stay that way
+fn sync_directory(path: &str) -> io::Result<()> {
+    let directory = match std::path::Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    File::open(directory)?.sync_all()
+}
+
+// Opens the file for reading and writing, creating it when it doesn't
+// exist
+fn read_write_file(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .read(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+// The cached page as it is written to disk, compressed when asked to and
+// that makes it shorter or encrypted with the cipher, with its trailer.
+// Page 0 is never compressed, its header is read straight from the file.
+// Encrypted pages aren't either, their zeros wouldn't be left as zeros.
+fn encode_page(page_num: usize, data: &[u8], compress: bool, cipher: Option<&Cipher>) -> Vec<u8> {
+    let usable_size = data.len() - PAGE_TRAILER_SIZE;
+    let compressed = (compress && page_num != 0 && cipher.is_none())
+        .then(|| compress_page(&data[..usable_size]))
+        .flatten();
+    let mut page = data.to_vec();
+    if let Some(compressed) = compressed {
+        page.fill(0);
+        page[..compressed.len()].copy_from_slice(&compressed);
+        write_u32(&mut page, usable_size, compressed.len());
+    }
+    if let Some(cipher) = cipher {
+        cipher.encrypt(page_num, &mut page[..usable_size]);
+    }
+    let sum = checksum(&page[..usable_size + 4]);
+    write_u32(&mut page, usable_size + 4, sum as usize);
+    page
+}
+
+// Whether a page read from disk has a matching checksum and could be
+// decrypted with the cipher and decompressed. It is left the way the cache
+// holds it.
+fn decode_page(page_num: usize, data: &mut [u8], cipher: Option<&Cipher>) -> bool {
+    if !page_intact(data) {
+        return false;
+    }
+    let usable_size = data.len() - PAGE_TRAILER_SIZE;
+    let compressed_size = read_u32(data, usable_size);
+    data[usable_size..].fill(0);
+    if let Some(cipher) = cipher
+        && !cipher.decrypt(page_num, &mut data[..usable_size])
+    {
+        return false;
+    }
+    if compressed_size == 0 {
+        return true;
+    }
+    if compressed_size >= usable_size {
+        return false;
+    }
+    let compressed = data[..compressed_size].to_vec();
+    decompress_page(&compressed, &mut data[..usable_size])
+}
+
+#[cfg(feature = "compression")]
+fn compress_page(data: &[u8]) -> Option<Vec<u8>> {
+    let compressed = lz4_flex::block::compress(data);
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress_page(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "compression")]
+fn decompress_page(compressed: &[u8], page: &mut [u8]) -> bool {
+    lz4_flex::block::decompress_into(compressed, page).is_ok_and(|size| size == page.len())
+}
+
+// Without the compression feature compressed pages can't be read
+#[cfg(not(feature = "compression"))]
+fn decompress_page(_compressed: &[u8], _page: &mut [u8]) -> bool {
+    false
+}
+
+// Encrypts the pages of a database opened with a key, with ChaCha20-Poly1305
+// under a key derived from it and the salt in the header. The page number
+// is authenticated along with each page, and the plain start of page 0.
+#[cfg(feature = "encryption")]
+struct Cipher {
+    salt: [u8; SALT_SIZE],
+    aead: chacha20poly1305::ChaCha20Poly1305,
+}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    // A cipher with a new salt, for a new database
+    fn create(key: &str) -> Option<Self> {
+        use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+        let mut salt = [0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        Self::open(key, salt)
+    }
+
+    fn open(key: &str, salt: [u8; SALT_SIZE]) -> Option<Self> {
+        use chacha20poly1305::KeyInit;
+        let mut derived = [0; 32];
+        pbkdf2::pbkdf2_hmac::<sha2::Sha256>(key.as_bytes(), &salt, KEY_ITERATIONS, &mut derived);
+        let aead = chacha20poly1305::ChaCha20Poly1305::new(&derived.into());
+        Some(Self { salt, aead })
+    }
+
+    fn salt(&self) -> [u8; SALT_SIZE] {
+        self.salt
+    }
+
+    // Encrypts the page, up to its trailer, in place under a new nonce
+    fn encrypt(&self, page_num: usize, page: &mut [u8]) {
+        use chacha20poly1305::AeadInPlace;
+        use chacha20poly1305::aead::{AeadCore, OsRng};
+        let (start, end) = encrypted_range(page_num, page.len());
+        let nonce = chacha20poly1305::ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let (plain, rest) = page.split_at_mut(end);
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(
+                &nonce,
+                &page_aad(page_num, plain, start),
+                &mut plain[start..],
+            )
+            .unwrap();
+        rest[..TAG_SIZE].copy_from_slice(&tag);
+        rest[TAG_SIZE..].copy_from_slice(&nonce);
+    }
+
+    // Decrypts the page in place, false when it doesn't authenticate
+    fn decrypt(&self, page_num: usize, page: &mut [u8]) -> bool {
+        use chacha20poly1305::AeadInPlace;
+        let (start, end) = encrypted_range(page_num, page.len());
+        let (cipher, rest) = page.split_at_mut(end);
+        let tag = chacha20poly1305::Tag::clone_from_slice(&rest[..TAG_SIZE]);
+        let nonce = chacha20poly1305::Nonce::clone_from_slice(&rest[TAG_SIZE..]);
+        let aad = page_aad(page_num, cipher, start);
+        let decrypted = (self.aead)
+            .decrypt_in_place_detached(&nonce, &aad, &mut cipher[start..], &tag)
+            .is_ok();
+        rest.fill(0);
+        decrypted
+    }
+}
+
+// Without the encryption feature there is never a cipher
+#[cfg(not(feature = "encryption"))]
+enum Cipher {}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    fn create(_key: &str) -> Option<Self> {
+        None
+    }
+
+    fn open(_key: &str, _salt: [u8; SALT_SIZE]) -> Option<Self> {
+        None
+    }
+
+    fn salt(&self) -> [u8; SALT_SIZE] {
+        match *self {}
+    }
+
+    fn encrypt(&self, _page_num: usize, _page: &mut [u8]) {
+        match *self {}
+    }
+
+    fn decrypt(&self, _page_num: usize, _page: &mut [u8]) -> bool {
+        match *self {}
+    }
+}
+
+// The bytes of a page ahead of its trailer that are encrypted: all but the
+// plain start of page 0 and the tag and nonce at the end
+#[cfg(feature = "encryption")]
+fn encrypted_range(page_num: usize, length: usize) -> (usize, usize) {
+    let start = if page_num == 0 { HEADER_PLAIN_SIZE } else { 0 };
+    (start, length - ENCRYPTION_RESERVE)
+}
+
+// Data authenticated with a page but not encrypted
+#[cfg(feature = "encryption")]
+fn page_aad(page_num: usize, page: &[u8], start: usize) -> Vec<u8> {
+    let mut aad = (page_num as u64).to_le_bytes().to_vec();
+    aad.extend_from_slice(&page[..start]);
+    aad
+}
+
+// Whether a page as it is on disk matches its checksum, which one written
+// only in part does not
+fn page_intact(data: &[u8]) -> bool {
+    let usable_size = data.len() - PAGE_TRAILER_SIZE;
+    read_u32(data, usable_size + 4) == checksum(&data[..usable_size + 4]) as usize
+}
+
+// Pages sit in the file one after another, the buffer is a page long
+fn read_page(file: &mut File, page_num: usize, data: &mut [u8]) {
+    let _ = file.seek(SeekFrom::Start((page_num * data.len()) as u64));
+    let _ = file.read_exact(data);
+}
+
+// Writes a page as it is on disk. The zeros between a compressed image and
+// the trailer are handed back to the file system where it can.
+fn write_page(file: &mut File, page_num: usize, data: &[u8]) -> io::Result<()> {
+    let start = (page_num * data.len()) as u64;
+    file.seek(SeekFrom::Start(start))?;
+    file.write_all(data)?;
+
+    let usable_size = data.len() - PAGE_TRAILER_SIZE;
+    let compressed_size = read_u32(data, usable_size);
+    if compressed_size != 0 {
+        punch_hole(
+            file,
+            start + compressed_size as u64,
+            start + usable_size as u64,
+        );
+    }
+    Ok(())
+}
+
+// Frees the blocks wholly inside the range, which reads back as zeros
+#[cfg(all(feature = "compression", target_os = "linux"))]
+fn punch_hole(file: &File, start: u64, end: u64) {
+    use std::os::fd::AsRawFd;
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // Nothing is lost when it fails, the zeros just stay on disk
+    unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            start as libc::off_t,
+            (end - start) as libc::off_t,
+        );
+    }
+}
+
+#[cfg(not(all(feature = "compression", target_os = "linux")))]
+fn punch_hole(_file: &File, _start: u64, _end: u64) {}
+
+// Bytes in a block of the file system the file is on, the least a hole can
+// free
+#[cfg(unix)]
+fn block_size(file: &File) -> usize {
+    use std::os::unix::fs::MetadataExt;
+    file.metadata()
+        .map_or(DEFAULT_PAGE_SIZE, |metadata| metadata.blksize() as usize)
+}
+
+#[cfg(not(unix))]
+fn block_size(_file: &File) -> usize {
+    DEFAULT_PAGE_SIZE
+}
+
+fn encode_frame(page_num: usize, data: &[u8], commit_size: usize) -> Vec<u8> {
+    let mut frame = vec![0; WAL_FRAME_HEADER_SIZE];
+    write_u32(&mut frame, 0, page_num);
+    write_u32(&mut frame, 4, commit_size);
+    write_u32(&mut frame, WAL_PAGE_SIZE_OFFSET, data.len());
+    frame.extend_from_slice(data);
+    let sum = frame_checksum(&frame);
+    write_u32(&mut frame, WAL_CHECKSUM_OFFSET, sum);
+    frame
+}
+
+// Reads a whole frame with a matching checksum
+fn read_frame(file: &mut File, offset: u64) -> Option<Vec<u8>> {
+    let mut frame = vec![0; WAL_FRAME_HEADER_SIZE];
+    let _ = file.seek(SeekFrom::Start(offset));
+    file.read_exact(&mut frame).ok()?;
+    let page_size = read_u32(&frame, WAL_PAGE_SIZE_OFFSET);
+    if !valid_page_size(page_size) {
+        return None;
+    }
+    frame.resize(WAL_FRAME_HEADER_SIZE + page_size, 0);
+    file.read_exact(&mut frame[WAL_FRAME_HEADER_SIZE..]).ok()?;
+    (read_u32(&frame, WAL_CHECKSUM_OFFSET) == frame_checksum(&frame)).then_some(frame)
+}
+
+fn valid_page_size(page_size: usize) -> bool {
+    (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) && page_size.is_power_of_two()
+}
+
+// Checksum of a frame, leaving out where it is stored
+fn frame_checksum(frame: &[u8]) -> usize {
+    let mut covered = frame[..WAL_CHECKSUM_OFFSET].to_vec();
+    covered.extend_from_slice(&frame[WAL_FRAME_HEADER_SIZE..]);
+    checksum(&covered) as usize
+}
+
+// How commits are kept whole through a crash
+#[derive(Clone, Copy, PartialEq)]
+pub enum JournalMode {
+    // Changed pages go to the write-ahead log before the database file
+    Wal,
+    // Pages' original images go to the rollback journal before they are
+    // overwritten
+    Delete,
+}
+
+impl JournalMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "wal" => Some(JournalMode::Wal),
+            "delete" => Some(JournalMode::Delete),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            JournalMode::Wal => "wal",
+            JournalMode::Delete => "delete",
+        }
+    }
+}
+
+// How hard commits make sure their pages reached the disk
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Synchronous {
+    // Nothing is synced, a crash of the machine can lose commits and
+    // corrupt the file
+    Off,
+    // Syncs that keep the file whole, a crash of the machine can still lose
+    // the latest commits
+    Normal,
+    // Every commit is on disk before the statement finishes
+    Full,
+}
+
+impl Synchronous {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Synchronous::Off),
+            "normal" => Some(Synchronous::Normal),
+            "full" => Some(Synchronous::Full),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Synchronous::Off => "off",
+            Synchronous::Normal => "normal",
+            Synchronous::Full => "full",
+        }
+    }
+}
+
+// Where the pages are kept past the cache
+enum Backend {
+    Disk(Box<Disk>),
+    // Nothing is written out, every page stays in the cache
+    Memory,
+}
+
+// The database file, with the log and the journal next to it. Every
+// process with the file open holds a shared lock on it, which the one
+// writing to it makes exclusive until its commit is done, so a process can
+// only write while no other has the file open.
+struct Disk {
+    file: File,
+    wal: Wal,
+    journal: Journal,
+    double_write: DoubleWrite,
+    // Set while the lock on the file is exclusive
+    exclusive: bool,
+    // The start of the file mapped into memory, pages in it are copied from
+    // there rather than read
+    #[cfg(feature = "mmap")]
+    map: Option<memmap2::Mmap>,
+}
+
+impl Disk {
+    fn open(filename: &str) -> Result<Self, String> {
+        let Ok(file) = read_write_file(filename) else {
+            return Err("Unable to open file.".to_string());
+        };
+        // Waits for a commit another process is making to finish
+        let _ = file.lock_shared();
+        let mut disk = Self {
+            file,
+            wal: Wal::open(format!("{}-wal", filename))
+                .map_err(|error| format!("Unable to open the log: {}.", error))?,
+            journal: Journal::new(format!("{}-journal", filename)),
+            double_write: DoubleWrite::new(format!("{}-dw", filename)),
+            exclusive: false,
+            #[cfg(feature = "mmap")]
+            map: None,
+        };
+        // A commit a crash cut short is undone when it was journaled, and
+        // finished when its frames made it to the log. Pages it left half
+        // written are put back first.
+        let journal_left = std::path::Path::new(&disk.journal.path).exists();
+        let double_write_left = disk.double_write.left_behind();
+        if journal_left || double_write_left || disk.wal.file.metadata().is_ok_and(|m| m.len() > 0)
+        {
+            if disk.lock_exclusive() {
+                let recovered = (disk.double_write.restore(&mut disk.file))
+                    .and_then(|()| disk.journal.roll_back(&mut disk.file))
+                    .and_then(|()| disk.checkpoint(Synchronous::Full));
+                disk.unlock_exclusive();
+                if let Err(error) = recovered {
+                    return Err(format!("Unable to recover the database: {}.", error));
+                }
+            } else if journal_left || double_write_left {
+                return Err(disk.reject("Database is locked."));
+            }
+            // Otherwise the log holds commits of another process that has
+            // the file open, pages are read from it until it is copied
+        }
+        Ok(disk)
+    }
+
+    // Makes the lock on the file exclusive before anything is written to
+    // it, false while another process has the file open. The lock is
+    // changed in place, which flock does on Unix.
+    fn lock_exclusive(&mut self) -> bool {
+        self.exclusive = self.exclusive || self.file.try_lock().is_ok();
+        self.exclusive
+    }
+
+    // Shares the file with other processes again once a commit is done
+    fn unlock_exclusive(&mut self) {
+        if self.exclusive {
+            let _ = self.file.lock_shared();
+            self.exclusive = false;
+        }
+    }
+
+    // Takes the log and the double-write buffer away, unless another
+    // process has the file open and may be using them
+    fn remove_files(&mut self) {
+        if self.lock_exclusive() {
+            let _ = std::fs::remove_file(&self.wal.path);
+            let _ = std::fs::remove_file(&self.double_write.path);
+        }
+    }
+
+    // Writes the pages into the file, once they are synced in the
+    // double-write buffer when it is on
+    fn write_pages(&mut self, pages: &[(usize, Vec<u8>)]) -> io::Result<()> {
+        for (page_num, data) in pages {
+            self.double_write.append(*page_num, data)?;
+        }
+        self.double_write.sync()?;
+        for (page_num, data) in pages {
+            write_page(&mut self.file, *page_num, data)?;
+        }
+        Ok(())
+    }
+
+    // Syncs the file when told to, and always when pages went into it
+    // through the double-write buffer, which is then emptied
+    fn sync_file(&mut self, sync: bool) -> io::Result<()> {
+        if sync || self.double_write.size > 0 {
+            self.file.sync_all()?;
+        }
+        self.double_write.clear()
+    }
+
+    fn file_length(&self) -> usize {
+        self.file.metadata().map_or(0, |m| m.len()) as usize
+    }
+
+    // Bytes in the database, which the log has when it holds commits
+    fn db_length(&self) -> usize {
+        match self.wal.db_size {
+            0 => self.file_length(),
+            db_size => db_size as usize,
+        }
+    }
+
+    // Fails unless the file starts with a header this version can read.
+    // Gives the page size in it, and the salt of the key when the file is
+    // encrypted.
+    fn check_header(&mut self) -> Result<(usize, Option<[u8; SALT_SIZE]>), String> {
+        let mut header = [0; HEADER_SIZE];
+        let _ = self.file.seek(SeekFrom::Start(0));
+        let read = self.wal.read_page(0, &mut header) || self.file.read_exact(&mut header).is_ok();
+        if !read || &header[..MAGIC.len()] != MAGIC {
+            return Err(self.reject("File is not a database."));
+        }
+        let version = read_u32(&header, HEADER_VERSION_OFFSET);
+        if version != FORMAT_VERSION {
+            let message = format!("Unsupported file format version {}.", version);
+            return Err(self.reject(&message));
+        }
+        let page_size = read_u32(&header, HEADER_PAGE_SIZE_OFFSET);
+        if !valid_page_size(page_size) {
+            return Err(self.reject(&format!("Unsupported page size {}.", page_size)));
+        }
+        let encrypted = read_u32(&header, HEADER_FLAGS_OFFSET) & FLAG_ENCRYPTED != 0;
+        let salt = encrypted.then(|| {
+            (header[HEADER_SALT_OFFSET..HEADER_PLAIN_SIZE])
+                .try_into()
+                .unwrap()
+        });
+        Ok((page_size, salt))
+    }
+
+    // Gives up on the file, taking away the log opened next to it when it
+    // is empty. Hands back the message saying why.
+    fn reject(&mut self, message: &str) -> String {
+        if self.wal.file.metadata().is_ok_and(|m| m.len() == 0) {
+            self.remove_files();
+        }
+        message.to_string()
+    }
+
+    // Copies the page from the log or the file into the buffer, false when
+    // it is in neither
+    fn read_page(&mut self, page_num: usize, num_pages: usize, data: &mut [u8]) -> bool {
+        if self.wal.read_page(page_num, data) {
+            return true;
+        }
+        if page_num >= num_pages {
+            return false;
+        }
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &self.map {
+            let start = page_num * data.len();
+            if let Some(page) = map.get(start..start + data.len()) {
+                data.copy_from_slice(page);
+                return true;
+            }
+        }
+        read_page(&mut self.file, page_num, data);
+        true
+    }
+
+    // Maps up to the size in bytes of the start of the file, as long as it
+    // is now. Called again whenever the file may have changed length, before
+    // anything is read through the old map.
+    #[cfg(feature = "mmap")]
+    fn remap(&mut self, mmap_size: usize) {
+        self.map = None;
+        let length = self.file_length().min(mmap_size);
+        if length == 0 {
+            return;
+        }
+        // The file is only changed through this pager, which maps it again
+        // after changing its length
+        self.map = unsafe { memmap2::MmapOptions::new().len(length).map(&self.file) }.ok();
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn remap(&mut self, _mmap_size: usize) {}
+
+    // Copies the committed pages in the log into the database file, then
+    // empties the log. The log is only emptied once the pages are written
+    // and the file synced, when that fails its pages are still read from it
+    // and the next checkpoint copies them again.
+    fn checkpoint(&mut self, synchronous: Synchronous) -> io::Result<()> {
+        if self.wal.frames.is_empty() {
+            return self.wal.reset(synchronous == Synchronous::Full);
+        }
+        let mut frames: Vec<(usize, u64)> = (self.wal.frames.iter())
+            .map(|(&page_num, &offset)| (page_num, offset))
+            .collect();
+        frames.sort();
+        if self.double_write.is_on() {
+            for &(page_num, offset) in &frames {
+                if let Some(frame) = read_frame(&mut self.wal.file, offset) {
+                    (self.double_write).append(page_num, &frame[WAL_FRAME_HEADER_SIZE..])?;
+                }
+            }
+            self.double_write.sync()?;
+        }
+        for (page_num, offset) in frames {
+            let Some(frame) = read_frame(&mut self.wal.file, offset) else {
+                continue;
+            };
+            write_page(&mut self.file, page_num, &frame[WAL_FRAME_HEADER_SIZE..])?;
+        }
+        // The database may have shrunk
+        self.file.set_len(self.wal.db_size)?;
+        self.sync_file(synchronous >= Synchronous::Normal)?;
+        self.wal.reset(synchronous == Synchronous::Full)
+    }
+}
+
+pub struct Pager {
+    backend: Backend,
+    journal_mode: JournalMode,
+    // Read from the header of an existing file
+    page_size: usize,
+    // Pages in the file, plus the ones allocated since it was opened
+    num_pages: usize,
+    pages: HashMap<usize, CachedPage>,
+    // Page numbers of the cached pages by when they were last used, least
+    // recently used first
+    recency: BTreeMap<u64, usize>,
+    clock: u64,
+    // Most pages kept in memory. The least recently used one is written
+    // out to make room for another.
+    cache_size: usize,
+    // The pages changed since each savepoint that is set, innermost last
+    savepoints: Vec<SavedPages>,
+    // Bytes at the start of the file read through a memory map, 0 when it
+    // isn't mapped. Always 0 without the mmap feature.
+    mmap_size: usize,
+    synchronous: Synchronous,
+    // Pages are compressed as they are written out. Always off without the
+    // compression feature.
+    compression: bool,
+    // Set when the database is encrypted
+    cipher: Option<Cipher>,
+    // Set when a page read from disk failed its checksum. It is handed out
+    // zeroed, and the changes since the last commit are thrown away.
+    corrupt: bool,
+    // Set when writing a page out of the cache failed. The changes since the
+    // last commit are thrown away at the next one, which fails with it.
+    write_error: Option<io::Error>,
+    // Where the running backups collect the pages commits write, gone once
+    // a backup is dropped
+    backups: Vec<Weak<RefCell<HashSet<usize>>>>,
+    // Pages in the log past which a commit copies it into the file, never
+    // when 0
+    wal_autocheckpoint: usize,
+    // Commits made within this long of the first one the log has not been
+    // synced for are left in the log unsynced, so one sync covers them all.
+    // The log is synced at the end of the window at the latest, a crash
+    // before then loses the commits of the group. Only in WAL mode.
+    commit_window: Duration,
+    // When the first commit of the group waiting for a sync was made
+    group_start: Option<Instant>,
+    // The pages kept for each snapshot that is still around
+    versions: Vec<Version>,
+    counts: PageCounts,
+}
+
+// Pages read from and written to the file or the log since the database was
+// opened, and the lookups that found the page in the cache or had to load it
+#[derive(Default, Clone, Copy)]
+pub struct PageCounts {
+    pub read: usize,
+    pub written: usize,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+// What .stats shows of the database and its pager
+pub struct Stats {
+    // Rows in the tables, not counting index entries
+    pub rows: usize,
+    pub pages: usize,
+    pub page_size: usize,
+    pub cached_pages: usize,
+    pub cache_size: usize,
+    pub counts: PageCounts,
+    // 0 for a database in memory
+    pub file_size: usize,
+}
+
+// The pages changed since a savepoint was set, as they were then. A page
+// only changed since a savepoint set after it is kept by that one alone.
+struct SavedPages {
+    num_pages: usize,
+    pages: HashMap<usize, Box<[u8]>>,
+}
+
+// The pages changed since a snapshot was taken, as they were then
+struct Version {
+    // The snapshot's catalog, gone once the snapshot is dropped
+    tables: Weak<Vec<TableEntry>>,
+    pages: HashMap<usize, Box<[u8]>>,
+}
+
+#[derive(Clone)]
+struct CachedPage {
+    data: Box<[u8]>,
+    last_used: u64,
+    // Changed since it was read or last written out
+    dirty: bool,
+}
+
+impl Pager {
+    // Opens the file, or a database in memory only for ":memory:"
+    // Pages of the file are encrypted when it is opened with a key, which
+    // has to be the one it was created with. An in-memory database ignores
+    // the key.
+    pub fn pager_open(filename: &str, key: Option<&str>) -> Result<Self, String> {
+        let backend = match filename {
+            MEMORY_DATABASE => Backend::Memory,
+            _ => Backend::Disk(Box::new(Disk::open(filename)?)),
+        };
+        let mut pager = Self {
+            backend,
+            journal_mode: JournalMode::Wal,
+            page_size: DEFAULT_PAGE_SIZE,
+            num_pages: 0,
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            cache_size: DEFAULT_CACHE_SIZE,
+            savepoints: Vec::new(),
+            mmap_size: 0,
+            synchronous: Synchronous::Full,
+            compression: false,
+            cipher: None,
+            corrupt: false,
+            write_error: None,
+            backups: Vec::new(),
+            wal_autocheckpoint: DEFAULT_WAL_AUTOCHECKPOINT,
+            commit_window: Duration::ZERO,
+            group_start: None,
+            versions: Vec::new(),
+            counts: PageCounts::default(),
+        };
+
+        if let Backend::Disk(disk) = &mut pager.backend {
+            if disk.file.metadata().is_err() {
+                return Err("Unable to get metadata.".to_string());
+            }
+            if disk.db_length() == 0 {
+                pager.cipher = match key.map(Cipher::create) {
+                    Some(None) => return Err(disk.reject(NO_ENCRYPTION)),
+                    cipher => cipher.flatten(),
+                };
+                return Ok(pager);
+            }
+
+            let (page_size, salt) = disk.check_header()?;
+            pager.page_size = page_size;
+            pager.cipher = match (salt, key) {
+                (None, None) => None,
+                (None, Some(_)) => return Err(disk.reject("File is not encrypted.")),
+                (Some(_), None) => return Err(disk.reject("File is encrypted, a key is needed.")),
+                (Some(salt), Some(key)) => match Cipher::open(key, salt) {
+                    Some(cipher) => Some(cipher),
+                    None => return Err(disk.reject(NO_ENCRYPTION)),
+                },
+            };
+            // Page 0 only decrypts with the right key
+            if let Some(cipher) = &pager.cipher {
+                let mut header = vec![0; page_size];
+                disk.read_page(0, 1, &mut header);
+                if !decode_page(0, &mut header, Some(cipher)) {
+                    return Err(disk.reject("Wrong key."));
+                }
+            }
+            // Enough to read the catalog, which has the number of pages
+            pager.num_pages = 1;
+        }
+        Ok(pager)
+    }
+
+    // Bytes of a page not taken by its trailer on disk, or the tag and
+    // nonce it is encrypted with
+    fn usable_size(&self) -> usize {
+        let reserve = if self.cipher.is_some() {
+            ENCRYPTION_RESERVE
+        } else {
+            0
+        };
+        self.page_size - PAGE_TRAILER_SIZE - reserve
+    }
+
+    pub fn get_page(&mut self, page_num: usize) -> &[u8] {
+        &self.load(page_num).data
+    }
+
+    // The page, marked to be written back to the file
+    pub fn get_page_mut(&mut self, page_num: usize) -> &mut [u8] {
+        self.preserve(page_num);
+        let page = self.load(page_num);
+        page.dirty = true;
+        &mut page.data
+    }
+
+    // Grows the database by a zeroed page, which isn't on disk yet
+    fn add_page(&mut self) -> usize {
+        let page_num = self.num_pages;
+        self.num_pages += 1;
+        self.shrink(self.cache_size - 1);
+        self.clock += 1;
+        self.recency.insert(self.clock, page_num);
+        let page = CachedPage {
+            data: vec![0; self.page_size].into_boxed_slice(),
+            last_used: self.clock,
+            dirty: true,
+        };
+        self.pages.insert(page_num, page);
+        page_num
+    }
+
+    // A page that was just loaded, which stays cached until the next one is
+    fn cached(&self, page_num: usize) -> &[u8] {
+        &self.pages[&page_num].data
+    }
+
+    // Keeps the page as it is now in the innermost savepoint and the
+    // snapshots that haven't kept it yet, before it changes
+    fn preserve(&mut self, page_num: usize) {
+        if let Some(saved) = self.savepoints.last()
+            && page_num < saved.num_pages
+            && !saved.pages.contains_key(&page_num)
+        {
+            let image = self.load(page_num).data.clone();
+            let saved = self.savepoints.last_mut().unwrap();
+            saved.pages.insert(page_num, image);
+        }
+        self.versions
+            .retain(|version| version.tables.strong_count() > 0);
+        if (self.versions.iter()).all(|version| version.pages.contains_key(&page_num)) {
+            return;
+        }
+        let image = self.load(page_num).data.clone();
+        for version in &mut self.versions {
+            (version.pages)
+                .entry(page_num)
+                .or_insert_with(|| image.clone());
+        }
+    }
+
+    // Starts keeping the pages the snapshot is to see as they are now
+    fn add_version(&mut self, tables: &Rc<Vec<TableEntry>>) {
+        self.versions.push(Version {
+            tables: Rc::downgrade(tables),
+            pages: HashMap::new(),
+        });
+    }
+
+    // The page as it was when the snapshot was taken, from the cache when
+    // it hasn't changed since
+    fn get_page_as_of(&mut self, page_num: usize, snapshot: &Snapshot) -> &[u8] {
+        if self.kept_image(page_num, snapshot).is_none() {
+            self.load(page_num);
+        }
+        self.cached_as_of(page_num, snapshot)
+    }
+
+    // Like cached, for a page the snapshot may have kept
+    fn cached_as_of(&self, page_num: usize, snapshot: &Snapshot) -> &[u8] {
+        self.kept_image(page_num, snapshot)
+            .unwrap_or_else(|| self.cached(page_num))
+    }
+
+    fn kept_image(&self, page_num: usize, snapshot: &Snapshot) -> Option<&[u8]> {
+        let version = (self.versions.iter())
+            .find(|version| version.tables.as_ptr() == Rc::as_ptr(&snapshot.tables))?;
+        version.pages.get(&page_num).map(|image| &image[..])
+    }
+
+    fn load(&mut self, page_num: usize) -> &mut CachedPage {
+        self.clock += 1;
+        let last_used = self.clock;
+        if let Some(page) = self.pages.get_mut(&page_num) {
+            self.counts.hits += 1;
+            self.recency.remove(&page.last_used);
+            self.recency.insert(last_used, page_num);
+            page.last_used = last_used;
+            return self.pages.get_mut(&page_num).unwrap();
+        }
+
+        self.counts.misses += 1;
+        self.shrink(self.cache_size - 1);
+        // Allocate memory and load from the log or the file
+        let mut data = vec![0; self.page_size].into_boxed_slice();
+        let on_disk = match &mut self.backend {
+            Backend::Disk(disk) => disk.read_page(page_num, self.num_pages, &mut data),
+            Backend::Memory => false,
+        };
+        if on_disk {
+            self.counts.read += 1;
+        }
+        if on_disk && !decode_page(page_num, &mut data, self.cipher.as_ref()) {
+            data.fill(0);
+            self.corrupt = true;
+        }
+        self.recency.insert(last_used, page_num);
+        let page = CachedPage {
+            data,
+            last_used,
+            dirty: false,
+        };
+        self.pages.entry(page_num).or_insert(page)
+    }
+
+    // Drops the least recently used pages, writing out the dirty ones,
+    // until no more than the count are left. A database in memory keeps
+    // them all, and so does one another process keeps from being written
+    // until the commit, which fails.
+    fn shrink(&mut self, count: usize) {
+        let Backend::Disk(disk) = &mut self.backend else {
+            return;
+        };
+        while self.pages.len() > count {
+            let (last_used, page_num) = self.recency.pop_first().unwrap();
+            if self.pages[&page_num].dirty && !disk.lock_exclusive() {
+                self.recency.insert(last_used, page_num);
+                return;
+            }
+            let page = self.pages.remove(&page_num).unwrap();
+            if !page.dirty {
+                continue;
+            }
+            let data = encode_page(page_num, &page.data, self.compression, self.cipher.as_ref());
+            let written = match self.journal_mode {
+                JournalMode::Wal => disk.wal.append(page_num, &data, 0),
+                JournalMode::Delete => {
+                    let sync = self.synchronous >= Synchronous::Normal;
+                    (disk.journal)
+                        .save(&mut disk.file, self.page_size, &[page_num], sync)
+                        .and_then(|()| disk.write_pages(&[(page_num, data)]))
+                }
+            };
+            if let Err(error) = written {
+                // The page stays cached until the commit throws it away
+                self.pages.insert(page_num, page);
+                self.recency.insert(last_used, page_num);
+                self.write_error = Some(error);
+                return;
+            }
+            self.counts.written += 1;
+        }
+    }
+
+    pub fn set_cache_size(&mut self, cache_size: usize) {
+        self.cache_size = cache_size.max(1);
+        self.shrink(self.cache_size);
+    }
+
+    // Starts keeping the images of the pages changed from now on, for a
+    // rollback to put back
+    fn set_savepoint(&mut self) {
+        self.savepoints.push(SavedPages {
+            num_pages: self.num_pages,
+            pages: HashMap::new(),
+        });
+    }
+
+    // Forgets the savepoints from the index on. The images they kept of
+    // pages the one before hasn't go to it, they are what the pages were
+    // when it was set.
+    fn release_savepoints(&mut self, index: usize) {
+        let released = self.savepoints.split_off(index);
+        let Some(outer) = self.savepoints.last_mut() else {
+            return;
+        };
+        for saved in released {
+            for (page_num, image) in saved.pages {
+                if page_num < outer.num_pages {
+                    outer.pages.entry(page_num).or_insert(image);
+                }
+            }
+        }
+    }
+
+    // Puts the pages back as they were when the savepoint at the index was
+    // set, which stays set. The images go into the cache as dirty pages, so
+    // they replace the ones written out since at the commit.
+    fn roll_back_savepoint(&mut self, index: usize) {
+        self.release_savepoints(index + 1);
+        let saved = &self.savepoints[index];
+        let num_pages = saved.num_pages;
+        let images: Vec<(usize, Box<[u8]>)> = (saved.pages.iter())
+            .map(|(&page_num, image)| (page_num, image.clone()))
+            .collect();
+        self.truncate(num_pages);
+        for (page_num, data) in images {
+            self.preserve(page_num);
+            self.clock += 1;
+            if let Some(page) = self.pages.get(&page_num) {
+                self.recency.remove(&page.last_used);
+            }
+            self.recency.insert(self.clock, page_num);
+            let page = CachedPage {
+                data,
+                last_used: self.clock,
+                dirty: true,
+            };
+            self.pages.insert(page_num, page);
+        }
+        self.shrink(self.cache_size);
+    }
+
+    // Makes the changes since the last commit durable. In memory the pages
+    // are only marked clean. False when another process has the file open,
+    // nothing is written then. Fails when writing the changes failed, they
+    // are to be thrown away then.
+    fn commit(&mut self) -> io::Result<bool> {
+        if let Some(error) = self.write_error.take() {
+            return Err(error);
+        }
+        let mut dirty: Vec<usize> = (self.pages.iter())
+            .filter(|(_, page)| page.dirty)
+            .map(|(&page_num, _)| page_num)
+            .collect();
+        dirty.sort();
+        self.backups.retain(|changes| match changes.upgrade() {
+            Some(changes) => {
+                changes.borrow_mut().extend(&dirty);
+                true
+            }
+            None => false,
+        });
+        if let Backend::Disk(_) = &self.backend {
+            self.counts.written += dirty.len();
+        }
+        let committed = match (&self.backend, self.journal_mode) {
+            (Backend::Memory, _) => {
+                for page in self.pages.values_mut() {
+                    page.dirty = false;
+                }
+                Ok(true)
+            }
+            (Backend::Disk(_), JournalMode::Wal) => self.commit_to_wal(dirty),
+            (Backend::Disk(_), JournalMode::Delete) => self.commit_journaled(dirty),
+        };
+        if let Backend::Disk(disk) = &mut self.backend {
+            disk.unlock_exclusive();
+        }
+        self.remap();
+        committed
+    }
+
+    // Maps the file again after it may have changed length
+    fn remap(&mut self) {
+        if let Backend::Disk(disk) = &mut self.backend {
+            disk.remap(self.mmap_size);
+        }
+    }
+
+    // The dirty pages go to the log, which is synced to disk, then into the
+    // database file. When the frames can't be written or synced they are
+    // cut off the log, and the commit fails.
+    fn commit_to_wal(&mut self, mut dirty: Vec<usize>) -> io::Result<bool> {
+        let pending = matches!(&self.backend, Backend::Disk(disk) if disk.wal.pending);
+        if dirty.is_empty() && !pending {
+            // A statement after the window ends the group even when it
+            // changed nothing
+            if (self.group_start).is_some_and(|start| start.elapsed() >= self.commit_window) {
+                self.sync_group()?;
+            }
+            return Ok(true);
+        }
+        // A commit ends on a frame, one of page 0 when there is nothing else
+        // left to write
+        if dirty.is_empty() {
+            dirty.push(0);
+            self.load(0);
+        }
+
+        let Backend::Disk(disk) = &mut self.backend else {
+            return Ok(true);
+        };
+        if !disk.lock_exclusive() {
+            return Ok(false);
+        }
+        let start_size = disk.wal.size;
+        let mut written = Ok(());
+        let last = dirty.len() - 1;
+        for (i, page_num) in dirty.into_iter().enumerate() {
+            let page = self.pages.get_mut(&page_num).unwrap();
+            page.dirty = false;
+            let commit_size = if i == last { self.num_pages } else { 0 };
+            let data = encode_page(page_num, &page.data, self.compression, self.cipher.as_ref());
+            written = disk.wal.append(page_num, &data, commit_size);
+            if written.is_err() {
+                break;
+            }
+        }
+        let now = Instant::now();
+        let start = *self.group_start.get_or_insert(now);
+        if written.is_ok() && now.duration_since(start) >= self.commit_window {
+            written = self.sync_group();
+        } else if written.is_ok() && start == now {
+            written = self.sync_later();
+        }
+        if let Err(error) = written {
+            if let Backend::Disk(disk) = &mut self.backend {
+                disk.wal.drop_from(start_size);
+            }
+            return Err(error);
+        }
+        Ok(true)
+    }
+
+    // Syncs the log once the window of the group that just started is over,
+    // in case no commit comes along to do it before
+    fn sync_later(&self) -> io::Result<()> {
+        let Backend::Disk(disk) = &self.backend else {
+            return Ok(());
+        };
+        if self.synchronous >= Synchronous::Normal {
+            let file = disk.wal.file.try_clone()?;
+            let window = self.commit_window;
+            std::thread::spawn(move || {
+                std::thread::sleep(window);
+                let _ = file.sync_all();
+            });
+        }
+        Ok(())
+    }
+
+    // Syncs the log with the commits of the group in it, then copies the
+    // log into the database file once it has grown past the limit
+    fn sync_group(&mut self) -> io::Result<()> {
+        self.group_start = None;
+        let Backend::Disk(disk) = &mut self.backend else {
+            return Ok(());
+        };
+        if self.synchronous >= Synchronous::Normal {
+            disk.wal.file.sync_all()?;
+        }
+        // Kept in the log, which is synced, when another process has the
+        // file open, or when the copy fails. The next checkpoint tries again.
+        if self.wal_autocheckpoint > 0
+            && disk.wal.num_frames(self.page_size) >= self.wal_autocheckpoint
+            && disk.lock_exclusive()
+        {
+            let _ = disk.checkpoint(self.synchronous);
+        }
+        Ok(())
+    }
+
+    // Copies the commits in the log into the database file and empties the
+    // log. False when another process has the file open, or pages a
+    // savepoint holds back were written to the log, they stay in the log
+    // then.
+    pub fn checkpoint(&mut self) -> io::Result<bool> {
+        if self.group_start.is_some() {
+            self.sync_group()?;
+        }
+        let Backend::Disk(disk) = &mut self.backend else {
+            return Ok(true);
+        };
+        if disk.wal.pending || !disk.lock_exclusive() {
+            return Ok(false);
+        }
+        disk.checkpoint(self.synchronous)?;
+        Ok(true)
+    }
+
+    // The original images of the dirty pages go to the journal, then the
+    // pages into the database file. Once that is synced to disk the journal
+    // is deleted.
+    fn commit_journaled(&mut self, dirty: Vec<usize>) -> io::Result<bool> {
+        let Backend::Disk(disk) = &mut self.backend else {
+            return Ok(true);
+        };
+        // Pages past the end of the database are cut off the file, which
+        // saves them as well
+        let file_pages = disk.file_length().div_ceil(self.page_size);
+        let removed: Vec<usize> = (self.num_pages..file_pages).collect();
+        if dirty.is_empty() && removed.is_empty() && disk.journal.file.is_none() {
+            return Ok(true);
+        }
+        if !disk.lock_exclusive() {
+            return Ok(false);
+        }
+        let sync = self.synchronous >= Synchronous::Normal;
+        (disk.journal).save(&mut disk.file, self.page_size, &dirty, false)?;
+        (disk.journal).save(&mut disk.file, self.page_size, &removed, sync)?;
+        let mut pages = Vec::new();
+        for page_num in dirty {
+            let page = self.pages.get_mut(&page_num).unwrap();
+            page.dirty = false;
+            let data = encode_page(page_num, &page.data, self.compression, self.cipher.as_ref());
+            pages.push((page_num, data));
+        }
+        disk.write_pages(&pages)?;
+        if !removed.is_empty() {
+            (disk.file).set_len((self.num_pages * self.page_size) as u64)?;
+        }
+        disk.sync_file(sync)?;
+        disk.journal.finish(self.synchronous == Synchronous::Full)?;
+        Ok(true)
+    }
+
+    // Throws away the changes since the last commit and empties the cache
+    fn discard(&mut self) {
+        self.preserve_dirty();
+        self.pages.clear();
+        self.recency.clear();
+        self.num_pages = 0;
+        if let Backend::Disk(disk) = &mut self.backend {
+            match self.journal_mode {
+                JournalMode::Wal => disk.wal.drop_pending(),
+                // A journal that can't be put back stays hot, it is put back
+                // when the file is opened again
+                JournalMode::Delete => {
+                    let _ = disk.journal.roll_back(&mut disk.file);
+                    let _ = disk.journal.finish(true);
+                    let _ = disk.sync_file(false);
+                }
+            }
+            self.num_pages = disk.db_length().div_ceil(self.page_size);
+            disk.unlock_exclusive();
+        }
+        self.remap();
+        self.savepoints.clear();
+        self.corrupt = false;
+        self.write_error = None;
+    }
+
+    // Empties a database that is not in the file yet and changes its page
+    // size. False once anything was written to the file, or committed in
+    // memory.
+    fn set_page_size(&mut self, page_size: usize) -> bool {
+        let written = match &self.backend {
+            Backend::Disk(disk) => disk.db_length() > 0,
+            Backend::Memory => self.pages.values().any(|page| !page.dirty),
+        };
+        if written {
+            return false;
+        }
+        self.discard();
+        self.page_size = page_size;
+        true
+    }
+
+    // Without the mmap feature pages are always read, and the size stays 0
+    pub fn set_mmap_size(&mut self, mmap_size: usize) {
+        if cfg!(feature = "mmap") && matches!(self.backend, Backend::Disk(_)) {
+            self.mmap_size = mmap_size;
+        }
+        self.remap();
+    }
+
+    // False when the pages are no larger than a block of the file system,
+    // holes in them would free nothing. Compression stays off then.
+    pub fn set_compression(&mut self, compression: bool) -> bool {
+        if let Backend::Disk(disk) = &self.backend
+            && compression
+            && self.page_size <= block_size(&disk.file)
+        {
+            return false;
+        }
+        self.compression = compression && cfg!(feature = "compression");
+        true
+    }
+
+    pub fn set_synchronous(&mut self, synchronous: Synchronous) {
+        self.synchronous = synchronous;
+    }
+
+    // False when commits in the log can't be copied into the file, which
+    // they have to be before pages are written to it directly, or while
+    // savepoints are set. The mode stays as it was then.
+    pub fn set_journal_mode(&mut self, journal_mode: JournalMode) -> io::Result<bool> {
+        if journal_mode != self.journal_mode
+            && (!self.savepoints.is_empty() || !self.checkpoint()?)
+        {
+            return Ok(false);
+        }
+        self.journal_mode = journal_mode;
+        Ok(true)
+    }
+
+    pub fn set_wal_autocheckpoint(&mut self, wal_autocheckpoint: usize) {
+        self.wal_autocheckpoint = wal_autocheckpoint;
+    }
+
+    // An in-memory database never writes its pages out, the buffer stays
+    // off
+    pub fn set_double_write(&mut self, on: bool) -> io::Result<()> {
+        match &mut self.backend {
+            Backend::Disk(disk) => disk.double_write.set_on(on),
+            Backend::Memory => Ok(()),
+        }
+    }
+
+    fn double_write(&self) -> bool {
+        matches!(&self.backend, Backend::Disk(disk) if disk.double_write.is_on())
+    }
+
+    // Whether commits are in the log that is not synced yet
+    fn unsynced(&self) -> bool {
+        self.group_start.is_some()
+    }
+
+    pub fn set_commit_window(&mut self, commit_window: Duration) {
+        self.commit_window = commit_window;
+    }
+
+    // Commits, empties the cache and removes the log. The log stays when
+    // its commits couldn't be copied into the file, they are copied when it
+    // is opened again. The file is left for others to write.
+    fn close(&mut self) -> io::Result<()> {
+        let committed = self.commit();
+        let checkpointed = self.checkpoint();
+        self.pages.clear();
+        self.recency.clear();
+        if let Backend::Disk(disk) = &mut self.backend {
+            if matches!(checkpointed, Ok(true)) {
+                disk.remove_files();
+            }
+            disk.unlock_exclusive();
+        }
+        committed.and(checkpointed).map(|_| ())
+    }
+
+    // Shrinks the database to the number of pages, dropping the cached pages
+    // past its end
+    fn truncate(&mut self, num_pages: usize) {
+        if !self.versions.is_empty() || !self.savepoints.is_empty() {
+            for page_num in num_pages..self.num_pages {
+                self.preserve(page_num);
+            }
+        }
+        self.num_pages = num_pages;
+        self.pages.retain(|&page_num, _| page_num < num_pages);
+        self.recency.retain(|_, page_num| *page_num < num_pages);
+    }
+
+    // Keeps the pages changed since the last commit in the snapshots, before
+    // the changes are undone
+    fn preserve_dirty(&mut self) {
+        let dirty: Vec<usize> = (self.pages.iter())
+            .filter(|(_, page)| page.dirty)
+            .map(|(&page_num, _)| page_num)
+            .collect();
+        for page_num in dirty {
+            self.preserve(page_num);
+        }
+    }
+}
+
+// Where a table's rows live, as recorded in the catalog on page 0
+#[derive(Clone)]
+pub struct TableEntry {
+    pub name: String,
+    root_page: usize,
+    num_rows: usize,
+    schema_page: usize,
+    // Kept up to date as rows come and go, meaningless without rows
+    min_key: i64,
+    max_key: i64,
+    pub schema: Schema,
+    // The tree of an index rather than a table
+    is_index: bool,
+}
+
+impl TableEntry {
+    // The keys the table's rows lie between, None when it has none
+    fn key_range(&self) -> Option<RangeInclusive<i64>> {
+        (self.num_rows > 0).then_some(self.min_key..=self.max_key)
+    }
+}
+
+// An open database file and the tables in it
+pub struct Table {
+    pager: Pager,
+    tables: Vec<TableEntry>,
+    // Pages the catalog goes on past page 0, in chain order
+    catalog_pages: Vec<usize>,
+    // First page of the free list, 0 when it is empty
+    free_list_head: usize,
+    // Whether commits give the free pages back by shrinking the file
+    auto_vacuum: bool,
+    // Bytes of rows an ORDER BY holds in memory, past them it sorts the
+    // rows in runs written to temporary files and merges the runs
+    sort_memory: usize,
+    // Triggers whose body is running, they don't fire again until it ends
+    firing: Vec<String>,
+    // Innermost last
+    savepoints: Vec<Savepoint>,
+    // The file and the key the database was opened with, to open it again
+    filename: String,
+    key: Option<String>,
+    // The snapshot pages and catalog entries are read from while a cursor
+    // reading through it has the table
+    view: Option<Snapshot>,
+    // Why the statement running stopped reading rows short, it fails with
+    // it once it ends
+    read_error: Option<ExecuteResult>,
+}
+
+// The database as it was when the snapshot was taken. Cursors started in
+// it see none of what is written to the database after, however their
+// reads and the writes interleave. The pager keeps the images of the pages
+// changed since for as long as the snapshot lives.
+//
+// Only a statement that writes a table while a cursor reads it needs one,
+// which CREATE INDEX does. A select writes nothing but the tables of its
+// WITH clause, and a delete reads every row it removes before it removes
+// any, so they read the trees as they are. No other process writes while
+// the file is open here, the lock on it keeps them out.
+#[derive(Clone)]
+pub struct Snapshot {
+    // The catalog as it was
+    tables: Rc<Vec<TableEntry>>,
+}
+
+// The catalog as it was when a savepoint was set. The pager keeps the
+// images of the pages changed since.
+struct Savepoint {
+    name: String,
+    tables: Vec<TableEntry>,
+    catalog_pages: Vec<usize>,
+    free_list_head: usize,
+}
+
+// A copy of the database being written to another file a few pages at a
+// time, while the database goes on being used
+pub struct Backup {
+    file: File,
+    page_size: usize,
+    // Pages from here on haven't been copied yet
+    next_page: usize,
+    // Pages commits wrote since the last step, and the ones among them that
+    // were already copied and have to be copied again
+    changes: Rc<RefCell<HashSet<usize>>>,
+    retry: BTreeSet<usize>,
+}
+
+impl Backup {
+    // Copies up to the count of pages, true once the copy is complete and
+    // synced. Pages changed by commits while the copy is made are copied
+    // again. Nothing is copied while savepoints hold back a commit.
+    pub fn step(&mut self, table: &mut Table, count: usize) -> io::Result<bool> {
+        if !table.savepoints.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "savepoints are open",
+            ));
+        }
+        let pager = &mut table.pager;
+        // The database was created over with another page size
+        if pager.page_size != self.page_size {
+            self.page_size = pager.page_size;
+            self.next_page = 0;
+            self.retry.clear();
+        }
+        let next_page = self.next_page;
+        (self.retry).extend(
+            self.changes
+                .borrow_mut()
+                .drain()
+                .filter(|&page_num| page_num < next_page),
+        );
+
+        for _ in 0..count {
+            let page_num = match self.retry.pop_first() {
+                Some(page_num) => page_num,
+                None if self.next_page < pager.num_pages => {
+                    self.next_page += 1;
+                    self.next_page - 1
+                }
+                None => break,
+            };
+            if page_num >= pager.num_pages {
+                continue;
+            }
+            let data = pager.get_page(page_num).to_vec();
+            if pager.corrupt {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "database disk image is malformed",
+                ));
+            }
+            let data = encode_page(page_num, &data, pager.compression, pager.cipher.as_ref());
+            write_page(&mut self.file, page_num, &data)?;
+        }
+
+        if !self.retry.is_empty() || self.next_page < pager.num_pages {
+            return Ok(false);
+        }
+        // The database may have shrunk since its last pages were copied
+        self.file
+            .set_len((pager.num_pages * self.page_size) as u64)?;
+        self.file.sync_all()?;
+        Ok(true)
+    }
+}
+
+type TableRef = Rc<RefCell<Table>>;
+
+pub struct Cursor {
+    table: TableRef,
+    // Position of the table being walked in the catalog
+    table_index: usize,
+    page_num: usize,
+    cell_num: usize,
+    end_of_table: bool,
+    // Pages of the table the cursor has been on
+    pages_read: usize,
+    // Set when the cursor reads the table as it was when the snapshot was
+    // taken
+    snapshot: Option<Snapshot>,
+    // The schema the cursor reads rows with as an iterator, taken from the
+    // catalog on its first row
+    schema: Option<Schema>,
+    // Set when the cursor advances towards the first row
+    reverse: bool,
+    // The keys the cursor stops at the end of, when it reads only some
+    range: Option<RangeInclusive<i64>>,
+}
+
+impl Cursor {
+    pub fn from_start(table: TableRef, table_index: usize) -> Self {
+        Self::start(table, table_index, None)
+    }
+
+    // A cursor at the start of the table as it was when the snapshot was
+    // taken, which goes on reading it that way while the table is written
+    pub fn from_start_in(table: TableRef, snapshot: &Snapshot, table_index: usize) -> Self {
+        Self::start(table, table_index, Some(snapshot.clone()))
+    }
+
+    fn start(table: TableRef, table_index: usize, snapshot: Option<Snapshot>) -> Self {
+        let mut cursor = Self {
+            table,
+            table_index,
+            page_num: 0,
+            cell_num: 0,
+            end_of_table: true,
+            pages_read: 0,
+            snapshot,
+            schema: None,
+            reverse: false,
+            range: None,
+        };
+        let (page_num, pages_read, num_rows) = cursor.read(|table| {
+            let (page_num, pages_read) = table.find_leaf(table_index, i64::MIN);
+            (page_num, pages_read, table.entry(table_index).num_rows)
+        });
+        cursor.page_num = page_num;
+        if num_rows > 0 {
+            cursor.end_of_table = false;
+            cursor.pages_read = pages_read;
+            cursor.skip_leaf_ends();
+        }
+        cursor
+    }
+
+    // Reads the table through the cursor's snapshot, when it has one
+    fn read<R>(&self, read: impl FnOnce(&mut Table) -> R) -> R {
+        let mut table = self.table.borrow_mut();
+        let view = std::mem::replace(&mut table.view, self.snapshot.clone());
+        let result = read(&mut table);
+        table.view = view;
+        result
+    }
+
+    // The first cell whose key is not below the key: the cell with the key,
+    // or the position a cell with it would be inserted at
+    pub fn seek(table: TableRef, table_index: usize, key: i64) -> Self {
+        let (mut page_num, mut pages_read) = table.borrow_mut().find_leaf(table_index, key);
+        // A key at the end of a leaf goes to the start of the next one, so
+        // the search carries on along the leaves past the one it landed on
+        let (cell_num, num_cells) = loop {
+            let mut table = table.borrow_mut();
+            let page = table.get_page(page_num);
+            let cell_num = leaf_find(page, key);
+            let num_cells = leaf_num_cells(page);
+            match leaf_next(page) {
+                next_leaf if next_leaf != 0 && cell_num >= num_cells => {
+                    page_num = next_leaf;
+                    pages_read += 1;
+                }
+                _ => break (cell_num, num_cells),
+            }
+        };
+        Self {
+            table,
+            table_index,
+            page_num,
+            cell_num,
+            end_of_table: cell_num >= num_cells,
+            pages_read,
+            snapshot: None,
+            schema: None,
+            reverse: false,
+            range: None,
+        }
+    }
+
+    // A cursor on the last row of the table, which advances towards the
+    // first
+    pub fn from_end_rev(table: TableRef, table_index: usize) -> Self {
+        let (page_num, pages_read, num_cells) = {
+            let mut table = table.borrow_mut();
+            let root_page = table.tables[table_index].root_page;
+            let (page_num, pages_read) = table.rightmost_leaf(root_page);
+            (
+                page_num,
+                pages_read,
+                leaf_num_cells(table.get_page(page_num)),
+            )
+        };
+        Self {
+            table,
+            table_index,
+            page_num,
+            cell_num: num_cells.saturating_sub(1),
+            end_of_table: num_cells == 0,
+            pages_read,
+            snapshot: None,
+            schema: None,
+            reverse: true,
+            range: None,
+        }
+    }
+
+    // A cursor on the first row with a key in the range, which reaches the
+    // end of the table after the last one
+    pub fn from_range(table: TableRef, table_index: usize, range: RangeInclusive<i64>) -> Self {
+        let mut cursor = Self::seek(table, table_index, *range.start());
+        cursor.range = Some(range);
+        cursor.leave_range();
+        cursor
+    }
+
+    // A cursor on the last row with a key in the range, which advances
+    // towards the first
+    pub fn from_range_rev(table: TableRef, table_index: usize, range: RangeInclusive<i64>) -> Self {
+        let mut cursor = Self::seek(table, table_index, *range.end());
+        cursor.reverse = true;
+        // The cell found is the first past the range unless it holds the
+        // range's last key, the one before it is then the last in range
+        if cursor.key().is_none_or(|key| key > *range.end()) {
+            if cursor.cell_num > 0 {
+                cursor.cell_num -= 1;
+                cursor.end_of_table = false;
+            } else if !cursor.end_of_table {
+                cursor.retreat();
+            }
+        }
+        cursor.range = Some(range);
+        cursor.leave_range();
+        cursor
+    }
+
+    // Ends the table for a cursor that has moved out of its range
+    fn leave_range(&mut self) {
+        if let (Some(range), Some(key)) = (&self.range, self.key())
+            && !range.contains(&key)
+        {
+            self.end_of_table = true;
+        }
+    }
+
+    // Offset of the current row within the page returned by get_value
+    pub fn row_offset(&self) -> usize {
+        leaf_cell_offset(&self.get_value(), self.cell_num) + KEY_SIZE
+    }
+
+    // The current row as it is stored, without its overflow
+    fn stored_row(&self) -> Vec<u8> {
+        let row_offset = self.row_offset();
+        let page = self.get_value();
+        page[row_offset..row_offset + row_length(&page[row_offset..])].to_vec()
+    }
+
+    // Reads the current row with its overflow into the buffer, which a scan
+    // reuses from row to row
+    fn read_row_into(&self, data: &mut Vec<u8>) {
+        let row_offset = self.row_offset();
+        data.clear();
+        {
+            let page = self.get_value();
+            let stored = &page[row_offset..];
+            data.extend_from_slice(&stored[..row_length(stored)]);
+        }
+        self.read(|table| table.append_overflow(self.table_index, data, 0));
+    }
+
+    // Reads the rows from the cursor to the end of its leaf or its range, at
+    // most max of them, one after the other with their overflow into the
+    // data, noting where each ends. The leaf's page is taken once for all
+    // of them rather than once a row. The cursor moves on past them.
+    fn read_leaf(&mut self, max: usize, data: &mut Vec<u8>, ends: &mut Vec<usize>) {
+        if self.end_of_table {
+            return;
+        }
+        let (page_num, first, table_index) = (self.page_num, self.cell_num, self.table_index);
+        let range = self.range.clone();
+        let read = self.read(|table| {
+            // The rows are copied out of the page before their overflow is
+            // read, which takes other pages
+            let mut stored = Vec::new();
+            let mut stored_ends = Vec::new();
+            let page = table.get_page(page_num);
+            for cell_num in first..leaf_num_cells(page).min(first + max) {
+                let cell = &page[leaf_cell_offset(page, cell_num)..];
+                if range
+                    .as_ref()
+                    .is_some_and(|range| !range.contains(&cell_key(cell)))
+                {
+                    break;
+                }
+                let row = &cell[KEY_SIZE..];
+                stored.extend_from_slice(&row[..row_length(row)]);
+                stored_ends.push(stored.len());
+            }
+            let mut row_start = 0;
+            for &row_end in &stored_ends {
+                let start = data.len();
+                data.extend_from_slice(&stored[row_start..row_end]);
+                table.append_overflow(table_index, data, start);
+                ends.push(data.len());
+                row_start = row_end;
+            }
+            stored_ends.len()
+        });
+        self.cell_num += read;
+        self.skip_leaf_ends();
+        self.leave_range();
+    }
+
+    pub fn get_value(&self) -> Ref<'_, [u8]> {
+        let page_num = self.page_num;
+        self.read(|table| {
+            table.get_page(page_num);
+        });
+
+        Ref::map(self.table.borrow(), |table| match &self.snapshot {
+            Some(snapshot) => table.pager.cached_as_of(page_num, snapshot),
+            None => table.pager.cached(page_num),
+        })
+    }
+
+    pub fn advance(&mut self) {
+        if self.reverse {
+            self.retreat();
+        } else {
+            self.cell_num += 1;
+            self.skip_leaf_ends();
+        }
+        self.leave_range();
+    }
+
+    // Moves back a cell, onto the last cell of the leaf before from the
+    // first cell of its own
+    fn retreat(&mut self) {
+        if self.cell_num > 0 {
+            self.cell_num -= 1;
+            return;
+        }
+        let (page_num, table_index) = (self.page_num, self.table_index);
+        let previous = self.read(|table| {
+            let first_key = leaf_key(table.get_page(page_num), 0);
+            let (leaf, pages_read) = table.previous_leaf(table_index, first_key)?;
+            let num_cells = leaf_num_cells(table.get_page(leaf));
+            (num_cells > 0).then_some((leaf, num_cells, pages_read))
+        });
+        match previous {
+            Some((leaf, num_cells, pages_read)) => {
+                self.page_num = leaf;
+                self.cell_num = num_cells - 1;
+                self.pages_read += pages_read;
+            }
+            None => self.end_of_table = true,
+        }
+    }
+
+    // Moves on to the next leaf while the cursor is past the last cell of
+    // its own, so it never rests at the end of a leaf but the last one.
+    // Only a root leaf is ever left without cells.
+    fn skip_leaf_ends(&mut self) {
+        loop {
+            let page_num = self.page_num;
+            let (num_cells, next_leaf) = self.read(|table| {
+                let page = table.get_page(page_num);
+                (leaf_num_cells(page), leaf_next(page))
+            });
+            if self.cell_num < num_cells {
+                return;
+            }
+            match next_leaf {
+                0 => {
+                    self.end_of_table = true;
+                    return;
+                }
+                next_leaf => {
+                    self.page_num = next_leaf;
+                    self.cell_num = 0;
+                    self.pages_read += 1;
+                }
+            }
+        }
+    }
+
+    // The key of the cell under the cursor
+    fn key(&self) -> Option<i64> {
+        (!self.end_of_table).then(|| leaf_key(&self.get_value(), self.cell_num))
+    }
+
+    // Puts a cell with the key at the cursor, false when the table is full
+    fn insert(&self, key: i64, data: &[u8]) -> bool {
+        (self.table.borrow_mut()).leaf_insert(
+            self.table_index,
+            self.page_num,
+            self.cell_num,
+            key,
+            data,
+        )
+    }
+
+    // Takes the cell under the cursor out of the tree. Nodes may merge on
+    // the way, so the cursor can't be used after it.
+    fn remove(self) {
+        let stored = self.stored_row();
+        let key = leaf_key(&self.get_value(), self.cell_num);
+        let mut table = self.table.borrow_mut();
+        table.free_overflow(self.table_index, &stored);
+        table.leaf_remove(self.table_index, self.page_num, self.cell_num);
+        let entry = &mut table.tables[self.table_index];
+        entry.num_rows -= 1;
+        if entry.num_rows > 0 && (key == entry.min_key || key == entry.max_key) {
+            table.read_key_range(self.table_index);
+        }
+    }
+}
+
+impl Table {
+    // Fails with the reason the file can't be opened as a database
+    pub fn db_open(filename: &str, key: Option<&str>) -> Result<Self, String> {
+        let pager = Pager::pager_open(filename, key)?;
+        let mut table = Self {
+            pager,
+            tables: Vec::new(),
+            catalog_pages: Vec::new(),
+            free_list_head: 0,
+            auto_vacuum: false,
+            sort_memory: DEFAULT_SORT_MEMORY,
+            firing: Vec::new(),
+            savepoints: Vec::new(),
+            filename: filename.to_string(),
+            key: key.map(str::to_string),
+            view: None,
+            read_error: None,
+        };
+
+        if table.pager.num_pages == 0 {
+            table.initialize();
+        } else {
+            table.load_catalog();
+        }
+
+        Ok(table)
+    }
+
+    // Opens the file the database was opened from again, with its key
+    pub fn reopen(&self) -> Result<Self, String> {
+        Self::db_open(&self.filename, self.key.as_deref())
+    }
+
+    // Whether the database is the file at the path, however it is named
+    pub fn is_file(&self, path: &str) -> bool {
+        self.filename != MEMORY_DATABASE
+            && matches!(
+                (std::fs::canonicalize(&self.filename), std::fs::canonicalize(path)),
+                (Ok(open), Ok(other)) if open == other
+            )
+    }
+
+    // A new database: reserve the header page and add the default table
+    fn initialize(&mut self) {
+        self.pager.add_page();
+        self.create_table(DEFAULT_TABLE, Schema::default());
+    }
+
+    // Flushes the page cache to disk
+    // Closes the database file
+    // Frees the memory for the pager and table data structures
+    // What savepoints still hold back is rolled back. Fails when what was
+    // committed couldn't be written out, it is then left in the log.
+    pub fn db_close(&mut self) -> io::Result<()> {
+        if self.pager.corrupt || !self.savepoints.is_empty() {
+            self.savepoints.clear();
+            self.pager.discard();
+        } else {
+            self.save_catalog();
+        }
+
+        self.pager.close()
+    }
+
+    // Makes what the statements so far changed durable, unless a savepoint
+    // holds them back. Fails when a page read since the last commit was
+    // corrupt, or when another process has the file open, everything since
+    // is then thrown away, savepoints included.
+    fn commit(&mut self) -> ExecuteResult {
+        if self.pager.corrupt {
+            self.roll_back_all();
+            return ExecuteResult::Corrupt;
+        }
+        if !self.savepoints.is_empty() {
+            return ExecuteResult::Success;
+        }
+        self.save_catalog();
+        if self.auto_vacuum && self.free_list_head != 0 {
+            self.shrink_free_pages();
+            self.save_catalog();
+        }
+        match self.pager.commit() {
+            Ok(true) => ExecuteResult::Success,
+            Ok(false) => {
+                self.roll_back_all();
+                ExecuteResult::Locked
+            }
+            Err(error) => {
+                self.roll_back_all();
+                error.into()
+            }
+        }
+    }
+
+    // Throws away everything since the last commit
+    fn roll_back_all(&mut self) {
+        self.savepoints.clear();
+        self.pager.discard();
+        self.load_catalog();
+    }
+
+    fn load_catalog(&mut self) {
+        let usable_size = self.pager.usable_size();
+        let header = self.pager.get_page(0);
+        let num_pages = read_u32(header, HEADER_NUM_PAGES_OFFSET);
+        let free_list_head = read_u32(header, HEADER_FREE_LIST_OFFSET);
+        let flags = read_u32(header, HEADER_FLAGS_OFFSET);
+        let auto_vacuum = flags & FLAG_AUTO_VACUUM != 0;
+        let compression = flags & FLAG_COMPRESSION != 0;
+        let mut next_page = read_u32(header, HEADER_CATALOG_OFFSET);
+        let num_tables = read_u32(header, HEADER_NUM_TABLES_OFFSET);
+        self.pager.num_pages = num_pages;
+
+        let mut tables = Vec::new();
+        let mut catalog_pages = Vec::new();
+        let (mut page_num, mut offset) = (0, HEADER_SIZE);
+        while tables.len() < num_tables {
+            if offset + CATALOG_ENTRY_SIZE > usable_size {
+                // A chain running past the file or back on itself is cut
+                // short rather than followed
+                if next_page == 0 || next_page >= num_pages || catalog_pages.contains(&next_page) {
+                    break;
+                }
+                (page_num, offset) = (next_page, CATALOG_PAGE_HEADER_SIZE);
+                catalog_pages.push(page_num);
+                next_page = read_u32(self.pager.get_page(page_num), 0);
+            }
+            let page = self.pager.get_page(page_num);
+            let entry = &page[offset..offset + CATALOG_ENTRY_SIZE];
+            let name = String::from_utf8_lossy(trim_padding(&entry[..TABLE_NAME_SIZE]));
+            tables.push(TableEntry {
+                name: name.to_string(),
+                root_page: read_u32(entry, TABLE_NAME_SIZE),
+                num_rows: read_u32(entry, TABLE_NAME_SIZE + 4),
+                schema_page: read_u32(entry, TABLE_NAME_SIZE + 8),
+                min_key: cell_key(&entry[TABLE_NAME_SIZE + 12..]),
+                max_key: cell_key(&entry[TABLE_NAME_SIZE + 12 + KEY_SIZE..]),
+                schema: Schema::default(),
+                is_index: false,
+            });
+            offset += CATALOG_ENTRY_SIZE;
+        }
+
+        self.free_list_head = free_list_head;
+        self.auto_vacuum = auto_vacuum;
+        self.pager.compression = compression && cfg!(feature = "compression");
+        self.catalog_pages = catalog_pages;
+        for table in tables.iter_mut() {
+            match Schema::read_from(self.pager.get_page(table.schema_page)) {
+                Some(schema) => table.schema = schema,
+                None => self.pager.corrupt = true,
+            }
+        }
+        let index_names: Vec<String> = (tables.iter())
+            .flat_map(|table| table.schema.indexes.iter().map(|index| index.name.clone()))
+            .collect();
+        for table in tables.iter_mut() {
+            table.is_index = index_names.contains(&table.name);
+        }
+        self.tables = tables;
+    }
+
+    // Writes the catalog to page 0 and the catalog pages, leaving those
+    // unchanged clean. Catalog pages no longer needed go to the free list.
+    fn save_catalog(&mut self) {
+        let usable_size = self.pager.usable_size();
+        while (self.catalog_pages.len())
+            .checked_sub(1)
+            .is_some_and(|needed| catalog_capacity(usable_size, needed) >= self.tables.len())
+        {
+            let page_num = self.catalog_pages.pop().unwrap();
+            self.free_page(page_num);
+        }
+
+        let num_pages = self.pager.num_pages;
+        let mut header = vec![0; self.pager.page_size];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        write_u32(&mut header, HEADER_VERSION_OFFSET, FORMAT_VERSION);
+        write_u32(&mut header, HEADER_PAGE_SIZE_OFFSET, self.pager.page_size);
+        let mut flags = 0;
+        if let Some(cipher) = &self.pager.cipher {
+            flags |= FLAG_ENCRYPTED;
+            header[HEADER_SALT_OFFSET..HEADER_PLAIN_SIZE].copy_from_slice(&cipher.salt());
+        }
+        if self.auto_vacuum {
+            flags |= FLAG_AUTO_VACUUM;
+        }
+        if self.pager.compression {
+            flags |= FLAG_COMPRESSION;
+        }
+        write_u32(&mut header, HEADER_FLAGS_OFFSET, flags);
+        write_u32(&mut header, HEADER_NUM_PAGES_OFFSET, num_pages);
+        write_u32(&mut header, HEADER_FREE_LIST_OFFSET, self.free_list_head);
+        let first_page = self.catalog_pages.first().copied().unwrap_or(0);
+        write_u32(&mut header, HEADER_CATALOG_OFFSET, first_page);
+        write_u32(&mut header, HEADER_NUM_TABLES_OFFSET, self.tables.len());
+
+        let mut pages = vec![(0, header)];
+        let mut offset = HEADER_SIZE;
+        for table in &self.tables {
+            if offset + CATALOG_ENTRY_SIZE > usable_size {
+                // Page 0 has the first catalog page in its header already
+                let next_page = self.catalog_pages[pages.len() - 1];
+                if pages.len() > 1 {
+                    write_u32(&mut pages.last_mut().unwrap().1, 0, next_page);
+                }
+                pages.push((next_page, vec![0; self.pager.page_size]));
+                offset = CATALOG_PAGE_HEADER_SIZE;
+            }
+            let entry = &mut pages.last_mut().unwrap().1[offset..];
+            entry[..table.name.len()].copy_from_slice(table.name.as_bytes());
+            write_u32(entry, TABLE_NAME_SIZE, table.root_page);
+            write_u32(entry, TABLE_NAME_SIZE + 4, table.num_rows);
+            write_u32(entry, TABLE_NAME_SIZE + 8, table.schema_page);
+            let keys = TABLE_NAME_SIZE + 12;
+            entry[keys..keys + KEY_SIZE].copy_from_slice(&table.min_key.to_le_bytes());
+            entry[keys + KEY_SIZE..keys + 2 * KEY_SIZE]
+                .copy_from_slice(&table.max_key.to_le_bytes());
+            offset += CATALOG_ENTRY_SIZE;
+        }
+        for (page_num, data) in pages {
+            if *self.pager.get_page(page_num) != data[..] {
+                self.pager.get_page_mut(page_num).copy_from_slice(&data);
+            }
+        }
+    }
+
+    // Adds catalog pages until the entries of the count of tables fit.
+    // False when the file has no room for them.
+    fn grow_catalog(&mut self, num_tables: usize) -> bool {
+        while catalog_capacity(self.pager.usable_size(), self.catalog_pages.len()) < num_tables {
+            let Some(page_num) = self.allocate_page() else {
+                return false;
+            };
+            self.catalog_pages.push(page_num);
+        }
+        true
+    }
+
+    pub fn page_counts(&self) -> PageCounts {
+        self.pager.counts
+    }
+
+    // Whether the last commits wait for the end of the commit window to be
+    // synced, a crash before then loses them
+    pub fn unsynced(&self) -> bool {
+        self.pager.unsynced()
+    }
+
+    // Fails the statement running once it ends, with the first reason it
+    // stopped reading rows short
+    fn read_failed(&mut self, error: ExecuteResult) {
+        self.read_error.get_or_insert(error);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let pager = &self.pager;
+        Stats {
+            rows: (self.tables.iter())
+                .filter(|table| !table.is_index)
+                .map(|table| table.num_rows)
+                .sum(),
+            pages: pager.num_pages,
+            page_size: pager.page_size,
+            cached_pages: pager.pages.len(),
+            cache_size: pager.cache_size,
+            counts: pager.counts,
+            file_size: match &pager.backend {
+                Backend::Disk(disk) => disk.file_length(),
+                Backend::Memory => 0,
+            },
+        }
+    }
+
+    // Names of the tables in the catalog, without the indexes, in
+    // alphabetical order
+    pub fn table_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = (self.tables.iter())
+            .filter(|table| !table.is_index)
+            .map(|table| table.name.as_str())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    // The columns and constraints of the table with the name
+    pub fn schema(&self, name: &str) -> Option<&Schema> {
+        (self.find_table(name)).map(|table_index| &self.entry(table_index).schema)
+    }
+
+    // The newest table with the name, so a common table hides the stored
+    // table it is named after
+    pub fn find_table(&self, name: &str) -> Option<usize> {
+        (self.tables.iter()).rposition(|table| table.name == name && !table.is_index)
+    }
+
+    // The tree of the index with the name. Indexes share their names with
+    // tables.
+    fn find_index(&self, name: &str) -> Option<usize> {
+        (self.tables.iter()).rposition(|table| table.name == name && table.is_index)
+    }
+
+    pub fn create_table(&mut self, name: &str, schema: Schema) -> ExecuteResult {
+        if self.find_table(name).is_some() || self.find_index(name).is_some() {
+            return ExecuteResult::TableExists;
+        }
+        self.add_table(name, schema)
+    }
+
+    // Creates the table whether or not the name is taken
+    fn add_table(&mut self, name: &str, mut schema: Schema) -> ExecuteResult {
+        if !schema.has_check_columns() {
+            return ExecuteResult::NoSuchColumn;
+        }
+        for column in schema.columns.iter_mut() {
+            if let Err(result) = column.convert_default() {
+                return result;
+            }
+        }
+        // Both the schema and at least one row have to fit on a page
+        let usable_size = self.pager.usable_size();
+        if schema.encoded_size() > usable_size
+            || KEY_SIZE + schema.max_row_size() > max_cell_size(usable_size)
+        {
+            return ExecuteResult::RowTooLarge;
+        }
+        if !self.grow_catalog(self.tables.len() + 1) {
+            return ExecuteResult::TableFull;
+        }
+
+        let Some(schema_page) = self.allocate_page() else {
+            return ExecuteResult::TableFull;
+        };
+        let Some(page_num) = self.allocate_page() else {
+            self.free_page(schema_page);
+            return ExecuteResult::TableFull;
+        };
+        schema.write_to(self.get_page_mut(schema_page));
+        initialize_leaf(self.get_page_mut(page_num), 0, 0);
+        self.tables.push(TableEntry {
+            name: name.to_string(),
+            root_page: page_num,
+            num_rows: 0,
+            schema_page,
+            min_key: 0,
+            max_key: 0,
+            schema,
+            is_index: false,
+        });
+
+        ExecuteResult::Success
+    }
+
+    pub fn savepoint(&mut self, name: &str) -> ExecuteResult {
+        self.savepoints.push(Savepoint {
+            name: name.to_string(),
+            tables: self.tables.clone(),
+            catalog_pages: self.catalog_pages.clone(),
+            free_list_head: self.free_list_head,
+        });
+        self.pager.set_savepoint();
+        ExecuteResult::Success
+    }
+
+    // Position of the innermost savepoint with the name
+    fn find_savepoint(&self, name: &str) -> Option<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.name == name)
+    }
+
+    pub fn release(&mut self, name: &str) -> ExecuteResult {
+        let Some(index) = self.find_savepoint(name) else {
+            return ExecuteResult::NoSuchSavepoint;
+        };
+        self.savepoints.truncate(index);
+        self.pager.release_savepoints(index);
+        ExecuteResult::Success
+    }
+
+    pub fn rollback_to(&mut self, name: &str) -> ExecuteResult {
+        let Some(index) = self.find_savepoint(name) else {
+            return ExecuteResult::NoSuchSavepoint;
+        };
+        self.savepoints.truncate(index + 1);
+
+        self.pager.roll_back_savepoint(index);
+        let savepoint = &self.savepoints[index];
+        self.tables = savepoint.tables.clone();
+        self.catalog_pages = savepoint.catalog_pages.clone();
+        self.free_list_head = savepoint.free_list_head;
+        ExecuteResult::Success
+    }
+
+    // Starts copying the database to a new file at the path, made by
+    // stepping the backup
+    pub fn backup_to(&mut self, path: &str) -> io::Result<Backup> {
+        let file = File::create(path)?;
+        let changes = Rc::new(RefCell::new(HashSet::new()));
+        self.pager.backups.push(Rc::downgrade(&changes));
+        Ok(Backup {
+            file,
+            page_size: self.pager.page_size,
+            next_page: 0,
+            changes,
+            retry: BTreeSet::new(),
+        })
+    }
+
+    // Hands out the setting's value, or changes it when given one
+    fn pragma(&mut self, pragma: &PragmaStmt, out: &mut RowSink) -> ExecuteResult {
+        match (pragma.name.as_str(), &pragma.value) {
+            ("cache_size", None) => out(vec![Value::Integer(self.pager.cache_size as i64)]),
+            ("cache_size", Some(value)) => match value.parse::<usize>() {
+                Ok(cache_size) if cache_size > 0 => self.pager.set_cache_size(cache_size),
+                _ => return ExecuteResult::InvalidPragmaValue,
+            },
+            ("journal_mode", None) => out(vec![Value::Text(
+                self.pager.journal_mode.name().to_string(),
+            )]),
+            ("journal_mode", Some(value)) => match JournalMode::from_name(value) {
+                Some(journal_mode) => match self.pager.set_journal_mode(journal_mode) {
+                    Ok(true) => {}
+                    Ok(false) => return ExecuteResult::Locked,
+                    Err(error) => return error.into(),
+                },
+                None => return ExecuteResult::InvalidPragmaValue,
+            },
+            ("wal_autocheckpoint", None) => {
+                out(vec![Value::Integer(self.pager.wal_autocheckpoint as i64)])
+            }
+            ("wal_autocheckpoint", Some(value)) => match value.parse::<usize>() {
+                Ok(wal_autocheckpoint) => self.pager.set_wal_autocheckpoint(wal_autocheckpoint),
+                _ => return ExecuteResult::InvalidPragmaValue,
+            },
+            ("wal_checkpoint", None) => match self.pager.checkpoint() {
+                Ok(true) => {}
+                Ok(false) => return ExecuteResult::Locked,
+                Err(error) => return error.into(),
+            },
+            ("mmap_size", None) => out(vec![Value::Integer(self.pager.mmap_size as i64)]),
+            ("mmap_size", Some(value)) => match value.parse::<usize>() {
+                Ok(mmap_size) => self.pager.set_mmap_size(mmap_size),
+                _ => return ExecuteResult::InvalidPragmaValue,
+            },
+            ("double_write", None) => out(vec![Value::Text(
+                (if self.pager.double_write() {
+                    "on"
+                } else {
+                    "off"
+                })
+                .to_string(),
+            )]),
+            ("double_write", Some(value)) => {
+                let on = match value.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return ExecuteResult::InvalidPragmaValue,
+                };
+                if let Err(error) = self.pager.set_double_write(on) {
+                    return error.into();
+                }
+            }
+            ("commit_window", None) => out(vec![Value::Integer(
+                self.pager.commit_window.as_millis() as i64,
+            )]),
+            ("commit_window", Some(value)) => match value.parse::<u64>() {
+                Ok(millis) => self.pager.set_commit_window(Duration::from_millis(millis)),
+                _ => return ExecuteResult::InvalidPragmaValue,
+            },
+            ("synchronous", None) => {
+                out(vec![Value::Text(self.pager.synchronous.name().to_string())])
+            }
+            ("synchronous", Some(value)) => match Synchronous::from_name(value) {
+                Some(synchronous) => self.pager.set_synchronous(synchronous),
+                None => return ExecuteResult::InvalidPragmaValue,
+            },
+            ("compression", None) => out(vec![Value::Text(
+                (if self.pager.compression { "on" } else { "off" }).to_string(),
+            )]),
+            ("compression", Some(value)) => match value.as_str() {
+                "on" => {
+                    if !self.pager.set_compression(true) {
+                        return ExecuteResult::PageTooSmall;
+                    }
+                }
+                "off" => {
+                    self.pager.set_compression(false);
+                }
+                _ => return ExecuteResult::InvalidPragmaValue,
+            },
+            ("auto_vacuum", None) => out(vec![Value::Text(
+                (if self.auto_vacuum { "full" } else { "none" }).to_string(),
+            )]),
+            ("auto_vacuum", Some(value)) => match value.as_str() {
+                "full" => self.auto_vacuum = true,
+                "none" => self.auto_vacuum = false,
+                _ => return ExecuteResult::InvalidPragmaValue,
+            },
+            ("sort_memory", None) => out(vec![Value::Integer(self.sort_memory as i64)]),
+            ("sort_memory", Some(value)) => match value.parse::<usize>() {
+                Ok(sort_memory) => self.sort_memory = sort_memory,
+                _ => return ExecuteResult::InvalidPragmaValue,
+            },
+            ("page_size", None) => out(vec![Value::Integer(self.pager.page_size as i64)]),
+            ("page_size", Some(value)) => match value.parse::<usize>() {
+                Ok(page_size) if valid_page_size(page_size) => {
+                    // Nothing has to be kept, so the database is created over
+                    if !self.savepoints.is_empty() || !self.pager.set_page_size(page_size) {
+                        return ExecuteResult::PageSizeFixed;
+                    }
+                    self.tables.clear();
+                    self.catalog_pages.clear();
+                    self.free_list_head = 0;
+                    self.initialize();
+                }
+                _ => return ExecuteResult::InvalidPragmaValue,
+            },
+            _ => return ExecuteResult::NoSuchPragma,
+        }
+        ExecuteResult::Success
+    }
+
+    // Appends the column to the table's schema page. The rows already in
+    // the table are left as they are and read the default for it.
+    pub fn add_column(&mut self, table_name: &str, mut column: ColumnDef) -> ExecuteResult {
+        let Some(table_index) = self.find_table(table_name) else {
+            return ExecuteResult::NoSuchTable;
+        };
+        let entry = &self.tables[table_index];
+        if entry.schema.column_index(&column.name).is_some() {
+            return ExecuteResult::ColumnExists;
+        }
+        if let Err(result) = column.convert_default() {
+            return result;
+        }
+        if column.not_null && column.default.is_null() {
+            return ExecuteResult::NotNullViolation;
+        }
+
+        let mut schema = entry.schema.clone();
+        schema.columns.push(column);
+        if KEY_SIZE + schema.max_row_size() > max_cell_size(self.pager.usable_size()) {
+            return ExecuteResult::RowTooLarge;
+        }
+        self.write_schema(table_index, schema)
+    }
+
+    // Adds the trigger to the table's schema page. Trigger names are unique
+    // across the database.
+    pub fn create_trigger(&mut self, table_name: &str, trigger: Trigger) -> ExecuteResult {
+        let Some(table_index) = self.find_table(table_name) else {
+            return ExecuteResult::NoSuchTable;
+        };
+        let exists = (self.tables.iter())
+            .flat_map(|entry| &entry.schema.triggers)
+            .any(|other| other.name == trigger.name);
+        if exists {
+            return ExecuteResult::TriggerExists;
+        }
+
+        let entry = &self.tables[table_index];
+        for statement in split_statements(&trigger.body) {
+            let (_, columns) = row_references(&statement, trigger.event.prefix());
+            if columns
+                .iter()
+                .any(|column| entry.schema.column_index(column).is_none())
+            {
+                return ExecuteResult::NoSuchColumn;
+            }
+        }
+
+        let mut schema = entry.schema.clone();
+        schema.triggers.push(trigger);
+        self.write_schema(table_index, schema)
+    }
+
+    // Replaces the table's schema, on its page as well
+    fn write_schema(&mut self, table_index: usize, schema: Schema) -> ExecuteResult {
+        if schema.encoded_size() > self.pager.usable_size() {
+            return ExecuteResult::RowTooLarge;
+        }
+        let schema_page = self.tables[table_index].schema_page;
+        let page = self.get_page_mut(schema_page);
+        page.fill(0);
+        schema.write_to(page);
+        self.tables[table_index].schema = schema;
+
+        ExecuteResult::Success
+    }
+
+    // Forgets the table along with its indexes
+    pub fn drop_table(&mut self, name: &str) -> ExecuteResult {
+        let Some(table_index) = self.find_table(name) else {
+            return ExecuteResult::NoSuchTable;
+        };
+
+        let indexes = self.tables[table_index].schema.indexes.clone();
+        self.remove_table(table_index);
+        for index in indexes {
+            if let Some(index_tree) = self.find_index(&index.name) {
+                self.remove_table(index_tree);
+            }
+        }
+
+        ExecuteResult::Success
+    }
+
+    // Hands every page of the tree and its schema page to the free list and
+    // takes the tree out of the catalog
+    fn remove_table(&mut self, table_index: usize) {
+        self.free_tree_overflow(table_index);
+        let root_page = self.tables[table_index].root_page;
+        self.free_children(root_page);
+        self.free_page(root_page);
+        self.free_page(self.tables[table_index].schema_page);
+        self.tables.remove(table_index);
+    }
+
+    // Drops the tables added after the first count of them, newest first
+    fn drop_tables_from(&mut self, count: usize) {
+        while self.tables.len() > count {
+            self.remove_table(self.tables.len() - 1);
+        }
+    }
+
+    // Empties a table and its indexes without reading their rows, other than
+    // to find their overflow pages
+    pub fn truncate_table(&mut self, name: &str) -> ExecuteResult {
+        let Some(table_index) = self.find_table(name) else {
+            return ExecuteResult::NoSuchTable;
+        };
+
+        self.clear_tree(table_index);
+        for index in self.tables[table_index].schema.indexes.clone() {
+            if let Some(index_tree) = self.find_index(&index.name) {
+                self.clear_tree(index_tree);
+            }
+        }
+
+        ExecuteResult::Success
+    }
+
+    // Rewrites every tree onto the fewest pages, one after another from the
+    // start of the file, which shrinks to them at the commit. Free pages go
+    // away with the rest.
+    pub fn vacuum(&mut self) -> ExecuteResult {
+        let mut trees = Vec::with_capacity(self.tables.len());
+        for table_index in 0..self.tables.len() {
+            let mut rows = Vec::new();
+            let (mut page_num, _) = self.find_leaf(table_index, i64::MIN);
+            while page_num != 0 {
+                let page = self.get_page(page_num);
+                let next_leaf = leaf_next(page);
+                for cell in leaf_cells(page) {
+                    let data = self.load_overflow(table_index, &cell[KEY_SIZE..]);
+                    rows.push((cell_key(&cell), data));
+                }
+                page_num = next_leaf;
+            }
+            trees.push(rows);
+        }
+
+        self.pager.truncate(1);
+        self.free_list_head = 0;
+        self.catalog_pages.clear();
+        self.grow_catalog(self.tables.len());
+        for (table_index, rows) in trees.into_iter().enumerate() {
+            let schema_page = self.allocate_page().unwrap();
+            (self.tables[table_index].schema).write_to(self.pager.get_page_mut(schema_page));
+            self.tables[table_index].schema_page = schema_page;
+
+            let mut cells = Vec::with_capacity(rows.len());
+            for (key, data) in rows {
+                let mut cell = key.to_le_bytes().to_vec();
+                cell.extend(self.store_overflow(table_index, key, &data).unwrap());
+                cells.push(cell);
+            }
+            self.tables[table_index].root_page = self.build_tree(cells);
+        }
+        ExecuteResult::Success
+    }
+
+    // Moves the pages in use past the end the file has without its free
+    // pages into the free pages before it, which leaves the free list empty
+    // and the file to shrink at the commit. Only the pages that move and
+    // the ones referring to them are read, so the work follows the number
+    // of pages freed rather than the size of the database.
+    fn shrink_free_pages(&mut self) {
+        let mut free_pages = Vec::new();
+        let mut page_num = self.free_list_head;
+        while page_num != 0 {
+            free_pages.push(page_num);
+            page_num = read_u32(self.get_page(page_num), 0);
+        }
+        free_pages.sort_unstable();
+        let num_pages = self.pager.num_pages - free_pages.len();
+
+        // As many pages in use lie past the new end as free pages before it
+        let slots = (free_pages.iter()).filter(|&&page_num| page_num < num_pages);
+        let moves: Vec<(usize, usize)> = (num_pages..self.pager.num_pages)
+            .filter(|page_num| free_pages.binary_search(page_num).is_err())
+            .zip(slots.copied())
+            .collect();
+        for (from, to) in moves {
+            self.move_page(from, to);
+        }
+
+        self.free_list_head = 0;
+        self.pager.truncate(num_pages);
+    }
+
+    // Copies the page to the free page and points what refers to it at its
+    // new number. The tables are whole again after each move, so the next
+    // one can search them.
+    fn move_page(&mut self, from: usize, to: usize) {
+        let page = self.get_page(from).to_vec();
+        self.get_page_mut(to).copy_from_slice(&page);
+
+        // Catalog pages are chained when the catalog is saved
+        if let Some(page_num) = (self.catalog_pages.iter_mut()).find(|page_num| **page_num == from)
+        {
+            *page_num = to;
+            return;
+        }
+        if let Some(entry) = (self.tables.iter_mut()).find(|entry| entry.schema_page == from) {
+            entry.schema_page = to;
+            return;
+        }
+        match page[NODE_TYPE_OFFSET] {
+            OVERFLOW_TYPE => self.move_overflow_page(from, to, &page),
+            _ => self.move_node(from, to, &page),
+        }
+    }
+
+    // The parent of a node, or the catalog for a root, refers to it, and so
+    // do the children of an internal node and the leaf before a leaf
+    fn move_node(&mut self, from: usize, to: usize, page: &[u8]) {
+        let previous_leaf = match page[NODE_TYPE_OFFSET] {
+            NODE_LEAF => self.leaf_before(from),
+            _ => 0,
+        };
+
+        let parent = node_parent(page);
+        if parent == 0 {
+            for entry in self.tables.iter_mut() {
+                if entry.root_page == from {
+                    entry.root_page = to;
+                }
+            }
+        } else {
+            let mut node = InternalNode::read(self.get_page(parent));
+            for child in node.children.iter_mut() {
+                if *child == from {
+                    *child = to;
+                }
+            }
+            node.write(self.get_page_mut(parent));
+        }
+
+        if page[NODE_TYPE_OFFSET] == NODE_INTERNAL {
+            for child in InternalNode::read(page).children {
+                set_node_parent(self.get_page_mut(child), to);
+            }
+        } else if previous_leaf != 0 {
+            write_u32(self.get_page_mut(previous_leaf), LEAF_NEXT_OFFSET, to);
+        }
+    }
+
+    // The leaf linking to the leaf, 0 for the first one. Keys can repeat
+    // across leaves in an index, so it is found through the parents rather
+    // than by key: up to the first node with a child left of the leaf's
+    // side, then down to the last leaf under that child.
+    fn leaf_before(&mut self, page_num: usize) -> usize {
+        let mut node = page_num;
+        loop {
+            let parent = node_parent(self.get_page(node));
+            if parent == 0 {
+                return 0;
+            }
+            let children = InternalNode::read(self.get_page(parent)).children;
+            let position = children.iter().position(|&child| child == node).unwrap();
+            if position > 0 {
+                return self.rightmost_leaf(children[position - 1]).0;
+            }
+            node = parent;
+        }
+    }
+
+    // An overflow page is referred to by the row its chain starts from, or
+    // by the page before it on the chain. The key on the page leads to the
+    // rows that can hold it, in the trees whose columns spill.
+    fn move_overflow_page(&mut self, from: usize, to: usize, page: &[u8]) {
+        let key = i64::from_le_bytes(
+            page[OVERFLOW_KEY_OFFSET..OVERFLOW_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        for table_index in 0..self.tables.len() {
+            let schema = &self.tables[table_index].schema;
+            if !schema.columns.iter().any(|column| column.ctype.spills()) {
+                continue;
+            }
+            // Index entries share keys, so every cell with the key is tried,
+            // carrying on into the next leaf like a cursor does
+            let (mut page_num, _) = self.find_leaf(table_index, key);
+            let mut cell_num = leaf_find(self.get_page(page_num), key);
+            while page_num != 0 {
+                let leaf = self.get_page(page_num).to_vec();
+                if cell_num >= leaf_num_cells(&leaf) {
+                    page_num = leaf_next(&leaf);
+                    if page_num != 0 {
+                        cell_num = leaf_find(self.get_page(page_num), key);
+                    }
+                    continue;
+                }
+                if leaf_key(&leaf, cell_num) != key {
+                    break;
+                }
+                let row_offset = leaf_cell_offset(&leaf, cell_num) + KEY_SIZE;
+                let slots = (self.tables[table_index].schema).overflow_slots(&leaf[row_offset..]);
+                for (offset, _) in slots {
+                    if self.redirect_chain(page_num, row_offset + offset, from, to) {
+                        return;
+                    }
+                }
+                cell_num += 1;
+            }
+        }
+    }
+
+    // Follows the chain starting at the page number stored at the offset of
+    // the page, and points the reference to the moved page at its new
+    // number. Whether the chain went through it.
+    fn redirect_chain(
+        &mut self,
+        mut page_num: usize,
+        mut offset: usize,
+        from: usize,
+        to: usize,
+    ) -> bool {
+        loop {
+            let next_page = read_u32(self.get_page(page_num), offset);
+            if next_page == from {
+                write_u32(self.get_page_mut(page_num), offset, to);
+                return true;
+            }
+            if next_page == 0 {
+                return false;
+            }
+            (page_num, offset) = (next_page, OVERFLOW_NEXT_OFFSET);
+        }
+    }
+
+    // Packs the cells, in key order, onto as few leaves as they fit on and
+    // builds the levels above them. Gives the root.
+    fn build_tree(&mut self, cells: Vec<Vec<u8>>) -> usize {
+        let usable_size = self.pager.usable_size();
+        let mut leaves: Vec<Vec<Vec<u8>>> = vec![Vec::new()];
+        for cell in cells {
+            let leaf = leaves.last_mut().unwrap();
+            leaf.push(cell);
+            if leaf.len() > 1 && leaf_size(leaf) > usable_size {
+                let cell = leaf.pop().unwrap();
+                leaves.push(vec![cell]);
+            }
+        }
+        // The last leaf shares the cells of the one before it when it
+        // would be less than half full
+        if leaves.len() > 1 && leaf_size(leaves.last().unwrap()) < usable_size / 2 {
+            let mut cells = leaves.pop().unwrap();
+            let mut both = leaves.pop().unwrap();
+            both.append(&mut cells);
+            let right = both.split_off(split_point(&both));
+            leaves.push(both);
+            leaves.push(right);
+        }
+
+        let pages: Vec<usize> = (leaves.iter())
+            .map(|_| self.allocate_page().unwrap())
+            .collect();
+        let mut level: Vec<(usize, i64)> = Vec::with_capacity(leaves.len());
+        for (i, cells) in leaves.iter().enumerate() {
+            let next_leaf = pages.get(i + 1).copied().unwrap_or(0);
+            let page = self.get_page_mut(pages[i]);
+            initialize_leaf(page, 0, next_leaf);
+            write_leaf_cells(page, cells);
+            let largest = cells.last().map_or(0, |cell| cell_key(cell));
+            level.push((pages[i], largest));
+        }
+
+        // Each level shares its nodes' children out evenly, which keeps them
+        // at least half full
+        let max_children = internal_max_keys(usable_size) + 1;
+        while level.len() > 1 {
+            let count = level.len().div_ceil(max_children);
+            let mut parents = Vec::with_capacity(count);
+            let mut children = level.into_iter();
+            for i in 0..count {
+                let share = children.len() / (count - i);
+                let nodes: Vec<(usize, i64)> = children.by_ref().take(share).collect();
+                let page_num = self.allocate_page().unwrap();
+                let node = InternalNode {
+                    children: nodes.iter().map(|&(child, _)| child).collect(),
+                    keys: nodes[..nodes.len() - 1]
+                        .iter()
+                        .map(|&(_, key)| key)
+                        .collect(),
+                };
+                node.write(self.get_page_mut(page_num));
+                for &(child, _) in &nodes {
+                    set_node_parent(self.get_page_mut(child), page_num);
+                }
+                parents.push((page_num, nodes.last().unwrap().1));
+            }
+            level = parents;
+        }
+        level[0].0
+    }
+
+    // Every page below the root goes to the free list and the root becomes a
+    // leaf without cells
+    fn clear_tree(&mut self, table_index: usize) {
+        self.free_tree_overflow(table_index);
+        let root_page = self.tables[table_index].root_page;
+        self.free_children(root_page);
+        let page = self.get_page_mut(root_page);
+        page.fill(0);
+        initialize_leaf(page, 0, 0);
+        self.tables[table_index].num_rows = 0;
+    }
+
+    // Reads the smallest and largest keys of the table off its first and
+    // last leaves, which have cells unless the table is empty
+    fn read_key_range(&mut self, table_index: usize) {
+        let (first_leaf, _) = self.find_leaf(table_index, i64::MIN);
+        let min_key = leaf_key(self.get_page(first_leaf), 0);
+        let (last_leaf, _) = self.find_leaf(table_index, i64::MAX);
+        let page = self.get_page(last_leaf);
+        let max_key = leaf_key(page, leaf_num_cells(page).saturating_sub(1));
+        let entry = &mut self.tables[table_index];
+        (entry.min_key, entry.max_key) = (min_key, max_key);
+    }
+
+    // Takes a page from the free list, or grows the file by one page
+    fn allocate_page(&mut self) -> Option<usize> {
+        let page_num = if self.free_list_head != 0 {
+            let page_num = self.free_list_head;
+            self.free_list_head = read_u32(self.get_page(page_num), 0);
+            page_num
+        } else if self.pager.num_pages < MAX_PAGES {
+            return Some(self.pager.add_page());
+        } else {
+            return None;
+        };
+
+        self.get_page_mut(page_num).fill(0);
+        Some(page_num)
+    }
+
+    // Whether the count of pages can be allocated, from the free list or by
+    // growing the file
+    fn has_free_pages(&mut self, count: usize) -> bool {
+        let mut available = MAX_PAGES - self.pager.num_pages;
+        let mut page_num = self.free_list_head;
+        while available < count && page_num != 0 {
+            available += 1;
+            page_num = read_u32(self.get_page(page_num), 0);
+        }
+        available >= count
+    }
+
+    fn free_page(&mut self, page_num: usize) {
+        let free_list_head = self.free_list_head;
+        let page = self.get_page_mut(page_num);
+        page.fill(0);
+        write_u32(page, 0, free_list_head);
+        self.free_list_head = page_num;
+    }
+
+    // Moves the rest of the long values of a serialized row onto new
+    // overflow pages, giving the row as it is stored in the table. None when
+    // there aren't enough free pages.
+    fn store_overflow(&mut self, table_index: usize, key: i64, data: &[u8]) -> Option<Vec<u8>> {
+        let usable_size = self.pager.usable_size();
+        let schema = &self.tables[table_index].schema;
+        let slots = schema.overflow_slots(data);
+        if !self.has_free_pages(schema.overflow_pages(data, usable_size)) {
+            return None;
+        }
+
+        let (stored, mut overflow) = data.split_at(row_length(data));
+        let mut stored = stored.to_vec();
+        for (offset, size) in slots {
+            let (bytes, rest) = overflow.split_at(size);
+            overflow = rest;
+            // The chain is written from its end so each page knows the next
+            let mut next_page = 0;
+            for chunk in bytes.chunks(overflow_data_size(usable_size)).rev() {
+                let page_num = self.allocate_page().unwrap();
+                let page = self.get_page_mut(page_num);
+                page[NODE_TYPE_OFFSET] = OVERFLOW_TYPE;
+                write_u32(page, OVERFLOW_NEXT_OFFSET, next_page);
+                page[OVERFLOW_KEY_OFFSET..OVERFLOW_HEADER_SIZE].copy_from_slice(&key.to_le_bytes());
+                page[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + chunk.len()]
+                    .copy_from_slice(chunk);
+                next_page = page_num;
+            }
+            write_u32(&mut stored, offset, next_page);
+        }
+        Some(stored)
+    }
+
+    // A stored row with the rest of its long values read back from their
+    // overflow pages, the way serialize_row gives it
+    fn load_overflow(&mut self, table_index: usize, stored: &[u8]) -> Vec<u8> {
+        let mut data = stored.to_vec();
+        self.append_overflow(table_index, &mut data, 0);
+        data
+    }
+
+    // Appends the overflow of the stored row the data holds from start on
+    fn append_overflow(&mut self, table_index: usize, data: &mut Vec<u8>, start: usize) {
+        let usable_size = self.pager.usable_size();
+        let slots = self
+            .entry(table_index)
+            .schema
+            .overflow_slots(&data[start..]);
+        for (offset, size) in slots {
+            let mut page_num = read_u32(data, start + offset);
+            let mut remaining = size;
+            while remaining > 0 && page_num != 0 {
+                let page = self.get_page(page_num);
+                let chunk = remaining.min(overflow_data_size(usable_size));
+                data.extend_from_slice(&page[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + chunk]);
+                remaining -= chunk;
+                page_num = read_u32(page, OVERFLOW_NEXT_OFFSET);
+            }
+        }
+    }
+
+    // Hands the overflow pages of a stored row to the free list
+    fn free_overflow(&mut self, table_index: usize, stored: &[u8]) {
+        for (offset, _) in self.tables[table_index].schema.overflow_slots(stored) {
+            let mut page_num = read_u32(stored, offset);
+            while page_num != 0 {
+                let next_page = read_u32(self.get_page(page_num), OVERFLOW_NEXT_OFFSET);
+                self.free_page(page_num);
+                page_num = next_page;
+            }
+        }
+    }
+
+    // Frees the overflow pages of every row of the tree, walking its leaves
+    // when its columns can have any
+    fn free_tree_overflow(&mut self, table_index: usize) {
+        let schema = &self.tables[table_index].schema;
+        if !schema.columns.iter().any(|column| column.ctype.spills()) {
+            return;
+        }
+        let (mut page_num, _) = self.find_leaf(table_index, i64::MIN);
+        while page_num != 0 {
+            let page = self.get_page(page_num);
+            let next_leaf = leaf_next(page);
+            for cell in leaf_cells(page) {
+                self.free_overflow(table_index, &cell[KEY_SIZE..]);
+            }
+            page_num = next_leaf;
+        }
+    }
+
+    // Sizes of the storage layout for the page size of the database, and the
+    // rows of the table with the name at their longest
+    pub fn constants(&self, name: &str) -> Option<Vec<(&'static str, usize)>> {
+        let schema = &self.entry(self.find_table(name)?).schema;
+        let usable_size = self.pager.usable_size();
+        let row_size = schema.max_row_size();
+        let leaf_cell_size = LEAF_CELL_POINTER_SIZE + KEY_SIZE + row_size;
+        Some(vec![
+            ("PAGE_SIZE", self.pager.page_size),
+            ("USABLE_SIZE", usable_size),
+            ("ROW_SIZE", row_size),
+            (
+                "ROWS_PER_PAGE",
+                (usable_size - LEAF_HEADER_SIZE) / leaf_cell_size,
+            ),
+            ("KEY_SIZE", KEY_SIZE),
+            ("NODE_HEADER_SIZE", NODE_HEADER_SIZE),
+            ("LEAF_HEADER_SIZE", LEAF_HEADER_SIZE),
+            ("LEAF_CELL_POINTER_SIZE", LEAF_CELL_POINTER_SIZE),
+            ("LEAF_MAX_CELL_SIZE", max_cell_size(usable_size)),
+            ("INTERNAL_HEADER_SIZE", INTERNAL_HEADER_SIZE),
+            ("INTERNAL_CELL_SIZE", INTERNAL_CELL_SIZE),
+            ("INTERNAL_MAX_KEYS", internal_max_keys(usable_size)),
+        ])
+    }
+
+    // The tree of the table or index with the name, a node per line indented
+    // by its level: internal nodes with their keys between their children,
+    // leaves with their cells and the keys they hold, then the depth
+    pub fn btree(&mut self, name: &str) -> Option<String> {
+        let table_index = self.find_table(name).or_else(|| self.find_index(name))?;
+        let mut tree = String::new();
+        let depth = self.print_node(self.entry(table_index).root_page, 0, &mut tree);
+        tree.push_str(&format!("depth: {}\n", depth));
+        Some(tree)
+    }
+
+    // Adds the node and those under it to the tree, giving the depth of the
+    // node's subtree
+    fn print_node(&mut self, page_num: usize, level: usize, tree: &mut String) -> usize {
+        let indent = "  ".repeat(level);
+        let page = self.get_page(page_num);
+        if page[NODE_TYPE_OFFSET] != NODE_INTERNAL {
+            let line = match leaf_num_cells(page) {
+                0 => format!("{}- leaf (page {}, cells: 0)\n", indent, page_num),
+                num_cells => format!(
+                    "{}- leaf (page {}, cells: {}, keys {} to {})\n",
+                    indent,
+                    page_num,
+                    num_cells,
+                    leaf_key(page, 0),
+                    leaf_key(page, num_cells - 1)
+                ),
+            };
+            tree.push_str(&line);
+            return 1;
+        }
+
+        let node = InternalNode::read(page);
+        let line = format!(
+            "{}- internal (page {}, keys: {})\n",
+            indent,
+            page_num,
+            node.keys.len()
+        );
+        tree.push_str(&line);
+        let mut depth = 0;
+        for (i, &child) in node.children.iter().enumerate() {
+            depth = depth.max(self.print_node(child, level + 1, tree));
+            if let Some(key) = node.keys.get(i) {
+                tree.push_str(&format!("{}  - key {}\n", indent, key));
+            }
+        }
+        depth + 1
+    }
+
+    // Hands every page below the node to the free list
+    fn free_children(&mut self, page_num: usize) {
+        let page = self.get_page(page_num);
+        if page[NODE_TYPE_OFFSET] != NODE_INTERNAL {
+            return;
+        }
+        for child in InternalNode::read(page).children {
+            self.free_children(child);
+            self.free_page(child);
+        }
+    }
+
+    // The leaf whose keys take in the key, and the number of pages read on
+    // the way down from the root
+    fn find_leaf(&mut self, table_index: usize, key: i64) -> (usize, usize) {
+        let mut page_num = self.entry(table_index).root_page;
+        let mut pages_read = 1;
+        loop {
+            let page = self.get_page(page_num);
+            if page[NODE_TYPE_OFFSET] != NODE_INTERNAL {
+                return (page_num, pages_read);
+            }
+            page_num = InternalNode::read(page).child_for(key);
+            pages_read += 1;
+        }
+    }
+
+    // The last leaf under the page, with the number of pages read to it
+    fn rightmost_leaf(&mut self, mut page_num: usize) -> (usize, usize) {
+        let mut pages_read = 1;
+        loop {
+            let page = self.get_page(page_num);
+            if page[NODE_TYPE_OFFSET] != NODE_INTERNAL {
+                return (page_num, pages_read);
+            }
+            page_num = *InternalNode::read(page).children.last().unwrap();
+            pages_read += 1;
+        }
+    }
+
+    // The leaf before the one holding the key, with the number of pages
+    // read to it. Leaves only link to the next one, so it is the last leaf
+    // under the nearest subtree left of the path down to the key. None on
+    // the first leaf.
+    fn previous_leaf(&mut self, table_index: usize, key: i64) -> Option<(usize, usize)> {
+        let mut page_num = self.entry(table_index).root_page;
+        let mut pages_read = 0;
+        let mut left = None;
+        loop {
+            let page = self.get_page(page_num);
+            pages_read += 1;
+            if page[NODE_TYPE_OFFSET] != NODE_INTERNAL {
+                break;
+            }
+            let node = InternalNode::read(page);
+            let position = node.keys.partition_point(|&other| other < key);
+            if position > 0 {
+                left = Some(node.children[position - 1]);
+            }
+            page_num = node.children[position];
+        }
+        let (leaf, more_pages) = self.rightmost_leaf(left?);
+        Some((leaf, pages_read + more_pages))
+    }
+
+    // Puts a cell with the key at the position on the leaf, moving the cells
+    // after it along. A full leaf is split in two first, false when there
+    // aren't enough free pages for the split.
+    fn leaf_insert(
+        &mut self,
+        table_index: usize,
+        page_num: usize,
+        cell_num: usize,
+        key: i64,
+        data: &[u8],
+    ) -> bool {
+        let usable_size = self.pager.usable_size();
+        let page = self.get_page_mut(page_num);
+        let num_cells = leaf_num_cells(page);
+        let next_leaf = leaf_next(page);
+        let parent = node_parent(page);
+
+        let mut cells = leaf_cells(page);
+        let mut cell = key.to_le_bytes().to_vec();
+        cell.extend_from_slice(data);
+        cells.insert(cell_num, cell);
+        if leaf_size(&cells) <= usable_size {
+            write_leaf_cells(page, &cells);
+            return true;
+        }
+
+        let pages = self.split_pages(page_num);
+        if !self.has_free_pages(pages) {
+            return false;
+        }
+        // An append to the table keeps the last leaf full and starts the
+        // next one with the new cell, other inserts split the bytes in half
+        let left_count = if cell_num == num_cells && next_leaf == 0 {
+            num_cells
+        } else {
+            split_point(&cells)
+        };
+        let (left, right) = cells.split_at(left_count);
+        let new_page = self.allocate_page().unwrap();
+        let page = self.get_page_mut(new_page);
+        initialize_leaf(page, parent, next_leaf);
+        write_leaf_cells(page, right);
+        let page = self.get_page_mut(page_num);
+        write_u32(page, LEAF_NEXT_OFFSET, new_page);
+        write_leaf_cells(page, left);
+
+        let left_key = cell_key(&left[left_count - 1]);
+        self.insert_into_parent(table_index, page_num, new_page, left_key);
+        true
+    }
+
+    // Takes the cell at the position out of the leaf. A leaf other than the
+    // root left under half full is rebalanced with a sibling.
+    fn leaf_remove(&mut self, table_index: usize, page_num: usize, cell_num: usize) {
+        let page = self.get_page_mut(page_num);
+        let mut cells = leaf_cells(page);
+        cells.remove(cell_num);
+        write_leaf_cells(page, &cells);
+
+        if node_parent(page) != 0 && leaf_size(&cells) < self.pager.usable_size() / 2 {
+            self.rebalance_leaf(table_index, page_num);
+        }
+    }
+
+    // Merges the leaf with a sibling under the same parent when the cells of
+    // both fit on one page, freeing the right one of the two. Otherwise the
+    // cells are shared out evenly between them.
+    fn rebalance_leaf(&mut self, table_index: usize, page_num: usize) {
+        let parent = node_parent(self.get_page(page_num));
+        let mut node = InternalNode::read(self.get_page(parent));
+        let (position, left, right) = node.siblings(page_num);
+
+        let page = self.get_page(right);
+        let right_next = leaf_next(page);
+        let right_cells = leaf_cells(page);
+        let mut cells = leaf_cells(self.get_page(left));
+        cells.extend(right_cells);
+
+        if leaf_size(&cells) <= self.pager.usable_size() {
+            let page = self.get_page_mut(left);
+            write_leaf_cells(page, &cells);
+            write_u32(page, LEAF_NEXT_OFFSET, right_next);
+            self.free_page(right);
+            // The left leaf takes over the right one's upper bound
+            node.children.remove(position + 1);
+            node.keys.remove(position);
+            node.write(self.get_page_mut(parent));
+            self.rebalance_internal(table_index, parent);
+            return;
+        }
+
+        let left_count = split_point(&cells);
+        let (left_cells, right_cells) = cells.split_at(left_count);
+        write_leaf_cells(self.get_page_mut(left), left_cells);
+        write_leaf_cells(self.get_page_mut(right), right_cells);
+        node.keys[position] = cell_key(&left_cells[left_count - 1]);
+        node.write(self.get_page_mut(parent));
+    }
+
+    // Fixes up an internal node that lost a child. A root left with a single
+    // child hands the root over to it, other nodes under half full merge with
+    // a sibling when both fit on one page, or share their children out with
+    // it otherwise.
+    fn rebalance_internal(&mut self, table_index: usize, page_num: usize) {
+        let page = self.get_page(page_num);
+        let parent = node_parent(page);
+        let num_keys = read_u32(page, INTERNAL_NUM_KEYS_OFFSET);
+        if parent == 0 {
+            if num_keys == 0 {
+                let child = InternalNode::read(page).children[0];
+                set_node_parent(self.get_page_mut(child), 0);
+                self.tables[table_index].root_page = child;
+                self.free_page(page_num);
+            }
+            return;
+        }
+        if num_keys >= internal_max_keys(self.pager.usable_size()) / 2 {
+            return;
+        }
+
+        let mut node = InternalNode::read(self.get_page(parent));
+        let (position, left, right) = node.siblings(page_num);
+        let mut merged = InternalNode::read(self.get_page(left));
+        let right_node = InternalNode::read(self.get_page(right));
+        // The key between them in the parent bounds the left node's last child
+        merged.keys.push(node.keys[position]);
+        merged.keys.extend(right_node.keys);
+        merged.children.extend(right_node.children);
+
+        if merged.keys.len() <= internal_max_keys(self.pager.usable_size()) {
+            merged.write(self.get_page_mut(left));
+            for &child in &merged.children {
+                set_node_parent(self.get_page_mut(child), left);
+            }
+            self.free_page(right);
+            node.children.remove(position + 1);
+            node.keys.remove(position);
+            node.write(self.get_page_mut(parent));
+            self.rebalance_internal(table_index, parent);
+            return;
+        }
+
+        // As in a split, the middle key moves up to the parent
+        let middle = merged.keys.len() / 2;
+        let right_node = InternalNode {
+            children: merged.children.split_off(middle + 1),
+            keys: merged.keys.split_off(middle + 1),
+        };
+        node.keys[position] = merged.keys.pop().unwrap();
+        for (page_num, half) in [(left, &merged), (right, &right_node)] {
+            half.write(self.get_page_mut(page_num));
+            for &child in &half.children {
+                set_node_parent(self.get_page_mut(child), page_num);
+            }
+        }
+        node.write(self.get_page_mut(parent));
+    }
+
+    // Pages adding a cell of the size to the leaf takes, none when it fits
+    fn insert_pages(&mut self, page_num: usize, cell_size: usize) -> usize {
+        let cells = leaf_cells(self.get_page(page_num));
+        if leaf_size(&cells) + LEAF_CELL_POINTER_SIZE + cell_size <= self.pager.usable_size() {
+            return 0;
+        }
+        self.split_pages(page_num)
+    }
+
+    // Pages a split of the node takes: one for its new sibling, as many
+    // again for every full node above it, and a new root when the splits
+    // reach the root
+    fn split_pages(&mut self, page_num: usize) -> usize {
+        let mut pages = 1;
+        let mut node = page_num;
+        loop {
+            let parent = node_parent(self.get_page(node));
+            if parent == 0 {
+                return pages + 1;
+            }
+            if read_u32(self.get_page(parent), INTERNAL_NUM_KEYS_OFFSET)
+                < internal_max_keys(self.pager.usable_size())
+            {
+                return pages;
+            }
+            pages += 1;
+            node = parent;
+        }
+    }
+
+    // Hooks the new right sibling of a node that was split into the parent,
+    // with the left node's largest key between them. A full parent is split
+    // in turn, and splitting the root gives the table a new root above it.
+    // The pages were checked to be available before the first split.
+    fn insert_into_parent(&mut self, table_index: usize, left: usize, right: usize, key: i64) {
+        let parent = node_parent(self.get_page(left));
+        if parent == 0 {
+            let root_page = self.allocate_page().unwrap();
+            let node = InternalNode {
+                children: vec![left, right],
+                keys: vec![key],
+            };
+            node.write(self.get_page_mut(root_page));
+            set_node_parent(self.get_page_mut(left), root_page);
+            set_node_parent(self.get_page_mut(right), root_page);
+            self.tables[table_index].root_page = root_page;
+            return;
+        }
+
+        let mut node = InternalNode::read(self.get_page(parent));
+        let position = (node.children.iter())
+            .position(|&child| child == left)
+            .unwrap();
+        node.children.insert(position + 1, right);
+        node.keys.insert(position, key);
+        set_node_parent(self.get_page_mut(right), parent);
+        if node.keys.len() <= internal_max_keys(self.pager.usable_size()) {
+            node.write(self.get_page_mut(parent));
+            return;
+        }
+
+        // The middle key moves up and the children after it go to a new
+        // node on its right
+        let middle = node.keys.len() / 2;
+        let sibling = InternalNode {
+            children: node.children.split_off(middle + 1),
+            keys: node.keys.split_off(middle + 1),
+        };
+        let middle_key = node.keys.pop().unwrap();
+        node.write(self.get_page_mut(parent));
+
+        let grandparent = node_parent(self.get_page(parent));
+        let new_page = self.allocate_page().unwrap();
+        let page = self.get_page_mut(new_page);
+        set_node_parent(page, grandparent);
+        sibling.write(page);
+        for &child in &sibling.children {
+            set_node_parent(self.get_page_mut(child), new_page);
+        }
+        self.insert_into_parent(table_index, parent, new_page, middle_key);
+    }
+
+    fn get_page(&mut self, page_num: usize) -> &[u8] {
+        match &self.view {
+            Some(snapshot) => self.pager.get_page_as_of(page_num, snapshot),
+            None => self.pager.get_page(page_num),
+        }
+    }
+
+    // The table's catalog entry, as the snapshot being read has it
+    fn entry(&self, table_index: usize) -> &TableEntry {
+        match &self.view {
+            Some(snapshot) => &snapshot.tables[table_index],
+            None => &self.tables[table_index],
+        }
+    }
+
+    // Takes a snapshot of the database as it is now, changes not committed
+    // yet included
+    pub fn snapshot(&mut self) -> Snapshot {
+        let tables = Rc::new(self.tables.clone());
+        self.pager.add_version(&tables);
+        Snapshot { tables }
+    }
+
+    fn get_page_mut(&mut self, page_num: usize) -> &mut [u8] {
+        self.pager.get_page_mut(page_num)
+    }
+}
+
+fn resolve_table(table: &TableRef, name: &str) -> Option<usize> {
+    table.borrow().find_table(name)
+}
+
+fn table_schema(table: &TableRef, table_index: usize) -> Schema {
+    table.borrow().tables[table_index].schema.clone()
+}
+
+// Lines the inserted values up with the schema and converts each one to its
+// column's type. Columns left out of the list get their default, and an
+// INTEGER PRIMARY KEY left out or NULL the key after the table's largest.
+fn build_row(
+    table: &TableRef,
+    table_index: usize,
+    schema: &Schema,
+    insert: &InsertStmt,
+) -> Result<Row, ExecuteResult> {
+    let mut values: Vec<Value> = (schema.columns.iter())
+        .map(|column| column.default.clone())
+        .collect();
+    match &insert.columns {
+        None if insert.values.len() != values.len() => {
+            return Err(ExecuteResult::ColumnCountMismatch);
+        }
+        None => values.clone_from(&insert.values),
+        Some(columns) => {
+            for (name, value) in columns.iter().zip(&insert.values) {
+                let Some(index) = schema.column_index(name) else {
+                    return Err(ExecuteResult::NoSuchColumn);
+                };
+                values[index] = value.clone();
+            }
+        }
+    }
+
+    let key_column = schema.key_column();
+    for (index, (column, value)) in schema.columns.iter().zip(values.iter_mut()).enumerate() {
+        *value = column
+            .ctype
+            .convert(std::mem::replace(value, Value::Null))?;
+        if value.is_null() && key_column == Some(index) {
+            let key = next_key(table, table_index).ok_or(ExecuteResult::TableFull)?;
+            *value = Value::Integer(key);
+        }
+        if column.not_null && value.is_null() {
+            return Err(ExecuteResult::NotNullViolation);
+        }
+    }
+
+    // Like WHERE, a check that comes out NULL doesn't reject the row
+    let row = Row { values };
+    if (schema.checks.iter()).any(|check| truth(&evaluate(check, &row, schema)) == Some(false)) {
+        return Err(ExecuteResult::CheckViolation);
+    }
+    Ok(row)
+}
+
+// The rows from the cursor on, to the end of the table. Rows that can't be
+// read are skipped, and fail the statement as corrupt.
+impl Iterator for Cursor {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Row> {
+        if self.schema.is_none() {
+            self.schema = Some(self.read(|table| table.entry(self.table_index).schema.clone()));
+        }
+        let mut data = Vec::new();
+        while !self.end_of_table {
+            self.read_row_into(&mut data);
+            let row = self
+                .schema
+                .as_ref()
+                .and_then(|schema| Row::deserialize_row(&data, schema));
+            self.advance();
+            match row {
+                Some(row) => return Some(row),
+                None => self.table.borrow_mut().read_failed(ExecuteResult::Corrupt),
+            }
+        }
+        None
+    }
+}
+
+// Writes the database out as SQL that builds it again: a CREATE TABLE for
+// each table followed by an INSERT for each of its rows, then its indexes
+// and triggers. Every database starts with the default table, so it is
+// dropped first for the dump's own to take its place. Fails when a row
+// can't be read.
+pub fn dump(table: &TableRef, out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "savepoint dump;")?;
+    writeln!(out, "drop table {};", DEFAULT_TABLE)?;
+    let names: Vec<String> = (table.borrow().table_names().into_iter())
+        .map(str::to_string)
+        .collect();
+    for name in names {
+        let table_index = table.borrow().find_table(&name).unwrap();
+        let schema = table.borrow().entry(table_index).schema.clone();
+
+        let mut definitions: Vec<String> =
+            schema.columns.iter().map(ColumnDef::to_string).collect();
+        definitions.extend(
+            schema
+                .checks
+                .iter()
+                .map(|check| format!("check ({})", check)),
+        );
+        writeln!(out, "create table {} ({});", name, definitions.join(", "))?;
+        for row in Cursor::from_start(Rc::clone(table), table_index) {
+            let values: Vec<String> = row.values.iter().map(Value::to_sql).collect();
+            writeln!(out, "insert into {} values ({});", name, values.join(", "))?;
+        }
+        for index in &schema.indexes {
+            writeln!(
+                out,
+                "create index {} on {} ({});",
+                index.name, name, index.column
+            )?;
+        }
+        for trigger in &schema.triggers {
+            let event = match trigger.event {
+                TriggerEvent::Insert => "insert",
+                TriggerEvent::Delete => "delete",
+            };
+            writeln!(
+                out,
+                "create trigger {} after {} on {} begin {} end;",
+                trigger.name, event, name, trigger.body
+            )?;
+        }
+    }
+    if table.borrow_mut().read_error.take().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "database disk image is malformed",
+        ));
+    }
+    writeln!(out, "release dump;")
+}
+
+// A cursor on the first row of the table that satisfies the predicate
+fn find_row(
+    table: &TableRef,
+    table_index: usize,
+    schema: &Schema,
+    mut predicate: impl FnMut(&Row) -> bool,
+) -> Option<Cursor> {
+    let mut cursor = Cursor::from_start(Rc::clone(table), table_index);
+
+    while !cursor.end_of_table {
+        let found = match read_row(&cursor, schema) {
+            Some(row) => predicate(&row),
+            None => {
+                table.borrow_mut().read_failed(ExecuteResult::Corrupt);
+                false
+            }
+        };
+        if found {
+            return Some(cursor);
+        }
+
+        cursor.advance();
+    }
+
+    None
+}
+
+// A cursor on the row stored under the key
+fn find_key(table: &TableRef, table_index: usize, key: i64) -> Option<Cursor> {
+    let cursor = Cursor::seek(Rc::clone(table), table_index, key);
+    (cursor.key() == Some(key)).then_some(cursor)
+}
+
+// A blob value of a row opened to be read and written a piece at a time,
+// without all of it being held in memory. Its length stays as it is, writes
+// go over bytes in place. The row is looked up again for every access, and
+// the handle expires once the value's length has changed.
+pub struct BlobHandle {
+    table: TableRef,
+    table_name: String,
+    column: usize,
+    key: i64,
+    size: usize,
+}
+
+// Where a run of a blob's bytes is: the page, the offset in the page, the
+// offset in the value and the number of bytes
+type BlobPiece = (usize, usize, usize, usize);
+
+impl BlobHandle {
+    // Opens the value of the blob column in the row stored under the key
+    pub fn open(
+        table: &TableRef,
+        table_name: &str,
+        column: &str,
+        key: i64,
+    ) -> Result<Self, ExecuteResult> {
+        let table_index = resolve_table(table, table_name).ok_or(ExecuteResult::NoSuchTable)?;
+        let schema = table_schema(table, table_index);
+        let column = schema
+            .column_index(column)
+            .ok_or(ExecuteResult::NoSuchColumn)?;
+        if !matches!(schema.columns[column].ctype, ColumnType::Blob(_)) {
+            return Err(ExecuteResult::TypeMismatch);
+        }
+        let cursor = find_key(table, table_index, key).ok_or(ExecuteResult::NoSuchRow)?;
+        let stored = cursor.stored_row();
+        let size = match schema.field_offset(&stored, column) {
+            Some(field) => read_u32(&stored, field),
+            None if column >= row_columns(&stored) => {
+                let row = read_row(&cursor, &schema).ok_or(ExecuteResult::Corrupt)?;
+                match &row.values[column] {
+                    Value::Blob(blob) => blob.len(),
+                    _ => 0,
+                }
+            }
+            None => 0,
+        };
+
+        Ok(Self {
+            table: Rc::clone(table),
+            table_name: table_name.to_string(),
+            column,
+            key,
+            size,
+        })
+    }
+
+    // Bytes in the value, 0 for NULL
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Copies the value's bytes from the offset into the buffer, as many as
+    // there are up to its end. Gives how many were copied.
+    pub fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, ExecuteResult> {
+        let end = (offset + buffer.len()).min(self.size);
+        if let Some((_, row)) = self.added_row()? {
+            if let Value::Blob(blob) = &row.values[self.column]
+                && offset < end
+            {
+                buffer[..end - offset].copy_from_slice(&blob[offset..end]);
+            }
+            return Ok(end.saturating_sub(offset));
+        }
+        let pieces = self.pieces(offset, end)?;
+        let mut table = self.table.borrow_mut();
+        for (page_num, page_offset, value_offset, length) in pieces {
+            let page = table.get_page(page_num);
+            buffer[value_offset - offset..value_offset - offset + length]
+                .copy_from_slice(&page[page_offset..page_offset + length]);
+        }
+        if table.pager.corrupt {
+            return Err(table.commit());
+        }
+        Ok(end.saturating_sub(offset))
+    }
+
+    // Writes the bytes over the value from the offset, they can't run past
+    // its end. Committed like a statement.
+    pub fn write(&self, offset: usize, data: &[u8]) -> ExecuteResult {
+        if offset + data.len() > self.size {
+            return ExecuteResult::StringTooLong;
+        }
+        let table_index = resolve_table(&self.table, &self.table_name);
+        if table_index
+            .is_some_and(|index| table_schema(&self.table, index).is_constrained(self.column))
+        {
+            return ExecuteResult::ReadOnlyBlob;
+        }
+        // The default goes into the row first, for its bytes to be written
+        // over in place
+        match self.added_row() {
+            Ok(Some((cursor, row))) => {
+                let schema = table_schema(&self.table, cursor.table_index);
+                self.table.borrow_mut().savepoint(STATEMENT_SAVEPOINT);
+                let replaced = replace_row(cursor, self.key, &row.serialize_row(&schema));
+                let mut table = self.table.borrow_mut();
+                if !replaced {
+                    table.rollback_to(STATEMENT_SAVEPOINT);
+                }
+                table.release(STATEMENT_SAVEPOINT);
+                if !replaced {
+                    return ExecuteResult::TableFull;
+                }
+            }
+            Ok(None) => {}
+            Err(result) => return result,
+        }
+        let pieces = match self.pieces(offset, offset + data.len()) {
+            Ok(pieces) => pieces,
+            Err(result) => return result,
+        };
+        let mut table = self.table.borrow_mut();
+        for (page_num, page_offset, value_offset, length) in pieces {
+            let page = table.get_page_mut(page_num);
+            page[page_offset..page_offset + length]
+                .copy_from_slice(&data[value_offset - offset..value_offset - offset + length]);
+        }
+        table.commit()
+    }
+
+    // The row, read with the column's default, when it was written before
+    // the column was added. None when the value is stored in the row.
+    fn added_row(&self) -> Result<Option<(Cursor, Row)>, ExecuteResult> {
+        let table_index =
+            resolve_table(&self.table, &self.table_name).ok_or(ExecuteResult::ExpiredBlob)?;
+        let schema = table_schema(&self.table, table_index);
+        let cursor =
+            find_key(&self.table, table_index, self.key).ok_or(ExecuteResult::ExpiredBlob)?;
+        if self.column < row_columns(&cursor.stored_row()) {
+            return Ok(None);
+        }
+        let row = read_row(&cursor, &schema).ok_or(ExecuteResult::Corrupt)?;
+        match &row.values[self.column] {
+            Value::Blob(blob) if blob.len() == self.size => Ok(Some((cursor, row))),
+            Value::Null if self.size == 0 => Ok(Some((cursor, row))),
+            _ => Err(ExecuteResult::ExpiredBlob),
+        }
+    }
+
+    // The runs of the value's bytes from the start up to the end, in order:
+    // the ones kept in the row, then the ones on each overflow page
+    fn pieces(&self, start: usize, end: usize) -> Result<Vec<BlobPiece>, ExecuteResult> {
+        let table_index =
+            resolve_table(&self.table, &self.table_name).ok_or(ExecuteResult::ExpiredBlob)?;
+        let schema = table_schema(&self.table, table_index);
+        let cursor =
+            find_key(&self.table, table_index, self.key).ok_or(ExecuteResult::ExpiredBlob)?;
+        let stored = cursor.stored_row();
+        let field = (schema.field_offset(&stored, self.column))
+            .filter(|&field| read_u32(&stored, field) == self.size)
+            .ok_or(ExecuteResult::ExpiredBlob)?;
+        let row_offset = cursor.row_offset();
+
+        let mut pieces = Vec::new();
+        let inline = self.size.min(MAX_INLINE_SIZE);
+        if start < inline.min(end) {
+            let page_offset = row_offset + field + TEXT_LENGTH_SIZE + start;
+            pieces.push((cursor.page_num, page_offset, start, inline.min(end) - start));
+        }
+
+        // Overflow pages are linked one to the next, so the ones before the
+        // start are read on the way
+        let mut table = self.table.borrow_mut();
+        let chunk = overflow_data_size(table.pager.usable_size());
+        let mut page_num = match self.size > inline {
+            true => read_u32(&stored, field + TEXT_LENGTH_SIZE + inline),
+            false => 0,
+        };
+        let mut page_start = inline;
+        while page_num != 0 && page_start < end {
+            let page_end = page_start + chunk;
+            if page_end > start {
+                let from = start.max(page_start);
+                let length = end.min(page_end) - from;
+                pieces.push((
+                    page_num,
+                    OVERFLOW_HEADER_SIZE + from - page_start,
+                    from,
+                    length,
+                ));
+            }
+            page_num = read_u32(table.get_page(page_num), OVERFLOW_NEXT_OFFSET);
+            page_start = page_end;
+        }
+        Ok(pieces)
+    }
+}
+
+// Whether both rows hold the same value in the column, by its collation
+fn same_key(schema: &Schema, index: usize, a: &Row, b: &Row) -> bool {
+    a.compare(b, index, schema.columns[index].collation) == Ordering::Equal
+}
+
+// The error for a row that repeats the value of the primary key or of a
+// unique column. NULLs never clash, and neither does the row being replaced
+// by insert or replace. An INTEGER PRIMARY KEY is looked up by its key and
+// columns with an index through the index, the others scan the table.
+fn key_conflict(
+    table: &TableRef,
+    table_index: usize,
+    schema: &Schema,
+    row: &Row,
+    key: i64,
+    replacing: bool,
+) -> Option<ExecuteResult> {
+    let key_column = schema.key_column();
+    if key_column.is_some() && !replacing && find_key(table, table_index, key).is_some() {
+        return Some(ExecuteResult::DuplicateKey);
+    }
+
+    let keys: Vec<usize> = (schema.columns.iter().enumerate())
+        .filter(|(index, column)| {
+            (column.primary_key || column.unique)
+                && key_column != Some(*index)
+                && !row.values[*index].is_null()
+        })
+        .map(|(index, _)| index)
+        .collect();
+    if keys.is_empty() {
+        return None;
+    }
+
+    let primary_key = schema.primary_key().filter(|_| replacing);
+    let clash = |existing: &Row, columns: &[usize]| {
+        if primary_key.is_some_and(|key| same_key(schema, key, existing, row)) {
+            return None;
+        }
+        let &index = (columns.iter()).find(|&&index| same_key(schema, index, existing, row))?;
+        Some(if schema.columns[index].primary_key {
+            ExecuteResult::DuplicateKey
+        } else {
+            ExecuteResult::UniqueViolation
+        })
+    };
+
+    let mut scanned = Vec::new();
+    for &index in &keys {
+        let name = &schema.columns[index].name;
+        let Some(row_keys) = index_lookup(table, schema, name, &row.values[index]) else {
+            scanned.push(index);
+            continue;
+        };
+        for row_key in row_keys {
+            let existing =
+                find_key(table, table_index, row_key).and_then(|cursor| read_row(&cursor, schema));
+            if let Some(conflict) = existing.and_then(|existing| clash(&existing, &[index])) {
+                return Some(conflict);
+            }
+        }
+    }
+    if scanned.is_empty() {
+        return None;
+    }
+
+    let mut conflict = None;
+    find_row(table, table_index, schema, |existing| {
+        conflict = clash(existing, &scanned);
+        conflict.is_some()
+    });
+    conflict
+}
+
+fn execute_insert(table: TableRef, insert: &InsertStmt, out: &mut RowSink) -> ExecuteResult {
+    let Some(table_index) = resolve_table(&table, &insert.table_name) else {
+        return ExecuteResult::NoSuchTable;
+    };
+
+    let schema = table_schema(&table, table_index);
+    if !has_returning_columns(&schema, &insert.returning) {
+        return ExecuteResult::NoSuchColumn;
+    }
+    let row = match build_row(&table, table_index, &schema, insert) {
+        Ok(row) => row,
+        Err(result) => return result,
+    };
+    let Some(key) = row_key(&table, table_index, &schema, &row) else {
+        return ExecuteResult::TableFull;
+    };
+    if let Some(result) = key_conflict(&table, table_index, &schema, &row, key, insert.or_replace) {
+        return result;
+    }
+    let serialized_data = row.serialize_row(&schema);
+
+    // insert or replace overwrites the row with the same primary key in
+    // place. It keeps its key, and its index entries make way for the new
+    // row's.
+    let existing = match schema.primary_key() {
+        Some(_) if !insert.or_replace => None,
+        Some(_) if schema.key_column().is_some() => find_key(&table, table_index, key),
+        Some(index) if !row.values[index].is_null() => {
+            find_row(&table, table_index, &schema, |existing| {
+                same_key(&schema, index, existing, &row)
+            })
+        }
+        _ => None,
+    };
+    let (key, replaced) = match &existing {
+        Some(cursor) => (cursor.key().unwrap_or(key), read_row(cursor, &schema)),
+        None => (key, None),
+    };
+    let entries = index_entries(&table, &schema, key, &row);
+    let mut rows: Vec<(usize, i64, &[u8])> = (entries.iter())
+        .map(|entry| (entry.tree, entry.key, &entry.data[..]))
+        .collect();
+    rows.push((table_index, key, &serialized_data));
+    if !has_room(&table, &rows) {
+        return ExecuteResult::TableFull;
+    }
+    match existing {
+        Some(cursor) => {
+            if let Some(replaced) = &replaced {
+                remove_index_entries(&table, &schema, key, replaced);
+            }
+            if !replace_row(cursor, key, &serialized_data) {
+                return ExecuteResult::TableFull;
+            }
+        }
+        None if !insert_row(&table, table_index, key, &serialized_data) => {
+            return ExecuteResult::TableFull;
+        }
+        None => {}
+    }
+    let inserted =
+        (entries.iter()).all(|entry| insert_row(&table, entry.tree, entry.key, &entry.data));
+    if !inserted {
+        return ExecuteResult::TableFull;
+    }
+
+    let rows = [row];
+    let result = fire_triggers(&table, &schema, TriggerEvent::Insert, &rows);
+    if !matches!(result, ExecuteResult::Success) {
+        return result;
+    }
+    if let Some(returning) = &insert.returning {
+        out(returned_values(&rows[0], &schema, returning));
+    }
+    ExecuteResult::Affected(1)
+}
+
+// Whether every column a RETURNING clause reads is in the schema
+fn has_returning_columns(schema: &Schema, returning: &Option<Vec<Expr>>) -> bool {
+    let mut names = Vec::new();
+    for expr in returning.iter().flatten() {
+        expr.column_names(&mut names);
+    }
+    names.iter().all(|name| schema.column_index(name).is_some())
+}
+
+// What RETURNING hands out for an affected row, the whole row for
+// returning *
+fn returned_values(row: &Row, schema: &Schema, returning: &[Expr]) -> Vec<Value> {
+    if returning.is_empty() {
+        return row.values.clone();
+    }
+    (returning.iter())
+        .map(|expr| evaluate(expr, row, schema))
+        .collect()
+}
+
+fn read_row(cursor: &Cursor, schema: &Schema) -> Option<Row> {
+    let mut data = Vec::new();
+    cursor.read_row_into(&mut data);
+    Row::deserialize_row(&data, schema)
+}
+
+// Swaps the row under the cursor for a serialized one under the same key.
+// Rows differ in size, so the cell is taken out and added again. False when
+// the table is full.
+fn replace_row(cursor: Cursor, key: i64, data: &[u8]) -> bool {
+    let table = Rc::clone(&cursor.table);
+    let table_index = cursor.table_index;
+    cursor.remove();
+    insert_row(&table, table_index, key, data)
+}
+
+// The key a new row is stored under: the value of its INTEGER PRIMARY KEY,
+// which is never NULL, or the key after the table's largest. None when the
+// keys have run out.
+fn row_key(table: &TableRef, table_index: usize, schema: &Schema, row: &Row) -> Option<i64> {
+    match schema.key_column().map(|index| &row.values[index]) {
+        Some(Value::Integer(key)) => Some(*key),
+        _ => next_key(table, table_index),
+    }
+}
+
+// One past the table's largest key, as the catalog has it
+fn next_key(table: &TableRef, table_index: usize) -> Option<i64> {
+    let table = table.borrow();
+    let entry = &table.tables[table_index];
+    match entry.num_rows {
+        0 => Some(1),
+        _ => entry.max_key.checked_add(1),
+    }
+}
+
+// Adds a serialized row at the position of its key, false when the table is
+// full
+fn insert_row(table: &TableRef, table_index: usize, key: i64, data: &[u8]) -> bool {
+    let Some(stored) = table.borrow_mut().store_overflow(table_index, key, data) else {
+        return false;
+    };
+    let cursor = Cursor::seek(Rc::clone(table), table_index, key);
+    if !cursor.insert(key, &stored) {
+        table.borrow_mut().free_overflow(table_index, &stored);
+        return false;
+    }
+    let mut table = table.borrow_mut();
+    let entry = &mut table.tables[table_index];
+    (entry.min_key, entry.max_key) = match entry.num_rows {
+        0 => (key, key),
+        _ => (entry.min_key.min(key), entry.max_key.max(key)),
+    };
+    entry.num_rows += 1;
+    true
+}
+
+// Whether the serialized rows can be stored in the tables, given as their
+// position in the catalog, without running out of pages. A replaced row
+// counts as a new cell too, as it is added again.
+fn has_room(table: &TableRef, rows: &[(usize, i64, &[u8])]) -> bool {
+    let mut pages = 0;
+    for &(table_index, key, data) in rows {
+        let page_num = Cursor::seek(Rc::clone(table), table_index, key).page_num;
+        let cell_size = KEY_SIZE + row_length(data);
+        let mut table = table.borrow_mut();
+        pages += table.insert_pages(page_num, cell_size);
+        let usable_size = table.pager.usable_size();
+        pages += table.tables[table_index]
+            .schema
+            .overflow_pages(data, usable_size);
+    }
+    table.borrow_mut().has_free_pages(pages)
+}
+
+// An entry of an index: the value of the indexed column and the key of the
+// row holding it, stored under a hash of the value
+struct IndexEntry {
+    // Position of the index's tree in the catalog
+    tree: usize,
+    key: i64,
+    data: Vec<u8>,
+}
+
+// Hash of the value an index stores it under, the same for values the
+// column's collation takes as equal. FNV-1a over the column's encoding of
+// the value, which doesn't change from one run to the next.
+fn index_key(column: &ColumnDef, value: &Value) -> i64 {
+    let (mut field, mut overflow) = (Vec::new(), Vec::new());
+    write_field(
+        &column.collation.key(value.clone()),
+        &mut field,
+        &mut overflow,
+    );
+    field.append(&mut overflow);
+    let hash = (field.iter()).fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash as i64
+}
+
+// The index's entry for the row stored under the key. Indexes leave NULLs
+// out.
+fn index_entry(
+    table: &TableRef,
+    schema: &Schema,
+    index: &Index,
+    key: i64,
+    row: &Row,
+) -> Option<IndexEntry> {
+    let tree = table.borrow().find_index(&index.name)?;
+    let value = row.get(schema, &index.column);
+    if value.is_null() {
+        return None;
+    }
+    let entry_schema = table_schema(table, tree);
+    let key_value = index_key(&entry_schema.columns[0], &value);
+    let entry = Row {
+        values: vec![value, Value::Integer(key)],
+    };
+    Some(IndexEntry {
+        tree,
+        key: key_value,
+        data: entry.serialize_row(&entry_schema),
+    })
+}
+
+fn index_entries(table: &TableRef, schema: &Schema, key: i64, row: &Row) -> Vec<IndexEntry> {
+    (schema.indexes.iter())
+        .filter_map(|index| index_entry(table, schema, index, key, row))
+        .collect()
+}
+
+// Takes the entries of the row stored under the key out of the table's
+// indexes
+fn remove_index_entries(table: &TableRef, schema: &Schema, key: i64, row: &Row) {
+    for entry in index_entries(table, schema, key, row) {
+        let entry_schema = table_schema(table, entry.tree);
+        let mut cursor = Cursor::seek(Rc::clone(table), entry.tree, entry.key);
+        while cursor.key() == Some(entry.key) {
+            let found = read_row(&cursor, &entry_schema)
+                .is_some_and(|found| found.values[1] == Value::Integer(key));
+            if found {
+                cursor.remove();
+                break;
+            }
+            cursor.advance();
+        }
+    }
+}
+
+// Keys of the rows holding the value in the column, found through an index
+// on it. None when the column has no index.
+fn index_lookup(
+    table: &TableRef,
+    schema: &Schema,
+    column: &str,
+    value: &Value,
+) -> Option<Vec<i64>> {
+    let index = (schema.indexes.iter()).find(|index| index.column == column)?;
+    let tree = table.borrow().find_index(&index.name)?;
+    let entry_schema = table_schema(table, tree);
+    let column = &entry_schema.columns[0];
+    let key = index_key(column, value);
+
+    // Entries whose values hash alike sit together, only the equal ones
+    // count
+    let mut keys = Vec::new();
+    let mut cursor = Cursor::seek(Rc::clone(table), tree, key);
+    while cursor.key() == Some(key) {
+        let entry = read_row(&cursor, &entry_schema);
+        if let Some([found, Value::Integer(row_key)]) =
+            entry.as_ref().map(|entry| &entry.values[..])
+            && column.collation.compare(found, value) == Ordering::Equal
+        {
+            keys.push(*row_key);
+        }
+        cursor.advance();
+    }
+    Some(keys)
+}
+
+// Builds the index over the rows already in the table and records it in the
+// table's schema, from then on inserts and deletes keep it up to date
+fn execute_create_index(table: TableRef, create: &CreateIndexStmt) -> ExecuteResult {
+    let Some(table_index) = resolve_table(&table, &create.table_name) else {
+        return ExecuteResult::NoSuchTable;
+    };
+    let index = &create.index;
+    if table.borrow().find_index(&index.name).is_some() {
+        return ExecuteResult::IndexExists;
+    }
+    if table.borrow().find_table(&index.name).is_some() {
+        return ExecuteResult::TableExists;
+    }
+    let mut schema = table_schema(&table, table_index);
+    let Some(column) = schema.column_index(&index.column) else {
+        return ExecuteResult::NoSuchColumn;
+    };
+    schema.indexes.push(index.clone());
+    if schema.encoded_size() > table.borrow().pager.usable_size() {
+        return ExecuteResult::RowTooLarge;
+    }
+
+    // Entries hold the value, typed and collated like the column, and the
+    // key of the row
+    let indexed = &schema.columns[column];
+    let mut value = ColumnDef::new(&indexed.name, indexed.ctype);
+    value.collation = indexed.collation;
+    let entry_schema = Schema {
+        columns: vec![value, ColumnDef::new("rowid", ColumnType::Integer)],
+        checks: Vec::new(),
+        triggers: Vec::new(),
+        indexes: Vec::new(),
+    };
+    let result = table.borrow_mut().add_table(&index.name, entry_schema);
+    if !matches!(result, ExecuteResult::Success) {
+        return result;
+    }
+    let tree = table.borrow().tables.len() - 1;
+    table.borrow_mut().tables[tree].is_index = true;
+
+    // The rows are read from a snapshot, which the entries going into the
+    // index can't change under the cursor
+    let snapshot = table.borrow_mut().snapshot();
+    let mut cursor = Cursor::from_start_in(Rc::clone(&table), &snapshot, table_index);
+    while !cursor.end_of_table {
+        if let (Some(key), Some(row)) = (cursor.key(), read_row(&cursor, &schema))
+            && let Some(entry) = index_entry(&table, &schema, index, key, &row)
+            && !insert_row(&table, entry.tree, entry.key, &entry.data)
+        {
+            table.borrow_mut().remove_table(tree);
+            return ExecuteResult::TableFull;
+        }
+        cursor.advance();
+    }
+
+    table.borrow_mut().write_schema(table_index, schema)
+}
+
+// Adds a serialized row after the table's last one, false when the table is
+// full
+fn append_row(table: &TableRef, table_index: usize, data: &[u8]) -> bool {
+    next_key(table, table_index).is_some_and(|key| insert_row(table, table_index, key, data))
+}
+
+// Removes the rows matching the condition one at a time, with their index
+// entries, once they are all read. Returns the rows removed.
+fn delete_rows(
+    table: &TableRef,
+    table_index: usize,
+    schema: &Schema,
+    condition: &Option<Expr>,
+) -> Vec<Row> {
+    let access = choose_access(schema, condition.as_ref());
+    let keys = access_keys(table, schema, &access);
+    let (mut scan_stats, mut filter_stats) = (NodeStats::default(), NodeStats::default());
+    let mut scan = Scan::new(
+        table,
+        table_index,
+        schema,
+        keys,
+        false,
+        Instant::now(),
+        &mut scan_stats,
+    )
+    .filtered(condition.as_ref(), &mut filter_stats);
+    let matched: Vec<(i64, Row)> = std::iter::from_fn(|| scan.next_keyed()).collect();
+
+    for (key, row) in &matched {
+        remove_index_entries(table, schema, *key, row);
+        if let Some(cursor) = find_key(table, table_index, *key) {
+            cursor.remove();
+        }
+    }
+    matched.into_iter().map(|(_, row)| row).collect()
+}
+
+// Removes the rows matching the condition, or every row by resetting the
+// table's pages. The rows are read first when a trigger or RETURNING needs
+// to see them.
+fn execute_delete(table: TableRef, delete: &DeleteStmt, out: &mut RowSink) -> ExecuteResult {
+    let Some(table_index) = resolve_table(&table, &delete.table_name) else {
+        return ExecuteResult::NoSuchTable;
+    };
+
+    let schema = table_schema(&table, table_index);
+    if !has_returning_columns(&schema, &delete.returning) {
+        return ExecuteResult::NoSuchColumn;
+    }
+    let has_triggers =
+        (schema.triggers.iter()).any(|trigger| trigger.event == TriggerEvent::Delete);
+    let rows = if let Some(condition) = &delete.where_clause {
+        let mut names = Vec::new();
+        condition.column_names(&mut names);
+        if !names.iter().all(|name| schema.column_index(name).is_some()) {
+            return ExecuteResult::NoSuchColumn;
+        }
+        delete_rows(&table, table_index, &schema, &delete.where_clause)
+    } else if has_triggers || delete.returning.is_some() {
+        Cursor::from_start(Rc::clone(&table), table_index).collect()
+    } else {
+        Vec::new()
+    };
+
+    let deleted = match delete.where_clause {
+        Some(_) => rows.len(),
+        None => table.borrow().tables[table_index].num_rows,
+    };
+    if delete.where_clause.is_none() {
+        let result = table.borrow_mut().truncate_table(&delete.table_name);
+        if !matches!(result, ExecuteResult::Success) {
+            return result;
+        }
+    }
+    let result = fire_triggers(&table, &schema, TriggerEvent::Delete, &rows);
+    if !matches!(result, ExecuteResult::Success) {
+        return result;
+    }
+    if let Some(returning) = &delete.returning {
+        for row in &rows {
+            out(returned_values(row, &schema, returning));
+        }
+    }
+    ExecuteResult::Affected(deleted)
+}
+
+// Runs the body of the table's triggers for the event once per row, with
+// the row's values bound to its new.<column> or old.<column> references.
+// Stops at the first statement that fails.
+fn fire_triggers(
+    table: &TableRef,
+    schema: &Schema,
+    event: TriggerEvent,
+    rows: &[Row],
+) -> ExecuteResult {
+    for trigger in schema
+        .triggers
+        .iter()
+        .filter(|trigger| trigger.event == event)
+    {
+        if table.borrow().firing.contains(&trigger.name) {
+            continue;
+        }
+
+        table.borrow_mut().firing.push(trigger.name.clone());
+        let result = run_trigger(table, schema, trigger, rows);
+        table.borrow_mut().firing.pop();
+        if !matches!(result, ExecuteResult::Success) {
+            return result;
+        }
+    }
+
+    ExecuteResult::Success
+}
+
+fn run_trigger(
+    table: &TableRef,
+    schema: &Schema,
+    trigger: &Trigger,
+    rows: &[Row],
+) -> ExecuteResult {
+    let statements: Vec<(Statement, Vec<String>)> = split_statements(&trigger.body)
+        .iter()
+        .filter_map(|statement| {
+            let (statement, columns) = row_references(statement, trigger.event.prefix());
+            // Every statement was prepared when the trigger was created
+            Some((prepare(&statement).ok()?, columns))
+        })
+        .collect();
+
+    for row in rows {
+        for (statement, columns) in &statements {
+            let values: Vec<Value> = columns
+                .iter()
+                .map(|column| row.get(schema, column))
+                .collect();
+            let Ok(statement) = statement.bind(&values) else {
+                continue;
+            };
+            // Rows the trigger's statements return go nowhere
+            let result = execute(Rc::clone(table), &statement, &mut |_| {});
+            if !result.is_success() {
+                return result;
+            }
+        }
+    }
+
+    ExecuteResult::Success
+}
+
+// SQL LIKE: % matches any sequence, _ any single character, ASCII letters
+// match regardless of case
+fn like_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last % seen and the text position it was matched at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '%' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len()
+            && (pattern[p] == '_' || pattern[p].eq_ignore_ascii_case(&text[t]))
+        {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the last % swallow one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '%')
+}
+
+// GLOB: * matches any sequence, ? any single character and [...] one
+// character of the set, which may hold ranges such as a-z and is negated
+// by a leading ^. Case matters.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last * seen and the text position it was matched at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        let next = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => glob_set(&pattern[p..], text[t]).map(|len| p + len),
+            Some(&c) if c == text[t] => Some(p + 1),
+            _ => None,
+        };
+        match (next, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            // Let the last * swallow one more character
+            (None, Some((star_p, star_t))) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                backtrack = Some((star_p, star_t + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Length of the [...] set the pattern starts with when the character is
+// in it. A ] right after the opening bracket is part of the set, and a set
+// that is never closed matches nothing.
+fn glob_set(pattern: &[char], c: char) -> Option<usize> {
+    let negated = pattern.get(1) == Some(&'^');
+    let mut i = if negated { 2 } else { 1 };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let &start = pattern.get(i)?;
+        if start == ']' && !first {
+            break;
+        }
+        first = false;
+        match pattern.get(i + 2) {
+            Some(&end) if pattern[i + 1] == '-' && end != ']' => {
+                matched |= (start..=end).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= start == c;
+                i += 1;
+            }
+        }
+    }
+    (matched != negated).then_some(i + 1)
+}
+
+// The rows expressions are evaluated over: owned ones, or ones read in place
+trait Columns {
+    // Value of the named column, NULL when the schema has no such column
+    fn column(&self, schema: &Schema, name: &str) -> Value;
+
+    fn to_values(&self) -> Vec<Value>;
+
+    // The named column compared to a value, NULL when either is NULL
+    fn compare_column(
+        &self,
+        schema: &Schema,
+        name: &str,
+        op: CompareOp,
+        value: &Value,
+        collation: Collation,
+    ) -> Value {
+        let column = self.column(schema, name);
+        if column.is_null() || value.is_null() {
+            Value::Null
+        } else {
+            Value::Boolean(compare_values(&column, op, value, collation))
+        }
+    }
+}
+
+impl Columns for Row {
+    fn column(&self, schema: &Schema, name: &str) -> Value {
+        self.get(schema, name)
+    }
+
+    fn to_values(&self) -> Vec<Value> {
+        self.values.clone()
+    }
+}
+
+impl Columns for RowRef<'_> {
+    fn column(&self, schema: &Schema, name: &str) -> Value {
+        schema
+            .column_index(name)
+            .map_or(Value::Null, |index| self.get(index).into_value())
+    }
+
+    fn to_values(&self) -> Vec<Value> {
+        self.to_row().values
+    }
+
+    // Text is compared where it is stored instead of being copied out
+    fn compare_column(
+        &self,
+        schema: &Schema,
+        name: &str,
+        op: CompareOp,
+        value: &Value,
+        collation: Collation,
+    ) -> Value {
+        let column = schema
+            .column_index(name)
+            .map_or(ValueRef::Null, |i| self.get(i));
+        if column.is_null() || value.is_null() {
+            Value::Null
+        } else {
+            Value::Boolean(op_holds(op, column.compare(value, collation)))
+        }
+    }
+}
+
+// Rows always match when there is no WHERE clause, otherwise only when the
+// condition is true (not false or NULL)
+fn row_matches(row: &impl Columns, schema: &Schema, condition: Option<&Expr>) -> bool {
+    match condition {
+        None => true,
+        Some(condition) => truth(&evaluate(condition, row, schema)) == Some(true),
+    }
+}
+
+// Arithmetic on two values that aren't NULL. Integers stay integers unless
+// the result overflows; division by zero is NULL.
+fn arithmetic(left: &Value, op: ArithOp, right: &Value) -> Value {
+    let (left, right) = (left.to_number(), right.to_number());
+    if let (Some(a), Some(b)) = (left.as_integer(), right.as_integer()) {
+        let result = match op {
+            ArithOp::Add => a.checked_add(b),
+            ArithOp::Subtract => a.checked_sub(b),
+            ArithOp::Multiply => a.checked_mul(b),
+            ArithOp::Divide if b == 0 => return Value::Null,
+            ArithOp::Divide => a.checked_div(b),
+            ArithOp::Remainder if b == 0 => return Value::Null,
+            ArithOp::Remainder => a.checked_rem(b),
+        };
+        if let Some(result) = result {
+            return Value::Integer(result);
+        }
+    }
+
+    let (a, b) = (left.as_real(), right.as_real());
+    match op {
+        ArithOp::Add => Value::Real(a + b),
+        ArithOp::Subtract => Value::Real(a - b),
+        ArithOp::Multiply => Value::Real(a * b),
+        ArithOp::Divide | ArithOp::Remainder if b == 0.0 => Value::Null,
+        ArithOp::Divide => Value::Real(a / b),
+        ArithOp::Remainder => Value::Real(a % b),
+    }
+}
+
+// Evaluates an expression over a row. Arithmetic and comparisons involving
+// NULL are NULL and AND, OR and NOT follow SQL's three-valued logic.
+fn evaluate(expr: &Expr, row: &impl Columns, schema: &Schema) -> Value {
+    let boolean = |truth: Option<bool>| truth.map_or(Value::Null, Value::Boolean);
+    match expr {
+        Expr::Literal(value) => value.clone(),
+        // Statements are bound before they are executed
+        Expr::Parameter(_) => Value::Null,
+        Expr::Column(name) => row.column(schema, name),
+        Expr::Arithmetic(left, op, right) => {
+            let left = evaluate(left, row, schema);
+            let right = evaluate(right, row, schema);
+            if left.is_null() || right.is_null() {
+                Value::Null
+            } else {
+                arithmetic(&left, *op, &right)
+            }
+        }
+        Expr::Negate(inner) => match evaluate(inner, row, schema) {
+            Value::Null => Value::Null,
+            value => arithmetic(&Value::Integer(0), ArithOp::Subtract, &value),
+        },
+        Expr::Concat(left, right) => {
+            match (evaluate(left, row, schema), evaluate(right, row, schema)) {
+                (Value::Null, _) | (_, Value::Null) => Value::Null,
+                (left, right) => Value::Text(format!("{}{}", left, right)),
+            }
+        }
+        Expr::Function(function, args) => {
+            let args: Vec<Value> = (args.iter())
+                .map(|arg| evaluate(arg, row, schema))
+                .collect();
+            call_function(*function, &args)
+        }
+        Expr::Cast(inner, ctype) => ctype.cast(evaluate(inner, row, schema)),
+        // Only changes how the value compares
+        Expr::Collate(inner, _) => evaluate(inner, row, schema),
+        Expr::Case {
+            operand,
+            branches,
+            otherwise,
+        } => {
+            let operand_value = operand
+                .as_ref()
+                .map(|operand| evaluate(operand, row, schema));
+            for (condition, value) in branches {
+                let condition_value = evaluate(condition, row, schema);
+                let matched = match (&operand, &operand_value) {
+                    (Some(operand), Some(operand_value)) => compare_values(
+                        operand_value,
+                        CompareOp::Equal,
+                        &condition_value,
+                        collation(operand, condition, schema),
+                    ),
+                    _ => truth(&condition_value) == Some(true),
+                };
+                if matched {
+                    return evaluate(value, row, schema);
+                }
+            }
+            match otherwise {
+                Some(otherwise) => evaluate(otherwise, row, schema),
+                None => Value::Null,
+            }
+        }
+        Expr::Like(inner, pattern) => match evaluate(inner, row, schema) {
+            Value::Null => Value::Null,
+            value => Value::Boolean(like_match(pattern, &value.to_string())),
+        },
+        Expr::Glob(inner, pattern) => match evaluate(inner, row, schema) {
+            Value::Null => Value::Null,
+            value => Value::Boolean(glob_match(pattern, &value.to_string())),
+        },
+        #[cfg(feature = "regexp")]
+        Expr::Regexp(inner, regex) => match evaluate(inner, row, schema) {
+            Value::Null => Value::Null,
+            value => Value::Boolean(regex.is_match(&value.to_string())),
+        },
+        // A linear search is fine for the short lists typed in the REPL
+        Expr::In(inner, list) => match evaluate(inner, row, schema) {
+            Value::Null => Value::Null,
+            value => Value::Boolean(list.iter().any(|item| {
+                let collation = collation(inner, item, schema);
+                compare_values(
+                    &value,
+                    CompareOp::Equal,
+                    &evaluate(item, row, schema),
+                    collation,
+                )
+            })),
+        },
+        // On the INTEGER PRIMARY KEY choose_access already limits the scan to
+        // the range, this still checks the rows for the other cases
+        Expr::Between(inner, low, high) => {
+            let collation = collation(inner, low, schema);
+            let value = evaluate(inner, row, schema);
+            let low = evaluate(low, row, schema);
+            let high = evaluate(high, row, schema);
+            if value.is_null() || low.is_null() || high.is_null() {
+                Value::Null
+            } else {
+                Value::Boolean(
+                    compare_values(&value, CompareOp::GreaterEqual, &low, collation)
+                        && compare_values(&value, CompareOp::LessEqual, &high, collation),
+                )
+            }
+        }
+        Expr::Compare(left, op, right) => {
+            let collation = collation(left, right, schema);
+            // The usual filter, a column against a literal, needs no copy
+            if let (Expr::Column(name), Expr::Literal(value)) = (left.as_ref(), right.as_ref()) {
+                return row.compare_column(schema, name, *op, value, collation);
+            }
+            let left = evaluate(left, row, schema);
+            let right = evaluate(right, row, schema);
+            if left.is_null() || right.is_null() {
+                Value::Null
+            } else {
+                Value::Boolean(compare_values(&left, *op, &right, collation))
+            }
+        }
+        Expr::And(left, right) => {
+            match (
+                truth(&evaluate(left, row, schema)),
+                truth(&evaluate(right, row, schema)),
+            ) {
+                (Some(false), _) | (_, Some(false)) => Value::Boolean(false),
+                (Some(true), Some(true)) => Value::Boolean(true),
+                _ => Value::Null,
+            }
+        }
+        Expr::Or(left, right) => {
+            match (
+                truth(&evaluate(left, row, schema)),
+                truth(&evaluate(right, row, schema)),
+            ) {
+                (Some(true), _) | (_, Some(true)) => Value::Boolean(true),
+                (Some(false), Some(false)) => Value::Boolean(false),
+                _ => Value::Null,
+            }
+        }
+        Expr::Not(inner) => boolean(truth(&evaluate(inner, row, schema)).map(|b| !b)),
+    }
+}
+
+// Scalar functions return NULL when any argument is NULL. Text functions
+// work on the text form of other values.
+fn call_function(function: Function, args: &[Value]) -> Value {
+    if args.iter().any(Value::is_null) {
+        return Value::Null;
+    }
+
+    let text = args[0].to_string();
+    match function {
+        Function::Upper => Value::Text(text.to_uppercase()),
+        Function::Lower => Value::Text(text.to_lowercase()),
+        Function::Length => match &args[0] {
+            Value::Blob(bytes) => Value::Integer(bytes.len() as i64),
+            _ => Value::Integer(text.chars().count() as i64),
+        },
+        Function::Substr => {
+            let chars: Vec<char> = text.chars().collect();
+            let len = chars.len() as i64;
+            // A negative start counts back from the end of the text
+            let start = args[1].to_number().as_real() as i64;
+            let start = if start < 0 { len + start } else { start - 1 }.clamp(0, len);
+            let end = match args.get(2) {
+                Some(count) => {
+                    let count = (count.to_number().as_real() as i64).max(0);
+                    start.saturating_add(count).min(len)
+                }
+                None => len,
+            };
+            Value::Text(chars[start as usize..end as usize].iter().collect())
+        }
+        Function::Trim => match args.get(1) {
+            Some(characters) => {
+                let characters: Vec<char> = characters.to_string().chars().collect();
+                Value::Text(text.trim_matches(characters.as_slice()).to_string())
+            }
+            None => Value::Text(text.trim_matches(' ').to_string()),
+        },
+    }
+}
+
+// Whether a value counts as true in a condition, None for NULL. Anything
+// that isn't a boolean is true when its number is not zero.
+fn truth(value: &Value) -> Option<bool> {
+    match value {
+        Value::Null => None,
+        Value::Boolean(b) => Some(*b),
+        _ => Some(value.to_number().as_real() != 0.0),
+    }
+}
+
+// The collation two compared expressions use: an explicit COLLATE on
+// either side first, then the collation of a column on either side, the
+// left one winning each time
+fn collation(left: &Expr, right: &Expr, schema: &Schema) -> Collation {
+    let explicit = |expr: &Expr| match expr {
+        Expr::Collate(_, collation) => Some(*collation),
+        _ => None,
+    };
+    let column = |expr: &Expr| match expr {
+        Expr::Column(name) => Some(schema.collation(name)),
+        _ => None,
+    };
+    (explicit(left).or_else(|| explicit(right)))
+        .or_else(|| column(left))
+        .or_else(|| column(right))
+        .unwrap_or_default()
+}
+
+// Comparisons with NULL are never true
+fn compare_values(left: &Value, op: CompareOp, right: &Value, collation: Collation) -> bool {
+    if left.is_null() || right.is_null() {
+        return false;
+    }
+
+    op_holds(op, collation.compare(left, right))
+}
+
+fn op_holds(op: CompareOp, ordering: Ordering) -> bool {
+    match op {
+        CompareOp::Equal => ordering.is_eq(),
+        CompareOp::NotEqual => ordering.is_ne(),
+        CompareOp::Less => ordering.is_lt(),
+        CompareOp::LessEqual => ordering.is_le(),
+        CompareOp::Greater => ordering.is_gt(),
+        CompareOp::GreaterEqual => ordering.is_ge(),
+    }
+}
+
+// Whether every column a single-table select mentions is in the schema
+fn has_columns(schema: &Schema, select: &SelectStmt) -> bool {
+    let mut names = Vec::new();
+    for item in &select.select_list {
+        match item {
+            SelectItem::Expr(expr) => expr.column_names(&mut names),
+            SelectItem::Aggregate(aggregate) => names.extend(aggregate.column()),
+        }
+    }
+    if let Some(condition) = &select.where_clause {
+        condition.column_names(&mut names);
+    }
+    names.extend(select.group_by.as_deref());
+    names.extend(
+        select
+            .having
+            .as_ref()
+            .and_then(|having| having.aggregate.column()),
+    );
+    names.extend(select.order_by.as_ref().map(|o| o.column.as_str()));
+
+    names.iter().all(|name| schema.column_index(name).is_some())
+}
+
+// Receives the result rows of a query
+pub type RowSink<'a> = dyn FnMut(Vec<Value>) + 'a;
+
+// The whole row when the select list is empty
+fn row_values(row: &impl Columns, schema: &Schema, select_list: &[SelectItem]) -> Vec<Value> {
+    if select_list.is_empty() {
+        return row.to_values();
+    }
+
+    select_list
+        .iter()
+        .filter_map(|item| match item {
+            SelectItem::Expr(expr) => Some(evaluate(expr, row, schema)),
+            SelectItem::Aggregate(_) => None,
+        })
+        .collect()
+}
+
+// How a select reaches the rows of its table
+pub enum Access<'a> {
+    // Every row
+    Scan,
+    // The row whose INTEGER PRIMARY KEY column holds the value
+    Key(&'a str, &'a Expr),
+    // The rows the named index finds for the value in the column
+    Index(String, &'a str, &'a Expr),
+    // The rows whose INTEGER PRIMARY KEY column is within the bounds
+    Range(String, Bound<&'a Expr>, Bound<&'a Expr>),
+}
+
+// How a select reads the rows of its table, planned from its WHERE and ORDER
+// BY clauses and the table's primary key and indexes before it runs. The
+// select runs the plan EXPLAIN shows.
+pub struct ScanPlan<'a> {
+    pub access: Access<'a>,
+    // Rows are read in descending key order
+    pub descending: bool,
+    // The order the rows are sorted in once they are all read, when reading
+    // them in key order doesn't give it
+    pub sort: Option<&'a OrderBy>,
+}
+
+impl<'a> ScanPlan<'a> {
+    pub fn new(schema: &Schema, select: &'a SelectStmt) -> Self {
+        let access = choose_access(schema, select.where_clause.as_ref());
+        // Rows come out in key order either way, so ordering by the INTEGER
+        // PRIMARY KEY needs no sort. Aggregates order their groups instead.
+        let order_by = select.order_by.as_ref().filter(|_| !select.is_aggregate());
+        let by_key = order_by.is_some_and(|order_by| {
+            (schema.key_column())
+                .is_some_and(|key| schema.column_index(&order_by.column) == Some(key))
+        });
+        Self {
+            access,
+            descending: by_key && order_by.is_some_and(|order_by| order_by.descending),
+            sort: order_by.filter(|_| !by_key),
+        }
+    }
+}
+
+// The WHERE clause can only hold for rows where the column equals the value
+// when the equality is one of its AND-ed terms and the value doesn't depend
+// on the row. The INTEGER PRIMARY KEY is used over an index, and either only
+// when the equality compares with the column's own collation. Without an
+// equality to use, bounds on the INTEGER PRIMARY KEY limit the rows to a
+// range of keys.
+fn choose_access<'a>(schema: &Schema, condition: Option<&'a Expr>) -> Access<'a> {
+    let mut equalities = Vec::new();
+    if let Some(condition) = condition {
+        required_equalities(condition, &mut equalities);
+    }
+    let usable = |column: &&Expr, value: &&Expr| match column {
+        Expr::Column(name) => schema.column_index(name).is_some_and(|index| {
+            collation(column, value, schema) == schema.columns[index].collation
+        }),
+        _ => false,
+    };
+    let equalities: Vec<(&str, &Expr)> = equalities
+        .into_iter()
+        .filter(|(column, value)| usable(column, value))
+        .filter_map(|(column, value)| match column {
+            Expr::Column(name) => Some((name.as_str(), value)),
+            _ => None,
+        })
+        .collect();
+
+    let key_column = schema.key_column().map(|index| &schema.columns[index].name);
+    if let Some(&(column, value)) =
+        (equalities.iter()).find(|(column, _)| Some(*column) == key_column.map(String::as_str))
+    {
+        return Access::Key(column, value);
+    }
+    for (column, value) in equalities {
+        if let Some(index) = (schema.indexes.iter()).find(|index| index.column == column) {
+            return Access::Index(index.name.clone(), column, value);
+        }
+    }
+    if let (Some(key_column), Some(condition)) = (key_column, condition) {
+        let mut bounds = (Bound::Unbounded, Bound::Unbounded);
+        required_bounds(condition, key_column.as_str(), &mut bounds);
+        if !matches!(bounds, (Bound::Unbounded, Bound::Unbounded)) {
+            return Access::Range(key_column.clone(), bounds.0, bounds.1);
+        }
+    }
+    Access::Scan
+}
+
+// Collects the lower and upper bounds on the column that the condition
+// requires: comparisons with values that don't depend on the row, in either
+// order, and BETWEEN. The first bound found on each side is kept.
+fn required_bounds<'a>(
+    condition: &'a Expr,
+    column: &str,
+    bounds: &mut (Bound<&'a Expr>, Bound<&'a Expr>),
+) {
+    use Bound::{Excluded, Included, Unbounded};
+    let is_column = |expr: &Expr| matches!(expr, Expr::Column(name) if name == column);
+    let (lower, upper) = match condition {
+        Expr::And(left, right) => {
+            required_bounds(left, column, bounds);
+            required_bounds(right, column, bounds);
+            return;
+        }
+        Expr::Between(inner, low, high)
+            if is_column(inner) && is_constant(low) && is_constant(high) =>
+        {
+            (Included(&**low), Included(&**high))
+        }
+        Expr::Compare(left, op, right) => match (&**left, op, &**right) {
+            (column, CompareOp::Greater, value) | (value, CompareOp::Less, column)
+                if is_column(column) && is_constant(value) =>
+            {
+                (Excluded(value), Unbounded)
+            }
+            (column, CompareOp::GreaterEqual, value) | (value, CompareOp::LessEqual, column)
+                if is_column(column) && is_constant(value) =>
+            {
+                (Included(value), Unbounded)
+            }
+            (column, CompareOp::Less, value) | (value, CompareOp::Greater, column)
+                if is_column(column) && is_constant(value) =>
+            {
+                (Unbounded, Excluded(value))
+            }
+            (column, CompareOp::LessEqual, value) | (value, CompareOp::GreaterEqual, column)
+                if is_column(column) && is_constant(value) =>
+            {
+                (Unbounded, Included(value))
+            }
+            _ => return,
+        },
+        _ => return,
+    };
+    if matches!(bounds.0, Unbounded) {
+        bounds.0 = lower;
+    }
+    if matches!(bounds.1, Unbounded) {
+        bounds.1 = upper;
+    }
+}
+
+// Collects the column = value terms the condition requires, in either order
+fn required_equalities<'a>(condition: &'a Expr, found: &mut Vec<(&'a Expr, &'a Expr)>) {
+    match condition {
+        Expr::And(left, right) => {
+            required_equalities(left, found);
+            required_equalities(right, found);
+        }
+        Expr::Compare(left, CompareOp::Equal, right) => match (&**left, &**right) {
+            (column @ Expr::Column(_), value) | (value, column @ Expr::Column(_))
+                if is_constant(value) =>
+            {
+                found.push((column, value))
+            }
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+fn is_constant(expr: &Expr) -> bool {
+    let mut names = Vec::new();
+    expr.column_names(&mut names);
+    names.is_empty()
+}
+
+// The key a row's INTEGER PRIMARY KEY must hold to equal the value, None
+// when no integer equals it
+fn key_for(value: &Value) -> Option<i64> {
+    let key = match value {
+        Value::Real(r) => *r as i64,
+        _ => value.as_integer()?,
+    };
+    Value::Integer(key).cmp(value).is_eq().then_some(key)
+}
+
+// The keys of the rows an access reads
+enum Keys {
+    All,
+    Range(RangeInclusive<i64>),
+    // In key order
+    Listed(Vec<i64>),
+}
+
+fn access_keys(table: &TableRef, schema: &Schema, access: &Access) -> Keys {
+    let constant = |value| evaluate(value, &Row { values: Vec::new() }, schema);
+    match access {
+        Access::Scan => Keys::All,
+        Access::Key(_, value) => Keys::Listed(key_for(&constant(value)).into_iter().collect()),
+        Access::Index(_, column, value) => {
+            // The index holds values converted to the column's type, the
+            // ones that can't be converted equal none of them
+            let Some(index) = schema.column_index(column) else {
+                return Keys::All;
+            };
+            let Ok(value) = schema.columns[index].ctype.convert(constant(value)) else {
+                return Keys::Listed(Vec::new());
+            };
+            let Some(mut keys) = index_lookup(table, schema, column, &value) else {
+                return Keys::All;
+            };
+            keys.sort_unstable();
+            Keys::Listed(keys)
+        }
+        Access::Range(_, low, high) => {
+            let low = lowest_key(low.map(constant));
+            let high = highest_key(high.map(constant));
+            match (low, high) {
+                (Some(low), Some(high)) => Keys::Range(low..=high),
+                _ => Keys::Listed(Vec::new()),
+            }
+        }
+    }
+}
+
+// The smallest key within a lower bound. Keys only compare by number with
+// numbers and booleans, other values leave the range open for the filter to
+// decide on. None when no key is within it.
+fn lowest_key(bound: Bound<Value>) -> Option<i64> {
+    match bound {
+        Bound::Included(Value::Null) | Bound::Excluded(Value::Null) => None,
+        Bound::Included(Value::Real(r)) => Some(r.ceil() as i64),
+        Bound::Excluded(Value::Real(r)) => (r.floor() as i64).checked_add(1),
+        Bound::Included(value @ (Value::Integer(_) | Value::Boolean(_))) => value.as_integer(),
+        Bound::Excluded(value @ (Value::Integer(_) | Value::Boolean(_))) => {
+            value.as_integer()?.checked_add(1)
+        }
+        _ => Some(i64::MIN),
+    }
+}
+
+// The largest key within an upper bound, like lowest_key
+fn highest_key(bound: Bound<Value>) -> Option<i64> {
+    match bound {
+        Bound::Included(Value::Null) | Bound::Excluded(Value::Null) => None,
+        Bound::Included(Value::Real(r)) => Some(r.floor() as i64),
+        Bound::Excluded(Value::Real(r)) => (r.ceil() as i64).checked_sub(1),
+        Bound::Included(value @ (Value::Integer(_) | Value::Boolean(_))) => value.as_integer(),
+        Bound::Excluded(value @ (Value::Integer(_) | Value::Boolean(_))) => {
+            value.as_integer()?.checked_sub(1)
+        }
+        _ => Some(i64::MAX),
+    }
+}
+
+// Queries run as a tree of operators, Volcano style: each hands out its rows
+// one at a time and pulls them from the operators under it only as it needs
+// them, so a limit stops everything under it from reading any further.
+// Operators that read their whole input take it a batch of rows at a time,
+// which scans and filters fill in a single call rather than one per row.
+trait Operator {
+    fn next(&mut self) -> Option<Row>;
+
+    // Fills the empty batch with up to BATCH_SIZE rows, leaving it empty
+    // once there are no more
+    fn next_batch(&mut self, batch: &mut Vec<Row>) {
+        batch.extend(std::iter::from_fn(|| self.next()).take(BATCH_SIZE));
+    }
+}
+
+type Input<'a> = Box<dyn Operator + 'a>;
+
+const BATCH_SIZE: usize = 256;
+
+// Every row left in the input
+fn read_all(input: &mut Input) -> Vec<Row> {
+    let mut rows = Vec::new();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    loop {
+        input.next_batch(&mut batch);
+        if batch.is_empty() {
+            return rows;
+        }
+        rows.append(&mut batch);
+    }
+}
+
+// Where a scan takes its rows from: a cursor over every row or over a range
+// of keys, or the rows under listed keys
+enum ScanSource {
+    Cursor(Cursor),
+    Keys(std::vec::IntoIter<i64>),
+}
+
+// The rows of a table an access reads. A WHERE clause over the table is
+// tested on the rows where they are stored, only the ones passing it are
+// copied out.
+struct Scan<'a> {
+    table: TableRef,
+    table_index: usize,
+    schema: &'a Schema,
+    source: ScanSource,
+    // Set once the row under the cursor has been read, the cursor moves on
+    // before the next one
+    read: bool,
+    // The stored row, reused from row to row, or the rows of a batch and
+    // where each of them ends
+    data: Vec<u8>,
+    ends: Vec<usize>,
+    filter: Option<(&'a Expr, &'a mut NodeStats)>,
+    started: Instant,
+    stats: &'a mut NodeStats,
+}
+
+impl<'a> Scan<'a> {
+    // Reads the rows in key order, or descending key order
+    fn new(
+        table: &TableRef,
+        table_index: usize,
+        schema: &'a Schema,
+        keys: Keys,
+        descending: bool,
+        started: Instant,
+        stats: &'a mut NodeStats,
+    ) -> Self {
+        let source = match keys {
+            Keys::All if descending => Cursor::from_end_rev(Rc::clone(table), table_index),
+            Keys::All => Cursor::from_start(Rc::clone(table), table_index),
+            Keys::Range(range) if descending => {
+                Cursor::from_range_rev(Rc::clone(table), table_index, range)
+            }
+            Keys::Range(range) => Cursor::from_range(Rc::clone(table), table_index, range),
+            Keys::Listed(mut keys) => {
+                // Keys outside the table's range are not looked for
+                let key_range = table.borrow().tables[table_index].key_range();
+                keys.retain(|key| key_range.as_ref().is_some_and(|range| range.contains(key)));
+                if descending {
+                    keys.reverse();
+                }
+                return Self::with_source(
+                    table,
+                    table_index,
+                    schema,
+                    ScanSource::Keys(keys.into_iter()),
+                    started,
+                    stats,
+                );
+            }
+        };
+        Self::with_source(
+            table,
+            table_index,
+            schema,
+            ScanSource::Cursor(source),
+            started,
+            stats,
+        )
+    }
+
+    fn with_source(
+        table: &TableRef,
+        table_index: usize,
+        schema: &'a Schema,
+        source: ScanSource,
+        started: Instant,
+        stats: &'a mut NodeStats,
+    ) -> Self {
+        Self {
+            table: Rc::clone(table),
+            table_index,
+            schema,
+            source,
+            read: false,
+            data: Vec::new(),
+            ends: Vec::new(),
+            filter: None,
+            started,
+            stats,
+        }
+    }
+
+    // Only hands out the rows the condition is true for
+    fn filtered(mut self, condition: Option<&'a Expr>, stats: &'a mut NodeStats) -> Self {
+        self.filter = condition.map(|condition| (condition, stats));
+        self
+    }
+
+    // Reads the next row into the buffer, returning its key
+    fn read_next(&mut self) -> Option<i64> {
+        match &mut self.source {
+            ScanSource::Cursor(cursor) => {
+                if self.read {
+                    cursor.advance();
+                }
+                self.read = true;
+                self.stats.pages = cursor.pages_read;
+                let key = cursor.key()?;
+                cursor.read_row_into(&mut self.data);
+                Some(key)
+            }
+            ScanSource::Keys(keys) => loop {
+                let key = keys.next()?;
+                if let Some(cursor) = find_key(&self.table, self.table_index, key) {
+                    self.stats.pages += cursor.pages_read;
+                    cursor.read_row_into(&mut self.data);
+                    return Some(key);
+                }
+            },
+        }
+    }
+
+    // The next row passing the filter, with its key
+    fn next_keyed(&mut self) -> Option<(i64, Row)> {
+        loop {
+            let key = self.read_next()?;
+            let Some(row) = RowRef::new(&self.data, self.schema) else {
+                self.table.borrow_mut().read_failed(ExecuteResult::Corrupt);
+                continue;
+            };
+            self.stats.produced(1, self.started);
+            if let Some((condition, stats)) = &mut self.filter {
+                if !row_matches(&row, self.schema, Some(condition)) {
+                    continue;
+                }
+                stats.produced(1, self.started);
+            }
+            return Some((key, row.to_row()));
+        }
+    }
+}
+
+impl Operator for Scan<'_> {
+    fn next(&mut self) -> Option<Row> {
+        self.next_keyed().map(|(_, row)| row)
+    }
+
+    // A cursor reading forwards takes the rows of a leaf together, the
+    // other sources still read a row at a time
+    fn next_batch(&mut self, batch: &mut Vec<Row>) {
+        while batch.len() < BATCH_SIZE {
+            let cursor = match &mut self.source {
+                ScanSource::Cursor(cursor) if !cursor.reverse => cursor,
+                _ => match self.next_keyed() {
+                    Some((_, row)) => {
+                        batch.push(row);
+                        continue;
+                    }
+                    None => return,
+                },
+            };
+            if std::mem::take(&mut self.read) {
+                cursor.advance();
+            }
+            self.data.clear();
+            self.ends.clear();
+            cursor.read_leaf(BATCH_SIZE - batch.len(), &mut self.data, &mut self.ends);
+            self.stats.pages = cursor.pages_read;
+            if self.ends.is_empty() {
+                return;
+            }
+
+            let (mut start, mut scanned, matched) = (0, 0, batch.len());
+            for &end in &self.ends {
+                let data = &self.data[start..end];
+                start = end;
+                let Some(row) = RowRef::new(data, self.schema) else {
+                    self.table.borrow_mut().read_failed(ExecuteResult::Corrupt);
+                    continue;
+                };
+                scanned += 1;
+                let condition = self.filter.as_ref().map(|(condition, _)| *condition);
+                if row_matches(&row, self.schema, condition) {
+                    batch.push(row.to_row());
+                }
+            }
+            self.stats.produced(scanned, self.started);
+            if let Some((_, stats)) = &mut self.filter {
+                stats.produced(batch.len() - matched, self.started);
+            }
+        }
+    }
+}
+
+// The single row, without columns, of a select without a table
+struct ConstantRow<'a> {
+    done: bool,
+    started: Instant,
+    stats: &'a mut NodeStats,
+}
+
+impl Operator for ConstantRow<'_> {
+    fn next(&mut self) -> Option<Row> {
+        if std::mem::replace(&mut self.done, true) {
+            return None;
+        }
+        self.stats.produced(1, self.started);
+        Some(Row { values: Vec::new() })
+    }
+}
+
+struct Filter<'a> {
+    input: Input<'a>,
+    condition: &'a Expr,
+    schema: &'a Schema,
+    started: Instant,
+    stats: &'a mut NodeStats,
+}
+
+impl Operator for Filter<'_> {
+    fn next(&mut self) -> Option<Row> {
+        loop {
+            let row = self.input.next()?;
+            if row_matches(&row, self.schema, Some(self.condition)) {
+                self.stats.produced(1, self.started);
+                return Some(row);
+            }
+        }
+    }
+
+    // Batches of the input are thinned out until one keeps a row
+    fn next_batch(&mut self, batch: &mut Vec<Row>) {
+        loop {
+            self.input.next_batch(batch);
+            let read = batch.len();
+            batch.retain(|row| row_matches(row, self.schema, Some(self.condition)));
+            self.stats.produced(batch.len(), self.started);
+            if read == 0 || !batch.is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+// How a join finds the right rows matching each left row
+#[derive(Clone, Copy)]
+pub enum JoinMethod {
+    NestedLoop,
+    // The input with fewer rows is hashed on its join column
+    Hash { build_left: bool },
+}
+
+// Hashing a row costs about as much as this many comparisons
+const HASH_COST: usize = 16;
+
+impl JoinMethod {
+    // Chosen on the row counts of the two tables. Nested loops compare every
+    // pair of rows, which only pays off while there are few of them.
+    fn new(left_rows: usize, right_rows: usize) -> Self {
+        let pairs = left_rows.saturating_mul(right_rows);
+        if pairs <= HASH_COST.saturating_mul(left_rows + right_rows) {
+            JoinMethod::NestedLoop
+        } else {
+            JoinMethod::Hash {
+                build_left: left_rows < right_rows,
+            }
+        }
+    }
+}
+
+// The right input is read into memory on the first row, and every left row
+// is compared against all of it. A left join keeps the left rows nothing
+// matched, with NULL for the right columns.
+struct NestedLoopJoin<'a> {
+    left: Input<'a>,
+    right: Input<'a>,
+    right_rows: Option<Vec<Row>>,
+    kind: JoinKind,
+    left_column: usize,
+    right_column: usize,
+    right_width: usize,
+    // Keys compare by the left column's collation
+    collation: Collation,
+    // The left row being joined, the position of the next right row to
+    // compare with it and whether any matched
+    current: Option<(Row, usize, bool)>,
+    started: Instant,
+    stats: &'a mut NodeStats,
+}
+
+impl Operator for NestedLoopJoin<'_> {
+    fn next(&mut self) -> Option<Row> {
+        let right = &mut self.right;
+        let right_rows = (self.right_rows).get_or_insert_with(|| read_all(right));
+        loop {
+            if self.current.is_none() {
+                self.current = Some((self.left.next()?, 0, false));
+            }
+            let (left, position, matched) = self.current.as_mut()?;
+            while let Some(right) = right_rows.get(*position) {
+                *position += 1;
+                let key = &left.values[self.left_column];
+                if compare_values(
+                    &right.values[self.right_column],
+                    CompareOp::Equal,
+                    key,
+                    self.collation,
+                ) {
+                    *matched = true;
+                    self.stats.produced(1, self.started);
+                    let values = left.values.iter().chain(&right.values).cloned().collect();
+                    return Some(Row { values });
+                }
+            }
+
+            let (left, _, matched) = self.current.take()?;
+            if !matched && matches!(self.kind, JoinKind::Left) {
+                let mut values = left.values;
+                values.resize(values.len() + self.right_width, Value::Null);
+                self.stats.produced(1, self.started);
+                return Some(Row { values });
+            }
+        }
+    }
+}
+
+// The column rows are sorted on, and how
+#[derive(Clone, Copy)]
+struct SortKey {
+    column: usize,
+    collation: Collation,
+    descending: bool,
+}
+
+impl SortKey {
+    fn compare(self, a: &Row, b: &Row) -> Ordering {
+        let ordering = a.compare(b, self.column, self.collation);
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+// Rows of the build input are hashed on their join column when the first
+// row is asked for, then each row of the probe input looks up the ones it
+// matches. The joined rows have the left columns first whichever input is
+// hashed. A left join keeps the left rows nothing matched, with NULL for
+// the right columns: as they are probed, or after every right row has been
+// when the left rows are the ones hashed.
+struct HashJoin<'a> {
+    build: Input<'a>,
+    probe: Input<'a>,
+    build_left: bool,
+    kind: JoinKind,
+    build_column: usize,
+    probe_column: usize,
+    right_width: usize,
+    // Keys compare by the left column's collation
+    collation: Collation,
+    built: Option<HashTable>,
+    // Joined rows not handed out yet, and whether the probe input is done
+    pending: std::vec::IntoIter<Row>,
+    probed: bool,
+    started: Instant,
+    stats: &'a mut NodeStats,
+}
+
+// The rows of a hash join's build input, with whether any probe row matched
+// each, and the positions of the rows under each key
+struct HashTable {
+    rows: Vec<(Row, bool)>,
+    positions: HashMap<Value, Vec<usize>>,
+}
+
+impl HashJoin<'_> {
+    fn build(&mut self) -> HashTable {
+        let rows = read_all(&mut self.build);
+        let mut positions: HashMap<Value, Vec<usize>> = HashMap::new();
+        for (position, row) in rows.iter().enumerate() {
+            // NULL matches nothing
+            let key = &row.values[self.build_column];
+            if !key.is_null() {
+                let key = self.collation.key(key.clone());
+                positions.entry(key).or_default().push(position);
+            }
+        }
+        let rows = rows.into_iter().map(|row| (row, false)).collect();
+        HashTable { rows, positions }
+    }
+
+    // The rows the probe row joins into
+    fn probe_row(&mut self, row: Row) -> Vec<Row> {
+        let Some(HashTable { rows, positions }) = &mut self.built else {
+            return Vec::new();
+        };
+        let key = &row.values[self.probe_column];
+        let matches = match key.is_null() {
+            true => None,
+            false => positions.get(&self.collation.key(key.clone())),
+        };
+        let mut joined = Vec::new();
+        for &position in matches.into_iter().flatten() {
+            let (built_row, matched) = &mut rows[position];
+            *matched = true;
+            let (left, right) = match self.build_left {
+                true => (&*built_row, &row),
+                false => (&row, &*built_row),
+            };
+            let values = left.values.iter().chain(&right.values).cloned().collect();
+            joined.push(Row { values });
+        }
+        if joined.is_empty() && !self.build_left && matches!(self.kind, JoinKind::Left) {
+            joined.push(self.unmatched(row));
+        }
+        joined
+    }
+
+    // The left row with NULL for the right columns
+    fn unmatched(&self, row: Row) -> Row {
+        let mut values = row.values;
+        values.resize(values.len() + self.right_width, Value::Null);
+        Row { values }
+    }
+}
+
+impl Operator for HashJoin<'_> {
+    fn next(&mut self) -> Option<Row> {
+        if self.built.is_none() {
+            self.built = Some(self.build());
+        }
+        loop {
+            if let Some(row) = self.pending.next() {
+                self.stats.produced(1, self.started);
+                return Some(row);
+            }
+            if self.probed {
+                return None;
+            }
+            let joined = match self.probe.next() {
+                Some(row) => self.probe_row(row),
+                None => {
+                    self.probed = true;
+                    match (self.build_left, &self.kind, self.built.take()) {
+                        (true, JoinKind::Left, Some(built)) => (built.rows.into_iter())
+                            .filter(|(_, matched)| !matched)
+                            .map(|(row, _)| self.unmatched(row))
+                            .collect(),
+                        _ => Vec::new(),
+                    }
+                }
+            };
+            self.pending = joined.into_iter();
+        }
+    }
+}
+
+// Reads every row of its input, then hands them out in order. Rows are
+// sorted in memory up to its budget. Past it, each time the budget fills
+// the rows are sorted and written out as a run to a temporary file, and
+// the runs are merged as the rows are handed out. Only MERGE_FAN_IN runs
+// are merged at a time, so more than that are first merged in groups into
+// longer runs, over as many passes as it takes. A temporary file that
+// can't be written or read back fails the statement.
+struct Sort<'a> {
+    input: Input<'a>,
+    table: TableRef,
+    schema: &'a Schema,
+    key: SortKey,
+    // Bytes of rows sorted in memory at a time
+    memory: usize,
+    // The runs being merged into the rows handed out
+    merge: Option<Merge>,
+    started: Instant,
+    stats: &'a mut NodeStats,
+}
+
+// Sorted rows, handed out one at a time while the runs are merged
+enum Run {
+    Memory(std::vec::IntoIter<Row>),
+    // Each row is its length in four bytes followed by the row serialized
+    File(io::BufReader<File>),
+}
+
+impl Run {
+    // Writes the rows out to a temporary file in the order they come
+    fn write(rows: impl IntoIterator<Item = io::Result<Row>>, schema: &Schema) -> io::Result<Self> {
+        let mut file = io::BufWriter::new(spill_file()?);
+        for row in rows {
+            let data = row?.serialize_row(schema);
+            file.write_all(&(data.len() as u32).to_le_bytes())?;
+            file.write_all(&data)?;
+        }
+        let mut file = file.into_inner().map_err(io::IntoInnerError::into_error)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Run::File(io::BufReader::new(file)))
+    }
+
+    // None once the run is over
+    fn next_row(&mut self, schema: &Schema) -> io::Result<Option<Row>> {
+        let file = match self {
+            Run::Memory(rows) => return Ok(rows.next()),
+            Run::File(file) => file,
+        };
+        let mut length = [0; 4];
+        match file.read_exact(&mut length) {
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let mut data = vec![0; u32::from_le_bytes(length) as usize];
+        file.read_exact(&mut data)?;
+        match Row::deserialize_row(&data, schema) {
+            Some(row) => Ok(Some(row)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "a sorted row read back is malformed",
+            )),
+        }
+    }
+}
+
+// The runs being merged, with the next row of each on a heap that hands
+// out the least of them
+#[derive(Default)]
+struct Merge {
+    runs: Vec<Run>,
+    heads: BinaryHeap<Head>,
+}
+
+// The next row of a run being merged
+struct Head {
+    row: Row,
+    run: usize,
+    key: SortKey,
+}
+
+// Reversed, the heap hands out its greatest. Runs hold the rows read before
+// the rows of the runs after them, so equal rows come out in the order of
+// their runs and the sort is stable.
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.key.compare(&other.row, &self.row)).then(other.run.cmp(&self.run))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl Merge {
+    fn new(mut runs: Vec<Run>, schema: &Schema, key: SortKey) -> io::Result<Self> {
+        let mut heads = BinaryHeap::with_capacity(runs.len());
+        for (run, rows) in runs.iter_mut().enumerate() {
+            if let Some(row) = rows.next_row(schema)? {
+                heads.push(Head { row, run, key });
+            }
+        }
+        Ok(Self { runs, heads })
+    }
+
+    fn next_row(&mut self, schema: &Schema) -> io::Result<Option<Row>> {
+        let Some(mut head) = self.heads.pop() else {
+            return Ok(None);
+        };
+        if let Some(row) = self.runs[head.run].next_row(schema)? {
+            let row = std::mem::replace(&mut head.row, row);
+            self.heads.push(head);
+            return Ok(Some(row));
+        }
+        Ok(Some(head.row))
+    }
+}
+
+// A temporary file for rows a sort writes out. It is removed as soon as it
+// is open, so it goes away with its handle.
+fn spill_file() -> io::Result<File> {
+    static SPILLED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let count = SPILLED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("rustlite-sort-{}-{count}", std::process::id()));
+    let file = (OpenOptions::new())
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    let _ = std::fs::remove_file(&path);
+    Ok(file)
+}
+
+// Roughly the memory the row takes
+fn row_size(row: &Row) -> usize {
+    let values = row.values.iter().map(|value| match value {
+        Value::Text(text) => size_of::<Value>() + text.len(),
+        Value::Blob(blob) => size_of::<Value>() + blob.len(),
+        _ => size_of::<Value>(),
+    });
+    size_of::<Row>() + values.sum::<usize>()
+}
+
+impl<'a> Sort<'a> {
+    fn new(
+        input: Input<'a>,
+        table: TableRef,
+        schema: &'a Schema,
+        order_by: &OrderBy,
+        memory: usize,
+        started: Instant,
+        stats: &'a mut NodeStats,
+    ) -> Self {
+        let column = schema.column_index(&order_by.column).unwrap_or_default();
+        Self {
+            input,
+            table,
+            schema,
+            key: SortKey {
+                column,
+                collation: (order_by.collation).unwrap_or(schema.columns[column].collation),
+                descending: order_by.descending,
+            },
+            memory,
+            merge: None,
+            started,
+            stats,
+        }
+    }
+
+    // Reads the input into sorted runs, the last of them kept in memory,
+    // and starts merging them
+    fn sort(&mut self) -> io::Result<Merge> {
+        let key = self.key;
+        let mut runs = Vec::new();
+        let (mut rows, mut size, mut sorted) = (Vec::new(), 0, 0);
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        loop {
+            self.input.next_batch(&mut batch);
+            if batch.is_empty() {
+                break;
+            }
+            sorted += batch.len();
+            size += batch.iter().map(row_size).sum::<usize>();
+            rows.append(&mut batch);
+            if size > self.memory {
+                rows.sort_by(|a, b| key.compare(a, b));
+                runs.push(Run::write(rows.drain(..).map(Ok), self.schema)?);
+                self.stats.runs += 1;
+                size = 0;
+            }
+        }
+        rows.sort_by(|a, b| key.compare(a, b));
+        runs.push(Run::Memory(rows.into_iter()));
+        self.stats.produced(sorted, self.started);
+
+        // Neighbouring runs are merged so the runs keep the order of their
+        // rows
+        while runs.len() > MERGE_FAN_IN {
+            let mut merged = Vec::new();
+            let mut runs_left = runs.into_iter().peekable();
+            while runs_left.peek().is_some() {
+                let group: Vec<Run> = runs_left.by_ref().take(MERGE_FAN_IN).collect();
+                if group.len() == 1 {
+                    merged.extend(group);
+                    continue;
+                }
+                let mut merge = Merge::new(group, self.schema, key)?;
+                let rows = std::iter::from_fn(|| merge.next_row(self.schema).transpose());
+                merged.push(Run::write(rows, self.schema)?);
+                self.stats.runs += 1;
+            }
+            runs = merged;
+        }
+        Merge::new(runs, self.schema, key)
+    }
+}
+
+impl Operator for Sort<'_> {
+    fn next(&mut self) -> Option<Row> {
+        let merge = match &mut self.merge {
+            Some(merge) => merge,
+            None => match self.sort() {
+                Ok(merge) => self.merge.insert(merge),
+                Err(error) => {
+                    self.merge = Some(Merge::default());
+                    self.table.borrow_mut().read_failed(error.into());
+                    return None;
+                }
+            },
+        };
+        match merge.next_row(self.schema) {
+            Ok(row) => row,
+            Err(error) => {
+                self.merge = Some(Merge::default());
+                self.table.borrow_mut().read_failed(error.into());
+                None
+            }
+        }
+    }
+}
+
+struct Limit<'a> {
+    input: Input<'a>,
+    // Rows still to be skipped, and handed out
+    offset: usize,
+    remaining: usize,
+}
+
+impl Operator for Limit<'_> {
+    fn next(&mut self) -> Option<Row> {
+        while self.offset > 0 {
+            self.offset -= 1;
+            self.input.next()?;
+        }
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        self.input.next()
+    }
+}
+
+struct Project<'a> {
+    input: Input<'a>,
+    schema: &'a Schema,
+    select_list: &'a [SelectItem],
+}
+
+impl Operator for Project<'_> {
+    fn next(&mut self) -> Option<Row> {
+        let row = self.input.next()?;
+        Some(Row {
+            values: row_values(&row, self.schema, self.select_list),
+        })
+    }
+
+    fn next_batch(&mut self, batch: &mut Vec<Row>) {
+        self.input.next_batch(batch);
+        for row in batch.iter_mut() {
+            row.values = row_values(row, self.schema, self.select_list);
+        }
+    }
+}
+
+// Where an aggregation takes its rows from
+enum AggregateSource<'a> {
+    Rows(Input<'a>),
+    // Only the number of rows, which the catalog knows without reading any
+    RowCount(usize),
+}
+
+// Reads every row of its input into groups, then hands out a row of the
+// select list's results for each group that passes the HAVING clause, in
+// the order of the groups
+struct Aggregation<'a> {
+    source: AggregateSource<'a>,
+    select: &'a SelectStmt,
+    schema: &'a Schema,
+    groups: Option<std::vec::IntoIter<Group<'a>>>,
+    started: Instant,
+    stats: &'a mut NodeStats,
+    having_stats: &'a mut NodeStats,
+}
+
+impl<'a> Aggregation<'a> {
+    fn new(
+        source: AggregateSource<'a>,
+        select: &'a SelectStmt,
+        schema: &'a Schema,
+        started: Instant,
+        stats: &'a mut NodeStats,
+        having_stats: &'a mut NodeStats,
+    ) -> Self {
+        Self {
+            source,
+            select,
+            schema,
+            groups: None,
+            started,
+            stats,
+            having_stats,
+        }
+    }
+
+    fn group(&mut self) -> Vec<Group<'a>> {
+        let select = self.select;
+        let mut aggregates: Vec<&Aggregate> = select
+            .select_list
+            .iter()
+            .filter_map(|item| match item {
+                SelectItem::Aggregate(aggregate) => Some(aggregate),
+                SelectItem::Expr(_) => None,
+            })
+            .collect();
+        if let Some(having) = &select.having {
+            aggregates.push(&having.aggregate);
+        }
+
+        // Groups are hashed on the value of the GROUP BY column. Without one
+        // there is a single group, which exists even when no row matches.
+        let mut groups: HashMap<Option<Value>, Group> = HashMap::new();
+        if select.group_by.is_none() {
+            groups.insert(None, Group::new(&aggregates));
+        }
+        match &mut self.source {
+            AggregateSource::RowCount(num_rows) => {
+                for accumulator in groups.get_mut(&None).unwrap().accumulators.iter_mut() {
+                    accumulator.count = *num_rows;
+                }
+            }
+            AggregateSource::Rows(input) => {
+                let mut batch = Vec::with_capacity(BATCH_SIZE);
+                loop {
+                    input.next_batch(&mut batch);
+                    if batch.is_empty() {
+                        break;
+                    }
+                    for row in batch.drain(..) {
+                        // Values equal under the column's collation share a
+                        // group
+                        let key = select.group_by.as_ref().map(|column| {
+                            self.schema
+                                .collation(column)
+                                .key(row.get(self.schema, column))
+                        });
+                        groups
+                            .entry(key)
+                            .or_insert_with(|| Group::new(&aggregates))
+                            .add(row, self.schema);
+                    }
+                }
+            }
+        }
+
+        // Groups come out ordered by their key
+        let mut groups: Vec<(Option<Value>, Group)> = groups.into_iter().collect();
+        groups.sort_by(|(a, _), (b, _)| a.cmp(b));
+        if select.order_by.as_ref().is_some_and(|o| o.descending) {
+            groups.reverse();
+        }
+        self.stats.produced(groups.len(), self.started);
+        groups.into_iter().map(|(_, group)| group).collect()
+    }
+}
+
+impl Operator for Aggregation<'_> {
+    fn next(&mut self) -> Option<Row> {
+        if self.groups.is_none() {
+            let groups = self.group();
+            self.groups = Some(groups.into_iter());
+        }
+        loop {
+            let group = self.groups.as_mut()?.next()?;
+            let passes = match &self.select.having {
+                Some(having) => group.accumulators.last().is_some_and(|result| {
+                    compare_values(
+                        &result.result(),
+                        having.op,
+                        &having.value,
+                        Collation::Binary,
+                    )
+                }),
+                None => true,
+            };
+            if !passes {
+                continue;
+            }
+            self.having_stats.produced(1, self.started);
+
+            let mut accumulators = group.accumulators.iter();
+            let values = (self.select.select_list.iter())
+                .map(|item| match item {
+                    SelectItem::Expr(expr) => group
+                        .first_row
+                        .as_ref()
+                        .map_or(Value::Null, |row| evaluate(expr, row, self.schema)),
+                    SelectItem::Aggregate(_) => {
+                        accumulators.next().map_or(Value::Null, Accumulator::result)
+                    }
+                })
+                .collect();
+            return Some(Row { values });
+        }
+    }
+}
+
+// Running state of one aggregate over the rows of a group
+struct Accumulator<'a> {
+    aggregate: &'a Aggregate,
+    // Rows seen, and how many of them had a value that wasn't NULL
+    count: usize,
+    values: usize,
+    sum: Value,
+    // Smallest or largest value seen so far for min/max
+    extreme: Option<Value>,
+}
+
+impl<'a> Accumulator<'a> {
+    fn new(aggregate: &'a Aggregate) -> Self {
+        Self {
+            aggregate,
+            count: 0,
+            values: 0,
+            sum: Value::Integer(0),
+            extreme: None,
+        }
+    }
+
+    fn add(&mut self, row: &impl Columns, schema: &Schema) {
+        self.count += 1;
+        let Some(column) = self.aggregate.column() else {
+            return;
+        };
+        // Aggregates skip NULL values
+        let value = row.column(schema, column);
+        if value.is_null() {
+            return;
+        }
+        self.values += 1;
+
+        match self.aggregate {
+            Aggregate::CountStar => {}
+            Aggregate::Sum(_) | Aggregate::Avg(_) => {
+                let value = value.to_number();
+                // Integer sums stay integers until they would overflow
+                self.sum = match (self.sum.as_integer(), value.as_integer()) {
+                    (Some(a), Some(b)) => a
+                        .checked_add(b)
+                        .map_or(Value::Real(a as f64 + b as f64), Value::Integer),
+                    _ => Value::Real(self.sum.as_real() + value.as_real()),
+                };
+            }
+            Aggregate::Min(_) => {
+                if self.extreme.as_ref().is_none_or(|min| value < *min) {
+                    self.extreme = Some(value);
+                }
+            }
+            Aggregate::Max(_) => {
+                if self.extreme.as_ref().is_none_or(|max| value > *max) {
+                    self.extreme = Some(value);
+                }
+            }
+        }
+    }
+
+    // Aggregates over no values are NULL, except for count
+    fn result(&self) -> Value {
+        match self.aggregate {
+            Aggregate::CountStar => Value::Integer(self.count as i64),
+            _ if self.values == 0 => Value::Null,
+            Aggregate::Sum(_) => self.sum.clone(),
+            Aggregate::Avg(_) => Value::Real(self.sum.as_real() / self.values as f64),
+            Aggregate::Min(_) | Aggregate::Max(_) => self.extreme.clone().unwrap_or(Value::Null),
+        }
+    }
+}
+
+struct Group<'a> {
+    // Source of the plain columns in the select list
+    first_row: Option<Row>,
+    // One per aggregate in the select list, followed by the HAVING aggregate
+    accumulators: Vec<Accumulator<'a>>,
+}
+
+impl<'a> Group<'a> {
+    fn new(aggregates: &[&'a Aggregate]) -> Self {
+        Self {
+            first_row: None,
+            accumulators: aggregates.iter().map(|a| Accumulator::new(a)).collect(),
+        }
+    }
+
+    fn add(&mut self, row: Row, schema: &Schema) {
+        for accumulator in self.accumulators.iter_mut() {
+            accumulator.add(&row, schema);
+        }
+        if self.first_row.is_none() {
+            self.first_row = Some(row);
+        }
+    }
+}
+
+// Whether the statement only needs the table's row count
+fn is_count_only(select: &SelectStmt) -> bool {
+    select.is_aggregate()
+        && !select.constant_row
+        && select.join.is_none()
+        && select.group_by.is_none()
+        && select.where_clause.is_none()
+        && select
+            .select_list
+            .iter()
+            .all(|item| matches!(item, SelectItem::Aggregate(Aggregate::CountStar)))
+        && select
+            .having
+            .as_ref()
+            .is_none_or(|having| matches!(having.aggregate, Aggregate::CountStar))
+}
+
+// A single select, without any UNION attached to it, run as a tree of
+// operators: the rows of its table, of the two tables it joins or the
+// constant row, then its filter, its aggregation or its sort, its limit
+// and its select list
+fn execute_query(
+    table: TableRef,
+    select: &SelectStmt,
+    out: &mut RowSink,
+    profile: &mut Profile,
+) -> ExecuteResult {
+    let joined = select.join.as_ref().map(|join| &join.right_table);
+    let mut tables = Vec::new();
+    if !select.constant_row {
+        for name in std::iter::once(&select.table_name).chain(joined) {
+            let Some(table_index) = resolve_table(&table, name) else {
+                return ExecuteResult::NoSuchTable;
+            };
+            tables.push((table_index, table_schema(&table, table_index)));
+        }
+    }
+    // The columns of the rows the select reads: none for the constant row,
+    // the table's, or the left table's followed by the right one's, named
+    // after their table
+    let schema = match tables.as_slice() {
+        [(_, schema)] => schema.clone(),
+        tables => Schema::with_columns(
+            (tables
+                .iter()
+                .zip(std::iter::once(&select.table_name).chain(joined)))
+            .flat_map(|((_, schema), name)| {
+                schema.columns.iter().map(move |column| ColumnDef {
+                    name: format!("{}.{}", name, column.name),
+                    ..column.clone()
+                })
+            })
+            .collect(),
+        ),
+    };
+    if !has_columns(&schema, select) {
+        return ExecuteResult::NoSuchColumn;
+    }
+
+    let started = profile.started;
+    let QueryStats {
+        scan,
+        right_scan,
+        filter,
+        join,
+        aggregate,
+        having,
+        sort,
+        output,
+    } = profile.next_query();
+    let planned = (tables.len() == 1).then(|| ScanPlan::new(&schema, select));
+    let mut input: Input = match (tables.as_slice(), &select.join) {
+        ([(table_index, _)], _) if is_count_only(select) => {
+            let num_rows = table.borrow().tables[*table_index].num_rows;
+            scan.produced(1, started);
+            let source = AggregateSource::RowCount(num_rows);
+            let aggregation = Aggregation::new(source, select, &schema, started, aggregate, having);
+            return output_rows(Box::new(aggregation), select, &schema, out, started, output);
+        }
+        ([(table_index, _)], _) => {
+            let planned = planned.as_ref().unwrap();
+            let keys = access_keys(&table, &schema, &planned.access);
+            let scan = Scan::new(
+                &table,
+                *table_index,
+                &schema,
+                keys,
+                planned.descending,
+                started,
+                scan,
+            );
+            Box::new(scan.filtered(select.where_clause.as_ref(), filter))
+        }
+        ([(left_index, left_schema), (right_index, right_schema)], Some(on)) => {
+            let (Some(left_column), Some(right_column)) = (
+                left_schema.column_index(&on.left_column),
+                right_schema.column_index(&on.right_column),
+            ) else {
+                return ExecuteResult::NoSuchColumn;
+            };
+            let left = Scan::new(
+                &table,
+                *left_index,
+                left_schema,
+                Keys::All,
+                false,
+                started,
+                scan,
+            );
+            let right = Scan::new(
+                &table,
+                *right_index,
+                right_schema,
+                Keys::All,
+                false,
+                started,
+                right_scan,
+            );
+            let (left_rows, right_rows) = {
+                let catalog = table.borrow();
+                let tables = &catalog.tables;
+                (tables[*left_index].num_rows, tables[*right_index].num_rows)
+            };
+            let right_width = right_schema.columns.len();
+            let collation = left_schema.columns[left_column].collation;
+            let joined: Input = match JoinMethod::new(left_rows, right_rows) {
+                JoinMethod::NestedLoop => Box::new(NestedLoopJoin {
+                    left: Box::new(left),
+                    right: Box::new(right),
+                    right_rows: None,
+                    kind: on.kind.clone(),
+                    left_column,
+                    right_column,
+                    right_width,
+                    collation,
+                    current: None,
+                    started,
+                    stats: join,
+                }),
+                JoinMethod::Hash { build_left } => {
+                    let (build, probe, build_column, probe_column): (Input, Input, _, _) =
+                        match build_left {
+                            true => (Box::new(left), Box::new(right), left_column, right_column),
+                            false => (Box::new(right), Box::new(left), right_column, left_column),
+                        };
+                    Box::new(HashJoin {
+                        build,
+                        probe,
+                        build_left,
+                        kind: on.kind.clone(),
+                        build_column,
+                        probe_column,
+                        right_width,
+                        collation,
+                        built: None,
+                        pending: Vec::new().into_iter(),
+                        probed: false,
+                        started,
+                        stats: join,
+                    })
+                }
+            };
+            match &select.where_clause {
+                Some(condition) => Box::new(Filter {
+                    input: joined,
+                    condition,
+                    schema: &schema,
+                    started,
+                    stats: filter,
+                }),
+                None => joined,
+            }
+        }
+        _ => {
+            let row = Box::new(ConstantRow {
+                done: false,
+                started,
+                stats: scan,
+            });
+            match &select.where_clause {
+                Some(condition) => Box::new(Filter {
+                    input: row,
+                    condition,
+                    schema: &schema,
+                    started,
+                    stats: filter,
+                }),
+                None => row,
+            }
+        }
+    };
+
+    if select.is_aggregate() {
+        let source = AggregateSource::Rows(input);
+        input = Box::new(Aggregation::new(
+            source, select, &schema, started, aggregate, having,
+        ));
+    } else {
+        let order_by = match &planned {
+            Some(planned) => planned.sort,
+            None => select.order_by.as_ref(),
+        };
+        if let Some(order_by) = order_by {
+            let memory = table.borrow().sort_memory;
+            input = Box::new(Sort::new(
+                input,
+                Rc::clone(&table),
+                &schema,
+                order_by,
+                memory,
+                started,
+                sort,
+            ));
+        }
+    }
+    output_rows(input, select, &schema, out, started, output)
+}
+
+// Hands the rows of the query to out, within its limit and projected onto
+// its select list
+fn output_rows<'a>(
+    mut input: Input<'a>,
+    select: &'a SelectStmt,
+    schema: &'a Schema,
+    out: &mut RowSink,
+    started: Instant,
+    stats: &mut NodeStats,
+) -> ExecuteResult {
+    if select.limit.is_some() || select.offset > 0 {
+        input = Box::new(Limit {
+            input,
+            offset: select.offset,
+            remaining: select.limit.unwrap_or(usize::MAX),
+        });
+    }
+    // Aggregations already hand out the select list's results
+    if !select.select_list.is_empty() && !select.is_aggregate() {
+        input = Box::new(Project {
+            input,
+            schema,
+            select_list: &select.select_list,
+        });
+    }
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    loop {
+        input.next_batch(&mut batch);
+        if batch.is_empty() {
+            return ExecuteResult::Success;
+        }
+        let rows = batch.len();
+        for row in batch.drain(..) {
+            out(row.values);
+        }
+        stats.produced(rows, started);
+    }
+}
+
+// Number of values in each result row of a single select, None when one of
+// its tables doesn't exist
+fn column_count(table: &TableRef, select: &SelectStmt) -> Option<usize> {
+    if !select.select_list.is_empty() {
+        return Some(select.select_list.len());
+    }
+
+    let width = |name: &str| {
+        let table_index = resolve_table(table, name)?;
+        Some(table.borrow().tables[table_index].schema.columns.len())
+    };
+    let mut count = width(&select.table_name)?;
+    if let Some(join) = &select.join {
+        count += width(&join.right_table)?;
+    }
+    Some(count)
+}
+
+// Runs both sides of every UNION and merges their rows. Plain UNION drops
+// duplicates from everything produced up to that point.
+fn execute_union(
+    table: TableRef,
+    select: &SelectStmt,
+    out: &mut RowSink,
+    profile: &mut Profile,
+) -> ExecuteResult {
+    if select.union.is_none() {
+        return execute_query(table, select, out, profile);
+    }
+
+    let Some(width) = column_count(&table, select) else {
+        return ExecuteResult::NoSuchTable;
+    };
+    let mut rows = Vec::new();
+    let result = execute_query(
+        Rc::clone(&table),
+        select,
+        &mut |values| rows.push(values),
+        profile,
+    );
+    if !matches!(result, ExecuteResult::Success) {
+        return result;
+    }
+
+    let mut next = &select.union;
+    while let Some(union) = next {
+        match column_count(&table, &union.select) {
+            None => return ExecuteResult::NoSuchTable,
+            Some(count) if count != width => return ExecuteResult::ColumnCountMismatch,
+            Some(_) => {}
+        }
+        let result = execute_query(
+            Rc::clone(&table),
+            &union.select,
+            &mut |values| rows.push(values),
+            profile,
+        );
+        if !matches!(result, ExecuteResult::Success) {
+            return result;
+        }
+
+        if !union.all {
+            let mut seen = HashSet::new();
+            rows.retain(|values| seen.insert(values.clone()));
+        }
+        let started = profile.started;
+        let mut stats = NodeStats::default();
+        stats.produced(rows.len(), started);
+        profile.unions.push(stats);
+        next = &union.select.union;
+    }
+
+    for values in rows {
+        out(values);
+    }
+
+    ExecuteResult::Success
+}
+
+// Materializes every common table into a temporary table named after it,
+// runs the select and drops the temporary tables again
+fn execute_with(
+    table: TableRef,
+    select: &SelectStmt,
+    out: &mut RowSink,
+    profile: &mut Profile,
+) -> ExecuteResult {
+    let stored_tables = table.borrow().tables.len();
+    let mut result = ExecuteResult::Success;
+    for common in &select.with {
+        result = materialize(&table, common, profile);
+        if !matches!(result, ExecuteResult::Success) {
+            break;
+        }
+    }
+    if matches!(result, ExecuteResult::Success) {
+        result = execute_union(Rc::clone(&table), select, out, profile);
+    }
+
+    table.borrow_mut().drop_tables_from(stored_tables);
+    result
+}
+
+fn materialize(table: &TableRef, common: &CommonTable, profile: &mut Profile) -> ExecuteResult {
+    let mut rows = Vec::new();
+    let result = execute_union(
+        Rc::clone(table),
+        &common.select,
+        &mut |values| rows.push(values),
+        profile,
+    );
+    if !matches!(result, ExecuteResult::Success) {
+        return result;
+    }
+
+    let schema = match common_table_schema(table, common, &rows) {
+        Ok(schema) => schema,
+        Err(result) => return result,
+    };
+    let result = table.borrow_mut().add_table(&common.name, schema.clone());
+    if !matches!(result, ExecuteResult::Success) {
+        return result;
+    }
+
+    let table_index = table.borrow().tables.len() - 1;
+    let mut stats = NodeStats::default();
+    for values in rows {
+        let values: Result<Vec<Value>, ExecuteResult> = (values.into_iter())
+            .zip(&schema.columns)
+            .map(|(value, column)| column.ctype.convert(value))
+            .collect();
+        let row = match values {
+            Ok(values) => Row { values },
+            Err(result) => return result,
+        };
+        if !append_row(table, table_index, &row.serialize_row(&schema)) {
+            return ExecuteResult::TableFull;
+        }
+        stats.produced(1, profile.started);
+    }
+    profile.materialized.push(stats);
+
+    ExecuteResult::Success
+}
+
+// Columns named after the select's columns unless the common table names
+// them, each typed after the values the select produced for it
+fn common_table_schema(
+    table: &TableRef,
+    common: &CommonTable,
+    rows: &[Vec<Value>],
+) -> Result<Schema, ExecuteResult> {
+    let select = &common.select;
+    let mut names: Vec<String> = Vec::new();
+    if select.select_list.is_empty() {
+        let joined = select.join.as_ref().map(|join| &join.right_table);
+        for name in std::iter::once(&select.table_name).chain(joined) {
+            let Some(table_index) = resolve_table(table, name) else {
+                return Err(ExecuteResult::NoSuchTable);
+            };
+            let schema = table_schema(table, table_index);
+            names.extend(schema.columns.into_iter().map(|column| column.name));
+        }
+    } else {
+        names.extend(item_names(select));
+    }
+    if let Some(columns) = &common.columns {
+        if columns.len() != names.len() {
+            return Err(ExecuteResult::ColumnCountMismatch);
+        }
+        names.clone_from(columns);
+    }
+
+    let columns = names
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut name)| {
+            // Names taken from expressions are cut to what the schema holds
+            while name.len() > COLUMN_NAME_SIZE {
+                name.pop();
+            }
+            let ctype = value_type(rows.iter().map(|values| &values[index]));
+            ColumnDef::new(&name, ctype)
+        })
+        .collect();
+    Ok(Schema {
+        columns,
+        checks: Vec::new(),
+        triggers: Vec::new(),
+        indexes: Vec::new(),
+    })
+}
+
+// The name each item of the select list was given, or the column it is
+// without its table, or the item as written
+fn item_names(select: &SelectStmt) -> Vec<String> {
+    let names = select.select_list.iter().map(|item| match item {
+        SelectItem::Expr(Expr::Column(name)) => name
+            .split_once('.')
+            .map_or(name.as_str(), |(_, column)| column)
+            .to_string(),
+        SelectItem::Expr(expr) => expr.to_string(),
+        SelectItem::Aggregate(aggregate) => aggregate.to_string(),
+    });
+    (names.zip(&select.aliases))
+        .map(|(name, alias)| alias.clone().unwrap_or(name))
+        .collect()
+}
+
+// Names of the columns of the rows the statement hands out, none when it
+// hands out no rows
+pub fn column_names(table: &Table, statement: &Statement) -> Vec<String> {
+    let (table_name, returning) = match statement {
+        Statement::Select(select) => return select_names(table, select, &select.with),
+        Statement::Insert(insert) => (&insert.table_name, &insert.returning),
+        Statement::Delete(delete) => (&delete.table_name, &delete.returning),
+        _ => return Vec::new(),
+    };
+    match returning {
+        Some(returning) if returning.is_empty() => table_column_names(table, table_name),
+        Some(returning) => returning.iter().map(Expr::to_string).collect(),
+        None => Vec::new(),
+    }
+}
+
+// The select list, or the columns of the tables when the select reads
+// whole rows. A common table can read the ones named before it.
+fn select_names(table: &Table, select: &SelectStmt, with: &[CommonTable]) -> Vec<String> {
+    if !select.select_list.is_empty() {
+        return item_names(select);
+    }
+    let joined = select.join.as_ref().map(|join| &join.right_table);
+    let mut names = Vec::new();
+    for name in std::iter::once(&select.table_name).chain(joined) {
+        match with.iter().rposition(|common| &common.name == name) {
+            Some(position) => {
+                let common = &with[position];
+                names.extend(match &common.columns {
+                    Some(columns) => columns.clone(),
+                    None => select_names(table, &common.select, &with[..position]),
+                });
+            }
+            None => names.extend(table_column_names(table, name)),
+        }
+    }
+    names
+}
+
+fn table_column_names(table: &Table, name: &str) -> Vec<String> {
+    (table.schema(name).into_iter())
+        .flat_map(|schema| schema.columns.iter().map(|column| column.name.clone()))
+        .collect()
+}
+
+// A type every one of the values can be stored as: their own when they
+// agree, real for a mix of integers and reals and text otherwise. Text and
+// blobs are sized to the longest value.
+fn value_type<'v>(values: impl Iterator<Item = &'v Value>) -> ColumnType {
+    let mut ctype = None;
+    let mut size = 0;
+    for value in values {
+        let value_type = match value {
+            Value::Null => continue,
+            Value::Integer(_) => ColumnType::Integer,
+            Value::Real(_) => ColumnType::Real,
+            Value::Boolean(_) => ColumnType::Boolean,
+            Value::Date(_) => ColumnType::Date,
+            Value::Timestamp(_) => ColumnType::Timestamp,
+            Value::Text(_) => ColumnType::Text(0),
+            Value::Blob(_) => ColumnType::Blob(0),
+        };
+        size = size.max(match value {
+            Value::Blob(bytes) => bytes.len(),
+            value => value.to_string().len(),
+        });
+        ctype = match ctype {
+            None => Some(value_type),
+            Some(ctype) if ctype == value_type => Some(ctype),
+            Some(ColumnType::Integer | ColumnType::Real)
+                if matches!(value_type, ColumnType::Integer | ColumnType::Real) =>
+            {
+                Some(ColumnType::Real)
+            }
+            Some(_) => Some(ColumnType::Text(0)),
+        };
+    }
+
+    match ctype.unwrap_or(ColumnType::Integer) {
+        ColumnType::Text(_) => ColumnType::Text(size),
+        ColumnType::Blob(_) => ColumnType::Blob(size),
+        ctype => ctype,
+    }
+}
+
+// What a plan node did while the statement ran under explain analyze
+#[derive(Default, Clone, Copy)]
+pub struct NodeStats {
+    rows: usize,
+    // Pages the node's cursor went over, only scans read pages
+    pages: usize,
+    // Sorted runs a sort wrote to temporary files
+    runs: usize,
+    // From the start of the statement until the node's last row came out
+    time: Duration,
+}
+
+impl NodeStats {
+    fn produced(&mut self, rows: usize, started: Instant) {
+        self.rows += rows;
+        self.time = started.elapsed();
+    }
+}
+
+// Stats of the nodes of a single select, named after the node they belong
+// to. The limit and project nodes share the rows handed out.
+#[derive(Default)]
+pub struct QueryStats {
+    scan: NodeStats,
+    right_scan: NodeStats,
+    filter: NodeStats,
+    join: NodeStats,
+    aggregate: NodeStats,
+    having: NodeStats,
+    sort: NodeStats,
+    output: NodeStats,
+}
+
+// Collected while running a statement, one QueryStats per select in the
+// order they ran, one NodeStats per UNION and one per common table
+pub struct Profile {
+    started: Instant,
+    queries: Vec<QueryStats>,
+    unions: Vec<NodeStats>,
+    materialized: Vec<NodeStats>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            queries: Vec::new(),
+            unions: Vec::new(),
+            materialized: Vec::new(),
+        }
+    }
+
+    fn next_query(&mut self) -> &mut QueryStats {
+        self.queries.push(QueryStats::default());
+        self.queries.last_mut().unwrap()
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Hands out the stats of a profile in the order they were collected, none
+// when there is no profile
+struct ProfileStats<'p> {
+    queries: std::slice::Iter<'p, QueryStats>,
+    unions: std::slice::Iter<'p, NodeStats>,
+    materialized: std::slice::Iter<'p, NodeStats>,
+}
+
+impl<'p> ProfileStats<'p> {
+    fn new(profile: Option<&'p Profile>) -> Self {
+        match profile {
+            Some(profile) => Self {
+                queries: profile.queries.iter(),
+                unions: profile.unions.iter(),
+                materialized: profile.materialized.iter(),
+            },
+            None => Self {
+                queries: [].iter(),
+                unions: [].iter(),
+                materialized: [].iter(),
+            },
+        }
+    }
+}
+
+// One step of a plan. Mirrors the choices made by the execute_* functions.
+pub enum PlanNode<'a> {
+    // Every row of the table in storage order
+    Scan(&'a str),
+    // Only the rows of the table the primary key or an index points at, or
+    // every row in descending key order
+    Search(&'a str, ScanPlan<'a>),
+    // The row count kept in the catalog, no page is read
+    RowCount(&'a str),
+    // The single row of a select without a table
+    ConstantRow,
+    Filter(&'a Expr),
+    // The two tables' rows joined by the method chosen on their row counts
+    Join(&'a Join, JoinMethod),
+    // Groups come out ordered by their GROUP BY value
+    Aggregate(Option<&'a str>),
+    Having(&'a Having),
+    Sort(&'a OrderBy),
+    Limit(Option<usize>, usize),
+    Project(&'a [SelectItem]),
+    Union(bool),
+    // Runs the common tables, then the select reading them
+    With,
+    // The rows of a common table stored in a temporary table
+    Materialize(&'a str),
+    // Statements that don't read rows
+    Write(String),
+}
+
+// How a statement is carried out, each node reads the rows of its inputs
+pub struct Plan<'a> {
+    pub node: PlanNode<'a>,
+    pub inputs: Vec<Plan<'a>>,
+    // Only known once the statement has run
+    pub stats: Option<NodeStats>,
+}
+
+impl<'a> Plan<'a> {
+    // The catalog tells which tables have indexes a select can use
+    pub fn new(statement: &'a Statement, catalog: &Table) -> Self {
+        Plan::build(statement, catalog, None)
+    }
+
+    // The plan with what each node did while the statement ran
+    pub fn analyzed(statement: &'a Statement, catalog: &Table, profile: &Profile) -> Self {
+        Plan::build(statement, catalog, Some(profile))
+    }
+
+    fn build(statement: &'a Statement, catalog: &Table, profile: Option<&Profile>) -> Self {
+        let description = match statement {
+            Statement::Select(select) => {
+                return Plan::select(select, catalog, &mut ProfileStats::new(profile));
+            }
+            Statement::Explain(explain) => {
+                return Plan::build(&explain.statement, catalog, profile);
+            }
+            Statement::Insert(insert) if insert.or_replace => {
+                format!("INSERT OR REPLACE INTO {}", insert.table_name)
+            }
+            Statement::Insert(insert) => format!("INSERT INTO {}", insert.table_name),
+            Statement::CreateTable(create) => format!("CREATE TABLE {}", create.table_name),
+            Statement::DropTable(table) => format!("DROP TABLE {}", table),
+            Statement::Delete(delete) if delete.where_clause.is_some() => {
+                format!("DELETE FROM {}", delete.table_name)
+            }
+            Statement::Delete(delete) => format!("TRUNCATE {}", delete.table_name),
+            Statement::AlterTable(alter) => format!("ALTER TABLE {}", alter.table_name),
+            Statement::CreateTrigger(create) => format!("CREATE TRIGGER ON {}", create.table_name),
+            Statement::CreateIndex(create) => {
+                format!(
+                    "CREATE INDEX {} ON {}",
+                    create.index.name, create.table_name
+                )
+            }
+            Statement::Savepoint(name) | Statement::Release(name) | Statement::RollbackTo(name) => {
+                format!("SAVEPOINT {}", name)
+            }
+            Statement::Pragma(pragma) => format!("PRAGMA {}", pragma.name),
+            Statement::Vacuum => "VACUUM".to_string(),
+        };
+        Plan::leaf(PlanNode::Write(description), None)
+    }
+
+    // A select with its common tables and the selects unioned after it
+    fn select(select: &'a SelectStmt, catalog: &Table, stats: &mut ProfileStats) -> Self {
+        let mut inputs = Vec::new();
+        for common in &select.with {
+            let plan = Plan::select(&common.select, catalog, stats);
+            let materialized = stats.materialized.next().copied();
+            inputs.push(plan.wrap(PlanNode::Materialize(&common.name), materialized));
+        }
+
+        let mut plan = Plan::query(select, catalog, stats.queries.next());
+        let mut next = &select.union;
+        while let Some(union) = next {
+            let right = Plan::query(&union.select, catalog, stats.queries.next());
+            plan = Plan {
+                node: PlanNode::Union(union.all),
+                inputs: vec![plan, right],
+                stats: stats.unions.next().copied(),
+            };
+            next = &union.select.union;
+        }
+        if inputs.is_empty() {
+            return plan;
+        }
+
+        // The statement's rows are the ones of the select reading the
+        // common tables
+        let stats = plan.stats;
+        inputs.push(plan);
+        Plan {
+            node: PlanNode::With,
+            inputs,
+            stats,
+        }
+    }
+
+    // A single select, see execute_query
+    fn query(select: &'a SelectStmt, catalog: &Table, stats: Option<&QueryStats>) -> Self {
+        let stat = |pick: fn(&QueryStats) -> NodeStats| stats.map(pick);
+        let table = select.table_name.as_str();
+        let planned = match catalog.find_table(table) {
+            Some(index) if select.join.is_none() => {
+                Some(ScanPlan::new(&catalog.tables[index].schema, select))
+            }
+            _ => None,
+        };
+        let sort = match &planned {
+            Some(planned) => planned.sort,
+            None => select.order_by.as_ref(),
+        };
+        let scan = match (select.constant_row, planned) {
+            (true, _) => PlanNode::ConstantRow,
+            (false, Some(planned))
+                if planned.descending || !matches!(planned.access, Access::Scan) =>
+            {
+                PlanNode::Search(table, planned)
+            }
+            (false, _) => PlanNode::Scan(table),
+        };
+        let mut plan = Plan::leaf(scan, stat(|stats| stats.scan));
+
+        if let Some(join) = &select.join {
+            let right = Plan::leaf(
+                PlanNode::Scan(&join.right_table),
+                stat(|stats| stats.right_scan),
+            );
+            let num_rows = |name: &str| {
+                (catalog.find_table(name)).map_or(0, |index| catalog.tables[index].num_rows)
+            };
+            let method = JoinMethod::new(num_rows(table), num_rows(&join.right_table));
+            plan = Plan {
+                node: PlanNode::Join(join, method),
+                inputs: vec![plan, right],
+                stats: stat(|stats| stats.join),
+            };
+        }
+        if is_count_only(select) {
+            plan = Plan::leaf(PlanNode::RowCount(table), stat(|stats| stats.scan));
+        }
+        if let Some(condition) = &select.where_clause {
+            plan = plan.wrap(PlanNode::Filter(condition), stat(|stats| stats.filter));
+        }
+        if select.is_aggregate() {
+            let group_by = select.group_by.as_deref();
+            plan = plan.wrap(PlanNode::Aggregate(group_by), stat(|stats| stats.aggregate));
+            if let Some(having) = &select.having {
+                plan = plan.wrap(PlanNode::Having(having), stat(|stats| stats.having));
+            }
+        } else if let Some(order_by) = sort {
+            plan = plan.wrap(PlanNode::Sort(order_by), stat(|stats| stats.sort));
+        }
+
+        if select.limit.is_some() || select.offset > 0 {
+            let limit = PlanNode::Limit(select.limit, select.offset);
+            plan = plan.wrap(limit, stat(|stats| stats.output));
+        }
+        if !select.select_list.is_empty() {
+            let project = PlanNode::Project(&select.select_list);
+            plan = plan.wrap(project, stat(|stats| stats.output));
+        }
+        plan
+    }
+
+    fn leaf(node: PlanNode<'a>, stats: Option<NodeStats>) -> Self {
+        Plan {
+            node,
+            inputs: Vec::new(),
+            stats,
+        }
+    }
+
+    // A node reading the rows of this one
+    fn wrap(self, node: PlanNode<'a>, stats: Option<NodeStats>) -> Self {
+        Plan {
+            node,
+            inputs: vec![self],
+            stats,
+        }
+    }
+
+    fn write_indented(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        write!(f, "{}{}", "  ".repeat(depth), self.node)?;
+        if let Some(stats) = &self.stats {
+            let time = stats.time.as_secs_f64() * 1000.0;
+            match self.node {
+                PlanNode::Write(_) => write!(f, " (time: {:.3} ms)", time)?,
+                PlanNode::Scan(_) | PlanNode::Search(..) => write!(
+                    f,
+                    " (rows: {}, pages: {}, time: {:.3} ms)",
+                    stats.rows, stats.pages, time
+                )?,
+                PlanNode::Sort(_) if stats.runs > 0 => write!(
+                    f,
+                    " (rows: {}, runs: {}, time: {:.3} ms)",
+                    stats.rows, stats.runs, time
+                )?,
+                _ => write!(f, " (rows: {}, time: {:.3} ms)", stats.rows, time)?,
+            }
+        }
+        writeln!(f)?;
+
+        for input in &self.inputs {
+            input.write_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+// The node's own line of the rendered plan
+impl fmt::Display for PlanNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanNode::Scan(table) => write!(f, "SCAN {}", table),
+            PlanNode::Search(table, plan) => {
+                match &plan.access {
+                    Access::Key(column, value) => write!(
+                        f,
+                        "SEARCH {} USING PRIMARY KEY ({} = {})",
+                        table, column, value
+                    )?,
+                    Access::Range(column, low, high) => {
+                        let low = match low {
+                            Bound::Included(value) => Some(format!("{} >= {}", column, value)),
+                            Bound::Excluded(value) => Some(format!("{} > {}", column, value)),
+                            Bound::Unbounded => None,
+                        };
+                        let high = match high {
+                            Bound::Included(value) => Some(format!("{} <= {}", column, value)),
+                            Bound::Excluded(value) => Some(format!("{} < {}", column, value)),
+                            Bound::Unbounded => None,
+                        };
+                        let bounds: Vec<String> = low.into_iter().chain(high).collect();
+                        write!(
+                            f,
+                            "SEARCH {} USING PRIMARY KEY ({})",
+                            table,
+                            bounds.join(" AND ")
+                        )?
+                    }
+                    Access::Index(index, column, value) => write!(
+                        f,
+                        "SEARCH {} USING INDEX {} ({} = {})",
+                        table, index, column, value
+                    )?,
+                    Access::Scan => write!(f, "SCAN {}", table)?,
+                }
+                if plan.descending {
+                    write!(f, " BACKWARDS")?;
+                }
+                Ok(())
+            }
+            PlanNode::RowCount(table) => write!(f, "ROW COUNT {}", table),
+            PlanNode::ConstantRow => write!(f, "CONSTANT ROW"),
+            PlanNode::Filter(condition) => write!(f, "FILTER {}", condition),
+            PlanNode::Join(join, method) => {
+                let name = match method {
+                    JoinMethod::NestedLoop => "NESTED LOOP",
+                    JoinMethod::Hash { .. } => "HASH",
+                };
+                let kind = match join.kind {
+                    JoinKind::Inner => "",
+                    JoinKind::Left => " LEFT",
+                };
+                write!(
+                    f,
+                    "{}{} JOIN ON {} = {}",
+                    name, kind, join.left_column, join.right_column
+                )?;
+                match method {
+                    JoinMethod::Hash { build_left: true } => write!(f, " (BUILD LEFT)"),
+                    JoinMethod::Hash { build_left: false } => write!(f, " (BUILD RIGHT)"),
+                    JoinMethod::NestedLoop => Ok(()),
+                }
+            }
+            PlanNode::Aggregate(Some(column)) => write!(f, "AGGREGATE GROUP BY {}", column),
+            PlanNode::Aggregate(None) => write!(f, "AGGREGATE"),
+            PlanNode::Having(having) => write!(
+                f,
+                "HAVING {} {} {}",
+                having.aggregate,
+                having.op,
+                having.value.to_sql()
+            ),
+            PlanNode::Sort(order_by) => {
+                write!(f, "SORT BY {}", order_by.column)?;
+                if let Some(collation) = order_by.collation {
+                    write!(f, " COLLATE {}", collation.to_string().to_uppercase())?;
+                }
+                if order_by.descending {
+                    write!(f, " DESC")?;
+                }
+                Ok(())
+            }
+            PlanNode::Limit(Some(limit), offset) => write!(f, "LIMIT {} OFFSET {}", limit, offset),
+            PlanNode::Limit(None, offset) => write!(f, "OFFSET {}", offset),
+            PlanNode::Project(select_list) => {
+                let items: Vec<String> = select_list
+                    .iter()
+                    .map(|item| match item {
+                        SelectItem::Expr(expr) => expr.to_string(),
+                        SelectItem::Aggregate(aggregate) => aggregate.to_string(),
+                    })
+                    .collect();
+                write!(f, "PROJECT {}", items.join(", "))
+            }
+            PlanNode::Union(true) => write!(f, "UNION ALL"),
+            PlanNode::Union(false) => write!(f, "UNION"),
+            PlanNode::With => write!(f, "WITH"),
+            PlanNode::Materialize(name) => write!(f, "MATERIALIZE {}", name),
+            PlanNode::Write(description) => write!(f, "{}", description),
+        }
+    }
+}
+
+// One node per line, inputs indented below the node reading them
+impl fmt::Display for Plan<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_indented(f, 0)
+    }
+}
+
+// Carries out a statement from the user, handing the rows it returns to
+// out, and commits what it changed
+pub fn execute_statement(
+    table: TableRef,
+    statement: &Statement,
+    out: &mut RowSink,
+) -> ExecuteResult {
+    let result = execute_atomically(Rc::clone(&table), statement, out);
+    match table.borrow_mut().commit() {
+        ExecuteResult::Success => result,
+        failure => failure,
+    }
+}
+
+// Carries out the statements one after another and commits them together.
+// Hands back what each did, or why the commit failed.
+pub fn execute_batch(
+    table: TableRef,
+    statements: &[Statement],
+) -> Result<Vec<ExecuteResult>, ExecuteResult> {
+    let results = (statements.iter())
+        .map(|statement| execute_atomically(Rc::clone(&table), statement, &mut |_| {}))
+        .collect();
+    match table.borrow_mut().commit() {
+        ExecuteResult::Success => Ok(results),
+        failure => Err(failure),
+    }
+}
+
+// Carries out a statement of the user's. One that fails part way, in a
+// trigger as well, leaves nothing of what it changed behind.
+fn execute_atomically(table: TableRef, statement: &Statement, out: &mut RowSink) -> ExecuteResult {
+    let writes = !matches!(
+        statement,
+        Statement::Select(_)
+            | Statement::Savepoint(_)
+            | Statement::Release(_)
+            | Statement::RollbackTo(_)
+            | Statement::Pragma(_)
+    );
+    if writes {
+        table.borrow_mut().savepoint(STATEMENT_SAVEPOINT);
+    }
+    let mut result = execute(Rc::clone(&table), statement, out);
+    if let Some(error) = table.borrow_mut().read_error.take() {
+        result = error;
+    }
+    if writes {
+        let mut table = table.borrow_mut();
+        if !result.is_success() {
+            table.rollback_to(STATEMENT_SAVEPOINT);
+        }
+        table.release(STATEMENT_SAVEPOINT);
+    }
+    result
+}
+
+// Carries out a statement, the user's or one of a trigger's
+fn execute(table: TableRef, statement: &Statement, out: &mut RowSink) -> ExecuteResult {
+    if statement.parameter_count() > 0 {
+        return ExecuteResult::UnboundParameters;
+    }
+
+    let mut profile = Profile::new();
+    let Statement::Explain(explain) = statement else {
+        return execute_profiled(table, statement, out, &mut profile);
+    };
+    if !explain.analyze {
+        let plan = Plan::new(&explain.statement, &table.borrow());
+        return ExecuteResult::Plan(plan.to_string());
+    }
+
+    // The rows of an analyzed select are only counted
+    let result = execute_profiled(
+        Rc::clone(&table),
+        &explain.statement,
+        &mut |_| {},
+        &mut profile,
+    );
+    if !result.is_success() {
+        return result;
+    }
+    let mut plan = Plan::analyzed(&explain.statement, &table.borrow(), &profile);
+    if let PlanNode::Write(_) = plan.node {
+        plan.stats = Some(NodeStats {
+            time: profile.started.elapsed(),
+            ..NodeStats::default()
+        });
+    }
+    ExecuteResult::Plan(plan.to_string())
+}
+
+// Carries out the statement, handing a select's rows to out
+fn execute_profiled(
+    table: TableRef,
+    statement: &Statement,
+    out: &mut RowSink,
+    profile: &mut Profile,
+) -> ExecuteResult {
+    match statement {
+        Statement::Select(select) => {
+            let mut returned = 0;
+            let mut count = |values| {
+                returned += 1;
+                out(values);
+            };
+            match execute_with(table, select, &mut count, profile) {
+                ExecuteResult::Success => ExecuteResult::Returned(returned),
+                failure => failure,
+            }
+        }
+        Statement::Insert(insert) => execute_insert(table, insert, out),
+        Statement::CreateTable(create) => table
+            .borrow_mut()
+            .create_table(&create.table_name, create.schema.clone()),
+        Statement::DropTable(name) => table.borrow_mut().drop_table(name),
+        Statement::Delete(delete) => execute_delete(table, delete, out),
+        Statement::CreateTrigger(create) => table
+            .borrow_mut()
+            .create_trigger(&create.table_name, create.trigger.clone()),
+        Statement::CreateIndex(create) => execute_create_index(table, create),
+        Statement::Savepoint(name) => table.borrow_mut().savepoint(name),
+        Statement::Release(name) => table.borrow_mut().release(name),
+        Statement::RollbackTo(name) => table.borrow_mut().rollback_to(name),
+        Statement::Pragma(pragma) => table.borrow_mut().pragma(pragma, out),
+        Statement::Vacuum => table.borrow_mut().vacuum(),
+        Statement::AlterTable(alter) => {
+            (table.borrow_mut()).add_column(&alter.table_name, alter.column.clone())
+        }
+        Statement::Explain(_) => execute(table, statement, out),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::prepare;
+
+    // A database file of the test's own in the temporary directory, removed
+    // with the files next to it once the test is done
+    struct TestFile(String);
+
+    impl TestFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "rustlite-test-{}-{}.db",
+                std::process::id(),
+                name
+            ));
+            let file = Self(path.to_string_lossy().into_owned());
+            file.remove();
+            file
+        }
+
+        fn remove(&self) {
+            for suffix in ["", "-wal", "-journal", "-dw"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.0, suffix));
+            }
+        }
+    }
+
+    impl Drop for TestFile {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    fn open(path: &str) -> TableRef {
+        match Table::db_open(path, None) {
+            Ok(table) => Rc::new(RefCell::new(table)),
+            Err(message) => panic!("{}", message),
+        }
+    }
+
+    // Runs the statement, handing back its result and the rows it returned
+    fn run(table: &TableRef, sql: &str) -> (ExecuteResult, Vec<Vec<Value>>) {
+        let Ok(statement) = prepare(sql) else {
+            panic!("can't prepare {}", sql);
+        };
+        let mut rows = Vec::new();
+        let result = execute_statement(Rc::clone(table), &statement, &mut |values| {
+            rows.push(values)
+        });
+        (result, rows)
+    }
+
+    fn succeed(table: &TableRef, sql: &str) {
+        assert!(run(table, sql).0.is_success(), "{} failed", sql);
+    }
+
+    fn count(table: &TableRef, name: &str) -> i64 {
+        match &run(table, &format!("select count(*) from {}", name)).1[..] {
+            [row] => match row[..] {
+                [Value::Integer(count)] => count,
+                _ => panic!("count(*) of {} isn't a number", name),
+            },
+            _ => panic!("count(*) of {} isn't one row", name),
+        }
+    }
+
+    fn close(table: &TableRef) {
+        assert!(table.borrow_mut().db_close().is_ok());
+    }
+
+    #[test]
+    fn failed_statement_leaves_nothing_behind() {
+        let file = TestFile::new("failed-statement");
+        let table = open(&file.0);
+        succeed(&table, "create table a (id integer primary key, x integer)");
+        succeed(
+            &table,
+            "create table l (id integer primary key, y integer not null)",
+        );
+        succeed(
+            &table,
+            "create trigger t after insert on a begin insert into l values (new.id, null); end",
+        );
+
+        let (result, _) = run(&table, "insert into a values (1, 2)");
+        assert!(matches!(result, ExecuteResult::NotNullViolation));
+        assert_eq!(count(&table, "a"), 0);
+        assert_eq!(count(&table, "l"), 0);
+
+        // Nor inside a savepoint, where what came before it stays
+        succeed(&table, "savepoint s");
+        succeed(&table, "insert into l values (7, 7)");
+        let (result, _) = run(&table, "insert into a values (3, 4)");
+        assert!(matches!(result, ExecuteResult::NotNullViolation));
+        succeed(&table, "release s");
+        assert_eq!(count(&table, "a"), 0);
+        assert_eq!(count(&table, "l"), 1);
+
+        close(&table);
+        let table = open(&file.0);
+        assert_eq!(count(&table, "a"), 0);
+        assert_eq!(count(&table, "l"), 1);
+        close(&table);
+    }
+
+    #[test]
+    fn log_is_replayed_after_a_crash_before_a_checkpoint() {
+        let file = TestFile::new("wal-replay");
+        let crashed = TestFile::new("wal-replay-crashed");
+        let table = open(&file.0);
+        succeed(&table, "pragma journal_mode = wal");
+        succeed(&table, "pragma wal_autocheckpoint = 100000");
+        succeed(&table, "create table t (id integer primary key, s text)");
+        for id in 1..=200 {
+            succeed(
+                &table,
+                &format!("insert into t values ({}, 'row {}')", id, id),
+            );
+        }
+
+        // The files as a crash would leave them, with every commit still in
+        // the log
+        assert!(std::fs::metadata(format!("{}-wal", file.0)).is_ok_and(|m| m.len() > 0));
+        std::fs::copy(&file.0, &crashed.0).unwrap();
+        std::fs::copy(format!("{}-wal", file.0), format!("{}-wal", crashed.0)).unwrap();
+        close(&table);
+
+        let table = open(&crashed.0);
+        assert_eq!(count(&table, "t"), 200);
+        let (_, rows) = run(&table, "select s from t where id = 200");
+        assert!(matches!(&rows[..], [row] if row[0] == Value::Text("row 200".to_string())));
+        close(&table);
+        assert!(std::fs::metadata(format!("{}-wal", crashed.0)).is_err());
+    }
+
+    #[test]
+    fn checkpoint_keeps_the_log_when_a_write_fails() {
+        let file = TestFile::new("failed-checkpoint");
+        let table = open(&file.0);
+        succeed(&table, "pragma journal_mode = wal");
+        succeed(&table, "pragma wal_autocheckpoint = 100000");
+        succeed(&table, "create table t (id integer primary key)");
+        for id in 1..=50 {
+            succeed(&table, &format!("insert into t values ({})", id));
+        }
+
+        // Writes to the database file fail through a handle only open for
+        // reading, which takes the lock over
+        let writable = {
+            let mut table = table.borrow_mut();
+            let Backend::Disk(disk) = &mut table.pager.backend else {
+                panic!("the database isn't on disk");
+            };
+            let read_only = File::open(&file.0).unwrap();
+            disk.file.unlock().unwrap();
+            read_only.lock_shared().unwrap();
+            std::mem::replace(&mut disk.file, read_only)
+        };
+        assert!(table.borrow_mut().pager.checkpoint().is_err());
+        {
+            let table = table.borrow();
+            let Backend::Disk(disk) = &table.pager.backend else {
+                unreachable!();
+            };
+            assert!(!disk.wal.frames.is_empty());
+            assert!(disk.wal.file.metadata().is_ok_and(|m| m.len() > 0));
+        }
+        assert_eq!(count(&table, "t"), 50);
+
+        {
+            let mut table = table.borrow_mut();
+            let Backend::Disk(disk) = &mut table.pager.backend else {
+                unreachable!();
+            };
+            disk.unlock_exclusive();
+            writable.lock_shared().unwrap();
+            disk.file = writable;
+        }
+        assert!(table.borrow_mut().pager.checkpoint().is_ok_and(|done| done));
+        close(&table);
+        let table = open(&file.0);
+        assert_eq!(count(&table, "t"), 50);
+        close(&table);
+    }
+
+    #[test]
+    fn savepoint_rolls_back_under_cache_pressure() {
+        for journal_mode in ["delete", "wal"] {
+            let file = TestFile::new(&format!("savepoint-{}", journal_mode));
+            let table = open(&file.0);
+            succeed(&table, &format!("pragma journal_mode = {}", journal_mode));
+            succeed(&table, "pragma cache_size = 4");
+            succeed(&table, "create table t (id integer primary key, s text)");
+            let padding = "x".repeat(200);
+            for id in 1..=100 {
+                succeed(
+                    &table,
+                    &format!("insert into t values ({}, '{}')", id, padding),
+                );
+            }
+
+            // Far more pages change than the cache holds, so they are
+            // written out before the rollback
+            succeed(&table, "savepoint s");
+            for id in 101..=400 {
+                succeed(
+                    &table,
+                    &format!("insert into t values ({}, '{}')", id, padding),
+                );
+            }
+            succeed(&table, "delete from t where id <= 50");
+            assert_eq!(count(&table, "t"), 350);
+            succeed(&table, "rollback to s");
+            succeed(&table, "release s");
+            assert_eq!(count(&table, "t"), 100);
+
+            close(&table);
+            let table = open(&file.0);
+            assert_eq!(count(&table, "t"), 100);
+            let (_, rows) = run(&table, "select id from t where id = 1");
+            assert_eq!(rows.len(), 1);
+            close(&table);
+        }
     }
 }
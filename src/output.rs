@@ -0,0 +1,195 @@
+use std::fmt;
+
+use crate::mem_storage::Value;
+
+// How the shell shows the rows a statement hands out, set with .mode
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum OutputMode {
+    // (1, alice, alice@example.com)
+    #[default]
+    Tuple,
+    // Values separated by |
+    List,
+    // A header of the column names, then the rows, quoted where needed
+    Csv,
+    // An array of objects keyed on the column names
+    Json,
+    // Columns padded to line up under a header
+    Column,
+    // Columns lined up in a box drawn with ASCII characters
+    Table,
+}
+
+impl OutputMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "tuple" => Some(OutputMode::Tuple),
+            "list" => Some(OutputMode::List),
+            "csv" => Some(OutputMode::Csv),
+            "json" => Some(OutputMode::Json),
+            "column" => Some(OutputMode::Column),
+            "table" => Some(OutputMode::Table),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for OutputMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputMode::Tuple => write!(f, "tuple"),
+            OutputMode::List => write!(f, "list"),
+            OutputMode::Csv => write!(f, "csv"),
+            OutputMode::Json => write!(f, "json"),
+            OutputMode::Column => write!(f, "column"),
+            OutputMode::Table => write!(f, "table"),
+        }
+    }
+}
+
+// Prints the rows of a statement as they come in the mode. Columns and
+// tables need the width of every value first, so their rows are held until
+// the statement is done. Nothing is printed for a statement without rows.
+pub struct Output {
+    mode: OutputMode,
+    names: Vec<String>,
+    rows: Vec<Vec<String>>,
+    printed: usize,
+}
+
+impl Output {
+    pub fn new(mode: OutputMode, names: Vec<String>) -> Self {
+        Self {
+            mode,
+            names,
+            rows: Vec::new(),
+            printed: 0,
+        }
+    }
+
+    pub fn row(&mut self, values: Vec<Value>) {
+        match self.mode {
+            OutputMode::Tuple => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                println!("({})", values.join(", "));
+            }
+            OutputMode::List => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                println!("{}", values.join("|"));
+            }
+            OutputMode::Csv => {
+                if self.printed == 0 {
+                    let names: Vec<String> =
+                        self.names.iter().map(|name| csv_field(name)).collect();
+                    println!("{}", names.join(","));
+                }
+                let values: Vec<String> = (values.iter())
+                    .map(|value| match value {
+                        Value::Null => String::new(),
+                        value => csv_field(&value.to_string()),
+                    })
+                    .collect();
+                println!("{}", values.join(","));
+            }
+            OutputMode::Json => {
+                let fields: Vec<String> = (self.names.iter())
+                    .zip(&values)
+                    .map(|(name, value)| format!("{}:{}", json_string(name), json_value(value)))
+                    .collect();
+                let start = if self.printed == 0 { "[" } else { ",\n" };
+                print!("{}{{{}}}", start, fields.join(","));
+            }
+            OutputMode::Column | OutputMode::Table => {
+                self.rows
+                    .push(values.iter().map(Value::to_string).collect());
+            }
+        }
+        self.printed += 1;
+    }
+
+    // Ends the output once the statement has handed out its last row
+    pub fn finish(self) {
+        if self.printed == 0 {
+            return;
+        }
+        match self.mode {
+            OutputMode::Json => println!("]"),
+            OutputMode::Column | OutputMode::Table => self.print_columns(),
+            _ => {}
+        }
+    }
+
+    fn print_columns(&self) {
+        let mut widths: Vec<usize> = self.names.iter().map(|name| name.chars().count()).collect();
+        for row in &self.rows {
+            widths.resize(widths.len().max(row.len()), 0);
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.chars().count());
+            }
+        }
+        let line = |values: &[String]| -> Vec<String> {
+            (widths.iter().enumerate())
+                .map(|(i, &width)| {
+                    let value = values.get(i).map_or("", String::as_str);
+                    format!("{}{}", value, " ".repeat(width - value.chars().count()))
+                })
+                .collect()
+        };
+
+        if self.mode == OutputMode::Column {
+            println!("{}", line(&self.names).join("  ").trim_end());
+            let dashes: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+            println!("{}", dashes.join("  "));
+            for row in &self.rows {
+                println!("{}", line(row).join("  ").trim_end());
+            }
+            return;
+        }
+        let dashes: Vec<String> = widths.iter().map(|&width| "-".repeat(width + 2)).collect();
+        let border = format!("+{}+", dashes.join("+"));
+        println!("{}", border);
+        println!("| {} |", line(&self.names).join(" | "));
+        println!("{}", border);
+        for row in &self.rows {
+            println!("| {} |", line(row).join(" | "));
+        }
+        println!("{}", border);
+    }
+}
+
+// The field as is, or quoted when it holds a comma, a quote or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) if r.is_finite() => value.to_string(),
+        Value::Real(_) => "null".to_string(),
+        Value::Boolean(b) => b.to_string(),
+        value => json_string(&value.to_string()),
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -1,91 +1,2825 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::iter::Peekable;
+use std::ops::Range;
+use std::rc::Rc;
+use std::str::CharIndices;
+
 use crate::{
-    InputBuffer,
-    mem_storage::{EMAIL_SIZE, Row, Table, USERNAME_SIZE},
+    InputBuffer, Settings,
+    mem_storage::{
+        BlobHandle, COLUMN_NAME_SIZE, Collation, ColumnDef, ColumnType, DEFAULT_TABLE,
+        DEFAULT_TEXT_SIZE, ExecuteResult, Index, MEMORY_DATABASE, SECONDS_PER_DAY, Schema,
+        TABLE_NAME_SIZE, Table, Trigger, TriggerEvent, Value, days_from_civil, dump, execute_batch,
+    },
+    output::OutputMode,
 };
 
+// Pages .backup copies at a time
+const BACKUP_STEP_PAGES: usize = 100;
+// Bytes .readblob and .writeblob move at a time
+const BLOB_CHUNK_SIZE: usize = 65536;
+// Rows .import reads from the file and commits at a time
+const IMPORT_BATCH_ROWS: usize = 1000;
+
 // Meta commands always start with a dot
 pub enum MetaCommandResult {
     CommandSuccess,
     CommandUnrecognizedCommand,
+    CommandFailed(ExecuteResult),
+    // .exit, the shell closes the database and ends
+    Exit,
 }
 
-pub fn do_meta_command(input_buffer: &InputBuffer, table: &mut Table) -> MetaCommandResult {
+pub fn do_meta_command(
+    input_buffer: &InputBuffer,
+    table: &Rc<RefCell<Table>>,
+    settings: &mut Settings,
+) -> MetaCommandResult {
     if input_buffer.buffer == ".exit" {
-        table.db_close();
-        std::process::exit(0);
+        MetaCommandResult::Exit
+    } else if input_buffer.buffer == ".tables" {
+        for name in table.borrow().table_names() {
+            println!("{}", name);
+        }
+        MetaCommandResult::CommandSuccess
+    } else if input_buffer.buffer == ".mode" {
+        println!("{}", settings.mode);
+        MetaCommandResult::CommandSuccess
+    } else if let Some(name) = input_buffer.buffer.strip_prefix(".mode ") {
+        match OutputMode::from_name(name.trim()) {
+            Some(mode) => {
+                settings.mode = mode;
+                MetaCommandResult::CommandSuccess
+            }
+            None => MetaCommandResult::CommandUnrecognizedCommand,
+        }
+    } else if let Some(state) = input_buffer.buffer.strip_prefix(".timer ") {
+        match state.trim() {
+            "on" => settings.timer = true,
+            "off" => settings.timer = false,
+            _ => return MetaCommandResult::CommandUnrecognizedCommand,
+        }
+        MetaCommandResult::CommandSuccess
+    } else if input_buffer.buffer == ".btree" || input_buffer.buffer.starts_with(".btree ") {
+        let name = input_buffer.buffer[".btree".len()..].trim();
+        let name = if name.is_empty() { DEFAULT_TABLE } else { name };
+        match table.borrow_mut().btree(name) {
+            Some(tree) => print!("{}", tree),
+            None => return MetaCommandResult::CommandFailed(ExecuteResult::NoSuchTable),
+        }
+        MetaCommandResult::CommandSuccess
+    } else if input_buffer.buffer == ".constants" || input_buffer.buffer.starts_with(".constants ")
+    {
+        let name = input_buffer.buffer[".constants".len()..].trim();
+        let name = if name.is_empty() { DEFAULT_TABLE } else { name };
+        let Some(constants) = table.borrow().constants(name) else {
+            return MetaCommandResult::CommandFailed(ExecuteResult::NoSuchTable);
+        };
+        for (name, value) in constants {
+            println!("{}: {}", name, value);
+        }
+        MetaCommandResult::CommandSuccess
+    } else if input_buffer.buffer == ".stats" {
+        let stats = table.borrow().stats();
+        println!("Rows: {}", stats.rows);
+        println!("Pages: {}, {} bytes each", stats.pages, stats.page_size);
+        println!(
+            "Cached pages: {} of {}",
+            stats.cached_pages, stats.cache_size
+        );
+        println!("Cache hits: {}", stats.counts.hits);
+        println!("Cache misses: {}", stats.counts.misses);
+        println!("Pages read: {}", stats.counts.read);
+        println!("Pages written: {}", stats.counts.written);
+        println!("File size: {} bytes", stats.file_size);
+        MetaCommandResult::CommandSuccess
+    } else if input_buffer.buffer == ".dump" {
+        if let Err(error) = dump(table, &mut io::stdout().lock()) {
+            println!("Error: Dump failed, {}.", error);
+        }
+        MetaCommandResult::CommandSuccess
+    } else if let Some(path) = input_buffer.buffer.strip_prefix(".dump ") {
+        let result = File::create(path.trim()).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            dump(table, &mut out)?;
+            out.flush()
+        });
+        if let Err(error) = result {
+            println!("Error: Dump failed, {}.", error);
+        }
+        MetaCommandResult::CommandSuccess
+    } else if let Some(path) = input_buffer.buffer.strip_prefix(".backup ") {
+        let table = &mut table.borrow_mut();
+        let result = table.backup_to(path.trim()).and_then(|mut backup| {
+            while !backup.step(table, BACKUP_STEP_PAGES)? {}
+            Ok(())
+        });
+        if let Err(error) = result {
+            println!("Error: Backup failed, {}.", error);
+        }
+        MetaCommandResult::CommandSuccess
+    } else if let Some(args) = input_buffer.buffer.strip_prefix(".open ") {
+        open_command(table, args)
+    } else if let Some(args) = input_buffer.buffer.strip_prefix(".import ") {
+        import_command(table, args)
+    } else if let Some(args) = input_buffer.buffer.strip_prefix(".readblob ") {
+        blob_command(table, args, false)
+    } else if let Some(args) = input_buffer.buffer.strip_prefix(".writeblob ") {
+        blob_command(table, args, true)
     } else {
+        MetaCommandResult::CommandUnrecognizedCommand
+    }
+}
+
+// .open FILE [KEY]: closes the database, writing out what it holds, and
+// opens the file in its place. When the file can't be opened the database
+// is opened again. Opening the file that is open does nothing.
+fn open_command(table: &Rc<RefCell<Table>>, args: &str) -> MetaCommandResult {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (path, key) = match args[..] {
+        [path] => (path, None),
+        [path, key] => (path, Some(key)),
+        _ => return MetaCommandResult::CommandUnrecognizedCommand,
+    };
+    let mut table = table.borrow_mut();
+    if table.is_file(path) {
+        return MetaCommandResult::CommandSuccess;
+    }
+    let closed = table.db_close();
+    match Table::db_open(path, key) {
+        Ok(opened) => *table = opened,
+        Err(message) => {
+            // Into memory when the database can't be opened again either
+            let reopened = table
+                .reopen()
+                .or_else(|_| Table::db_open(MEMORY_DATABASE, None));
+            if let Ok(reopened) = reopened {
+                *table = reopened;
+            }
+            return MetaCommandResult::CommandFailed(ExecuteResult::CannotOpen(message));
+        }
+    }
+    match closed {
+        Ok(()) => MetaCommandResult::CommandSuccess,
+        Err(error) => MetaCommandResult::CommandFailed(error.into()),
+    }
+}
+
+// .import FILE TABLE: inserts the records of a CSV file as rows of the
+// table, committing them a batch at a time. A first record naming columns
+// of the table is a header, and the values go to those columns, otherwise
+// they go to the columns in order. Records that make no row are reported by
+// line and left out. A full or unwritable database stops the import, the
+// batches committed before stay.
+fn import_command(table: &Rc<RefCell<Table>>, args: &str) -> MetaCommandResult {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [path, table_name] = args[..] else {
+        return MetaCommandResult::CommandUnrecognizedCommand;
+    };
+    let Some(schema) = table.borrow().schema(table_name).cloned() else {
+        return MetaCommandResult::CommandFailed(ExecuteResult::NoSuchTable);
+    };
+    let Ok(file) = File::open(path) else {
+        println!("Error: Unable to open '{}'.", path);
+        return MetaCommandResult::CommandSuccess;
+    };
+
+    let mut records = CsvReader::new(BufReader::new(file)).peekable();
+    let columns: Option<Vec<String>> = match records.peek() {
+        Some((_, Ok(fields))) => (fields.iter())
+            .map(|field| (field.clone()).filter(|name| schema.column_index(name).is_some()))
+            .collect(),
+        _ => None,
+    };
+    if columns.is_some() {
+        records.next();
+    }
+
+    let mut loaded = 0;
+    let mut rejected = 0;
+    let mut failure = None;
+    let mut lines = Vec::new();
+    let mut inserts = Vec::new();
+    while failure.is_none() && records.peek().is_some() {
+        for (line, fields) in records.by_ref().take(IMPORT_BATCH_ROWS) {
+            match fields {
+                Ok(fields) => {
+                    lines.push(line);
+                    inserts.push(Statement::Insert(InsertStmt {
+                        table_name: table_name.to_string(),
+                        columns: columns.clone(),
+                        values: (fields.into_iter())
+                            .map(|field| field.map_or(Value::Null, Value::Text))
+                            .collect(),
+                        parameters: Vec::new(),
+                        or_replace: false,
+                        returning: None,
+                    }));
+                }
+                Err(error) => {
+                    println!("Line {}: Error: {}.", line, error);
+                    rejected += 1;
+                }
+            }
+        }
+        let results = match execute_batch(Rc::clone(table), &inserts) {
+            Ok(results) => results,
+            Err(result) => {
+                failure = Some(result);
+                break;
+            }
+        };
+        for (line, result) in lines.drain(..).zip(results) {
+            match result {
+                result if result.is_success() => loaded += 1,
+                ExecuteResult::TableFull => {
+                    failure = Some(result);
+                    break;
+                }
+                result => {
+                    print!("Line {}: ", line);
+                    crate::print_result(result);
+                    rejected += 1;
+                }
+            }
+        }
+        inserts.clear();
+    }
+
+    println!(
+        "Imported {}, rejected {}.",
+        crate::plural(loaded, "row"),
+        crate::plural(rejected, "line")
+    );
+    match failure {
+        Some(result) => MetaCommandResult::CommandFailed(result),
+        None => MetaCommandResult::CommandSuccess,
+    }
+}
+
+// Records of a CSV file, read a line at a time. Fields are separated by
+// commas and may be quoted, with "" for a quote inside them, to hold
+// commas and line breaks. An empty field that isn't quoted is None.
+struct CsvReader<R> {
+    reader: R,
+    // Line the last record ended on, counted from 1
+    line: usize,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, line: 0 }
+    }
+}
+
+impl<R: BufRead> Iterator for CsvReader<R> {
+    // The line the record starts on, and its fields or why it can't be read
+    type Item = (usize, Result<Vec<Option<String>>, String>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut start = self.line + 1;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut in_quotes = false;
+        let mut text = String::new();
+        loop {
+            text.clear();
+            match self.reader.read_line(&mut text) {
+                Ok(0) if self.line < start => return None,
+                Ok(0) => return Some((start, Err("unterminated quoted field".to_string()))),
+                Ok(_) => self.line += 1,
+                Err(error) => {
+                    self.line += 1;
+                    return Some((start, Err(error.to_string())));
+                }
+            }
+            // Blank lines between records hold no record
+            if !in_quotes && text.trim_end_matches(['\r', '\n']).is_empty() {
+                start = self.line + 1;
+                continue;
+            }
+
+            let mut chars = text.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if in_quotes && chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' if in_quotes => in_quotes = false,
+                    '"' if field.is_empty() && !quoted => {
+                        in_quotes = true;
+                        quoted = true;
+                    }
+                    ',' if !in_quotes => {
+                        fields.push(
+                            (quoted || !field.is_empty()).then(|| std::mem::take(&mut field)),
+                        );
+                        quoted = false;
+                    }
+                    '\r' | '\n' if !in_quotes => {}
+                    c => field.push(c),
+                }
+            }
+            if !in_quotes {
+                fields.push((quoted || !field.is_empty()).then_some(field));
+                return Some((start, Ok(fields)));
+            }
+        }
+    }
+}
+
+// .readblob and .writeblob TABLE COLUMN KEY FILE: copies a blob value of a
+// row to the file, or the file over the start of the value, a chunk at a
+// time
+fn blob_command(table: &Rc<RefCell<Table>>, args: &str, write: bool) -> MetaCommandResult {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [table_name, column, key, path] = args[..] else {
+        return MetaCommandResult::CommandUnrecognizedCommand;
+    };
+    let Ok(key) = key.parse::<i64>() else {
         return MetaCommandResult::CommandUnrecognizedCommand;
+    };
+    let blob = match BlobHandle::open(table, table_name, column, key) {
+        Ok(blob) => blob,
+        Err(result) => return MetaCommandResult::CommandFailed(result),
+    };
+    let file = match write {
+        true => File::open(path),
+        false => File::create(path),
+    };
+    let Ok(mut file) = file else {
+        println!("Error: Unable to open '{}'.", path);
+        return MetaCommandResult::CommandSuccess;
+    };
+
+    let mut buffer = vec![0; BLOB_CHUNK_SIZE];
+    let mut offset = 0;
+    if write {
+        if file.metadata().map_or(0, |m| m.len()) as usize > blob.size() {
+            return MetaCommandResult::CommandFailed(ExecuteResult::StringTooLong);
+        }
+        while let Ok(read @ 1..) = file.read(&mut buffer) {
+            match blob.write(offset, &buffer[..read]) {
+                ExecuteResult::Success => offset += read,
+                result => return MetaCommandResult::CommandFailed(result),
+            }
+        }
+    } else {
+        while offset < blob.size() {
+            let read = match blob.read(offset, &mut buffer) {
+                Ok(read) => read,
+                Err(result) => return MetaCommandResult::CommandFailed(result),
+            };
+            if file.write_all(&buffer[..read]).is_err() {
+                println!("Error: Unable to write '{}'.", path);
+                break;
+            }
+            offset += read;
+        }
     }
+    MetaCommandResult::CommandSuccess
 }
 
-pub enum PrepareResult {
-    Success,
+pub enum PrepareError {
     UnrecognizedStatement,
-    SyntaxError,
+    SyntaxError(SyntaxError),
     StringTooLong,
-    IdIssue,
+    // bind was given a different number of values than there are ?s
+    ParameterCountMismatch,
 }
 
-pub enum StatementType {
-    Insert,
-    Select,
+// The rest of the input when it starts with the keyword as a whole word
+fn strip_keyword<'a>(input: &'a str, keyword: &str) -> Option<&'a str> {
+    let rest = input.get(keyword.len()..)?;
+    (input[..keyword.len()].eq_ignore_ascii_case(keyword)
+        && !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+    .then_some(rest)
 }
 
-pub struct Statement {
-    pub stype: StatementType,
-    pub row_to_insert: Row,
+impl PrepareError {
+    // Moves the error's span by the bytes cut from the front of the input
+    fn shifted(self, offset: usize) -> Self {
+        match self {
+            PrepareError::SyntaxError(error) => PrepareError::SyntaxError(error.shifted(offset)),
+            error => error,
+        }
+    }
 }
 
-impl Statement {
-    pub fn new() -> Self {
+// Where the statement stopped making sense, as a byte range of its text,
+// and what was expected there
+pub struct SyntaxError {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+impl SyntaxError {
+    fn new(span: Range<usize>, message: &str) -> Self {
+        Self {
+            span,
+            message: message.to_string(),
+        }
+    }
+
+    fn shifted(self, offset: usize) -> Self {
         Self {
-            stype: StatementType::Select,
-            row_to_insert: Row::new(),
-        }
-    }
-
-    // Check and parse the user's input
-    pub fn prepare_statement(&mut self, input_buffer: &InputBuffer) -> PrepareResult {
-        if input_buffer.buffer.len() >= 6 && &input_buffer.buffer[..6] == "insert" {
-            self.stype = StatementType::Insert;
-
-            let mut parts = input_buffer.buffer.split_whitespace();
-            let _command = parts.next();
-            let id = parts.next();
-            let username = parts.next();
-            let email = parts.next();
-
-            // Check if the arguments are valid
-            match (id, username, email) {
-                (Some(id), Some(username), Some(email)) => {
-                    if username.len() <= USERNAME_SIZE && email.len() <= EMAIL_SIZE {
-                        if let Ok(id) = id.parse::<u32>() {
-                            let row = Row {
-                                id,
-                                username: username.to_string(),
-                                email: email.to_string(),
-                            };
-                            self.row_to_insert = row;
-                        } else {
-                            return PrepareResult::IdIssue;
-                        }
-                    } else {
-                        return PrepareResult::StringTooLong;
+            span: self.span.start + offset..self.span.end + offset,
+            message: self.message,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Token {
+    // Keywords, identifiers and numbers, lowercased so that neither
+    // keywords nor names depend on case
+    Word(String),
+    // Single-quoted string literal without the quotes, '' stands for a quote
+    Str(String),
+    Symbol(char),
+}
+
+// Split the user's input into tokens along with their byte ranges, fails on
+// an unterminated string
+pub fn tokenize(input: &str) -> Result<Vec<(Token, Range<usize>)>, SyntaxError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        let token = if c.is_whitespace() {
+            chars.next();
+            continue;
+        } else if c == '\'' {
+            match quoted(&mut chars) {
+                Some(literal) => Token::Str(literal),
+                None => return Err(SyntaxError::new(start..input.len(), "unterminated string")),
+            }
+        } else if c.is_alphanumeric() || c == '_' {
+            // Numbers may contain a decimal point
+            let numeric = c.is_ascii_digit();
+            let mut word = String::new();
+            while let Some((_, c)) =
+                chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_' || (numeric && c == '.'))
+            {
+                word.push(c);
+            }
+            Token::Word(word.to_lowercase())
+        } else {
+            chars.next();
+            Token::Symbol(c)
+        };
+
+        let end = chars.peek().map_or(input.len(), |&(end, _)| end);
+        tokens.push((token, start..end));
+    }
+
+    Ok(tokens)
+}
+
+// Split the input into trimmed statements on the semicolons outside of
+// string literals, leaving out the empty ones. -- comments run to the end of
+// the line and /* */ comments to their closing */ (or the end of the input),
+// both are dropped.
+pub fn split_statements(input: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut statement = String::new();
+    let mut in_string = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_string = !in_string;
+                statement.push(c);
+            }
+            _ if in_string => statement.push(c),
+            // The statements of a trigger's body belong to the create trigger
+            ';' if is_open_trigger(&statement) => statement.push(c),
+            ';' => statements.push(std::mem::take(&mut statement)),
+            '-' if chars.next_if_eq(&'-').is_some() => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '/' if chars.next_if_eq(&'*').is_some() => {
+                while let Some(c) = chars.next() {
+                    if c == '*' && chars.next_if_eq(&'/').is_some() {
+                        break;
                     }
                 }
-                _ => {
-                    return PrepareResult::SyntaxError;
-                }
+                // Keeps the words on either side of the comment apart
+                statement.push(' ');
+            }
+            _ => statement.push(c),
+        }
+    }
+    statements.push(statement);
+
+    statements
+        .into_iter()
+        .map(|statement| statement.trim().to_string())
+        .filter(|statement| !statement.is_empty())
+        .collect()
+}
+
+// Whether the text is a create trigger whose body hasn't reached its end yet
+fn is_open_trigger(statement: &str) -> bool {
+    let is_trigger = strip_keyword(statement.trim_start(), "create")
+        .and_then(|rest| strip_keyword(rest.trim_start(), "trigger"))
+        .is_some();
+    is_trigger && strip_last_keyword(statement.trim_end(), "end").is_none()
+}
+
+// The input without the keyword it ends with as a whole word
+fn strip_last_keyword<'a>(input: &'a str, keyword: &str) -> Option<&'a str> {
+    let start = input.len().checked_sub(keyword.len())?;
+    let rest = input.get(..start)?;
+    (input[start..].eq_ignore_ascii_case(keyword)
+        && !rest.ends_with(|c: char| c.is_alphanumeric() || c == '_'))
+    .then_some(rest)
+}
+
+// Replaces the <prefix>.<column> references of a trigger body statement
+// with ? placeholders, returning the statement and the columns in the
+// order of their placeholders
+pub fn row_references(sql: &str, prefix: &str) -> (String, Vec<String>) {
+    let is_name = |c: char| c.is_alphanumeric() || c == '_';
+    let mut rewritten = String::new();
+    let mut columns = Vec::new();
+    let mut in_string = false;
+    let mut rest = sql;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '\'' {
+            in_string = !in_string;
+        } else if !in_string && !rewritten.ends_with(is_name) {
+            let reference = strip_keyword(rest, prefix)
+                .and_then(|after| after.strip_prefix('.'))
+                .map(|after| after.split(|c: char| !is_name(c)).next().unwrap_or(""));
+            if let Some(column) = reference.filter(|column| !column.is_empty()) {
+                columns.push(column.to_lowercase());
+                rewritten.push('?');
+                rest = &rest[prefix.len() + 1 + column.len()..];
+                continue;
+            }
+        }
+        rewritten.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    (rewritten, columns)
+}
+
+// The rest of a string literal whose opening quote is next, None when it is
+// never closed
+fn quoted(chars: &mut Peekable<CharIndices>) -> Option<String> {
+    chars.next();
+    let mut literal = String::new();
+    loop {
+        match chars.next()? {
+            (_, '\'') if chars.next_if(|&(_, c)| c == '\'').is_none() => return Some(literal),
+            (_, c) => literal.push(c),
+        }
+    }
+}
+
+// Split the positional insert's values on whitespace, a quoted value may
+// contain spaces and is kept as a Str so a quoted '?' is not a placeholder
+fn split_values(input: &str) -> Result<Vec<(Token, Range<usize>)>, SyntaxError> {
+    let mut values = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        let value = if c.is_whitespace() {
+            chars.next();
+            continue;
+        } else if c == '\'' {
+            match quoted(&mut chars) {
+                Some(literal) => Token::Str(literal),
+                None => return Err(SyntaxError::new(start..input.len(), "unterminated string")),
+            }
+        } else {
+            let mut word = String::new();
+            while let Some((_, c)) = chars.next_if(|&(_, c)| !c.is_whitespace()) {
+                word.push(c);
+            }
+            Token::Word(word)
+        };
+
+        let end = chars.peek().map_or(input.len(), |&(end, _)| end);
+        values.push((value, start..end));
+    }
+
+    Ok(values)
+}
+
+// Walks over the tokens of a single statement
+struct Parser {
+    tokens: Vec<Token>,
+    spans: Vec<Range<usize>>,
+    // Length of the input, where errors at its end point to
+    end: usize,
+    pos: usize,
+    // Number of ? placeholders seen so far
+    parameters: usize,
+    // The furthest token looked at and what would have been accepted there,
+    // a failed parse is reported at that token
+    furthest: Cell<usize>,
+    expected: RefCell<Vec<String>>,
+    // Set for input that parses but makes no sense, replaces the expected list
+    failure: RefCell<Option<String>>,
+}
+
+impl Parser {
+    fn new(input: &str) -> Result<Self, SyntaxError> {
+        let (tokens, spans) = tokenize(input)?.into_iter().unzip();
+        Ok(Self {
+            tokens,
+            spans,
+            end: input.len(),
+            pos: 0,
+            parameters: 0,
+            furthest: Cell::new(0),
+            expected: RefCell::new(Vec::new()),
+            failure: RefCell::new(None),
+        })
+    }
+
+    // Numbers the ? that was just eaten
+    fn parameter(&mut self) -> usize {
+        self.parameters += 1;
+        self.parameters - 1
+    }
+
+    fn reach(&self, pos: usize) {
+        if pos > self.furthest.get() {
+            self.furthest.set(pos);
+            self.expected.borrow_mut().clear();
+        }
+    }
+
+    // Records what would have been accepted at the token
+    fn expect_at(&self, pos: usize, what: &str) {
+        self.reach(pos);
+        let mut expected = self.expected.borrow_mut();
+        if pos == self.furthest.get() && !expected.iter().any(|other| other == what) {
+            expected.push(what.to_string());
+        }
+    }
+
+    fn expect(&self, what: &str) {
+        self.expect_at(self.pos, what);
+    }
+
+    // Reports the error at the token whatever was looked at after it
+    fn fail_at(&self, pos: usize, message: &str) {
+        self.furthest.set(pos);
+        self.expected.borrow_mut().clear();
+        *self.failure.borrow_mut() = Some(message.to_string());
+    }
+
+    fn look(&self) -> Option<&Token> {
+        self.reach(self.pos);
+        self.tokens.get(self.pos)
+    }
+
+    // The furthest token looked at with what was expected there
+    fn error(&self) -> SyntaxError {
+        let pos = self.furthest.get();
+        let span = self.spans.get(pos).cloned().unwrap_or(self.end..self.end);
+        let expected = self.expected.borrow();
+        let message = match expected.split_last() {
+            _ if self.failure.borrow().is_some() => self.failure.borrow().clone().unwrap(),
+            Some((last, [])) => format!("expected {}", last),
+            Some((last, others)) => format!("expected {} or {}", others.join(", "), last),
+            None if pos < self.tokens.len() => "unexpected input".to_string(),
+            None => "unexpected end of statement".to_string(),
+        };
+        SyntaxError { span, message }
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.reach(self.pos);
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn is_done(&self) -> bool {
+        let done = self.look().is_none();
+        if !done {
+            self.expect("end of statement");
+        }
+        done
+    }
+
+    fn peek_word(&self) -> Option<&str> {
+        match self.look() {
+            Some(Token::Word(word)) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn peek_symbol(&self, symbol: char) -> bool {
+        self.look() == Some(&Token::Symbol(symbol))
+    }
+
+    fn eat_symbol(&mut self, symbol: char) -> bool {
+        if self.look() == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            true
+        } else {
+            self.expect(&format!("'{}'", symbol));
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.look() {
+            Some(Token::Word(word)) if word == keyword => {
+                self.pos += 1;
+                true
+            }
+            _ => {
+                self.expect(keyword);
+                false
+            }
+        }
+    }
+
+    // A table name or other identifier
+    fn name(&mut self) -> Option<String> {
+        match self.look() {
+            Some(Token::Word(word)) => {
+                let word = word.clone();
+                self.pos += 1;
+                Some(word)
+            }
+            _ => {
+                self.expect("a name");
+                None
+            }
+        }
+    }
+
+    // <name>, ...) following an opening parenthesis, each name only once
+    fn column_list(&mut self) -> Option<Vec<String>> {
+        let mut columns = Vec::new();
+        loop {
+            let start = self.pos;
+            let column = self.name()?;
+            if columns.contains(&column) {
+                self.fail_at(start, "duplicate column");
+                return None;
+            }
+            columns.push(column);
+            if !self.eat_symbol(',') {
+                break;
+            }
+        }
+        if !self.eat_symbol(')') {
+            return None;
+        }
+        Some(columns)
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        match self.look() {
+            Some(Token::Word(word)) if word.parse::<usize>().is_ok() => {
+                let number = word.parse().ok();
+                self.pos += 1;
+                number
+            }
+            _ => {
+                self.expect("a number");
+                None
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        match self.look() {
+            Some(Token::Str(literal)) => {
+                let literal = literal.clone();
+                self.pos += 1;
+                Some(literal)
+            }
+            _ => {
+                self.expect("a string");
+                None
+            }
+        }
+    }
+
+    fn compare_op(&mut self) -> Option<CompareOp> {
+        let op = if self.eat_symbol('=') {
+            CompareOp::Equal
+        } else if self.eat_symbol('!') {
+            if !self.eat_symbol('=') {
+                return None;
+            }
+            CompareOp::NotEqual
+        } else if self.eat_symbol('<') {
+            if self.eat_symbol('=') {
+                CompareOp::LessEqual
+            } else if self.eat_symbol('>') {
+                CompareOp::NotEqual
+            } else {
+                CompareOp::Less
+            }
+        } else if self.eat_symbol('>') {
+            if self.eat_symbol('=') {
+                CompareOp::GreaterEqual
+            } else {
+                CompareOp::Greater
+            }
+        } else {
+            return None;
+        };
+        Some(op)
+    }
+
+    // A column qualified by its table name, as in users.id
+    fn qualified_column(&mut self) -> Option<(String, String)> {
+        let table = self.name()?;
+        if !self.eat_symbol('.') {
+            return None;
+        }
+        Some((table, self.name()?))
+    }
+
+    // [left [outer] | inner] join <table> on <table>.<column> = <table>.<column>
+    fn join(&mut self, left_table: &str) -> Option<Join> {
+        let kind = if self.eat_keyword("left") {
+            self.eat_keyword("outer");
+            JoinKind::Left
+        } else {
+            self.eat_keyword("inner");
+            JoinKind::Inner
+        };
+        if !self.eat_keyword("join") {
+            return None;
+        }
+        let right_table = self.name()?;
+
+        if !self.eat_keyword("on") {
+            return None;
+        }
+        let (first_table, first_column) = self.qualified_column()?;
+        if !self.eat_symbol('=') {
+            return None;
+        }
+        let (second_table, second_column) = self.qualified_column()?;
+
+        // The ON columns may be written in either order
+        let (left_column, right_column) =
+            if first_table == left_table && second_table == right_table {
+                (first_column, second_column)
+            } else if first_table == right_table && second_table == left_table {
+                (second_column, first_column)
+            } else {
+                return None;
+            };
+
+        Some(Join {
+            kind,
+            right_table,
+            left_column,
+            right_column,
+        })
+    }
+
+    // [as <name>] after an item of the select list. Only None when it is
+    // there but malformed, otherwise Some of the name.
+    fn alias(&mut self) -> Option<Option<String>> {
+        if !self.eat_keyword("as") {
+            return Some(None);
+        }
+        Some(Some(self.name()?))
+    }
+
+    // An expression or an aggregate call in the select list
+    fn select_item(&mut self) -> Option<SelectItem> {
+        if self.peek_word().is_some_and(is_aggregate_function) {
+            Some(SelectItem::Aggregate(self.aggregate()?))
+        } else {
+            Some(SelectItem::Expr(self.expr()?))
+        }
+    }
+
+    // [returning * | returning <expr>, ...], an empty list standing for
+    // the whole row. Only None when the clause is there but malformed,
+    // otherwise Some of whether it is there.
+    fn returning(&mut self) -> Option<Option<Vec<Expr>>> {
+        if !self.eat_keyword("returning") {
+            return Some(None);
+        }
+        if self.eat_symbol('*') {
+            return Some(Some(Vec::new()));
+        }
+        let mut items = vec![self.expr()?];
+        while self.eat_symbol(',') {
+            items.push(self.expr()?);
+        }
+        Some(Some(items))
+    }
+
+    // An aggregate call such as count(*) or sum(id)
+    fn aggregate(&mut self) -> Option<Aggregate> {
+        let function = self.name()?;
+        if !self.eat_symbol('(') {
+            return None;
+        }
+
+        let aggregate = if function == "count" {
+            if !self.eat_symbol('*') {
+                return None;
+            }
+            Aggregate::CountStar
+        } else {
+            let column = self.name()?;
+            match function.as_str() {
+                "sum" => Aggregate::Sum(column),
+                "avg" => Aggregate::Avg(column),
+                "min" => Aggregate::Min(column),
+                "max" => Aggregate::Max(column),
+                _ => return None,
+            }
+        };
+
+        if !self.eat_symbol(')') {
+            return None;
+        }
+        Some(aggregate)
+    }
+
+    // A number (optionally negative), a quoted string, a blob written as
+    // x'<hex digits>', date '<date>', timestamp '<date time>', true, false
+    // or null
+    fn literal(&mut self) -> Option<Value> {
+        let start = self.pos;
+        let value = self.literal_value();
+        if value.is_none() {
+            self.expect_at(start, "a value");
+        }
+        value
+    }
+
+    fn literal_value(&mut self) -> Option<Value> {
+        let negative = self.eat_symbol('-');
+        let value = match self.next()?.clone() {
+            Token::Str(literal) => Value::Text(literal),
+            Token::Word(word) => match word.as_str() {
+                "null" => Value::Null,
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                "x" => Value::Blob(parse_hex(&self.string()?)?),
+                "timestamp" => parse_timestamp(&self.string()?)?,
+                "date" => match parse_timestamp(&self.string()?)? {
+                    Value::Timestamp(seconds) => {
+                        Value::Date(seconds - seconds.rem_euclid(SECONDS_PER_DAY))
+                    }
+                    _ => return None,
+                },
+                _ => match word.parse::<i64>() {
+                    Ok(number) => Value::Integer(number),
+                    Err(_) => Value::Real(word.parse().ok()?),
+                },
+            },
+            Token::Symbol(_) => return None,
+        };
+
+        match value {
+            _ if !negative => Some(value),
+            Value::Integer(number) => Some(Value::Integer(-number)),
+            Value::Real(number) => Some(Value::Real(-number)),
+            _ => None,
+        }
+    }
+
+    // (<expr>) following the check keyword
+    fn check(&mut self) -> Option<Expr> {
+        if !self.eat_symbol('(') {
+            return None;
+        }
+        let expr = self.expr()?;
+        if !self.eat_symbol(')') {
+            return None;
+        }
+        Some(expr)
+    }
+
+    // <expr> [or <expr>]...
+    fn expr(&mut self) -> Option<Expr> {
+        let mut expr = self.and_expr()?;
+        while self.eat_keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and_expr()?));
+        }
+        Some(expr)
+    }
+
+    fn and_expr(&mut self) -> Option<Expr> {
+        let mut expr = self.not_expr()?;
+        while self.eat_keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not_expr()?));
+        }
+        Some(expr)
+    }
+
+    fn not_expr(&mut self) -> Option<Expr> {
+        if self.eat_keyword("not") {
+            return Some(Expr::Not(Box::new(self.not_expr()?)));
+        }
+        self.comparison()
+    }
+
+    // <sum> [<op> <sum> | like|glob|regexp '<pattern>' | in (<exprs>) |
+    // between <sum> and <sum>]
+    fn comparison(&mut self) -> Option<Expr> {
+        let left = Box::new(self.sum()?);
+
+        if self.eat_keyword("like") {
+            return Some(Expr::Like(left, self.string()?));
+        }
+        if self.eat_keyword("glob") {
+            return Some(Expr::Glob(left, self.string()?));
+        }
+        if self.eat_keyword("regexp") {
+            return self.regexp(*left);
+        }
+        if self.eat_keyword("in") {
+            if !self.eat_symbol('(') {
+                return None;
+            }
+            let mut list = vec![self.expr()?];
+            while self.eat_symbol(',') {
+                list.push(self.expr()?);
+            }
+            if !self.eat_symbol(')') {
+                return None;
+            }
+            return Some(Expr::In(left, list));
+        }
+        if self.eat_keyword("between") {
+            // The and here belongs to the between, not to a conjunction
+            let low = Box::new(self.sum()?);
+            if !self.eat_keyword("and") {
+                return None;
+            }
+            return Some(Expr::Between(left, low, Box::new(self.sum()?)));
+        }
+
+        let start = self.pos;
+        match self.compare_op() {
+            Some(op) => Some(Expr::Compare(left, op, Box::new(self.sum()?))),
+            None => {
+                self.pos = start;
+                Some(*left)
             }
+        }
+    }
 
-            return PrepareResult::Success;
+    // The pattern is compiled once, when the statement is prepared
+    #[cfg(feature = "regexp")]
+    fn regexp(&mut self, left: Expr) -> Option<Expr> {
+        let start = self.pos;
+        let pattern = self.string()?;
+        match regex::Regex::new(&pattern) {
+            Ok(regex) => Some(Expr::Regexp(Box::new(left), regex)),
+            Err(_) => {
+                self.fail_at(start, "invalid regular expression");
+                None
+            }
         }
+    }
+
+    #[cfg(not(feature = "regexp"))]
+    fn regexp(&mut self, _left: Expr) -> Option<Expr> {
+        self.fail_at(self.pos - 1, "regexp needs the regexp feature");
+        None
+    }
 
-        if input_buffer.buffer == "select" {
-            self.stype = StatementType::Select;
-            return PrepareResult::Success;
+    // <product> [+|- <product>]...
+    fn sum(&mut self) -> Option<Expr> {
+        let mut expr = self.product()?;
+        loop {
+            let op = if self.eat_symbol('+') {
+                ArithOp::Add
+            } else if self.eat_symbol('-') {
+                ArithOp::Subtract
+            } else {
+                return Some(expr);
+            };
+            expr = Expr::Arithmetic(Box::new(expr), op, Box::new(self.product()?));
         }
+    }
+
+    // <concat> [*|/|% <concat>]...
+    fn product(&mut self) -> Option<Expr> {
+        let mut expr = self.concat()?;
+        loop {
+            let op = if self.eat_symbol('*') {
+                ArithOp::Multiply
+            } else if self.eat_symbol('/') {
+                ArithOp::Divide
+            } else if self.eat_symbol('%') {
+                ArithOp::Remainder
+            } else {
+                return Some(expr);
+            };
+            expr = Expr::Arithmetic(Box::new(expr), op, Box::new(self.concat()?));
+        }
+    }
+
+    // <unary> [|| <unary>]...
+    fn concat(&mut self) -> Option<Expr> {
+        let mut expr = self.unary()?;
+        while self.peek_symbol('|') {
+            let start = self.pos;
+            self.pos += 1;
+            if !self.eat_symbol('|') {
+                self.pos = start;
+                break;
+            }
+            expr = Expr::Concat(Box::new(expr), Box::new(self.unary()?));
+        }
+        Some(expr)
+    }
+
+    // [-]<primary> [collate <name>]
+    fn unary(&mut self) -> Option<Expr> {
+        if self.eat_symbol('-') {
+            return Some(Expr::Negate(Box::new(self.unary()?)));
+        }
+        let mut expr = self.primary()?;
+        while self.eat_keyword("collate") {
+            expr = Expr::Collate(Box::new(expr), self.collation()?);
+        }
+        Some(expr)
+    }
+
+    // binary or nocase
+    fn collation(&mut self) -> Option<Collation> {
+        let start = self.pos;
+        let name = self.name()?;
+        let collation = Collation::from_name(&name);
+        if collation.is_none() {
+            self.fail_at(start, "unknown collation");
+        }
+        collation
+    }
+
+    // A literal, a column, one qualified by its table as in users.id, a
+    // function call or a parenthesized expression
+    fn primary(&mut self) -> Option<Expr> {
+        if self.eat_symbol('?') {
+            return Some(Expr::Parameter(self.parameter()));
+        }
+        if self.eat_symbol('(') {
+            let expr = self.expr()?;
+            if !self.eat_symbol(')') {
+                return None;
+            }
+            return Some(expr);
+        }
+
+        let start = self.pos;
+        if let Some(value) = self.literal() {
+            return Some(Expr::Literal(value));
+        }
+        self.pos = start;
+
+        let name = self.name()?;
+        if name == "case" {
+            return self.case();
+        }
+        if self.peek_symbol('.') {
+            self.pos += 1;
+            let column = self.name()?;
+            return Some(Expr::Column(format!("{}.{}", name, column)));
+        }
+        if !self.eat_symbol('(') {
+            return Some(Expr::Column(name));
+        }
+        if name == "cast" {
+            return self.cast();
+        }
+        let Some(function) = Function::from_name(&name) else {
+            self.fail_at(start, "unknown function");
+            return None;
+        };
+        let mut args = Vec::new();
+        if !self.eat_symbol(')') {
+            args.push(self.expr()?);
+            while self.eat_symbol(',') {
+                args.push(self.expr()?);
+            }
+            if !self.eat_symbol(')') {
+                return None;
+            }
+        }
+        if !function.arity().contains(&args.len()) {
+            self.fail_at(start, "wrong number of arguments");
+            return None;
+        }
+        Some(Expr::Function(function, args))
+    }
+
+    // [<expr>] when <expr> then <expr> ... [else <expr>] end following "case"
+    fn case(&mut self) -> Option<Expr> {
+        let operand = match self.peek_word() {
+            Some("when") => None,
+            _ => Some(Box::new(self.expr()?)),
+        };
+
+        let mut branches = Vec::new();
+        while self.eat_keyword("when") {
+            let condition = self.expr()?;
+            if !self.eat_keyword("then") {
+                return None;
+            }
+            branches.push((condition, self.expr()?));
+        }
+        if branches.is_empty() {
+            return None;
+        }
+
+        let otherwise = if self.eat_keyword("else") {
+            Some(Box::new(self.expr()?))
+        } else {
+            None
+        };
+        if !self.eat_keyword("end") {
+            return None;
+        }
+
+        Some(Expr::Case {
+            operand,
+            branches,
+            otherwise,
+        })
+    }
+
+    // <expr> as <type>) following "cast("
+    fn cast(&mut self) -> Option<Expr> {
+        let expr = self.expr()?;
+        if !self.eat_keyword("as") {
+            return None;
+        }
+        let ctype = self.column_type()?;
+        if !self.eat_symbol(')') {
+            return None;
+        }
+        Some(Expr::Cast(Box::new(expr), ctype))
+    }
+
+    // <name> <type> [default <literal>] [not null] [unique] [primary key]
+    // [collate <name>] [check (<expr>)], where text and blob columns may
+    // give their size in bytes as in text(32). Checks are added to the
+    // table's list.
+    fn column_def(&mut self, checks: &mut Vec<Expr>) -> Option<ColumnDef> {
+        let name = self.name()?;
+        let ctype = self.column_type()?;
+
+        // Constraints may come in any order
+        let mut column = ColumnDef::new(&name, ctype);
+        loop {
+            if self.eat_keyword("default") {
+                column.default = self.literal()?;
+            } else if self.eat_keyword("not") {
+                if !self.eat_keyword("null") {
+                    return None;
+                }
+                column.not_null = true;
+            } else if self.eat_keyword("unique") {
+                column.unique = true;
+            } else if self.eat_keyword("primary") {
+                if !self.eat_keyword("key") {
+                    return None;
+                }
+                column.primary_key = true;
+                column.not_null = true;
+            } else if self.eat_keyword("collate") {
+                column.collation = self.collation()?;
+            } else if self.eat_keyword("check") {
+                checks.push(self.check()?);
+            } else {
+                break;
+            }
+        }
+        Some(column)
+    }
+
+    // A type name such as integer or text(32)
+    fn column_type(&mut self) -> Option<ColumnType> {
+        let start = self.pos;
+        let type_name = self.name()?;
+        let size = if self.eat_symbol('(') {
+            let size = self.number()?;
+            if !self.eat_symbol(')') {
+                return None;
+            }
+            Some(size)
+        } else {
+            None
+        };
+
+        let ctype = match (type_name.as_str(), size) {
+            ("integer" | "int", None) => ColumnType::Integer,
+            ("real" | "float" | "double", None) => ColumnType::Real,
+            ("boolean" | "bool", None) => ColumnType::Boolean,
+            ("date", None) => ColumnType::Date,
+            ("timestamp" | "datetime", None) => ColumnType::Timestamp,
+            ("text" | "varchar" | "char", size) => {
+                ColumnType::Text(size.unwrap_or(DEFAULT_TEXT_SIZE))
+            }
+            ("blob", size) => ColumnType::Blob(size.unwrap_or(DEFAULT_TEXT_SIZE)),
+            _ => {
+                self.fail_at(start, "unknown column type");
+                return None;
+            }
+        };
+        Some(ctype)
+    }
+}
+
+// 'YYYY-MM-DD' or 'YYYY-MM-DD HH:MM:SS' (a T may separate the date and the
+// time) as a timestamp in UTC
+pub fn parse_timestamp(text: &str) -> Option<Value> {
+    let text = text.trim();
+    let (date, time) = match text.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time.trim())),
+        None => (text, None),
+    };
+
+    let date_parts: Vec<i64> =
+        (date.split('-').map(|part| part.parse().ok())).collect::<Option<_>>()?;
+    let [year, month, day] = date_parts[..] else {
+        return None;
+    };
+    let month_length = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if !(1..=month_length).contains(&day) {
+        return None;
+    }
+
+    let mut seconds = 0;
+    if let Some(time) = time {
+        let parts: Vec<i64> =
+            (time.split(':').map(|part| part.parse().ok())).collect::<Option<_>>()?;
+        let (hour, minute, second) = match parts[..] {
+            [hour, minute] => (hour, minute, 0),
+            [hour, minute, second] => (hour, minute, second),
+            _ => return None,
+        };
+        if !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..60).contains(&second) {
+            return None;
+        }
+        seconds = hour * 3600 + minute * 60 + second;
+    }
+
+    Some(Value::Timestamp(
+        days_from_civil(year, month, day) * SECONDS_PER_DAY + seconds,
+    ))
+}
+
+// Parses the SQL text of a single expression, as stored for CHECK
+// constraints
+pub fn parse_expr(text: &str) -> Option<Expr> {
+    let mut parser = Parser::new(text).ok()?;
+    let expr = parser.expr()?;
+    if !parser.is_done() {
+        return None;
+    }
+    Some(expr)
+}
+
+// Two hex digits per byte
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn is_aggregate_function(name: &str) -> bool {
+    matches!(name, "count" | "sum" | "avg" | "min" | "max")
+}
+
+// Words that end the select list when it is left out
+fn is_clause_keyword(word: &str) -> bool {
+    matches!(
+        word,
+        "from" | "where" | "group" | "having" | "order" | "limit" | "offset" | "union"
+    )
+}
+
+#[derive(Clone, Copy)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Clone, Copy)]
+pub enum ArithOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+// Scalar functions, called once per row
+#[derive(Clone, Copy)]
+pub enum Function {
+    Upper,
+    Lower,
+    Length,
+    // substr(text, start [, length]) with start counted from 1
+    Substr,
+    // trim(text [, characters]), spaces when no characters are given
+    Trim,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "upper" => Some(Function::Upper),
+            "lower" => Some(Function::Lower),
+            "length" => Some(Function::Length),
+            "substr" | "substring" => Some(Function::Substr),
+            "trim" => Some(Function::Trim),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Function::Upper => "upper",
+            Function::Lower => "lower",
+            Function::Length => "length",
+            Function::Substr => "substr",
+            Function::Trim => "trim",
+        }
+    }
+
+    // Number of arguments the function takes
+    fn arity(self) -> std::ops::RangeInclusive<usize> {
+        match self {
+            Function::Upper | Function::Lower | Function::Length => 1..=1,
+            Function::Substr => 2..=3,
+            Function::Trim => 1..=2,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Expr {
+    Literal(Value),
+    // A ? placeholder, numbered from 0 in the order they appear
+    Parameter(usize),
+    Column(String),
+    Arithmetic(Box<Expr>, ArithOp, Box<Expr>),
+    Negate(Box<Expr>),
+    // The || operator
+    Concat(Box<Expr>, Box<Expr>),
+    Function(Function, Vec<Expr>),
+    Cast(Box<Expr>, ColumnType),
+    // <expr> collate <name>: compare the value with the collation
+    Collate(Box<Expr>, Collation),
+    // The first branch whose condition is true, or whose value equals the
+    // operand when there is one. NULL when no branch matches and there is
+    // no else.
+    Case {
+        operand: Option<Box<Expr>>,
+        branches: Vec<(Expr, Expr)>,
+        otherwise: Option<Box<Expr>>,
+    },
+    Compare(Box<Expr>, CompareOp, Box<Expr>),
+    // Matches with % (any sequence) and _ (any single character)
+    Like(Box<Expr>, String),
+    // Matches with * (any sequence), ? (any single character) and [...]
+    // (one character of a set), case sensitive
+    Glob(Box<Expr>, String),
+    // Matches when the regular expression matches somewhere in the value
+    #[cfg(feature = "regexp")]
+    Regexp(Box<Expr>, regex::Regex),
+    In(Box<Expr>, Vec<Expr>),
+    // Inclusive on both ends
+    Between(Box<Expr>, Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+impl Expr {
+    // Calls f on the expression and on every expression inside it
+    pub fn walk<'a>(&'a self, f: &mut impl FnMut(&'a Expr)) {
+        f(self);
+        match self {
+            Expr::Literal(_) | Expr::Parameter(_) | Expr::Column(_) => {}
+            Expr::Arithmetic(left, _, right)
+            | Expr::Concat(left, right)
+            | Expr::Compare(left, _, right)
+            | Expr::And(left, right)
+            | Expr::Or(left, right) => {
+                left.walk(f);
+                right.walk(f);
+            }
+            Expr::Negate(inner)
+            | Expr::Not(inner)
+            | Expr::Like(inner, _)
+            | Expr::Glob(inner, _)
+            | Expr::Cast(inner, _)
+            | Expr::Collate(inner, _) => inner.walk(f),
+            #[cfg(feature = "regexp")]
+            Expr::Regexp(inner, _) => inner.walk(f),
+            Expr::In(inner, list) => {
+                inner.walk(f);
+                for item in list {
+                    item.walk(f);
+                }
+            }
+            Expr::Function(_, args) => {
+                for arg in args {
+                    arg.walk(f);
+                }
+            }
+            Expr::Case {
+                operand,
+                branches,
+                otherwise,
+            } => {
+                let operands = operand.iter().chain(otherwise);
+                for expr in operands {
+                    expr.walk(f);
+                }
+                for (condition, value) in branches {
+                    condition.walk(f);
+                    value.walk(f);
+                }
+            }
+            Expr::Between(inner, low, high) => {
+                inner.walk(f);
+                low.walk(f);
+                high.walk(f);
+            }
+        }
+    }
+
+    // Adds the names of the columns the expression reads
+    pub fn column_names<'a>(&'a self, names: &mut Vec<&'a str>) {
+        self.walk(&mut |expr| {
+            if let Expr::Column(name) = expr {
+                names.push(name);
+            }
+        });
+    }
+
+    // One past the highest placeholder number in the expression
+    fn parameter_count(&self) -> usize {
+        let mut count = 0;
+        self.walk(&mut |expr| {
+            if let Expr::Parameter(index) = expr {
+                count = count.max(index + 1);
+            }
+        });
+        count
+    }
+
+    // Replaces every placeholder with its value
+    fn bind(&mut self, values: &[Value]) {
+        self.walk_mut(&mut |expr| {
+            if let Expr::Parameter(index) = expr {
+                *expr = Expr::Literal(values[*index].clone());
+            }
+        });
+    }
+
+    // Calls f on the expression, then on every expression inside what f
+    // left of it
+    fn walk_mut(&mut self, f: &mut impl FnMut(&mut Expr)) {
+        f(self);
+        match self {
+            Expr::Literal(_) | Expr::Parameter(_) | Expr::Column(_) => {}
+            Expr::Arithmetic(left, _, right)
+            | Expr::Concat(left, right)
+            | Expr::Compare(left, _, right)
+            | Expr::And(left, right)
+            | Expr::Or(left, right) => {
+                left.walk_mut(f);
+                right.walk_mut(f);
+            }
+            Expr::Negate(inner)
+            | Expr::Not(inner)
+            | Expr::Like(inner, _)
+            | Expr::Glob(inner, _)
+            | Expr::Cast(inner, _)
+            | Expr::Collate(inner, _) => inner.walk_mut(f),
+            #[cfg(feature = "regexp")]
+            Expr::Regexp(inner, _) => inner.walk_mut(f),
+            Expr::In(inner, list) => {
+                inner.walk_mut(f);
+                for item in list {
+                    item.walk_mut(f);
+                }
+            }
+            Expr::Function(_, args) => {
+                for arg in args {
+                    arg.walk_mut(f);
+                }
+            }
+            Expr::Case {
+                operand,
+                branches,
+                otherwise,
+            } => {
+                let operands = operand.iter_mut().chain(otherwise);
+                for expr in operands {
+                    expr.walk_mut(f);
+                }
+                for (condition, value) in branches {
+                    condition.walk_mut(f);
+                    value.walk_mut(f);
+                }
+            }
+            Expr::Between(inner, low, high) => {
+                inner.walk_mut(f);
+                low.walk_mut(f);
+                high.walk_mut(f);
+            }
+        }
+    }
+}
+
+impl fmt::Display for ArithOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            ArithOp::Add => "+",
+            ArithOp::Subtract => "-",
+            ArithOp::Multiply => "*",
+            ArithOp::Divide => "/",
+            ArithOp::Remainder => "%",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            CompareOp::Equal => "=",
+            CompareOp::NotEqual => "<>",
+            CompareOp::Less => "<",
+            CompareOp::LessEqual => "<=",
+            CompareOp::Greater => ">",
+            CompareOp::GreaterEqual => ">=",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+// SQL text of the expression, parenthesized so it parses back the same way
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Literal(value) => write!(f, "{}", value.to_sql()),
+            Expr::Parameter(_) => write!(f, "?"),
+            Expr::Column(name) => write!(f, "{}", name),
+            Expr::Arithmetic(left, op, right) => write!(f, "({} {} {})", left, op, right),
+            Expr::Negate(inner) => write!(f, "(-{})", inner),
+            Expr::Concat(left, right) => write!(f, "({} || {})", left, right),
+            Expr::Cast(inner, ctype) => write!(f, "cast({} as {})", inner, ctype),
+            Expr::Collate(inner, collation) => write!(f, "({} collate {})", inner, collation),
+            Expr::Case {
+                operand,
+                branches,
+                otherwise,
+            } => {
+                write!(f, "case")?;
+                if let Some(operand) = operand {
+                    write!(f, " {}", operand)?;
+                }
+                for (condition, value) in branches {
+                    write!(f, " when {} then {}", condition, value)?;
+                }
+                if let Some(otherwise) = otherwise {
+                    write!(f, " else {}", otherwise)?;
+                }
+                write!(f, " end")
+            }
+            Expr::Function(function, args) => {
+                let args: Vec<String> = args.iter().map(Expr::to_string).collect();
+                write!(f, "{}({})", function.name(), args.join(", "))
+            }
+            Expr::Compare(left, op, right) => write!(f, "({} {} {})", left, op, right),
+            Expr::Like(inner, pattern) => {
+                let pattern = Value::Text(pattern.clone()).to_sql();
+                write!(f, "({} like {})", inner, pattern)
+            }
+            Expr::Glob(inner, pattern) => {
+                let pattern = Value::Text(pattern.clone()).to_sql();
+                write!(f, "({} glob {})", inner, pattern)
+            }
+            #[cfg(feature = "regexp")]
+            Expr::Regexp(inner, regex) => {
+                let pattern = Value::Text(regex.as_str().to_string()).to_sql();
+                write!(f, "({} regexp {})", inner, pattern)
+            }
+            Expr::In(inner, list) => {
+                let list: Vec<String> = list.iter().map(Expr::to_string).collect();
+                write!(f, "({} in ({}))", inner, list.join(", "))
+            }
+            Expr::Between(inner, low, high) => {
+                write!(f, "({} between {} and {})", inner, low, high)
+            }
+            Expr::And(left, right) => write!(f, "({} and {})", left, right),
+            Expr::Or(left, right) => write!(f, "({} or {})", left, right),
+            Expr::Not(inner) => write!(f, "(not {})", inner),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Aggregate {
+    // count(*)
+    CountStar,
+    Sum(String),
+    Avg(String),
+    Min(String),
+    Max(String),
+}
+
+impl Aggregate {
+    // The column the aggregate reads, None for count(*)
+    pub fn column(&self) -> Option<&str> {
+        match self {
+            Aggregate::CountStar => None,
+            Aggregate::Sum(column)
+            | Aggregate::Avg(column)
+            | Aggregate::Min(column)
+            | Aggregate::Max(column) => Some(column),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Aggregate::CountStar => write!(f, "count(*)"),
+            Aggregate::Sum(column) => write!(f, "sum({})", column),
+            Aggregate::Avg(column) => write!(f, "avg({})", column),
+            Aggregate::Min(column) => write!(f, "min({})", column),
+            Aggregate::Max(column) => write!(f, "max({})", column),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum SelectItem {
+    Expr(Expr),
+    Aggregate(Aggregate),
+}
+
+// HAVING filters groups on the value of an aggregate
+#[derive(Clone)]
+pub struct Having {
+    pub aggregate: Aggregate,
+    pub op: CompareOp,
+    pub value: Value,
+}
+
+#[derive(Clone)]
+pub enum JoinKind {
+    Inner,
+    // Left rows without a match are kept, paired with NULLs
+    Left,
+}
+
+// Rows of the statement's table paired with the rows of the right table
+// whose right_column equals the left row's left_column
+#[derive(Clone)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub right_table: String,
+    pub left_column: String,
+    pub right_column: String,
+}
+
+// A select whose rows are appended to the ones of the select before it
+#[derive(Clone)]
+pub struct Union {
+    // UNION ALL keeps duplicate rows
+    pub all: bool,
+    pub select: Box<SelectStmt>,
+}
+
+#[derive(Clone)]
+pub struct OrderBy {
+    pub column: String,
+    // Overrides the column's collation
+    pub collation: Option<Collation>,
+    pub descending: bool,
+}
+
+// A parsed statement, produced by prepare and carried out by
+// execute_statement
+#[derive(Clone)]
+pub enum Statement {
+    Select(Box<SelectStmt>),
+    Insert(InsertStmt),
+    CreateTable(CreateTableStmt),
+    DropTable(String),
+    Delete(DeleteStmt),
+    AlterTable(AlterTableStmt),
+    CreateTrigger(CreateTriggerStmt),
+    CreateIndex(CreateIndexStmt),
+    Savepoint(String),
+    // Forgets the savepoint and the ones set after it
+    Release(String),
+    // Undoes everything done since the savepoint, which stays set
+    RollbackTo(String),
+    Explain(ExplainStmt),
+    Pragma(PragmaStmt),
+    // Rebuilds the database file without its free pages
+    Vacuum,
+}
+
+// select [<items>] [from <table> [<join>]] [where ...] [group by ...]
+// [having ...] [order by ...] [limit ...] [offset ...] [union <select>]
+#[derive(Clone)]
+pub struct SelectStmt {
+    // Common tables the select and the ones unioned with it can read from
+    pub with: Vec<CommonTable>,
+    pub table_name: String,
+    // Reads no table: the select list is evaluated once
+    pub constant_row: bool,
+    // Empty when selecting whole rows
+    pub select_list: Vec<SelectItem>,
+    // The names given to the items of the select list with AS, in order
+    pub aliases: Vec<Option<String>>,
+    pub join: Option<Join>,
+    pub where_clause: Option<Expr>,
+    pub group_by: Option<String>,
+    pub having: Option<Having>,
+    pub order_by: Option<OrderBy>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub union: Option<Union>,
+}
+
+// with <name> [(<columns>)] as (<select>): the rows of the select, read
+// like the rows of a table
+#[derive(Clone)]
+pub struct CommonTable {
+    pub name: String,
+    // Names given to the select's columns, None to name them after it
+    pub columns: Option<Vec<String>>,
+    pub select: SelectStmt,
+}
+
+#[derive(Clone)]
+pub struct InsertStmt {
+    pub table_name: String,
+    // Names given in the column list, None for all of them in order
+    pub columns: Option<Vec<String>>,
+    pub values: Vec<Value>,
+    // Position in values of each ? placeholder, in order
+    pub parameters: Vec<usize>,
+    // insert or replace: overwrite the row with the same primary key
+    pub or_replace: bool,
+    pub returning: Option<Vec<Expr>>,
+}
+
+// Removes the rows of the table matching the condition, every row without
+// one
+#[derive(Clone)]
+pub struct DeleteStmt {
+    pub table_name: String,
+    pub where_clause: Option<Expr>,
+    pub returning: Option<Vec<Expr>>,
+}
+
+#[derive(Clone)]
+pub struct CreateTableStmt {
+    pub table_name: String,
+    // Columns of the table being created
+    pub schema: Schema,
+}
+
+#[derive(Clone)]
+pub struct CreateTriggerStmt {
+    pub table_name: String,
+    pub trigger: Trigger,
+}
+
+#[derive(Clone)]
+pub struct CreateIndexStmt {
+    pub table_name: String,
+    pub index: Index,
+}
+
+// Adds the column to the end of the table's columns
+#[derive(Clone)]
+pub struct AlterTableStmt {
+    pub table_name: String,
+    pub column: ColumnDef,
+}
+
+// pragma <name> [= <value>]: reads or changes a setting of the database
+#[derive(Clone)]
+pub struct PragmaStmt {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+// explain <statement>: print the plan instead of executing it
+#[derive(Clone)]
+pub struct ExplainStmt {
+    // explain analyze <statement>: execute it and print the plan with what
+    // each node did
+    pub analyze: bool,
+    pub statement: Box<Statement>,
+}
+
+impl Statement {
+    // A copy of the statement with its placeholders replaced by values, the
+    // statement itself stays reusable
+    pub fn bind(&self, values: &[Value]) -> Result<Statement, PrepareError> {
+        if values.len() != self.parameter_count() {
+            return Err(PrepareError::ParameterCountMismatch);
+        }
+
+        let mut statement = self.clone();
+        statement.bind_values(values);
+        Ok(statement)
+    }
+
+    fn bind_values(&mut self, values: &[Value]) {
+        match self {
+            Statement::Insert(insert) => {
+                for (index, &position) in insert.parameters.iter().enumerate() {
+                    insert.values[position] = values[index].clone();
+                }
+                insert.parameters.clear();
+                for expr in insert.returning.iter_mut().flatten() {
+                    expr.bind(values);
+                }
+            }
+            Statement::Delete(delete) => {
+                let returning = delete.returning.iter_mut().flatten();
+                for expr in delete.where_clause.iter_mut().chain(returning) {
+                    expr.bind(values);
+                }
+            }
+            Statement::Select(select) => select.bind(values),
+            Statement::Explain(explain) => explain.statement.bind_values(values),
+            _ => {}
+        }
+    }
+
+    // Number of ? placeholders that have to be bound before executing
+    pub fn parameter_count(&self) -> usize {
+        match self {
+            // Placeholders in the values come before the ones in RETURNING
+            Statement::Insert(insert) => (insert.returning.iter().flatten())
+                .map(Expr::parameter_count)
+                .fold(insert.parameters.len(), usize::max),
+            Statement::Delete(delete) => (delete.where_clause.iter())
+                .chain(delete.returning.iter().flatten())
+                .map(Expr::parameter_count)
+                .max()
+                .unwrap_or(0),
+            Statement::Select(select) => select.parameter_count(),
+            Statement::Explain(explain) => explain.statement.parameter_count(),
+            _ => 0,
+        }
+    }
+
+    // Table and column names have to fit in the catalog and schema pages
+    fn check_names(&self) -> Result<(), PrepareError> {
+        let too_long = |name: &String| name.len() > TABLE_NAME_SIZE;
+        let fits = match self {
+            Statement::Select(select) => select.names_fit(),
+            Statement::Insert(insert) => !too_long(&insert.table_name),
+            Statement::CreateTable(create) => {
+                !too_long(&create.table_name)
+                    && (create.schema.columns.iter())
+                        .all(|column| column.name.len() <= COLUMN_NAME_SIZE)
+            }
+            Statement::CreateTrigger(create) => !too_long(&create.table_name),
+            Statement::CreateIndex(create) => {
+                !too_long(&create.table_name) && !too_long(&create.index.name)
+            }
+            Statement::Delete(delete) => !too_long(&delete.table_name),
+            Statement::DropTable(name) => !too_long(name),
+            Statement::AlterTable(alter) => {
+                !too_long(&alter.table_name) && alter.column.name.len() <= COLUMN_NAME_SIZE
+            }
+            Statement::Savepoint(_) | Statement::Release(_) | Statement::RollbackTo(_) => true,
+            Statement::Pragma(_) | Statement::Vacuum => true,
+            Statement::Explain(explain) => return explain.statement.check_names(),
+        };
+
+        if !fits {
+            return Err(PrepareError::StringTooLong);
+        }
+        Ok(())
+    }
+}
+
+impl SelectStmt {
+    // A select of the whole rows of the default table
+    pub fn new() -> Self {
+        Self {
+            with: Vec::new(),
+            table_name: DEFAULT_TABLE.to_string(),
+            constant_row: false,
+            select_list: Vec::new(),
+            aliases: Vec::new(),
+            join: None,
+            where_clause: None,
+            group_by: None,
+            having: None,
+            order_by: None,
+            limit: None,
+            offset: 0,
+            union: None,
+        }
+    }
+
+    // Table names of the select, the common tables and the selects unioned
+    // with it, and the common tables' column names
+    fn names_fit(&self) -> bool {
+        let joined = self.join.as_ref().map(|join| &join.right_table);
+        let tables_fit = (self.table_name.len() <= TABLE_NAME_SIZE)
+            && joined.is_none_or(|name| name.len() <= TABLE_NAME_SIZE);
+        let with_fits = self.with.iter().all(|common| {
+            let columns = common.columns.iter().flatten();
+            common.name.len() <= TABLE_NAME_SIZE
+                && columns
+                    .into_iter()
+                    .all(|column| column.len() <= COLUMN_NAME_SIZE)
+                && common.select.names_fit()
+        });
+        let union_fits = (self.union.as_ref()).is_none_or(|union| union.select.names_fit());
+        tables_fit && with_fits && union_fits
+    }
+
+    // Whether the select produces one row per group instead of one per row
+    pub fn is_aggregate(&self) -> bool {
+        self.group_by.is_some()
+            || self.having.is_some()
+            || self
+                .select_list
+                .iter()
+                .any(|item| matches!(item, SelectItem::Aggregate(_)))
+    }
+
+    // Expressions of the select list and the WHERE clause
+    fn exprs_mut(&mut self) -> impl Iterator<Item = &mut Expr> {
+        let items = self.select_list.iter_mut().filter_map(|item| match item {
+            SelectItem::Expr(expr) => Some(expr),
+            SelectItem::Aggregate(_) => None,
+        });
+        items.chain(&mut self.where_clause)
+    }
+
+    fn bind(&mut self, values: &[Value]) {
+        for common in &mut self.with {
+            common.select.bind(values);
+        }
+        for expr in self.exprs_mut() {
+            expr.bind(values);
+        }
+        if let Some(union) = &mut self.union {
+            union.select.bind(values);
+        }
+    }
+
+    // Placeholders are numbered across the whole statement, so this is one
+    // past the highest number used here or in the selects unioned after it
+    fn parameter_count(&self) -> usize {
+        let items = self.select_list.iter().filter_map(|item| match item {
+            SelectItem::Expr(expr) => Some(expr),
+            SelectItem::Aggregate(_) => None,
+        });
+        let count = items
+            .chain(&self.where_clause)
+            .map(Expr::parameter_count)
+            .chain(
+                self.with
+                    .iter()
+                    .map(|common| common.select.parameter_count()),
+            )
+            .max()
+            .unwrap_or(0);
+        match &self.union {
+            Some(union) => count.max(union.select.parameter_count()),
+            None => count,
+        }
+    }
+}
+
+impl Default for SelectStmt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Parse a statement once so it can be bound and executed many times
+pub fn prepare(input: &str) -> Result<Statement, PrepareError> {
+    let sql = input.trim_start();
+    let statement = prepare_trimmed(sql).map_err(|error| error.shifted(input.len() - sql.len()))?;
+    statement.check_names()?;
+    Ok(statement)
+}
+
+fn prepare_trimmed(sql: &str) -> Result<Statement, PrepareError> {
+    if let Some(rest) =
+        strip_keyword(sql, "create").and_then(|rest| strip_keyword(rest.trim_start(), "trigger"))
+    {
+        return prepare_create_trigger(rest)
+            .map(Statement::CreateTrigger)
+            .map_err(|error| PrepareError::SyntaxError(error.shifted(sql.len() - rest.len())));
+    }
+    if let Some(rest) = strip_keyword(sql, "explain") {
+        let (analyze, rest) = match strip_keyword(rest.trim_start(), "analyze") {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let statement = prepare(rest).map_err(|error| error.shifted(sql.len() - rest.len()))?;
+        return Ok(Statement::Explain(ExplainStmt {
+            analyze,
+            statement: Box::new(statement),
+        }));
+    }
+    if sql
+        .get(..6)
+        .is_some_and(|command| command.eq_ignore_ascii_case("insert"))
+    {
+        return prepare_insert(sql)
+            .map(Statement::Insert)
+            .map_err(PrepareError::SyntaxError);
+    }
+
+    let mut parser = Parser::new(sql).map_err(PrepareError::SyntaxError)?;
+    let parsed = if parser.eat_keyword("with") {
+        prepare_with(&mut parser).map(|select| Statement::Select(Box::new(select)))
+    } else if parser.eat_keyword("select") {
+        prepare_select(&mut parser).map(|select| Statement::Select(Box::new(select)))
+    } else if parser.eat_keyword("create") {
+        if parser.eat_keyword("index") {
+            prepare_create_index(&mut parser).map(Statement::CreateIndex)
+        } else {
+            prepare_create_table(&mut parser).map(Statement::CreateTable)
+        }
+    } else if parser.eat_keyword("drop") {
+        prepare_table_name(&mut parser).map(Statement::DropTable)
+    } else if parser.eat_keyword("delete") {
+        prepare_delete(&mut parser).map(Statement::Delete)
+    } else if parser.eat_keyword("truncate") {
+        prepare_truncate(&mut parser).map(|table_name| {
+            Statement::Delete(DeleteStmt {
+                table_name,
+                where_clause: None,
+                returning: None,
+            })
+        })
+    } else if parser.eat_keyword("alter") {
+        prepare_add_column(&mut parser).map(Statement::AlterTable)
+    } else if parser.eat_keyword("savepoint") {
+        prepare_savepoint(&mut parser).map(Statement::Savepoint)
+    } else if parser.eat_keyword("release") {
+        parser.eat_keyword("savepoint");
+        prepare_savepoint(&mut parser).map(Statement::Release)
+    } else if parser.eat_keyword("rollback") {
+        if !parser.eat_keyword("to") {
+            return Err(PrepareError::SyntaxError(parser.error()));
+        }
+        parser.eat_keyword("savepoint");
+        prepare_savepoint(&mut parser).map(Statement::RollbackTo)
+    } else if parser.eat_keyword("pragma") {
+        prepare_pragma(&mut parser).map(Statement::Pragma)
+    } else if parser.eat_keyword("vacuum") {
+        parser.is_done().then_some(Statement::Vacuum)
+    } else {
+        return Err(PrepareError::UnrecognizedStatement);
+    };
+
+    parsed.ok_or_else(|| PrepareError::SyntaxError(parser.error()))
+}
+
+// The clauses following "select", up to the end of the statement
+fn prepare_select(parser: &mut Parser) -> Option<SelectStmt> {
+    let mut select = SelectStmt::new();
+    select.prepare_clauses(parser)?;
+    if !parser.is_done() {
+        return None;
+    }
+    Some(select)
+}
+
+// <name> [(<columns>)] as (select ...), ... select ...
+// following "with". Each common table can read the ones before it.
+fn prepare_with(parser: &mut Parser) -> Option<SelectStmt> {
+    let mut with: Vec<CommonTable> = Vec::new();
+    loop {
+        let start = parser.pos;
+        let name = parser.name()?;
+        if with.iter().any(|common| common.name == name) {
+            parser.fail_at(start, "duplicate common table");
+            return None;
+        }
+        let columns = if parser.eat_symbol('(') {
+            Some(parser.column_list()?)
+        } else {
+            None
+        };
+        if !parser.eat_keyword("as") || !parser.eat_symbol('(') || !parser.eat_keyword("select") {
+            return None;
+        }
+        let mut select = SelectStmt::new();
+        select.prepare_clauses(parser)?;
+        if !parser.eat_symbol(')') {
+            return None;
+        }
+        with.push(CommonTable {
+            name,
+            columns,
+            select,
+        });
+        if !parser.eat_symbol(',') {
+            break;
+        }
+    }
+
+    if !parser.eat_keyword("select") {
+        return None;
+    }
+    let mut select = prepare_select(parser)?;
+    select.with = with;
+    Some(select)
+}
+
+// insert [or replace] [into <table>] [(<columns>)] values (<literals>)
+// or
+// insert [or replace] [into <table>] <value> <value> ...
+fn prepare_insert(sql: &str) -> Result<InsertStmt, SyntaxError> {
+    let mut insert = InsertStmt {
+        table_name: DEFAULT_TABLE.to_string(),
+        columns: None,
+        values: Vec::new(),
+        parameters: Vec::new(),
+        or_replace: false,
+        returning: None,
+    };
+
+    if let Ok(mut parser) = Parser::new(sql) {
+        parser.eat_keyword("insert");
+        if parser.eat_keyword("or") {
+            if !parser.eat_keyword("replace") {
+                return Err(parser.error());
+            }
+            insert.or_replace = true;
+        }
+        if parser.eat_keyword("into") {
+            match parser.name() {
+                Some(name) => insert.table_name = name,
+                None => return Err(parser.error()),
+            }
+        }
+        if parser.peek_symbol('(') || parser.peek_word() == Some("values") {
+            return match insert.prepare_values(&mut parser) {
+                Some(()) => Ok(insert),
+                None => Err(parser.error()),
+            };
+        }
+    }
+
+    // Every value starts out as text and is converted to the type of its
+    // column when the row is built
+    let mut parts = split_values(sql)?.into_iter().peekable();
+    // Only the keywords are matched without case, values are kept as typed
+    let keyword = |part: &(Token, Range<usize>), keyword: &str| matches!(&part.0, Token::Word(word) if word.eq_ignore_ascii_case(keyword));
+    let _command = parts.next();
+    if parts.next_if(|part| keyword(part, "or")).is_some() {
+        match parts.next() {
+            Some(part) if keyword(&part, "replace") => insert.or_replace = true,
+            part => {
+                let span = part.map_or(sql.len()..sql.len(), |(_, span)| span);
+                return Err(SyntaxError::new(span, "expected replace"));
+            }
+        }
+    }
+    if parts.next_if(|part| keyword(part, "into")).is_some() {
+        parts.next();
+    }
+    for part in parts {
+        match part {
+            (Token::Word(word), _) if word == "?" => {
+                insert.parameters.push(insert.values.len());
+                insert.values.push(Value::Null);
+            }
+            (Token::Word(value) | Token::Str(value), _) => insert.values.push(Value::Text(value)),
+            (Token::Symbol(_), span) => {
+                return Err(SyntaxError::new(span, "expected a value"));
+            }
+        }
+    }
+    if insert.values.is_empty() {
+        let end = sql.len()..sql.len();
+        return Err(SyntaxError::new(end, "expected a value"));
+    }
+
+    Ok(insert)
+}
+
+impl InsertStmt {
+    fn prepare_values(&mut self, parser: &mut Parser) -> Option<()> {
+        if parser.eat_symbol('(') {
+            self.columns = Some(parser.column_list()?);
+        }
+
+        // (<literal or ?>, ...)
+        let start = parser.pos;
+        if !parser.eat_keyword("values") || !parser.eat_symbol('(') {
+            return None;
+        }
+        loop {
+            if parser.eat_symbol('?') {
+                parser.parameter();
+                self.parameters.push(self.values.len());
+                self.values.push(Value::Null);
+            } else {
+                self.values.push(parser.literal()?);
+            }
+            if !parser.eat_symbol(',') {
+                break;
+            }
+        }
+        if !parser.eat_symbol(')') {
+            return None;
+        }
+        if (self.columns.as_ref()).is_some_and(|columns| columns.len() != self.values.len()) {
+            parser.fail_at(start, "value count does not match the column list");
+            return None;
+        }
+        self.returning = parser.returning()?;
+
+        if !parser.is_done() {
+            return None;
+        }
+        Some(())
+    }
+}
+
+// <name> after insert|delete on <table> begin <statement>; ... end
+// following "create trigger". The body is kept as text and prepared
+// again every time the trigger fires.
+fn prepare_create_trigger(sql: &str) -> Result<CreateTriggerStmt, SyntaxError> {
+    let end = sql.len()..sql.len();
+    let lower = sql.to_ascii_lowercase();
+    let begin = lower.match_indices("begin").map(|(i, _)| i).find(|&i| {
+        strip_keyword(&sql[i..], "begin").is_some()
+            && !sql[..i].ends_with(|c: char| c.is_alphanumeric() || c == '_')
+    });
+    let Some(begin) = begin else {
+        return Err(SyntaxError::new(end, "expected begin"));
+    };
+
+    let mut parser = Parser::new(&sql[..begin])?;
+    let Some((name, event, table_name)) = prepare_trigger_header(&mut parser) else {
+        return Err(parser.error());
+    };
+
+    // Only inserts and deletes, reading the row through new.<column> or
+    // old.<column>
+    let after = &sql[begin + "begin".len()..];
+    let Some(inner) = strip_last_keyword(after.trim_end(), "end") else {
+        return Err(SyntaxError::new(end, "expected end"));
+    };
+    let body = inner.trim();
+    let start = sql.len() - after.len() + inner.len() - inner.trim_start().len();
+    let statements = split_statements(body);
+    let valid = !statements.is_empty()
+        && statements.iter().all(|statement| {
+            let (statement, _) = row_references(statement, event.prefix());
+            prepare(&statement).is_ok_and(|statement| match statement {
+                Statement::Insert(insert) => insert.returning.is_none(),
+                Statement::Delete(delete) => delete.returning.is_none(),
+                _ => false,
+            })
+        });
+    if !valid {
+        let span = start..start + body.len();
+        return Err(SyntaxError::new(
+            span,
+            "expected insert or delete statements",
+        ));
+    }
+
+    Ok(CreateTriggerStmt {
+        table_name,
+        trigger: Trigger {
+            name,
+            event,
+            body: body.to_string(),
+        },
+    })
+}
+
+// <name> after insert|delete on <table>
+fn prepare_trigger_header(parser: &mut Parser) -> Option<(String, TriggerEvent, String)> {
+    let name = parser.name()?;
+    if !parser.eat_keyword("after") {
+        return None;
+    }
+    let event = if parser.eat_keyword("insert") {
+        TriggerEvent::Insert
+    } else if parser.eat_keyword("delete") {
+        TriggerEvent::Delete
+    } else {
+        return None;
+    };
+    if !parser.eat_keyword("on") {
+        return None;
+    }
+    let table_name = parser.name()?;
+    if !parser.is_done() {
+        return None;
+    }
+    Some((name, event, table_name))
+}
+
+// table <name>
+fn prepare_table_name(parser: &mut Parser) -> Option<String> {
+    if !parser.eat_keyword("table") {
+        return None;
+    }
+    let table_name = parser.name()?;
+
+    if !parser.is_done() {
+        return None;
+    }
+    Some(table_name)
+}
+
+// index <name> on <table> (<column>)
+fn prepare_create_index(parser: &mut Parser) -> Option<CreateIndexStmt> {
+    let name = parser.name()?;
+    if !parser.eat_keyword("on") {
+        return None;
+    }
+    let table_name = parser.name()?;
+    if !parser.eat_symbol('(') {
+        return None;
+    }
+    let column = parser.name()?;
+    if !parser.eat_symbol(')') || !parser.is_done() {
+        return None;
+    }
+    Some(CreateIndexStmt {
+        table_name,
+        index: Index { name, column },
+    })
+}
+
+// table <name> [(<column> <type>, ..., [check (<expr>)], ...)]
+fn prepare_create_table(parser: &mut Parser) -> Option<CreateTableStmt> {
+    if !parser.eat_keyword("table") {
+        return None;
+    }
+    let table_name = parser.name()?;
+
+    // Tables created without a column list get the id/username/email one
+    let mut schema = Schema::default();
+    if parser.eat_symbol('(') {
+        let mut columns: Vec<ColumnDef> = Vec::new();
+        let mut checks = Vec::new();
+        loop {
+            if parser.eat_keyword("check") {
+                checks.push(parser.check()?);
+                if !parser.eat_symbol(',') {
+                    break;
+                }
+                continue;
+            }
+
+            // Names must be unique and only one column can be the key
+            let start = parser.pos;
+            let column = parser.column_def(&mut checks)?;
+            if columns.iter().any(|other| other.name == column.name) {
+                parser.fail_at(start, "duplicate column");
+                return None;
+            }
+            if columns
+                .iter()
+                .any(|other| other.primary_key && column.primary_key)
+            {
+                parser.fail_at(start, "more than one primary key");
+                return None;
+            }
+            columns.push(column);
+            if !parser.eat_symbol(',') {
+                break;
+            }
+        }
+        if !parser.eat_symbol(')') {
+            return None;
+        }
+        if columns.is_empty() {
+            return None;
+        }
+        schema = Schema {
+            columns,
+            checks,
+            triggers: Vec::new(),
+            indexes: Vec::new(),
+        };
+    }
+
+    // Checks are stored with the table, there is nothing to bind a ? to
+    if !parser.is_done() || parser.parameters > 0 {
+        return None;
+    }
+    Some(CreateTableStmt { table_name, schema })
+}
+
+// from <name> [where <condition>] [returning <items>]
+// Deleting every row is done by resetting the table's pages rather than
+// removing rows one at a time
+fn prepare_delete(parser: &mut Parser) -> Option<DeleteStmt> {
+    if !parser.eat_keyword("from") {
+        return None;
+    }
+    let table_name = parser.name()?;
+    let where_clause = match parser.eat_keyword("where") {
+        true => Some(parser.expr()?),
+        false => None,
+    };
+    let returning = parser.returning()?;
+
+    if !parser.is_done() {
+        return None;
+    }
+    Some(DeleteStmt {
+        table_name,
+        where_clause,
+        returning,
+    })
+}
+
+// [table] <name>
+fn prepare_truncate(parser: &mut Parser) -> Option<String> {
+    parser.eat_keyword("table");
+    let table_name = parser.name()?;
+
+    if !parser.is_done() {
+        return None;
+    }
+    Some(table_name)
+}
+
+// <name> [= <value>] following "pragma"
+fn prepare_pragma(parser: &mut Parser) -> Option<PragmaStmt> {
+    let name = parser.name()?;
+    let value = if parser.eat_symbol('=') {
+        Some(parser.name()?)
+    } else {
+        None
+    };
+
+    if !parser.is_done() {
+        return None;
+    }
+    Some(PragmaStmt { name, value })
+}
+
+// <name> of a savepoint
+fn prepare_savepoint(parser: &mut Parser) -> Option<String> {
+    let name = parser.name()?;
+
+    if !parser.is_done() {
+        return None;
+    }
+    Some(name)
+}
+
+// table <name> add [column] <column definition>
+// The rows already in the table get the column's default, so it can't be
+// a key, unique or checked
+fn prepare_add_column(parser: &mut Parser) -> Option<AlterTableStmt> {
+    if !parser.eat_keyword("table") {
+        return None;
+    }
+    let table_name = parser.name()?;
+    if !parser.eat_keyword("add") {
+        return None;
+    }
+    parser.eat_keyword("column");
+
+    let start = parser.pos;
+    let mut checks = Vec::new();
+    let column = parser.column_def(&mut checks)?;
+    if column.primary_key || column.unique {
+        parser.fail_at(start, "cannot add a primary key or unique column");
+        return None;
+    }
+    if !checks.is_empty() {
+        parser.fail_at(start, "cannot add a column with a check");
+        return None;
+    }
+
+    if !parser.is_done() {
+        return None;
+    }
+    Some(AlterTableStmt { table_name, column })
+}
+
+impl SelectStmt {
+    // Parse the optional clauses following "select", up to the end of the
+    // statement or the parenthesis closing a common table
+    fn prepare_clauses(&mut self, parser: &mut Parser) -> Option<()> {
+        // "*" is the same as leaving the select list out
+        let starts_select_list = !parser.eat_symbol('*')
+            && !parser.is_done()
+            && !parser.peek_word().is_some_and(is_clause_keyword);
+        if starts_select_list {
+            loop {
+                self.select_list.push(parser.select_item()?);
+                self.aliases.push(parser.alias()?);
+                if !parser.eat_symbol(',') {
+                    break;
+                }
+            }
+        }
+
+        if parser.eat_keyword("from") {
+            self.table_name = parser.name()?;
+
+            if matches!(parser.peek_word(), Some("join" | "left" | "inner")) {
+                self.join = Some(parser.join(&self.table_name)?);
+            }
+        } else {
+            // A list such as 1 + 2 or upper('abc') needs no table, while
+            // one reading columns or counting rows reads the default table
+            let mut names = Vec::new();
+            let constant = self.select_list.iter().all(|item| match item {
+                SelectItem::Expr(expr) => {
+                    expr.column_names(&mut names);
+                    names.is_empty()
+                }
+                SelectItem::Aggregate(_) => false,
+            });
+            self.constant_row = constant && !self.select_list.is_empty();
+        }
+
+        if parser.eat_keyword("where") {
+            self.where_clause = Some(parser.expr()?);
+        }
+
+        // Only the columns of a join go by their table's name as well, a
+        // single table's are read by their own
+        if self.join.is_none() {
+            let table = format!("{}.", self.table_name);
+            for expr in self.exprs_mut() {
+                expr.walk_mut(&mut |expr| {
+                    if let Expr::Column(name) = expr
+                        && let Some(column) = name.strip_prefix(&table)
+                    {
+                        *name = column.to_string();
+                    }
+                });
+            }
+        }
+
+        if parser.eat_keyword("group") {
+            if !parser.eat_keyword("by") {
+                return None;
+            }
+            self.group_by = Some(parser.name()?);
+        }
+
+        if parser.eat_keyword("having") {
+            let aggregate = parser.aggregate()?;
+            let op = parser.compare_op()?;
+            let value = parser.literal()?;
+            self.having = Some(Having {
+                aggregate,
+                op,
+                value,
+            });
+        }
+
+        if parser.eat_keyword("order") {
+            if !parser.eat_keyword("by") {
+                return None;
+            }
+
+            let column = parser.name()?;
+            let collation = if parser.eat_keyword("collate") {
+                Some(parser.collation()?)
+            } else {
+                None
+            };
+            let descending = if parser.eat_keyword("desc") {
+                true
+            } else {
+                parser.eat_keyword("asc");
+                false
+            };
+
+            self.order_by = Some(OrderBy {
+                column,
+                collation,
+                descending,
+            });
+        }
+
+        self.prepare_limit_clauses(parser)
+    }
+
+    fn prepare_limit_clauses(&mut self, parser: &mut Parser) -> Option<()> {
+        if parser.eat_keyword("limit") {
+            self.limit = Some(parser.number()?);
+        }
+
+        if parser.eat_keyword("offset") {
+            self.offset = parser.number()?;
+        }
+
+        // Clauses before a UNION belong to the select on their left
+        if parser.eat_keyword("union") {
+            let all = parser.eat_keyword("all");
+            if !parser.eat_keyword("select") {
+                return None;
+            }
+
+            // Column counts are compared once the tables' schemas are known
+            let mut select = SelectStmt::new();
+            select.prepare_clauses(parser)?;
+            self.union = Some(Union {
+                all,
+                select: Box::new(select),
+            });
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem_storage::execute_statement;
+
+    #[test]
+    fn open_of_the_open_file_does_nothing() {
+        let directory = std::env::temp_dir();
+        let name = format!("rustlite-test-{}-open.db", std::process::id());
+        let path = directory.join(&name).to_string_lossy().into_owned();
+        let _ = std::fs::remove_file(&path);
+        let table = Rc::new(RefCell::new(Table::db_open(&path, None).unwrap()));
+        let run = |sql: &str| {
+            let Ok(statement) = prepare(sql) else {
+                panic!("can't prepare {}", sql);
+            };
+            let mut rows = Vec::new();
+            let result = execute_statement(Rc::clone(&table), &statement, &mut |values| {
+                rows.push(values)
+            });
+            assert!(result.is_success(), "{} failed", sql);
+            rows
+        };
+        run("create table t (id integer primary key)");
+        run("savepoint s");
+        run("insert into t values (1)");
+
+        // Closing the database would have rolled the savepoint back, the
+        // file is the same however it is named
+        let same = directory.join(".").join(&name);
+        let result = open_command(&table, &same.to_string_lossy());
+        assert!(matches!(result, MetaCommandResult::CommandSuccess));
+        run("release s");
+        assert_eq!(run("select * from t").len(), 1);
 
-        PrepareResult::UnrecognizedStatement
+        assert!(table.borrow_mut().db_close().is_ok());
+        let _ = std::fs::remove_file(&path);
     }
 }